@@ -0,0 +1,372 @@
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::anyhow;
+use deadpool_sqlite::Pool;
+use rusqlite::{params, OptionalExtension};
+use tokio::{sync::broadcast, task::JoinHandle};
+use tokio_stream::{wrappers::BroadcastStream, StreamExt, StreamMap};
+
+use crate::{Message, ThreadRef};
+
+#[derive(Clone, Debug)]
+pub(crate) struct ArchivedMessage {
+    /// The bus the message went over.
+    pub(crate) bus: String,
+    pub(crate) transport: String,
+    pub(crate) username: String,
+    pub(crate) message: Option<String>,
+    pub(crate) is_action: bool,
+    /// The thread the message was posted in, if any.
+    pub(crate) thread_root_id: Option<String>,
+    pub(crate) reply_target_id: Option<u64>,
+    /// Unix time the message first went over the bus.
+    pub(crate) created: i64,
+}
+
+/// Keeps the text and thread membership of everything that goes over the
+/// buses, so that transports without native threads can show a thread's
+/// history on request. Messages older than `days` are pruned.
+pub(crate) struct Archive {
+    pool: Pool,
+    days: u32,
+}
+
+impl Archive {
+    pub async fn new(pool: Pool, days: u32) -> anyhow::Result<Archive> {
+        // Thread references for IRC reactions and /whois-message depend on
+        // the archive, so it can't be turned off.
+        if days == 0 {
+            return Err(anyhow!("archive_days must be at least 1"));
+        }
+
+        pool.get()
+            .await?
+            .interact(|conn| -> anyhow::Result<()> {
+                conn.execute_batch(
+                    "CREATE TABLE IF NOT EXISTS archive (
+                                     pipo_id         INTEGER PRIMARY KEY,
+                                     bus             TEXT NOT NULL,
+                                     transport       TEXT NOT NULL,
+                                     username        TEXT NOT NULL,
+                                     message         TEXT,
+                                     is_action       INTEGER NOT NULL,
+                                     thread_root_id  TEXT,
+                                     reply_target_id INTEGER,
+                                     created         INTEGER DEFAULT (CAST(strftime('%s', 'now') AS INTEGER))
+                                     );
+                     CREATE INDEX IF NOT EXISTS archive_thread_root_id
+                       ON archive (bus, thread_root_id);
+                     CREATE INDEX IF NOT EXISTS archive_reply_target_id
+                       ON archive (bus, reply_target_id);",
+                )?;
+
+                Ok(())
+            })
+            .await
+            .unwrap_or_else(|_| Err(anyhow!("Interact Error")))?;
+
+        Ok(Archive { pool, days })
+    }
+
+    /// Subscribes to every bus right away, so nothing sent after this
+    /// returns is missed, and archives messages in the background.
+    pub fn spawn(
+        self: Arc<Self>,
+        bus_map: &HashMap<String, broadcast::Sender<Message>>,
+    ) -> JoinHandle<()> {
+        let mut buses = StreamMap::new();
+
+        for (bus, sender) in bus_map.iter() {
+            buses.insert(bus.clone(), BroadcastStream::new(sender.subscribe()));
+        }
+
+        tokio::spawn(async move {
+            while let Some((bus, message)) = buses.next().await {
+                let Ok(message) = message else {
+                    eprintln!("Archive lagged behind on bus {}", bus);
+                    continue;
+                };
+
+                if let Err(e) = self.handle_bus_message(&bus, message).await {
+                    eprintln!("Failed to archive message on bus {}: {:#}", bus, e);
+                }
+            }
+        })
+    }
+
+    async fn handle_bus_message(&self, bus: &str, message: Message) -> anyhow::Result<()> {
+        let (pipo_id, transport, username, message, is_action, thread, is_edit) = match message {
+            Message::Text {
+                pipo_id,
+                transport,
+                username,
+                thread,
+                message,
+                is_edit,
+                ..
+            } => (
+                pipo_id, transport, username, message, false, thread, is_edit,
+            ),
+            Message::Action {
+                pipo_id,
+                transport,
+                username,
+                thread,
+                message,
+                is_edit,
+                ..
+            } => (pipo_id, transport, username, message, true, thread, is_edit),
+            Message::Delete { pipo_id, .. } => return self.delete(pipo_id).await,
+            _ => return Ok(()),
+        };
+        let conn = self.pool.get().await?;
+        let bus = bus.to_string();
+        let days = self.days;
+        let thread_root_id = thread
+            .as_ref()
+            .and_then(|thread| thread.thread_root_id.clone());
+        let reply_target_id = thread
+            .as_ref()
+            .and_then(|thread| thread.reply_target_id)
+            .map(|id| id as i64);
+
+        conn.interact(move |conn| -> anyhow::Result<()> {
+            // Edits only replace the text; the message keeps its place in
+            // the thread.
+            if is_edit
+                && conn.execute(
+                    "UPDATE archive SET message = ?2 WHERE pipo_id = ?1",
+                    params![pipo_id, message],
+                )? > 0
+            {
+                return Ok(());
+            }
+
+            conn.execute(
+                "DELETE FROM archive
+                 WHERE created < CAST(strftime('%s', 'now', ?1) AS INTEGER)",
+                params![format!("-{} days", days)],
+            )?;
+            conn.execute(
+                "INSERT OR IGNORE INTO archive
+                   (pipo_id, bus, transport, username, message, is_action,
+                    thread_root_id, reply_target_id)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                params![
+                    pipo_id,
+                    bus,
+                    transport,
+                    username,
+                    message,
+                    is_action,
+                    thread_root_id,
+                    reply_target_id
+                ],
+            )?;
+
+            Ok(())
+        })
+        .await
+        .unwrap_or_else(|_| Err(anyhow!("Interact Error")))
+    }
+
+    async fn delete(&self, pipo_id: i64) -> anyhow::Result<()> {
+        let conn = self.pool.get().await?;
+
+        conn.interact(move |conn| -> anyhow::Result<()> {
+            conn.execute("DELETE FROM archive WHERE pipo_id = ?1", params![pipo_id])?;
+
+            Ok(())
+        })
+        .await
+        .unwrap_or_else(|_| Err(anyhow!("Interact Error")))
+    }
+
+    /// The archived copy of `pipo_id`, if it's still kept.
+    pub async fn select(&self, pipo_id: i64) -> anyhow::Result<Option<ArchivedMessage>> {
+        let conn = self.pool.get().await?;
+
+        conn.interact(move |conn| -> anyhow::Result<Option<ArchivedMessage>> {
+            Ok(conn
+                .query_row(
+                    "SELECT transport, username, message, is_action, created,
+                            thread_root_id, reply_target_id, bus
+                     FROM archive
+                     WHERE pipo_id = ?1",
+                    params![pipo_id],
+                    |row| {
+                        Ok(ArchivedMessage {
+                            transport: row.get(0)?,
+                            username: row.get(1)?,
+                            message: row.get(2)?,
+                            is_action: row.get(3)?,
+                            created: row.get(4)?,
+                            thread_root_id: row.get(5)?,
+                            reply_target_id: row.get::<_, Option<i64>>(6)?.map(|id| id as u64),
+                            bus: row.get(7)?,
+                        })
+                    },
+                )
+                .optional()?)
+        })
+        .await
+        .unwrap_or_else(|_| Err(anyhow!("Interact Error")))
+    }
+
+    /// The last `limit` messages of `thread` on `bus`, oldest first. Replies
+    /// from every transport are matched by either of the thread's ids, and
+    /// the root message is found through the messages table.
+    pub async fn thread_history(
+        &self,
+        bus: &str,
+        thread: &ThreadRef,
+        limit: usize,
+    ) -> anyhow::Result<Vec<ArchivedMessage>> {
+        let conn = self.pool.get().await?;
+        let bus = bus.to_string();
+        let thread_root_id = thread.thread_root_id.clone();
+        let reply_target_id = thread.reply_target_id.map(|id| id as i64);
+
+        conn.interact(move |conn| -> anyhow::Result<Vec<ArchivedMessage>> {
+            let mut stmt = conn.prepare(
+                "SELECT transport, username, message, is_action, created,
+                        thread_root_id, reply_target_id, bus
+                 FROM archive
+                 WHERE bus = ?1
+                   AND (thread_root_id = ?2
+                        OR reply_target_id = ?3
+                        OR pipo_id IN (SELECT id FROM messages
+                                       WHERE slackid = ?2 OR discordid = ?3))
+                 ORDER BY pipo_id DESC
+                 LIMIT ?4",
+            )?;
+            let mut messages = stmt
+                .query_map(
+                    params![bus, thread_root_id, reply_target_id, limit as i64],
+                    |row| {
+                        Ok(ArchivedMessage {
+                            transport: row.get(0)?,
+                            username: row.get(1)?,
+                            message: row.get(2)?,
+                            is_action: row.get(3)?,
+                            created: row.get(4)?,
+                            thread_root_id: row.get(5)?,
+                            reply_target_id: row.get::<_, Option<i64>>(6)?.map(|id| id as u64),
+                            bus: row.get(7)?,
+                        })
+                    },
+                )?
+                .collect::<Result<Vec<_>, _>>()?;
+
+            messages.reverse();
+
+            Ok(messages)
+        })
+        .await
+        .unwrap_or_else(|_| Err(anyhow!("Interact Error")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use deadpool_sqlite::{Config, Runtime};
+
+    fn text(
+        pipo_id: i64,
+        username: &str,
+        message: &str,
+        thread: Option<ThreadRef>,
+        is_edit: bool,
+    ) -> Message {
+        Message::Text {
+            sender: 0,
+            pipo_id,
+            transport: "Slack".to_string(),
+            username: username.to_string(),
+            avatar_url: None,
+            thread,
+            reply_to: None,
+            message: Some(message.to_string()),
+            attachments: None,
+            is_edit,
+            irc_flag: false,
+            late: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn thread_history_includes_root_and_edits() {
+        let pool = Config::new(":memory:")
+            // Every connection to ":memory:" opens its own database.
+            .builder(Runtime::Tokio1)
+            .expect("pool config")
+            .max_size(1)
+            .build()
+            .expect("pool");
+
+        pool.get()
+            .await
+            .expect("connection")
+            .interact(|conn| {
+                conn.execute_batch(
+                    "CREATE TABLE messages (id INTEGER PRIMARY KEY, slackid TEXT, discordid INTEGER);
+                     INSERT INTO messages (id, slackid) VALUES (1, '1700000000.000100');",
+                )
+            })
+            .await
+            .expect("interact")
+            .expect("messages table");
+
+        let archive = Archive::new(pool, 30).await.expect("archive");
+        let thread = ThreadRef {
+            origin_transport: "Slack".to_string(),
+            thread_root_id: Some("1700000000.000100".to_string()),
+            ..Default::default()
+        };
+
+        for message in [
+            text(1, "alice", "root", None, false),
+            text(2, "bob", "reply", Some(thread.clone()), false),
+            text(3, "carol", "unrelated", None, false),
+            text(2, "bob", "edited reply", Some(thread.clone()), true),
+        ] {
+            archive
+                .handle_bus_message("main", message)
+                .await
+                .expect("archive message");
+        }
+
+        let history = archive
+            .thread_history("main", &thread, 10)
+            .await
+            .expect("history");
+
+        assert_eq!(
+            history
+                .iter()
+                .map(|entry| (entry.username.as_str(), entry.message.as_deref()))
+                .collect::<Vec<_>>(),
+            vec![("alice", Some("root")), ("bob", Some("edited reply"))]
+        );
+        assert!(history.iter().all(|entry| entry.bus == "main"));
+        assert!(archive
+            .thread_history("other", &thread, 10)
+            .await
+            .expect("history")
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn zero_days_are_rejected() {
+        let pool = Config::new(":memory:")
+            // Every connection to ":memory:" opens its own database.
+            .builder(Runtime::Tokio1)
+            .expect("pool config")
+            .max_size(1)
+            .build()
+            .expect("pool");
+
+        assert!(Archive::new(pool, 0).await.is_err());
+    }
+}
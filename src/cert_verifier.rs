@@ -0,0 +1,153 @@
+use std::sync::Arc;
+
+use sha2::{Digest, Sha256};
+use tokio_rustls::rustls::{
+    client::{
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        WebPkiServerVerifier,
+    },
+    crypto::{
+        aws_lc_rs, verify_tls12_signature, verify_tls13_signature, WebPkiSupportedAlgorithms,
+    },
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    DigitallySignedStruct, Error, SignatureScheme,
+};
+
+/// Accepts server certificates whose SHA-256 fingerprint is pinned. All
+/// others are left to `webpki`, or refused without it.
+#[derive(Debug)]
+pub(crate) struct PinnedCertVerifier {
+    fingerprints: Vec<String>,
+    webpki: Option<Arc<WebPkiServerVerifier>>,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl PinnedCertVerifier {
+    /// Accepts only `cert`, e.g. a Mumble server's self-signed one.
+    pub fn new(cert: &CertificateDer<'_>) -> Self {
+        Self::with_fingerprints(vec![fingerprint(cert)], None)
+    }
+
+    /// Accepts certificates with one of `fingerprints`, "AB:CD:..." or
+    /// "abcd...", and whatever `webpki` accepts.
+    pub fn with_fingerprints(
+        fingerprints: Vec<String>,
+        webpki: Option<Arc<WebPkiServerVerifier>>,
+    ) -> Self {
+        Self {
+            fingerprints: fingerprints
+                .iter()
+                .map(|fingerprint| normalize_fingerprint(fingerprint))
+                .collect(),
+            webpki,
+            algorithms: aws_lc_rs::default_provider().signature_verification_algorithms,
+        }
+    }
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, Error> {
+        let fingerprint = fingerprint(end_entity);
+
+        if self.fingerprints.contains(&fingerprint) {
+            return Ok(ServerCertVerified::assertion());
+        }
+
+        let Some(webpki) = self.webpki.as_ref() else {
+            return Err(Error::General(format!(
+                "invalid peer certificate: its fingerprint {} isn't pinned",
+                fingerprint
+            )));
+        };
+
+        webpki
+            .verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)
+            .map_err(|e| {
+                if self.fingerprints.is_empty() {
+                    e
+                } else {
+                    Error::General(format!(
+                        "{} and its fingerprint {} isn't pinned",
+                        e, fingerprint
+                    ))
+                }
+            })
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+/// The lowercase hex SHA-256 fingerprint of `cert`.
+fn fingerprint(cert: &CertificateDer<'_>) -> String {
+    Sha256::digest(cert.as_ref())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// "AB:CD:..." or "abcd..." in, "abcd..." out.
+fn normalize_fingerprint(fingerprint: &str) -> String {
+    fingerprint
+        .chars()
+        .filter(char::is_ascii_hexdigit)
+        .map(|ch| ch.to_ascii_lowercase())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fingerprints_are_normalized() {
+        assert_eq!(normalize_fingerprint("AB:cd:0F"), "abcd0f");
+        assert_eq!(normalize_fingerprint(" ab cd "), "abcd");
+    }
+
+    #[test]
+    fn only_pinned_certificates_are_accepted_without_webpki() {
+        let cert = CertificateDer::from(vec![1, 2, 3]);
+        let verifier = PinnedCertVerifier::new(&cert);
+        let server_name = ServerName::try_from("mumble.example.net").expect("server name");
+
+        assert!(verifier
+            .verify_server_cert(&cert, &[], &server_name, &[], UnixTime::now())
+            .is_ok());
+        assert!(verifier
+            .verify_server_cert(
+                &CertificateDer::from(vec![4, 5, 6]),
+                &[],
+                &server_name,
+                &[],
+                UnixTime::now()
+            )
+            .is_err());
+    }
+}
@@ -0,0 +1,179 @@
+use anyhow::anyhow;
+use deadpool_sqlite::Pool;
+use rusqlite::{params, OptionalExtension};
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::BroadcastStream;
+
+use crate::Message;
+
+/// Carries private messages between users of different transports over a
+/// dedicated bus. Only users on their own transport's allowlist can send
+/// or receive bridged DMs. Who last wrote to whom is kept in the
+/// `direct_conversations` table, so that a plain reply goes back to the
+/// right person, also after a restart.
+pub(crate) struct DirectBridge {
+    transport_id: usize,
+    bus: broadcast::Sender<Message>,
+    allowlist: Vec<String>,
+    // Folds user names before conversations are stored or looked up, e.g.
+    // with the rfc1459 casemapping of IRC nicks.
+    casemapping: fn(&str) -> String,
+    pool: Pool,
+}
+
+impl DirectBridge {
+    pub async fn new(
+        transport_id: usize,
+        bus: broadcast::Sender<Message>,
+        allowlist: &[String],
+        casemapping: fn(&str) -> String,
+        pool: Pool,
+    ) -> anyhow::Result<DirectBridge> {
+        let conn = pool.get().await?;
+
+        conn.interact(|conn| -> anyhow::Result<()> {
+            conn.execute_batch(
+                "CREATE TABLE IF NOT EXISTS direct_conversations (
+                                 transport_id      INTEGER NOT NULL,
+                                 user              TEXT NOT NULL,
+                                 peer_transport_id INTEGER,
+                                 peer_user         TEXT NOT NULL,
+                                 updated           INTEGER NOT NULL,
+                                 PRIMARY KEY (transport_id, user)
+                                 );",
+            )?;
+
+            Ok(())
+        })
+        .await
+        .unwrap_or_else(|_| Err(anyhow!("Interact Error")))?;
+
+        Ok(DirectBridge {
+            transport_id,
+            bus,
+            allowlist: allowlist.to_vec(),
+            casemapping,
+            pool,
+        })
+    }
+
+    pub fn subscribe(&self) -> BroadcastStream<Message> {
+        BroadcastStream::new(self.bus.subscribe())
+    }
+
+    /// Whether a user known by any of `names` (nick, username, id) is on
+    /// the allowlist. Names are compared case-insensitively.
+    pub fn allows(&self, names: &[&str]) -> bool {
+        self.allowlist.iter().any(|allowed| {
+            names
+                .iter()
+                .any(|name| !name.is_empty() && allowed.eq_ignore_ascii_case(name))
+        })
+    }
+
+    /// Whether a DM addressed to `recipient_transport` is ours to deliver.
+    /// DMs that don't name a transport go to every transport that knows
+    /// the recipient.
+    pub fn is_for(&self, sender: usize, recipient_transport: Option<usize>) -> bool {
+        sender != self.transport_id && recipient_transport.is_none_or(|id| id == self.transport_id)
+    }
+
+    pub fn send(
+        &self,
+        transport: &str,
+        username: &str,
+        recipient: &str,
+        recipient_transport: Option<usize>,
+        message: String,
+    ) -> anyhow::Result<()> {
+        self.bus
+            .send(Message::Direct {
+                sender: self.transport_id,
+                transport: transport.to_string(),
+                username: username.to_string(),
+                recipient: recipient.to_string(),
+                recipient_transport,
+                message,
+            })
+            .map_err(|e| anyhow!("Couldn't send message: {:#}", e))?;
+
+        Ok(())
+    }
+
+    /// Records that `user` on this transport is talking to `peer_user` on
+    /// `peer_transport_id`, which isn't known until the peer first answers.
+    pub async fn remember(
+        &self,
+        user: &str,
+        peer_transport_id: Option<usize>,
+        peer_user: &str,
+    ) -> anyhow::Result<()> {
+        let conn = self.pool.get().await?;
+        let transport_id = self.transport_id;
+        let user = (self.casemapping)(user);
+        let peer_user = peer_user.to_string();
+
+        conn.interact(move |conn| -> anyhow::Result<usize> {
+            Ok(conn.execute(
+                "INSERT OR REPLACE INTO direct_conversations
+                   (transport_id, user, peer_transport_id, peer_user, updated)
+                 VALUES (?1, ?2, ?3, ?4, CAST(strftime('%s', 'now') AS INTEGER))",
+                params![transport_id, user, peer_transport_id, peer_user],
+            )?)
+        })
+        .await
+        .unwrap_or_else(|_| Err(anyhow!("Interact Error")))?;
+
+        Ok(())
+    }
+
+    /// The transport and name of whoever `user` last talked to.
+    pub async fn peer(&self, user: &str) -> anyhow::Result<Option<(Option<usize>, String)>> {
+        let conn = self.pool.get().await?;
+        let transport_id = self.transport_id;
+        let user = (self.casemapping)(user);
+
+        conn.interact(
+            move |conn| -> anyhow::Result<Option<(Option<usize>, String)>> {
+                Ok(conn
+                    .query_row(
+                        "SELECT peer_transport_id, peer_user FROM direct_conversations
+                     WHERE transport_id = ?1 AND user = ?2",
+                        params![transport_id, user],
+                        |row| Ok((row.get(0)?, row.get(1)?)),
+                    )
+                    .optional()?)
+            },
+        )
+        .await
+        .unwrap_or_else(|_| Err(anyhow!("Interact Error")))
+    }
+
+    /// Splits "@alice hello" into the addressed user and the message.
+    pub fn parse_recipient(text: &str) -> Option<(&str, &str)> {
+        let (recipient, message) = text.trim_start().strip_prefix('@')?.split_once(' ')?;
+        let message = message.trim();
+
+        if recipient.is_empty() || message.is_empty() {
+            return None;
+        }
+
+        Some((recipient, message))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_recipient_splits_address_and_text() {
+        assert_eq!(
+            DirectBridge::parse_recipient("@alice hello there"),
+            Some(("alice", "hello there"))
+        );
+        assert_eq!(DirectBridge::parse_recipient("@alice"), None);
+        assert_eq!(DirectBridge::parse_recipient("@ hello"), None);
+        assert_eq!(DirectBridge::parse_recipient("hello @alice"), None);
+    }
+}
@@ -8,10 +8,13 @@ use chrono::prelude::*;
 use deadpool_sqlite::Pool;
 use lazy_static::lazy_static;
 use regex::Regex;
-use rusqlite::params;
+use rusqlite::{params, OptionalExtension};
 use serenity::{
     async_trait,
-    builder::{CreateThread, CreateWebhook, EditMessage, EditWebhookMessage, ExecuteWebhook},
+    builder::{
+        CreateAttachment, CreateMessage, CreateThread, CreateWebhook, EditChannel, EditMessage,
+        EditThread, EditWebhookMessage, ExecuteWebhook,
+    },
     http::{CacheHttp, Http},
     model::{
         channel::{Channel, Message as SerenityMessage},
@@ -24,11 +27,22 @@ use serenity::{
 use tokio::sync::{broadcast, Mutex as AsyncMutex};
 use tokio_stream::{wrappers::BroadcastStream, StreamExt, StreamMap};
 
-use crate::{Message, ThreadRef};
+use crate::{
+    archive::Archive, direct::DirectBridge, media::MediaStore, threads::ThreadMap, DiscordConfig,
+    Late, Message, ReplyRef, Services, ThreadRef,
+};
+
+mod commands;
+
+use commands::NamesRequest;
 
 const TRANSPORT_NAME: &'static str = "Discord";
+// Bridges name their posts "user (Transport)", after the transport the
+// message came from.
+const BRIDGED_TRANSPORTS: [&str; 5] = ["Discord", "IRC", "Mumble", "Rachni", "Slack"];
 
 const VALID_CHARS: &'static str = "0123456789";
+const DISCORD_TOPIC_MAX_CHARS: usize = 1024;
 
 pub(crate) struct Discord {
     transport_id: usize,
@@ -38,6 +52,14 @@ pub(crate) struct Discord {
     pool: Pool,
     pipo_id: Arc<Mutex<i64>>,
     cache_http: Option<Arc<dyn CacheHttp>>,
+    topic_sync: bool,
+    direct: Option<Arc<DirectBridge>>,
+    media: Option<Arc<MediaStore>>,
+    // Larger files are linked instead of attached.
+    upload_max_bytes: u64,
+    ignored_ids: HashSet<u64>,
+    archive: Arc<Archive>,
+    threads: Arc<ThreadMap>,
 }
 
 struct Handler {
@@ -49,10 +71,20 @@ struct RealHandler {
     shared: Arc<Shared>,
     pool: Pool,
     pipo_id: Arc<Mutex<i64>>,
+    topic_sync: bool,
+    direct: Option<Arc<DirectBridge>>,
+    media: Option<Arc<MediaStore>>,
+    archive: Arc<Archive>,
+    threads: Arc<ThreadMap>,
+    // The bot's own user, known once connected.
+    user_id: Option<UserId>,
+    // Bots and webhooks whose messages aren't bridged.
+    ignored_ids: HashSet<u64>,
 }
 
 #[derive(Clone)]
 struct HandlerChannel {
+    bus: String,
     sender: broadcast::Sender<Message>,
     webhook: Option<u64>,
 }
@@ -65,7 +97,20 @@ struct State {
     channels: HashMap<u64, HandlerChannel>,
     emojis: HashMap<String, Emoji>,
     threads: HashMap<u64, u64>,
+    archived_threads: HashSet<u64>,
+    // First message of each thread, looked up once it has been bridged.
+    thread_roots: HashMap<u64, ThreadRoot>,
     pins: HashSet<MessageId>,
+    topics: HashMap<u64, String>,
+    // Pending /names, by channel and the name of whoever asked.
+    names_requests: HashMap<(u64, String), NamesRequest>,
+}
+
+#[derive(Clone, Debug, Default)]
+struct ThreadRoot {
+    pipo_id: Option<i64>,
+    author: Option<String>,
+    excerpt: Option<String>,
 }
 
 impl Shared {
@@ -107,6 +152,14 @@ impl Shared {
             .and_then(|c| c.webhook.map(|wh| WebhookId::from(wh)))
     }
 
+    fn is_own_webhook(&self, webhook: WebhookId) -> bool {
+        let state = self.state.lock().unwrap();
+        state
+            .channels
+            .values()
+            .any(|c| c.webhook == Some(webhook.get()))
+    }
+
     fn get_emoji(&self, emoji: &str) -> Option<Emoji> {
         let state = self.state.lock().unwrap();
         state.emojis.get(emoji).map(|e| e.clone())
@@ -134,6 +187,32 @@ impl Shared {
             .insert(thread.as_ref().get(), channel.as_ref().get());
     }
 
+    /// Forgets everything about `thread`, once it's gone.
+    fn remove_thread<C: AsRef<ChannelId>>(&self, thread: C) {
+        let mut state = self.state.lock().unwrap();
+        let thread = thread.as_ref().get();
+
+        state.threads.remove(&thread);
+        state.archived_threads.remove(&thread);
+        state.thread_roots.remove(&thread);
+    }
+
+    fn is_thread_archived<C: AsRef<ChannelId>>(&self, thread: C) -> bool {
+        let state = self.state.lock().unwrap();
+        state.archived_threads.contains(&thread.as_ref().get())
+    }
+
+    fn set_thread_archived<C: AsRef<ChannelId>>(&self, thread: C, archived: bool) {
+        let mut state = self.state.lock().unwrap();
+        let thread = thread.as_ref().get();
+
+        if archived {
+            state.archived_threads.insert(thread);
+        } else {
+            state.archived_threads.remove(&thread);
+        }
+    }
+
     fn get_pins(&self) -> HashSet<MessageId> {
         let state = self.state.lock().unwrap();
         state.pins.clone()
@@ -160,6 +239,30 @@ impl Shared {
             .map(|p| ChannelId::from(*p))
     }
 
+    fn get_thread_root<C: AsRef<ChannelId>>(&self, thread: C) -> Option<ThreadRoot> {
+        let state = self.state.lock().unwrap();
+        state
+            .thread_roots
+            .get(&thread.as_ref().get())
+            .map(|r| r.clone())
+    }
+
+    fn set_thread_root<C: AsRef<ChannelId>>(&self, thread: C, root: ThreadRoot) {
+        let mut state = self.state.lock().unwrap();
+        state.thread_roots.insert(thread.as_ref().get(), root);
+    }
+
+    /// Records `topic` as the topic of `channel`, returning whether it
+    /// differs from the one known so far.
+    fn update_topic<C: AsRef<ChannelId>>(&self, channel: C, topic: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let previous = state
+            .topics
+            .insert(channel.as_ref().get(), topic.to_string());
+
+        previous.as_deref() != Some(topic)
+    }
+
     fn set_webhook(&self, channel: &ChannelId, webhook: &WebhookId) {
         let mut state = self.state.lock().unwrap();
         state
@@ -251,6 +354,31 @@ impl RealHandler {
         self.shared.set_pins(new_pins);
     }
 
+    async fn channel_update(&mut self, _ctx: Context, channel: GuildChannel) {
+        if !self.topic_sync {
+            return;
+        }
+
+        let topic = channel.topic.unwrap_or_default();
+
+        if !self.shared.update_topic(channel.id, &topic) {
+            return;
+        }
+
+        if let Some(sender) = self.shared.get_sender(channel.id) {
+            let message = Message::Topic {
+                sender: self.transport_id,
+                transport: TRANSPORT_NAME.to_string(),
+                username: None,
+                topic,
+            };
+
+            if let Err(e) = sender.send(message) {
+                eprintln!("Couldn't send message: {:#}", e);
+            }
+        }
+    }
+
     async fn guild_create(&mut self, ctx: Context, guild: Guild) {
         let http = CacheHttp::http(&ctx);
 
@@ -291,6 +419,22 @@ impl RealHandler {
             }
         }
 
+        if guild
+            .channels
+            .keys()
+            .any(|id| self.shared.contains_channel(id))
+        {
+            self.register_commands(&ctx, guild.id).await;
+        }
+
+        // Remember the current topics, so that only changes get relayed
+        for (id, channel) in guild.channels.iter() {
+            if self.shared.contains_channel(id) {
+                self.shared
+                    .update_topic(id, channel.topic.as_deref().unwrap_or(""));
+            }
+        }
+
         // Setup threads
         for thread in guild.threads {
             eprintln!("Thread: {}", thread);
@@ -303,6 +447,33 @@ impl RealHandler {
             }
         }
 
+        // Threads bridged before that aren't active anymore have been
+        // archived, and are unarchived once a reply comes in.
+        match self.threads.select_all(self.transport_id).await {
+            Ok(threads) => {
+                for thread in threads {
+                    let (Ok(thread_id), Ok(channel_id)) = (
+                        thread.thread_id.parse::<u64>(),
+                        thread.channel.parse::<u64>(),
+                    ) else {
+                        continue;
+                    };
+
+                    if self.shared.contains_thread(&thread_id)
+                        || !self.shared.contains_channel(ChannelId::new(channel_id))
+                    {
+                        continue;
+                    }
+
+                    self.shared
+                        .insert_thread(ChannelId::new(thread_id), ChannelId::new(channel_id));
+                    self.shared
+                        .set_thread_archived(ChannelId::new(thread_id), true);
+                }
+            }
+            Err(e) => eprintln!("Couldn't load threads from database: {:#}", e),
+        }
+
         eprintln!("Threads: {}", self.shared.format_threads());
     }
 
@@ -312,8 +483,7 @@ impl RealHandler {
         // channel, so log to stdout when some error happens, with a
         // description of it.
         eprintln!("Author: {:#?}", msg.author);
-        let http = CacheHttp::http(&ctx);
-        if msg.author.bot {
+        if self.is_bridged_message(&msg.author, msg.webhook_id) {
             return;
         }
         if msg.kind != MessageType::Regular && msg.kind != MessageType::InlineReply {
@@ -328,7 +498,15 @@ impl RealHandler {
             }
         };
 
-        if let Channel::Guild(channel) = channel {
+        if let Channel::Private(_) = channel {
+            if let Err(e) = self.direct_message(&ctx, &msg).await {
+                eprintln!("Error handling direct message: {:#}", e);
+            }
+
+            return;
+        }
+
+        if let Channel::Guild(_) = channel {
             let mut thread = None;
             let channel_id = msg.channel_id;
             // Check if this message is from a channel or
@@ -349,6 +527,16 @@ impl RealHandler {
                     return;
                 }
             };
+
+            // The first message of a forum post starts the thread rather
+            // than replying in it.
+            if msg.id.get() == channel_id.get() {
+                thread = None;
+            }
+
+            self.describe_thread_root(&ctx, channel_id, &mut thread)
+                .await;
+
             let mut content = msg.content.clone();
 
             lazy_static! {
@@ -366,62 +554,20 @@ impl RealHandler {
                 }
             };
 
-            for attachment in msg.attachments.iter() {
-                content.insert_str(content.len(), &format!("\n{}", attachment.proxy_url));
-            }
-
-            let mut attachments = Vec::new();
-            let id = 0;
-
-            if let Some(reply) = msg.referenced_message {
-                eprintln!("Reply: {}", reply.id);
-                let mut fallback = None;
-                let pipo_id = match self.select_id_from_messages(reply.as_ref()).await {
-                    Ok(id) => id,
-                    Err(e) => {
-                        eprintln!(
-                            "Failed to get id for message from \
-                       database: {}",
-                            e
-                        );
-
-                        return;
-                    }
-                };
-                let nick: String;
-                if reply.member.is_some() {
-                    match &reply.member.as_ref().unwrap().nick {
-                        Some(s) => nick = s.clone(),
-                        None => nick = reply.author.name.clone(),
-                    }
-                } else {
-                    nick = reply.author.name.clone()
-                }
-                let author_name = Some(format!("{} ({})", nick, TRANSPORT_NAME));
-                let author_icon = reply.author.avatar_url().map(|s| s.clone());
-
-                if let Ok(msg) = channel.message(http, *reply).await {
-                    let ts = msg.timestamp.format("%B %e, %Y %l:%M %p");
-                    let user = msg.author.name;
-                    let content = msg.content;
-
-                    fallback = Some(format!("[{}] {}: {}", ts, user, content));
-                }
-                attachments.push(crate::Attachment {
-                    id,
-                    pipo_id: Some(pipo_id),
-                    fallback,
-                    author_name,
-                    author_icon,
-                    ..Default::default()
-                });
-
-                // id += 1;
-            }
+            let attachments = self
+                .message_attachments(&msg.attachments, &msg.embeds, &msg.sticker_items)
+                .await;
 
-            let attachments = match attachments.len() {
-                0 => None,
-                _ => Some(attachments),
+            content = [content]
+                .into_iter()
+                .chain(RealHandler::sticker_names(&msg.sticker_items))
+                .filter(|line| !line.is_empty())
+                .collect::<Vec<_>>()
+                .join("\n");
+
+            let reply_to = match msg.referenced_message {
+                Some(reply) => Some(self.describe_reply(&reply).await),
+                None => None,
             };
 
             let message = if let Some(captures) = RE.captures(&content) {
@@ -437,10 +583,12 @@ impl RealHandler {
                     username: msg.author.name.clone(),
                     avatar_url: msg.author.avatar_url(),
                     thread,
+                    reply_to,
                     message: Some(content),
                     attachments,
                     is_edit: false,
                     irc_flag: false,
+                    late: None,
                 }
             } else {
                 Message::Text {
@@ -450,10 +598,13 @@ impl RealHandler {
                     username: msg.author.name.clone(),
                     avatar_url: msg.author.avatar_url(),
                     thread,
-                    message: Some(content),
+                    reply_to,
+                    // Stickers and embeds needn't come with any text.
+                    message: Some(content).filter(|content| !content.is_empty()),
                     attachments,
                     is_edit: false,
                     irc_flag: false,
+                    late: None,
                 }
             };
 
@@ -528,7 +679,7 @@ impl RealHandler {
             Some(s) => s,
             None => return,
         };
-        if author.bot {
+        if self.is_bridged_message(&author, msg.webhook_id.flatten()) {
             return;
         }
         let channel = match msg.channel_id.to_channel(&ctx).await {
@@ -559,6 +710,14 @@ impl RealHandler {
                     return;
                 }
             };
+
+            if msg.id.get() == channel_id.get() {
+                thread = None;
+            }
+
+            self.describe_thread_root(&ctx, channel_id, &mut thread)
+                .await;
+
             let mut content = match msg.content {
                 Some(s) => s,
                 None => return,
@@ -578,11 +737,26 @@ impl RealHandler {
                 }
             };
 
-            if let Some(attachments) = msg.attachments {
-                for attachment in attachments.iter() {
-                    content.insert_str(content.len(), &format!("\n{}", attachment.proxy_url));
-                }
-            }
+            let reply_to = match msg.referenced_message.flatten() {
+                Some(reply) => Some(self.describe_reply(&reply).await),
+                None => None,
+            };
+
+            let stickers = msg.sticker_items.unwrap_or_default();
+            let attachments = self
+                .message_attachments(
+                    &msg.attachments.unwrap_or_default(),
+                    &msg.embeds.unwrap_or_default(),
+                    &stickers,
+                )
+                .await;
+
+            content = [content]
+                .into_iter()
+                .chain(RealHandler::sticker_names(&stickers))
+                .filter(|line| !line.is_empty())
+                .collect::<Vec<_>>()
+                .join("\n");
 
             let message = if let Some(captures) = RE.captures(&content) {
                 content = match captures.get(1) {
@@ -597,10 +771,12 @@ impl RealHandler {
                     username: author.name.clone(),
                     avatar_url: author.avatar_url(),
                     thread,
+                    reply_to,
                     message: Some(content),
-                    attachments: None,
+                    attachments,
                     is_edit: true,
                     irc_flag: true,
+                    late: None,
                 }
             } else {
                 Message::Text {
@@ -610,10 +786,12 @@ impl RealHandler {
                     username: author.name.clone(),
                     avatar_url: author.avatar_url(),
                     thread,
-                    message: Some(content),
-                    attachments: None,
+                    reply_to,
+                    message: Some(content).filter(|content| !content.is_empty()),
+                    attachments,
                     is_edit: true,
                     irc_flag: true,
+                    late: None,
                 }
             };
 
@@ -642,7 +820,22 @@ impl RealHandler {
     async fn thread_update(&mut self, _ctx: Context, thread: GuildChannel) {
         eprintln!("Updated Thread: {:?}", thread);
 
-        if thread.thread_metadata.unwrap().archived {}
+        let Some(channel_id) = thread.parent_id else {
+            return;
+        };
+
+        if !self.shared.contains_channel(channel_id) {
+            return;
+        }
+
+        // Threads unarchived by someone else may not be known yet.
+        self.shared.insert_thread(thread.id, channel_id);
+        self.shared.set_thread_archived(
+            thread.id,
+            thread
+                .thread_metadata
+                .is_some_and(|metadata| metadata.archived),
+        );
     }
 
     async fn reaction_add(&mut self, ctx: Context, reaction: Reaction) {
@@ -791,10 +984,219 @@ impl RealHandler {
 
     async fn ready(&mut self, _: Context, ready: Ready) {
         println!("{} is connected!", ready.user.name);
+        self.user_id = Some(ready.user.id);
     }
 }
 
 impl RealHandler {
+    /// Where other transports should fetch `attachment` from. Discord's own
+    /// links expire, so with a media store they get a copy that doesn't.
+    async fn attachment_url(&self, attachment: &Attachment) -> String {
+        let Some(media) = self.media.as_ref() else {
+            return attachment.proxy_url.clone();
+        };
+
+        match media
+            .cache(&attachment.url, &attachment.filename, None)
+            .await
+        {
+            Ok(url) => url,
+            Err(e) => {
+                eprintln!("Failed to cache attachment {}: {:#}", attachment.url, e);
+                attachment.proxy_url.clone()
+            }
+        }
+    }
+
+    /// Everything attached to a message: its files, for the transports
+    /// that can carry them (the others get a link to `from_url`), link
+    /// previews and embeds, and stickers that are images.
+    async fn message_attachments(
+        &self,
+        files: &[Attachment],
+        embeds: &[Embed],
+        stickers: &[StickerItem],
+    ) -> Option<Vec<crate::Attachment>> {
+        let mut attachments = Vec::new();
+
+        for attachment in files {
+            let url = self.attachment_url(attachment).await;
+
+            attachments.push(crate::Attachment {
+                filename: Some(attachment.filename.clone()),
+                content_type: attachment.content_type.clone(),
+                size: Some(attachment.size as u64),
+                image_width: attachment.width.map(u64::from),
+                image_height: attachment.height.map(u64::from),
+                from_url: Some(url.clone()),
+                original_url: Some(attachment.url.clone()),
+                fallback: Some(url),
+                ..Default::default()
+            });
+        }
+
+        attachments.extend(embeds.iter().map(RealHandler::embed_attachment));
+        attachments.extend(stickers.iter().filter_map(|sticker| {
+            let url = RealHandler::sticker_image_url(sticker)?;
+
+            Some(crate::Attachment {
+                service_name: Some("Sticker".to_string()),
+                image_url: Some(url.clone()),
+                fallback: Some(format!("{} {}", sticker.name, url)),
+                ..Default::default()
+            })
+        }));
+
+        for (id, attachment) in attachments.iter_mut().enumerate() {
+            attachment.id = id as u64;
+        }
+
+        Some(attachments).filter(|attachments| !attachments.is_empty())
+    }
+
+    /// A link preview, or the card of a bot or webhook, such as a GitHub
+    /// notification.
+    fn embed_attachment(embed: &Embed) -> crate::Attachment {
+        let provider = embed.provider.as_ref();
+        let author = embed.author.as_ref();
+        let footer = embed.footer.as_ref();
+        // Link previews are of a link that's in the message already, but
+        // the cards of bots link to what they're about.
+        let url = embed
+            .url
+            .as_ref()
+            .filter(|_| embed.kind.as_deref() == Some("rich"));
+        let text = embed
+            .title
+            .iter()
+            .chain(url)
+            .chain(embed.description.iter())
+            .cloned()
+            .chain(
+                embed
+                    .fields
+                    .iter()
+                    .map(|field| format!("{}: {}", field.name, field.value)),
+            )
+            .collect::<Vec<_>>()
+            .join("\n");
+        let image = match (&embed.image, &embed.thumbnail) {
+            (Some(image), _) => Some((image.url.clone(), image.width, image.height)),
+            (None, Some(thumbnail)) => {
+                Some((thumbnail.url.clone(), thumbnail.width, thumbnail.height))
+            }
+            (None, None) => None,
+        };
+
+        crate::Attachment {
+            service_name: provider.and_then(|provider| provider.name.clone()),
+            service_url: provider.and_then(|provider| provider.url.clone()),
+            author_name: author.map(|author| author.name.clone()),
+            author_link: author.and_then(|author| author.url.clone()),
+            author_icon: author.and_then(|author| author.icon_url.clone()),
+            footer: footer.map(|footer| footer.text.clone()),
+            footer_icon: footer.and_then(|footer| footer.icon_url.clone()),
+            text: Some(text).filter(|text| !text.is_empty()),
+            image_url: image.as_ref().map(|(url, _, _)| url.clone()),
+            image_width: image
+                .as_ref()
+                .and_then(|(_, width, _)| width.map(u64::from)),
+            image_height: image.and_then(|(_, _, height)| height.map(u64::from)),
+            fallback: embed.title.clone().or(embed.url.clone()),
+            ..Default::default()
+        }
+    }
+
+    /// The picture of `sticker`, unless it's animated with Lottie, which
+    /// only Discord can play.
+    fn sticker_image_url(sticker: &StickerItem) -> Option<String> {
+        match sticker.format_type {
+            StickerFormatType::Lottie => None,
+            _ => sticker.image_url(),
+        }
+    }
+
+    /// "[sticker: name]" for every sticker that isn't bridged as a picture.
+    fn sticker_names(stickers: &[StickerItem]) -> Vec<String> {
+        stickers
+            .iter()
+            .filter(|sticker| RealHandler::sticker_image_url(sticker).is_none())
+            .map(|sticker| format!("[sticker: {}]", sticker.name))
+            .collect()
+    }
+
+    /// Whether a message was posted by a bridge: by this one, through a
+    /// channel's webhook or as the bot, or by another one, whose webhook
+    /// posts are named after the transport they came from. Bots and
+    /// webhooks in `ignored_ids` are skipped too; all others are bridged
+    /// like anyone else.
+    fn is_bridged_message(&self, author: &User, webhook: Option<WebhookId>) -> bool {
+        self.user_id == Some(author.id)
+            || self.ignored_ids.contains(&author.id.get())
+            || webhook.is_some_and(|webhook| {
+                self.shared.is_own_webhook(webhook)
+                    || self.ignored_ids.contains(&webhook.get())
+                    || RealHandler::has_transport_suffix(&author.name)
+            })
+    }
+
+    /// Whether `name` ends in " (Transport)".
+    fn has_transport_suffix(name: &str) -> bool {
+        BRIDGED_TRANSPORTS.iter().any(|transport| {
+            name.strip_suffix(&format!(" ({})", transport))
+                .is_some_and(|user| !user.is_empty())
+        })
+    }
+
+    /// Bridges a DM sent to the bot. "@bob hello" starts a conversation
+    /// with bob; anything else goes to whoever the author last talked to.
+    async fn direct_message(&self, ctx: &Context, msg: &SerenityMessage) -> anyhow::Result<()> {
+        let Some(direct) = self.direct.as_ref() else {
+            return Ok(());
+        };
+        let author_id = msg.author.id.get().to_string();
+
+        if !direct.allows(&[&msg.author.name, &author_id]) {
+            msg.channel_id
+                .say(ctx, "Bridged direct messages aren't enabled for you.")
+                .await?;
+
+            return Ok(());
+        }
+
+        if let Some((recipient, text)) = DirectBridge::parse_recipient(&msg.content) {
+            direct.send(
+                TRANSPORT_NAME,
+                &msg.author.name,
+                recipient,
+                None,
+                text.to_string(),
+            )?;
+
+            return direct.remember(&author_id, None, recipient).await;
+        }
+
+        match direct.peer(&author_id).await? {
+            Some((peer_transport, peer)) => direct.send(
+                TRANSPORT_NAME,
+                &msg.author.name,
+                &peer,
+                peer_transport,
+                msg.content.clone(),
+            ),
+            None => {
+                msg.channel_id
+                    .say(
+                        ctx,
+                        "Start a conversation with: @name your message. Replies then go to the last person you wrote to.",
+                    )
+                    .await?;
+
+                Ok(())
+            }
+        }
+    }
+
     async fn insert_into_messages_table<T: AsRef<MessageId>>(
         &self,
         message_id: T,
@@ -876,6 +1278,115 @@ impl RealHandler {
         }
     }
 
+    /// Fills in what `thread` needs to be shown as a thread elsewhere: the
+    /// pipo_id, author and an excerpt of the first message of the thread
+    /// `channel_id`.
+    async fn describe_thread_root(
+        &self,
+        ctx: &Context,
+        channel_id: ChannelId,
+        thread: &mut Option<ThreadRef>,
+    ) {
+        let Some(thread_ref) = thread.as_mut() else {
+            return;
+        };
+        let root = match self.shared.get_thread_root(channel_id) {
+            Some(root) => root,
+            None => {
+                let root = self.fetch_thread_root(ctx, channel_id).await;
+
+                if let (Some(root_pipo_id), Some(parent)) =
+                    (root.pipo_id, self.shared.get_thread(channel_id))
+                {
+                    if let Err(e) = self
+                        .threads
+                        .insert(
+                            root_pipo_id,
+                            self.transport_id,
+                            &channel_id.to_string(),
+                            &parent.to_string(),
+                        )
+                        .await
+                    {
+                        eprintln!("Failed to store thread {}: {:#}", channel_id, e);
+                    }
+                }
+
+                // A root that isn't bridged yet may be soon, e.g. when its
+                // message is still on its way through the bus, so only
+                // roots with a pipo_id are kept.
+                if root.pipo_id.is_some() {
+                    self.shared.set_thread_root(channel_id, root.clone());
+                }
+
+                root
+            }
+        };
+
+        thread_ref.root_pipo_id = root.pipo_id;
+        thread_ref.root_author = root.author;
+        thread_ref.root_excerpt = root.excerpt;
+    }
+
+    async fn fetch_thread_root(&self, ctx: &Context, channel_id: ChannelId) -> ThreadRoot {
+        // Threads share the id of the message they were started from,
+        // which is in the parent channel, or in the thread itself for
+        // forum posts.
+        let root_id = MessageId::new(channel_id.get());
+        let starter = match self.shared.get_thread(channel_id) {
+            Some(parent) => parent.message(ctx, root_id).await.ok(),
+            None => None,
+        };
+        let starter = match starter {
+            Some(starter) => Some(starter),
+            None => channel_id.message(ctx, root_id).await.ok(),
+        };
+
+        // The thread map still knows threads whose first message has been
+        // deleted since.
+        let pipo_id = match self
+            .threads
+            .select_root(self.transport_id, &channel_id.to_string())
+            .await
+        {
+            Ok(Some(pipo_id)) => Some(pipo_id),
+            _ => match starter.as_ref() {
+                Some(starter) => self.select_id_from_messages(starter.id).await.ok(),
+                None => None,
+            },
+        };
+
+        match starter {
+            Some(starter) => ThreadRoot {
+                pipo_id,
+                author: Some(starter.author.name),
+                excerpt: ThreadRef::excerpt(Some(&starter.content)),
+            },
+            // Threads started without a message only have their name to go
+            // by.
+            None => ThreadRoot {
+                pipo_id,
+                author: None,
+                excerpt: channel_id.name(ctx).await.ok(),
+            },
+        }
+    }
+
+    /// Describes the message `reply` answers, which needn't have been
+    /// bridged.
+    async fn describe_reply(&self, reply: &SerenityMessage) -> ReplyRef {
+        let author = match reply.member.as_ref().and_then(|member| member.nick.clone()) {
+            Some(nick) => nick,
+            None => reply.author.name.clone(),
+        };
+
+        ReplyRef {
+            pipo_id: self.select_id_from_messages(reply.id).await.ok(),
+            author: Some(author),
+            excerpt: ThreadRef::excerpt(Some(&reply.content)),
+        }
+    }
+
     async fn delete_message(&self, message_id: MessageId, sender: &broadcast::Sender<Message>) {
         let pipo_id = match self.select_id_from_messages(message_id).await {
             Ok(id) => id,
@@ -1292,11 +1803,16 @@ impl EventHandler for Handler {
 
     async fn channel_update(
         &self,
-        _ctx: Context,
+        ctx: Context,
         _old: Option<GuildChannel>,
         channel: GuildChannel,
     ) {
         eprintln!("Channel updated: {}", channel);
+        self.real_handler
+            .lock()
+            .await
+            .channel_update(ctx, channel)
+            .await;
     }
 
     async fn guild_create(&self, ctx: Context, guild: Guild, _is_new: Option<bool>) {
@@ -1316,6 +1832,14 @@ impl EventHandler for Handler {
         self.real_handler.lock().await.message(ctx, msg).await;
     }
 
+    async fn interaction_create(&self, ctx: Context, interaction: Interaction) {
+        self.real_handler
+            .lock()
+            .await
+            .interaction_create(ctx, interaction)
+            .await;
+    }
+
     async fn message_delete(
         &self,
         ctx: Context,
@@ -1405,19 +1929,19 @@ impl Discord {
     pub async fn new(
         transport_id: usize,
         bus_map: &HashMap<String, broadcast::Sender<Message>>,
-        pipo_id: Arc<Mutex<i64>>,
-        pool: Pool,
-        token: String,
-        guild_id: u64,
-        channel_mapping: &HashMap<Arc<String>, Arc<String>>,
+        discord: &DiscordConfig,
+        services: &Services,
+        direct: Option<Arc<DirectBridge>>,
     ) -> anyhow::Result<Discord> {
-        let channels = channel_mapping
+        let channels = discord
+            .channel_mapping
             .iter()
             .filter_map(|(channelname, busname)| {
                 if let Some(sender) = bus_map.get(busname.as_ref()) {
                     Some((
                         channelname.parse::<u64>().unwrap(),
                         HandlerChannel {
+                            bus: busname.to_string(),
                             sender: sender.clone(),
                             webhook: None,
                         },
@@ -1434,18 +1958,49 @@ impl Discord {
                 channels,
                 emojis: HashMap::new(),
                 threads: HashMap::new(),
+                archived_threads: HashSet::new(),
+                thread_roots: HashMap::new(),
                 pins: HashSet::new(),
+                topics: HashMap::new(),
+                names_requests: HashMap::new(),
             }),
         });
 
+        services
+            .pool
+            .get()
+            .await?
+            .interact(|conn| -> anyhow::Result<()> {
+                // The thread of every message posted into one, since
+                // webhooks can only edit and delete those when told which
+                // thread they are in.
+                conn.execute_batch(
+                    "CREATE TABLE IF NOT EXISTS discord_thread_messages (
+                                     message_id INTEGER PRIMARY KEY,
+                                     thread_id  INTEGER NOT NULL
+                                     );",
+                )?;
+
+                Ok(())
+            })
+            .await
+            .unwrap_or_else(|_| Err(anyhow!("Interact Error")))?;
+
         Ok(Discord {
             transport_id,
-            token,
-            guild: GuildId::from(guild_id),
+            token: discord.token.to_string(),
+            guild: GuildId::from(discord.guild_id),
             shared,
-            pipo_id,
-            pool,
+            pipo_id: services.pipo_id.clone(),
+            pool: services.pool.clone(),
             cache_http: None,
+            topic_sync: discord.topic_sync,
+            direct,
+            media: services.media.clone(),
+            upload_max_bytes: discord.upload_max_bytes,
+            ignored_ids: discord.ignored_ids.iter().copied().collect(),
+            archive: services.archive.clone(),
+            threads: services.threads.clone(),
         })
     }
 
@@ -1488,6 +2043,63 @@ impl Discord {
         Ok(())
     }
 
+    async fn select_id_from_discordid(&self, discord_id: u64) -> anyhow::Result<Option<i64>> {
+        self.pool
+            .get()
+            .await?
+            .interact(move |conn| -> anyhow::Result<Option<i64>> {
+                Ok(conn
+                    .query_row(
+                        "SELECT id FROM messages WHERE discordid = ?1",
+                        params![discord_id],
+                        |row| row.get(0),
+                    )
+                    .optional()?)
+            })
+            .await
+            .unwrap_or_else(|_| Err(anyhow!("Interact Error")))
+    }
+
+    async fn insert_thread_message(&self, message_id: MessageId, thread: ChannelId) {
+        let conn = self.pool.get().await.unwrap();
+        let (message_id, thread_id) = (message_id.get(), thread.get());
+
+        if let Err(e) = conn
+            .interact(move |conn| -> anyhow::Result<usize> {
+                Ok(conn.execute(
+                    "INSERT OR REPLACE INTO discord_thread_messages (message_id, thread_id)
+                     VALUES (?1, ?2)",
+                    params![message_id, thread_id],
+                )?)
+            })
+            .await
+            .unwrap_or_else(|_| Err(anyhow!("Interact Error")))
+        {
+            eprintln!("Failed to store thread of message {}: {:#}", message_id, e);
+        }
+    }
+
+    /// The thread `message_id` was posted in, if it was posted in one.
+    async fn select_thread_of_message(&self, message_id: MessageId) -> Option<ChannelId> {
+        let conn = self.pool.get().await.unwrap();
+        let message_id = message_id.get();
+
+        conn.interact(move |conn| -> anyhow::Result<Option<u64>> {
+            Ok(conn
+                .query_row(
+                    "SELECT thread_id FROM discord_thread_messages WHERE message_id = ?1",
+                    params![message_id],
+                    |row| row.get(0),
+                )
+                .optional()?)
+        })
+        .await
+        .unwrap_or_else(|_| Err(anyhow!("Interact Error")))
+        .ok()
+        .flatten()
+        .map(ChannelId::new)
+    }
+
     async fn select_discordid_from_messages(&self, pipo_id: i64) -> anyhow::Result<Option<u64>> {
         let conn = self.pool.get().await.unwrap();
 
@@ -1536,77 +2148,178 @@ impl Discord {
         Ok(ret)
     }
 
+    /// The thread `thread_ref` is posted in on Discord. Threads are looked
+    /// up in the thread map first, then created from the Discord copy of
+    /// their first message, and unarchived when they have been archived.
+    /// Threads that have been deleted are created again. Threads whose
+    /// first message never made it to Discord, or that can't be reopened,
+    /// are posted to `channel` instead.
     async fn get_threadid(
         &self,
         channel: ChannelId,
-        slack_ts: Option<String>,
+        thread_ref: &ThreadRef,
         message: &Option<String>,
     ) -> anyhow::Result<ChannelId> {
         let http = self.cache_http.as_ref().unwrap().http();
+        let mut root_pipo_id = thread_ref.root_pipo_id;
 
-        // Check for Slack timestamp and, if found, look for a
-        // corresponding Discord `MessageId` to set `channel` to.
-        // Otherwise, treat this as a non-threaded message by
-        // setting `channel` to the `channel` received by this
-        // method.
-
-        if slack_ts.is_some() {
-            let ts = slack_ts.unwrap();
-
-            if let Some(id) = self.get_discordid_from_slackid(ts).await? {
-                // If a corresponding `MessageId` exists for
-                // this timestamp, see if there is already
-                // a Discord thread for it stored locally.
-                // If not,
-                // create a
-                // new Discord thread from the `MessageId`.
-                eprintln!("found disid: {}", id);
-                if self.shared.contains_thread(&id) {
-                    return Ok(ChannelId::from(id));
-                }
+        if let Some(root_pipo_id) = root_pipo_id {
+            if let Some(native) = self
+                .threads
+                .select_thread(root_pipo_id, self.transport_id)
+                .await?
+            {
+                if native.channel == channel.to_string() {
+                    let thread = ChannelId::new(native.thread_id.parse()?);
 
-                let name = match message.clone() {
-                    Some(mut s) => {
-                        if s.len() < 2 {
-                            format!("{}!", s)
-                        } else {
-                            s.truncate(200);
-                            s
-                        }
-                    }
-                    None => String::from("New Thread"),
-                };
-                let ret = channel
-                    .create_thread_from_message(
-                        http,
-                        id,
-                        CreateThread::new(name)
-                            .auto_archive_duration(AutoArchiveDuration::OneDay)
-                            .kind(ChannelType::PublicThread),
-                    )
-                    .await;
+                    self.shared.insert_thread(thread, channel);
 
-                if let Ok(thread) = ret {
-                    return Ok(thread.id);
-                } else {
-                    if let Err(SerenityError::Http(e)) = ret {
-                        if let HttpError::UnsuccessfulRequest(e) = e {
-                            if e.error.code == 160004 {
-                                return Ok(ChannelId::from(id));
-                            }
-                        }
+                    if self.unarchive_thread(thread).await? {
+                        return Ok(thread);
                     }
 
-                    return Err(anyhow!("I don't know"));
+                    // Deleted or locked, so start over from the first
+                    // message.
+                    self.forget_thread(thread, Some(root_pipo_id)).await;
                 }
             }
         }
 
-        return Ok(channel);
-    }
+        let mut root_id = None;
 
-    async fn find_emoji<H: AsRef<Http>>(
-        &self,
+        if let Some(root_pipo_id) = root_pipo_id {
+            root_id = self
+                .select_discordid_from_messages(root_pipo_id)
+                .await
+                .ok()
+                .flatten();
+        }
+        if root_id.is_none() {
+            if let Some(ts) = thread_ref.thread_root_id.clone() {
+                root_id = self.get_discordid_from_slackid(ts).await.ok().flatten();
+            }
+        }
+
+        let Some(root_id) = root_id.or(thread_ref.reply_target_id) else {
+            return Ok(channel);
+        };
+
+        if root_pipo_id.is_none() {
+            root_pipo_id = self.select_id_from_discordid(root_id).await.ok().flatten();
+        }
+
+        // Threads share the id of the message they were started from.
+        let thread = if self.shared.contains_thread(&root_id) {
+            ChannelId::new(root_id)
+        } else {
+            let name = match message.as_deref() {
+                Some(s) if s.chars().count() < 2 => format!("{}!", s),
+                Some(s) => s.chars().take(100).collect(),
+                None => String::from("New Thread"),
+            };
+            let ret = channel
+                .create_thread_from_message(
+                    http,
+                    MessageId::new(root_id),
+                    CreateThread::new(name)
+                        .auto_archive_duration(AutoArchiveDuration::OneDay)
+                        .kind(ChannelType::PublicThread),
+                )
+                .await;
+
+            match ret {
+                Ok(thread) => thread.id,
+                // The thread exists already, but we haven't seen it since
+                // it was archived.
+                Err(SerenityError::Http(HttpError::UnsuccessfulRequest(e)))
+                    if e.error.code == 160004 =>
+                {
+                    self.shared
+                        .set_thread_archived(ChannelId::new(root_id), true);
+
+                    ChannelId::new(root_id)
+                }
+                // The first message has been deleted since.
+                Err(SerenityError::Http(HttpError::UnsuccessfulRequest(e)))
+                    if e.error.code == 10008 =>
+                {
+                    return Ok(channel)
+                }
+                Err(e) => return Err(anyhow!("Couldn't create thread from {}: {}", root_id, e)),
+            }
+        };
+
+        self.shared.insert_thread(thread, channel);
+
+        if !self.unarchive_thread(thread).await? {
+            self.forget_thread(thread, root_pipo_id).await;
+
+            return Ok(channel);
+        }
+
+        if let Some(root_pipo_id) = root_pipo_id {
+            if let Err(e) = self
+                .threads
+                .insert(
+                    root_pipo_id,
+                    self.transport_id,
+                    &thread.to_string(),
+                    &channel.to_string(),
+                )
+                .await
+            {
+                eprintln!("Failed to store thread {}: {:#}", thread, e);
+            }
+        }
+
+        Ok(thread)
+    }
+
+    /// Reopens `thread` if it has been archived. Returns false when it's
+    /// gone, or locked so that it can't be reopened.
+    async fn unarchive_thread(&self, thread: ChannelId) -> anyhow::Result<bool> {
+        if !self.shared.is_thread_archived(thread) {
+            return Ok(true);
+        }
+
+        let http = self.cache_http.as_ref().unwrap().http();
+
+        match thread
+            .edit_thread(http, EditThread::new().archived(false))
+            .await
+        {
+            Ok(_) => (),
+            // Unknown Channel, or an archived thread that we may not touch.
+            Err(SerenityError::Http(HttpError::UnsuccessfulRequest(e)))
+                if e.error.code == 10003 || e.error.code == 50083 =>
+            {
+                eprintln!("Thread {} can't be reopened: {}", thread, e.error.message);
+
+                return Ok(false);
+            }
+            Err(e) => return Err(e.into()),
+        }
+        self.shared.set_thread_archived(thread, false);
+
+        Ok(true)
+    }
+
+    /// Forgets `thread`, which no longer takes messages, along with its
+    /// place in the thread map.
+    async fn forget_thread(&self, thread: ChannelId, root_pipo_id: Option<i64>) {
+        self.shared.remove_thread(thread);
+
+        let Some(root_pipo_id) = root_pipo_id else {
+            return;
+        };
+
+        if let Err(e) = self.threads.delete(root_pipo_id, self.transport_id).await {
+            eprintln!("Failed to forget thread {}: {:#}", thread, e);
+        }
+    }
+
+    async fn find_emoji<H: AsRef<Http>>(
+        &self,
         http: H,
         emoji: &str,
     ) -> anyhow::Result<Option<Emoji>> {
@@ -1637,6 +2350,7 @@ impl Discord {
         transport: String,
         username: String,
         avatar_url: Option<String>,
+        thread: Option<ThreadRef>,
         message: Option<String>,
         is_edit: bool,
     ) -> anyhow::Result<()> {
@@ -1646,6 +2360,12 @@ impl Discord {
 
         let mut content = MessageBuilder::new();
         let http = self.cache_http.as_ref().unwrap().http();
+        // Actions go to threads the same way as text.
+        let mut thread_id = match thread {
+            Some(thread_ref) => Some(self.get_threadid(channel, &thread_ref, &message).await?)
+                .filter(|thread_id| *thread_id != channel),
+            None => None,
+        };
         let message = message.unwrap();
 
         content.push_italic(message);
@@ -1657,18 +2377,20 @@ impl Discord {
                 None => return Err(anyhow!("Could find discordid for id: {}", pipo_id)),
             };
 
+            if let Some(posted_in) = self.select_thread_of_message(msgid).await {
+                thread_id = Some(posted_in);
+            }
+
             let id = self.shared.get_webhook_id(channel);
 
             if let Some(id) = id {
                 if let Ok(wh) = WebhookId::from(id).to_webhook(http).await {
-                    if let Ok(msg) = wh
-                        .edit_message(
-                            http,
-                            msgid,
-                            EditWebhookMessage::new().content(content.to_string()),
-                        )
-                        .await
-                    {
+                    let mut edit = EditWebhookMessage::new().content(content.to_string());
+                    if let Some(thread_id) = thread_id {
+                        edit = edit.in_thread(thread_id);
+                    }
+
+                    if let Ok(msg) = wh.edit_message(http, msgid, edit).await {
                         return self.update_messages_table(pipo_id, msg).await;
                     }
                 }
@@ -1680,7 +2402,8 @@ impl Discord {
                 .push_line(format!(" [{}]", transport))
                 .push(content.to_string());
 
-            channel
+            thread_id
+                .unwrap_or(channel)
                 .edit_message(http, msgid, EditMessage::new().content(msg.to_string()))
                 .await?;
 
@@ -1699,10 +2422,17 @@ impl Discord {
                     if let Some(url) = avatar_url.clone() {
                         exec = exec.avatar_url(url);
                     }
+                    if let Some(thread_id) = thread_id {
+                        exec = exec.in_thread(thread_id);
+                    }
 
-                    if let Ok(msg) = wh.execute(http, true, exec).await {
+                    if let Ok(Some(msg)) = wh.execute(http, true, exec).await {
                         eprintln!("Message: {:?}", msg);
-                        return self.update_messages_table(pipo_id, msg.unwrap()).await;
+                        if let Some(thread_id) = thread_id {
+                            self.insert_thread_message(msg.id, thread_id).await;
+                        }
+
+                        return self.update_messages_table(pipo_id, msg).await;
                     }
                 }
             }
@@ -1713,8 +2443,16 @@ impl Discord {
                 .push_line(format!(" [{}]", transport))
                 .push(content.to_string());
 
-            self.update_messages_table(pipo_id, channel.say(http, msg.to_string()).await?)
-                .await
+            let msg = thread_id
+                .unwrap_or(channel)
+                .say(http, msg.to_string())
+                .await?;
+
+            if let Some(thread_id) = thread_id {
+                self.insert_thread_message(msg.id, thread_id).await;
+            }
+
+            self.update_messages_table(pipo_id, msg).await
         }
     }
 
@@ -1725,16 +2463,21 @@ impl Discord {
         match message_id {
             Some(id) => {
                 let msg_id = MessageId::from(id);
+                let thread = self.select_thread_of_message(msg_id).await;
 
+                // Webhooks belong to the parent channel of a thread.
                 let id = self.shared.get_webhook_id(channel);
 
                 if let Some(id) = id {
                     if let Ok(wh) = WebhookId::from(id).to_webhook(http).await {
-                        return Ok(wh.delete_message(http, None, msg_id).await?);
+                        return Ok(wh.delete_message(http, thread, msg_id).await?);
                     }
                 }
 
-                Ok(channel.delete_message(http, msg_id).await?)
+                Ok(thread
+                    .unwrap_or(channel)
+                    .delete_message(http, msg_id)
+                    .await?)
             }
             None => Err(anyhow!("No message for associated id")),
         }
@@ -1750,10 +2493,17 @@ impl Discord {
         let message_id = self.select_discordid_from_messages(pipo_id).await?;
 
         match message_id {
-            Some(id) => match remove {
-                false => Ok(channel.pin(http, id).await?),
-                true => Ok(channel.unpin(http, id).await?),
-            },
+            Some(id) => {
+                let channel = self
+                    .select_thread_of_message(MessageId::new(id))
+                    .await
+                    .unwrap_or(channel);
+
+                match remove {
+                    false => Ok(channel.pin(http, id).await?),
+                    true => Ok(channel.unpin(http, id).await?),
+                }
+            }
             None => Err(anyhow!("No message for associated id")),
         }
     }
@@ -1780,6 +2530,10 @@ impl Discord {
         }
 
         let message_id = message_id.unwrap();
+        let channel = self
+            .select_thread_of_message(MessageId::new(message_id))
+            .await
+            .unwrap_or(channel);
 
         if !remove {
             return Ok(channel.create_reaction(http, message_id, emoji).await?);
@@ -1798,6 +2552,7 @@ impl Discord {
         username: String,
         avatar_url: Option<String>,
         thread: Option<ThreadRef>,
+        reply_to: Option<ReplyRef>,
         message: Option<String>,
         attachments: Option<Vec<crate::Attachment>>,
         is_edit: bool,
@@ -1808,43 +2563,80 @@ impl Discord {
 
         let mut content = MessageBuilder::new();
         let http = self.cache_http.as_ref().unwrap().http();
-        let channel = match thread {
-            Some(thread_ref) => {
-                self.get_threadid(channel, thread_ref.thread_root_id, &message)
-                    .await?
-            }
-            None => channel,
+        // Posts into threads go through the webhook of their parent
+        // channel, so both are needed.
+        let mut thread_id = match thread {
+            Some(thread_ref) => Some(self.get_threadid(channel, &thread_ref, &message).await?)
+                .filter(|thread_id| *thread_id != channel),
+            None => None,
         };
 
+        let (files, links) = self.files_to_attach(attachments.as_deref().unwrap_or_default());
+
         if let Some(ref message) = message {
             content.push(message);
         }
 
-        if let Some(attachments) = attachments {
-            if message.is_none() {
-                content.push_line("Attachment:");
-            }
+        if message.is_none() && files.is_empty() && links.is_empty() && attachments.is_some() {
+            content.push_line("Attachment:");
+        }
 
-            for attachment in attachments {
-                if attachment.pipo_id.is_some() {
-                    let mut message_id = None;
-                    let pipo_id = attachment.pipo_id.unwrap();
-                    if let Ok(id) = self.select_discordid_from_messages(pipo_id).await {
-                        message_id = id;
-                    }
+        for link in links {
+            content.push(format!("\n{}", link));
+        }
 
-                    if let Some(message_id) = message_id {
-                        if let Ok(message) = channel.message(http, message_id).await {
-                            let message = message.reply(http, content.build()).await?;
+        Discord::quote_attachments(&mut content, attachments.as_deref().unwrap_or_default());
+
+        // Replies to messages posted here become native replies, the rest
+        // quote what they answer.
+        if let Some(reply_to) = reply_to {
+            let message_id = match reply_to.pipo_id {
+                Some(reply_pipo_id) => self
+                    .select_discordid_from_messages(reply_pipo_id)
+                    .await
+                    .ok()
+                    .flatten(),
+                None => None,
+            };
+            let replied = match message_id {
+                Some(message_id) => thread_id
+                    .unwrap_or(channel)
+                    .message(http, MessageId::new(message_id))
+                    .await
+                    .ok(),
+                None => None,
+            };
 
-                            return self.update_messages_table(pipo_id, message).await;
-                        }
-                    }
+            if let Some(replied) = replied {
+                if !is_edit {
+                    let mut msg = MessageBuilder::new();
+
+                    msg.push_bold(username)
+                        .push_line(format!(" [{}]", transport))
+                        .push(content.to_string());
+
+                    let msg = thread_id
+                        .unwrap_or(channel)
+                        .send_message(
+                            http,
+                            CreateMessage::new()
+                                .content(msg.build())
+                                .reference_message(&replied)
+                                .add_files(files),
+                        )
+                        .await?;
 
-                    if let Some(fallback) = attachment.fallback {
-                        content.push_quote_line_safe(fallback);
+                    if let Some(thread_id) = thread_id {
+                        self.insert_thread_message(msg.id, thread_id).await;
                     }
+
+                    return self.update_messages_table(pipo_id, msg).await;
                 }
+            } else if let Some(quote) = reply_to.quote() {
+                let mut quoted = MessageBuilder::new();
+
+                quoted.push_quote_line_safe(quote).push(content.to_string());
+                content = quoted;
             }
         }
 
@@ -1855,18 +2647,22 @@ impl Discord {
                 None => return Err(anyhow!("Could find discordid for id: {}", pipo_id)),
             };
 
+            // Where the message went matters more than where the edit
+            // says it should be.
+            if let Some(posted_in) = self.select_thread_of_message(msgid).await {
+                thread_id = Some(posted_in);
+            }
+
             let id = self.shared.get_webhook_id(channel);
 
             if let Some(id) = id {
                 if let Ok(wh) = WebhookId::from(id).to_webhook(http).await {
-                    if let Ok(msg) = wh
-                        .edit_message(
-                            http,
-                            msgid,
-                            EditWebhookMessage::new().content(content.to_string()),
-                        )
-                        .await
-                    {
+                    let mut edit = EditWebhookMessage::new().content(content.to_string());
+                    if let Some(thread_id) = thread_id {
+                        edit = edit.in_thread(thread_id);
+                    }
+
+                    if let Ok(msg) = wh.edit_message(http, msgid, edit).await {
                         return self.update_messages_table(pipo_id, msg).await;
                     }
                 }
@@ -1878,7 +2674,8 @@ impl Discord {
                 .push_line(format!(" [{}]", transport))
                 .push(content.to_string());
 
-            channel
+            thread_id
+                .unwrap_or(channel)
                 .edit_message(http, msgid, EditMessage::new().content(msg.to_string()))
                 .await?;
 
@@ -1897,10 +2694,18 @@ impl Discord {
                     if let Some(url) = avatar_url.clone() {
                         exec = exec.avatar_url(url);
                     }
+                    if let Some(thread_id) = thread_id {
+                        exec = exec.in_thread(thread_id);
+                    }
+                    exec = exec.add_files(files.clone());
 
-                    if let Ok(msg) = wh.execute(http, true, exec).await {
+                    if let Ok(Some(msg)) = wh.execute(http, true, exec).await {
                         eprintln!("Message: {:?}", msg);
-                        return self.update_messages_table(pipo_id, msg.unwrap()).await;
+                        if let Some(thread_id) = thread_id {
+                            self.insert_thread_message(msg.id, thread_id).await;
+                        }
+
+                        return self.update_messages_table(pipo_id, msg).await;
                     }
                 }
             }
@@ -1911,19 +2716,200 @@ impl Discord {
                 .push_line(format!(" [{}]", transport))
                 .push(content.to_string());
 
-            self.update_messages_table(pipo_id, channel.say(http, msg.to_string()).await?)
-                .await
+            let msg = thread_id
+                .unwrap_or(channel)
+                .send_message(
+                    http,
+                    CreateMessage::new()
+                        .content(msg.to_string())
+                        .add_files(files),
+                )
+                .await?;
+
+            if let Some(thread_id) = thread_id {
+                self.insert_thread_message(msg.id, thread_id).await;
+            }
+
+            self.update_messages_table(pipo_id, msg).await
+        }
+    }
+
+    /// The files among `attachments` that can be attached to a message
+    /// here, and links to the others: those that are too large, or whose
+    /// contents didn't come along.
+    /// Quotes attachments that aren't files, such as messages shared on
+    /// Slack, which Discord has no place for. Only their `fallback` text
+    /// is shown.
+    fn quote_attachments(content: &mut MessageBuilder, attachments: &[crate::Attachment]) {
+        for fallback in attachments
+            .iter()
+            .filter(|attachment| !attachment.is_file())
+            .filter_map(|attachment| attachment.fallback.as_deref())
+            .filter(|fallback| !fallback.trim().is_empty())
+        {
+            if !content.0.is_empty() && !content.0.ends_with('\n') {
+                content.push('\n');
+            }
+
+            content.push_quote_line_safe(fallback);
+        }
+    }
+
+    fn files_to_attach(
+        &self,
+        attachments: &[crate::Attachment],
+    ) -> (Vec<CreateAttachment>, Vec<String>) {
+        let mut files = Vec::new();
+        let mut links = Vec::new();
+
+        for attachment in attachments.iter().filter(|a| a.is_file()) {
+            let filename = attachment.filename.clone().unwrap_or_default();
+
+            match attachment
+                .data
+                .as_ref()
+                .filter(|data| data.0.len() as u64 <= self.upload_max_bytes)
+            {
+                Some(data) => files.push(CreateAttachment::bytes(data.0.to_vec(), filename)),
+                None => links.extend(attachment.from_url.clone()),
+            }
+        }
+
+        (files, links)
+    }
+
+    /// Delivers a DM from another transport to the guild member called
+    /// `recipient`, provided they are on the allowlist.
+    async fn handle_direct_message(
+        &self,
+        sender: usize,
+        transport: String,
+        username: String,
+        recipient: String,
+        recipient_transport: Option<usize>,
+        message: String,
+    ) -> anyhow::Result<()> {
+        let Some(direct) = self.direct.as_ref() else {
+            return Ok(());
+        };
+
+        if !direct.is_for(sender, recipient_transport) {
+            return Ok(());
+        }
+
+        let http = self.cache_http.as_ref().unwrap().http();
+        let member = self
+            .guild
+            .search_members(http, &recipient, Some(10))
+            .await?
+            .into_iter()
+            .find(|member| {
+                member.user.name.eq_ignore_ascii_case(&recipient)
+                    || member
+                        .nick
+                        .as_deref()
+                        .is_some_and(|nick| nick.eq_ignore_ascii_case(&recipient))
+                    || member
+                        .user
+                        .global_name
+                        .as_deref()
+                        .is_some_and(|name| name.eq_ignore_ascii_case(&recipient))
+            });
+        let Some(member) = member else {
+            return Ok(());
+        };
+        let user_id = member.user.id.get().to_string();
+
+        if !direct.allows(&[&member.user.name, &user_id]) {
+            return Ok(());
+        }
+
+        let mut content = MessageBuilder::new();
+
+        content
+            .push_bold(username.clone())
+            .push_line(format!(" [{}]", transport))
+            .push(message);
+        member
+            .user
+            .id
+            .create_dm_channel(http)
+            .await?
+            .say(http, content.to_string())
+            .await?;
+
+        direct.remember(&user_id, Some(sender), &username).await
+    }
+
+    async fn handle_presence_message(
+        &self,
+        channel: ChannelId,
+        transport: String,
+        message: String,
+    ) -> anyhow::Result<()> {
+        let http = self.cache_http.as_ref().unwrap().http();
+        let mut content = MessageBuilder::new();
+
+        content.push_italic(format!("[{}] {}", transport, message));
+        channel.say(http, content.to_string()).await?;
+
+        Ok(())
+    }
+
+    async fn handle_topic_message(
+        &self,
+        channel: ChannelId,
+        transport: String,
+        username: Option<String>,
+        topic: String,
+    ) -> anyhow::Result<()> {
+        let topic = topic
+            .chars()
+            .take(DISCORD_TOPIC_MAX_CHARS)
+            .collect::<String>();
+
+        if !self.topic_sync || !self.shared.update_topic(channel, &topic) {
+            return Ok(());
         }
+
+        let http = self.cache_http.as_ref().unwrap().http();
+
+        let reason = match username {
+            Some(username) => format!("Topic set by {} on {}", username, transport),
+            None => format!("Topic set on {}", transport),
+        };
+
+        channel
+            .edit(
+                http,
+                EditChannel::new().topic(topic).audit_log_reason(&reason),
+            )
+            .await?;
+
+        Ok(())
     }
 
     pub async fn connect(&mut self) -> anyhow::Result<()> {
         let mut input_buses = self.create_input_buses();
+        let mut direct_messages = StreamMap::new();
+
+        if let Some(direct) = self.direct.as_ref() {
+            direct_messages.insert((), direct.subscribe());
+        }
+
         let handler = Handler {
             real_handler: AsyncMutex::new(RealHandler {
                 transport_id: self.transport_id,
                 shared: self.shared.clone(),
                 pool: self.pool.clone(),
                 pipo_id: self.pipo_id.clone(),
+                topic_sync: self.topic_sync,
+                direct: self.direct.clone(),
+                media: self.media.clone(),
+                archive: self.archive.clone(),
+                threads: self.threads.clone(),
+                user_id: None,
+                ignored_ids: self.ignored_ids.clone(),
             }),
         };
         let mut client = Client::builder(self.token.clone(), GatewayIntents::all())
@@ -1943,6 +2929,29 @@ impl Discord {
 
         loop {
             tokio::select! {
+            Some(((), message)) = StreamExt::next(&mut direct_messages) => {
+                if let Ok(Message::Direct {
+                    sender,
+                    transport,
+                    username,
+                    recipient,
+                    recipient_transport,
+                    message,
+                }) = message {
+                    if let Err(e) = self
+                        .handle_direct_message(sender,
+                                   transport,
+                                   username,
+                                   recipient,
+                                   recipient_transport,
+                                   message)
+                        .await {
+                        eprintln!("Error handling \
+                               Message::Direct: \
+                               {}", e);
+                    }
+                }
+            }
             stream = StreamExt::next(&mut input_buses) => {
                 match stream {
                 Some((channel, message)) => {
@@ -1956,11 +2965,13 @@ impl Discord {
                         transport,
                         username,
                         avatar_url,
-                        thread: _,
+                        thread,
+                        reply_to: _,
                         message,
                         attachments: _,
                         is_edit,
                         irc_flag: _,
+                        late,
                     }=> {
                         if sender != self.transport_id {
                         if let Err(e) = self
@@ -1969,7 +2980,8 @@ impl Discord {
                                        transport,
                                        username,
                                        avatar_url,
-                                       message,
+                                       thread,
+                                       Late::mark(late, message),
                                        is_edit)
                         .await {
                             eprintln!("Error handling \
@@ -2005,13 +3017,65 @@ impl Discord {
                             }
                         }
                     },
+                    Message::Direct { .. } => {
+                        continue
+                    },
                     Message::Names {
-                        sender: _,
-                        transport: _,
-                        username: _,
-                        message: _,
+                        sender,
+                        transport,
+                        username,
+                        message,
                     } => {
-                        continue
+                        if sender != self.transport_id {
+                        if let Err(e) = self
+                            .handle_names_message(channel_id,
+                                      transport,
+                                      username,
+                                      message)
+                            .await {
+                            eprintln!("Error handling \
+                                   Message::Names: \
+                                   {}", e);
+                            }
+                        }
+                    },
+                    Message::Presence {
+                        sender,
+                        transport,
+                        message,
+                    } => {
+                        if sender != self.transport_id {
+                        if let Err(e) = self
+                            .handle_presence_message(channel_id,
+                                         transport,
+                                         message)
+                            .await {
+                            eprintln!("Error handling \
+                                   Message::Presence: \
+                                   {}", e);
+                            }
+                        }
+                    },
+                    Message::Notice {
+                        sender,
+                        transport,
+                        username,
+                        message,
+                        late,
+                    } => {
+                        if sender != self.transport_id {
+                        let line = format!("-{}- {}", username, message);
+
+                        if let Err(e) = self
+                            .handle_presence_message(channel_id,
+                                         transport,
+                                         Late::mark(late, Some(line)).unwrap_or_default())
+                            .await {
+                            eprintln!("Error handling \
+                                   Message::Notice: \
+                                   {}", e);
+                            }
+                        }
                     },
                     Message::Pin {
                         sender,
@@ -2057,10 +3121,12 @@ impl Discord {
                         username,
                         avatar_url,
                         thread,
+                        reply_to,
                         message,
                         attachments,
                         is_edit,
                         irc_flag: _,
+                        late,
                     } => {
                         if sender != self.transport_id {
                         if let Err(e) = self
@@ -2070,7 +3136,8 @@ impl Discord {
                                      username,
                                      avatar_url,
                                      thread,
-                                     message,
+                                     reply_to,
+                                     Late::mark(late, message),
                                      attachments,
                                      is_edit)
                         .await {
@@ -2080,6 +3147,25 @@ impl Discord {
                         }
                         }
                     },
+                    Message::Topic {
+                        sender,
+                        transport,
+                        username,
+                        topic,
+                    } => {
+                        if sender != self.transport_id {
+                        if let Err(e) = self
+                            .handle_topic_message(channel_id,
+                                      transport,
+                                      username,
+                                      topic)
+                            .await {
+                            eprintln!("Error handling \
+                                   Message::Topic: \
+                                   {}", e);
+                            }
+                        }
+                    },
                     }
                 },
                 None => break
@@ -2102,21 +3188,51 @@ mod tests {
                 channels: HashMap::new(),
                 emojis: HashMap::new(),
                 threads: HashMap::new(),
+                archived_threads: HashSet::new(),
+                thread_roots: HashMap::new(),
                 pins: HashSet::new(),
+                topics: HashMap::new(),
+                names_requests: HashMap::new(),
             }),
         })
     }
 
-    fn make_handler(shared: Arc<Shared>) -> RealHandler {
+    async fn make_handler(shared: Arc<Shared>) -> RealHandler {
         let pool = Config::new(":memory:")
-            .create_pool(Runtime::Tokio1)
+            // Every connection to ":memory:" opens its own database.
+            .builder(Runtime::Tokio1)
+            .expect("pool config")
+            .max_size(1)
+            .build()
             .expect("pool");
 
+        pool.get()
+            .await
+            .expect("connection")
+            .interact(|conn| {
+                conn.execute_batch(
+                    "CREATE TABLE messages (id INTEGER PRIMARY KEY, slackid TEXT, discordid INTEGER);",
+                )
+            })
+            .await
+            .expect("interact")
+            .expect("messages table");
+
+        let archive = Arc::new(Archive::new(pool.clone(), 30).await.expect("archive"));
+        let threads = Arc::new(ThreadMap::new(pool.clone()).await.expect("threads"));
+
         RealHandler {
             transport_id: 42,
             shared,
             pool,
             pipo_id: Arc::new(Mutex::new(0)),
+            topic_sync: false,
+            direct: None,
+            media: None,
+            archive,
+            threads,
+            user_id: None,
+            ignored_ids: HashSet::new(),
         }
     }
 
@@ -2132,6 +3248,7 @@ mod tests {
             state.channels.insert(
                 10,
                 HandlerChannel {
+                    bus: "main".to_string(),
                     sender,
                     webhook: None,
                 },
@@ -2146,6 +3263,38 @@ mod tests {
         assert_eq!(shared.get_webhook_id(channel_id), Some(webhook_id));
     }
 
+    #[test]
+    fn slack_shares_are_quoted_and_files_left_alone() {
+        let attachments = vec![
+            crate::Attachment {
+                pipo_id: Some(7),
+                author_name: Some("alice".to_string()),
+                from_url: Some(
+                    "https://example.slack.com/archives/C01/p1700000000000100".to_string(),
+                ),
+                text: Some("see you at *noon*".to_string()),
+                fallback: Some("[October 1st, 2026 11:02 AM] alice: see you at *noon*".to_string()),
+                ..Default::default()
+            },
+            crate::Attachment {
+                id: 1,
+                filename: Some("notes.txt".to_string()),
+                from_url: Some("https://files.slack.com/notes.txt".to_string()),
+                fallback: Some("notes.txt".to_string()),
+                ..Default::default()
+            },
+        ];
+        let mut content = MessageBuilder::new();
+
+        content.push("Shared a message");
+        Discord::quote_attachments(&mut content, &attachments);
+
+        assert_eq!(
+            content.build(),
+            "Shared a message\n> [October 1st, 2026 11:02 AM] alice: see you at *noon*\n"
+        );
+    }
+
     #[test]
     fn shared_thread_mapping_round_trip() {
         let shared = make_shared();
@@ -2178,13 +3327,14 @@ mod tests {
             state.channels.insert(
                 7,
                 HandlerChannel {
+                    bus: "main".to_string(),
                     sender: direct_sender.clone(),
                     webhook: None,
                 },
             );
         }
 
-        let handler = make_handler(shared);
+        let handler = make_handler(shared).await;
         let mut thread = None;
         let sender = handler
             .get_sender_and_thread(ChannelId::from(7), &mut thread)
@@ -2204,6 +3354,7 @@ mod tests {
             state.channels.insert(
                 55,
                 HandlerChannel {
+                    bus: "main".to_string(),
                     sender: parent_sender.clone(),
                     webhook: None,
                 },
@@ -2211,7 +3362,7 @@ mod tests {
             state.threads.insert(66, 55);
         }
 
-        let handler = make_handler(shared);
+        let handler = make_handler(shared).await;
         let mut thread = None;
         let sender = handler
             .get_sender_and_thread(ChannelId::from(66), &mut thread)
@@ -2227,4 +3378,82 @@ mod tests {
             })
         );
     }
+
+    #[tokio::test]
+    async fn replies_describe_the_message_they_answer() {
+        let handler = make_handler(make_shared()).await;
+
+        handler
+            .pool
+            .get()
+            .await
+            .expect("connection")
+            .interact(|conn| {
+                conn.execute("INSERT INTO messages (id, discordid) VALUES (3, 77)", [])
+            })
+            .await
+            .expect("interact")
+            .expect("bridged message");
+
+        let mut reply = SerenityMessage::default();
+        reply.id = MessageId::new(77);
+        reply.author.name = "alice".to_string();
+        reply.content = "did the deploy go out?".to_string();
+
+        assert_eq!(
+            handler.describe_reply(&reply).await,
+            ReplyRef {
+                pipo_id: Some(3),
+                author: Some("alice".to_string()),
+                excerpt: Some("did the deploy go out?".to_string()),
+            }
+        );
+
+        reply.id = MessageId::new(78);
+        assert_eq!(handler.describe_reply(&reply).await.pipo_id, None);
+    }
+
+    #[test]
+    fn other_bridges_are_recognised_by_their_transport_suffix() {
+        assert!(RealHandler::has_transport_suffix("alice (IRC)"));
+        assert!(RealHandler::has_transport_suffix("bob (Slack)"));
+        assert!(!RealHandler::has_transport_suffix(" (IRC)"));
+        assert!(!RealHandler::has_transport_suffix("GitHub"));
+        assert!(!RealHandler::has_transport_suffix("carol (she/her)"));
+    }
+
+    #[test]
+    fn bot_embeds_and_stickers_become_attachments() {
+        let embed: Embed = serde_json::from_value(serde_json::json!({
+            "type": "rich",
+            "title": "[pipo] 1 new commit",
+            "url": "https://github.com/example/pipo/commit/abc",
+            "description": "Fix the build",
+            "author": {"name": "alice", "icon_url": "https://example.net/alice.png"},
+            "fields": [{"name": "Branch", "value": "main", "inline": true}],
+        }))
+        .expect("embed");
+        let attachment = RealHandler::embed_attachment(&embed);
+
+        assert_eq!(attachment.author_name.as_deref(), Some("alice"));
+        assert_eq!(
+            attachment.text.as_deref(),
+            Some(
+                "[pipo] 1 new commit\nhttps://github.com/example/pipo/commit/abc\nFix the build\nBranch: main"
+            )
+        );
+        assert!(!attachment.is_file());
+
+        let stickers: Vec<StickerItem> = serde_json::from_value(serde_json::json!([
+            {"id": "1", "name": "wave", "format_type": 1},
+            {"id": "2", "name": "dance", "format_type": 3},
+        ]))
+        .expect("stickers");
+
+        assert!(RealHandler::sticker_image_url(&stickers[0]).is_some());
+        assert_eq!(
+            RealHandler::sticker_names(&stickers),
+            vec!["[sticker: dance]".to_string()]
+        );
+    }
 }
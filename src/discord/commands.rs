@@ -0,0 +1,390 @@
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+use rusqlite::params;
+use serenity::{
+    builder::{
+        CreateCommand, CreateCommandOption, CreateInteractionResponse,
+        CreateInteractionResponseFollowup, CreateInteractionResponseMessage,
+    },
+    model::prelude::*,
+    prelude::*,
+    utils::MessageBuilder,
+};
+
+use super::{Discord, RealHandler, Shared, ThreadRoot, TRANSPORT_NAME};
+use crate::Message;
+
+// Discord accepts followups to an interaction for this long.
+const INTERACTION_TTL: Duration = Duration::from_secs(15 * 60);
+// As many threads as IRC's /threads lists.
+const MAX_LISTED_THREADS: usize = 8;
+
+/// A /names waiting for the other transports to answer, which they do on
+/// the bus with the name of whoever asked.
+#[derive(Clone)]
+pub(super) struct NamesRequest {
+    interaction: CommandInteraction,
+    asked: Instant,
+}
+
+/// The slash commands registered in every guild with bridged channels.
+pub(super) fn definitions() -> Vec<CreateCommand> {
+    vec![
+        CreateCommand::new("names").description("List who is on the other side of the bridge"),
+        CreateCommand::new("threads").description("List the bridged threads of this channel"),
+        CreateCommand::new("whois-message")
+            .description("Show where a bridged message comes from")
+            .add_option(
+                CreateCommandOption::new(
+                    CommandOptionType::String,
+                    "message",
+                    "Link to the message, or its ID",
+                )
+                .required(true),
+            ),
+        CreateCommand::new("bridge")
+            .description("Bridge information")
+            .add_option(CreateCommandOption::new(
+                CommandOptionType::SubCommand,
+                "status",
+                "Show how this channel is bridged",
+            )),
+    ]
+}
+
+/// The message ID at the end of a message link, or a bare ID.
+fn parse_message_id(value: &str) -> Option<MessageId> {
+    value
+        .trim()
+        .trim_end_matches('/')
+        .rsplit('/')
+        .next()?
+        .parse::<u64>()
+        .ok()
+        .filter(|id| *id != 0)
+        .map(MessageId::new)
+}
+
+impl Shared {
+    fn insert_names_request(&self, channel: ChannelId, username: &str, request: NamesRequest) {
+        let mut state = self.state.lock().unwrap();
+
+        state
+            .names_requests
+            .retain(|_, request| request.asked.elapsed() < INTERACTION_TTL);
+        state
+            .names_requests
+            .insert((channel.get(), username.to_string()), request);
+    }
+
+    fn get_names_request(&self, channel: ChannelId, username: &str) -> Option<NamesRequest> {
+        let state = self.state.lock().unwrap();
+
+        state
+            .names_requests
+            .get(&(channel.get(), username.to_string()))
+            .filter(|request| request.asked.elapsed() < INTERACTION_TTL)
+            .cloned()
+    }
+
+    /// The threads of `channel`, newest first, with whether they're
+    /// archived and their first message when it's known.
+    fn threads_of(&self, channel: ChannelId) -> Vec<(ChannelId, bool, Option<ThreadRoot>)> {
+        let state = self.state.lock().unwrap();
+        let mut threads = state
+            .threads
+            .iter()
+            .filter(|(_, parent)| **parent == channel.get())
+            .map(|(thread, _)| {
+                (
+                    ChannelId::new(*thread),
+                    state.archived_threads.contains(thread),
+                    state.thread_roots.get(thread).cloned(),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        threads.sort_by(|a, b| b.0.cmp(&a.0));
+
+        threads
+    }
+}
+
+impl RealHandler {
+    pub(super) async fn register_commands(&self, ctx: &Context, guild: GuildId) {
+        if let Err(e) = guild.set_commands(ctx, definitions()).await {
+            eprintln!("Couldn't register commands in guild {}: {}", guild, e);
+        }
+    }
+
+    pub(super) async fn interaction_create(&mut self, ctx: Context, interaction: Interaction) {
+        let Interaction::Command(command) = interaction else {
+            return;
+        };
+        // Commands used in a thread are about the channel it's in.
+        let channel = self
+            .shared
+            .get_thread(command.channel_id)
+            .unwrap_or(command.channel_id);
+        let bridged = self.shared.contains_channel(channel);
+        let content = match command.data.name.as_str() {
+            _ if !bridged => "This channel isn't bridged.".to_string(),
+            "names" => "Asking the other side who is here…".to_string(),
+            "threads" => self.list_threads(channel),
+            "whois-message" => {
+                let message_id = command
+                    .data
+                    .options
+                    .iter()
+                    .find(|option| option.name == "message")
+                    .and_then(|option| option.value.as_str())
+                    .and_then(parse_message_id);
+
+                match message_id {
+                    Some(message_id) => self.describe_bridged_message(channel, message_id).await,
+                    None => "That isn't a message link or ID.".to_string(),
+                }
+            }
+            "bridge" => match command.data.options.first() {
+                Some(option) if option.name == "status" => self.bridge_status(channel).await,
+                _ => "Usage: /bridge status".to_string(),
+            },
+            name => format!("Unknown command /{}", name),
+        };
+        let response = CreateInteractionResponse::Message(
+            CreateInteractionResponseMessage::new()
+                .content(content)
+                .ephemeral(true),
+        );
+
+        if let Err(e) = command.create_response(&ctx, response).await {
+            eprintln!("Couldn't answer /{}: {}", command.data.name, e);
+
+            return;
+        }
+
+        // Answers can only be followed up once the response exists.
+        if bridged && command.data.name == "names" {
+            self.request_names(channel, command);
+        }
+    }
+
+    fn request_names(&self, channel: ChannelId, interaction: CommandInteraction) {
+        let Some(sender) = self.shared.get_sender(channel) else {
+            return;
+        };
+        let username = interaction.user.name.clone();
+
+        self.shared.insert_names_request(
+            channel,
+            &username,
+            NamesRequest {
+                interaction,
+                asked: Instant::now(),
+            },
+        );
+
+        let message = Message::Names {
+            sender: self.transport_id,
+            transport: TRANSPORT_NAME.to_string(),
+            username,
+            message: Some("/names".to_string()),
+        };
+
+        if let Err(e) = sender.send(message) {
+            eprintln!("Couldn't send message {:#}", e);
+        }
+    }
+
+    fn list_threads(&self, channel: ChannelId) -> String {
+        let threads = self.shared.threads_of(channel);
+
+        if threads.is_empty() {
+            return "No bridged threads in this channel.".to_string();
+        }
+
+        let mut content = MessageBuilder::new();
+
+        for (thread, archived, root) in threads.into_iter().take(MAX_LISTED_THREADS) {
+            content.push("- ").mention(&thread);
+
+            if archived {
+                content.push(" (archived)");
+            }
+            if let Some(excerpt) = root.as_ref().and_then(|root| root.excerpt.clone()) {
+                content.push(" — ");
+
+                if let Some(author) = root.and_then(|root| root.author) {
+                    content.push_bold_safe(author).push(": ");
+                }
+
+                content.push_safe(excerpt);
+            }
+
+            content.push("\n");
+        }
+
+        content.build()
+    }
+
+    /// Who posted the bridged message `message_id`, where and when, and
+    /// what it's called on the other transports. Only messages that went
+    /// over the bus of `channel` are described, so that nobody learns
+    /// about channels they can't see.
+    async fn describe_bridged_message(&self, channel: ChannelId, message_id: MessageId) -> String {
+        let pipo_id = match self.select_id_from_messages(message_id).await {
+            Ok(pipo_id) => pipo_id,
+            Err(_) => return "That message wasn't bridged.".to_string(),
+        };
+        let archived = match self.archive.select(pipo_id).await {
+            Ok(Some(archived))
+                if self
+                    .shared
+                    .get_channel(channel)
+                    .is_some_and(|handler_channel| handler_channel.bus == archived.bus) =>
+            {
+                archived
+            }
+            Ok(_) => return "That message wasn't bridged to this channel.".to_string(),
+            Err(e) => {
+                eprintln!("Failed to look up archived message {}: {:#}", pipo_id, e);
+
+                return "Couldn't look that message up.".to_string();
+            }
+        };
+        let conn = self.pool.get().await.unwrap();
+        let (slackid, ircid) = conn
+            .interact(
+                move |conn| -> anyhow::Result<(Option<String>, Option<String>)> {
+                    Ok(conn.query_row(
+                        "SELECT slackid, ircid FROM messages WHERE id = ?1",
+                        params![pipo_id],
+                        |row| Ok((row.get(0)?, row.get(1)?)),
+                    )?)
+                },
+            )
+            .await
+            .unwrap_or_else(|_| Err(anyhow!("Interact Error")))
+            .unwrap_or((None, None));
+        let mut content = MessageBuilder::new();
+
+        content
+            .push(format!("Bridged message #{}, posted by ", pipo_id))
+            .push_bold_safe(archived.username)
+            .push(format!(
+                " on {} <t:{}:R>.\n",
+                archived.transport, archived.created
+            ));
+
+        if let Some(slackid) = slackid {
+            content.push("Slack: ").push_mono_line_safe(slackid);
+        }
+        if let Some(ircid) = ircid {
+            content.push("IRC: ").push_mono_line_safe(ircid);
+        }
+
+        content.build()
+    }
+
+    async fn bridge_status(&self, channel: ChannelId) -> String {
+        let Some(handler_channel) = self.shared.get_channel(channel) else {
+            return "This channel isn't bridged.".to_string();
+        };
+        let threads = self.shared.threads_of(channel);
+        let archived = threads.iter().filter(|(_, archived, _)| *archived).count();
+        let on_off = |on: bool| if on { "on" } else { "off" };
+        let mut content = MessageBuilder::new();
+
+        content
+            .push("Bus: ")
+            .push_mono_line_safe(handler_channel.bus)
+            .push_line(format!(
+                "Webhook: {}",
+                if handler_channel.webhook.is_some() {
+                    "ready"
+                } else {
+                    "missing, posting as the bot"
+                }
+            ))
+            .push_line(format!(
+                "Threads: {} active, {} archived",
+                threads.len() - archived,
+                archived
+            ))
+            .push_line(format!("Topic sync: {}", on_off(self.topic_sync)))
+            .push_line(format!(
+                "Direct messages: {}",
+                on_off(self.direct.is_some())
+            ))
+            .push_line(format!("Media store: {}", on_off(self.media.is_some())));
+
+        content.build()
+    }
+}
+
+impl Discord {
+    /// Passes an answer to a /names on to whoever asked, as a followup
+    /// only they can see.
+    pub(super) async fn handle_names_message(
+        &self,
+        channel: ChannelId,
+        transport: String,
+        username: String,
+        message: Option<String>,
+    ) -> anyhow::Result<()> {
+        let Some(message) = message.filter(|message| message != "/names") else {
+            // Discord doesn't list its members to the other transports.
+            return Ok(());
+        };
+        let Some(request) = self.shared.get_names_request(channel, &username) else {
+            return Ok(());
+        };
+        let http = self.cache_http.as_ref().unwrap().http();
+        let users = serde_json::from_str::<Vec<String>>(&message)?;
+        let mut content = MessageBuilder::new();
+
+        content.push_bold_safe(transport).push(": ");
+
+        if users.is_empty() {
+            content.push("No users.");
+        } else {
+            content.push_safe(users.join(", "));
+        }
+
+        request
+            .interaction
+            .create_followup(
+                http,
+                CreateInteractionResponseFollowup::new()
+                    .content(content.build())
+                    .ephemeral(true),
+            )
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message_ids_are_read_from_links_and_ids() {
+        assert_eq!(
+            parse_message_id("https://discord.com/channels/1/2/1234567890"),
+            Some(MessageId::new(1234567890))
+        );
+        assert_eq!(
+            parse_message_id(" 1234567890 "),
+            Some(MessageId::new(1234567890))
+        );
+        assert_eq!(
+            parse_message_id("https://discord.com/channels/1/2/1234567890/"),
+            Some(MessageId::new(1234567890))
+        );
+        assert_eq!(parse_message_id("hello"), None);
+        assert_eq!(parse_message_id("0"), None);
+    }
+}
@@ -0,0 +1,211 @@
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time::{timeout, Duration},
+};
+
+use crate::{identicon, media::MediaStore, paste::PasteService};
+
+const MAX_REQUEST_BYTES: usize = 8192;
+// Identicon seeds are hex-encoded hash prefixes.
+const MAX_IDENTICON_SEED_LEN: usize = 64;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct HttpConfig {
+    /// Address the built-in HTTP server binds to, e.g. "0.0.0.0:8080".
+    pub(crate) listen: String,
+    /// Base URL under which `listen` is reachable from outside; links
+    /// handed out to chat users are built from it.
+    pub(crate) public_url: String,
+    /// Directory holding uploaded avatars and cached attachment copies.
+    #[serde(default = "default_media_dir")]
+    pub(crate) media_dir: String,
+    /// Attachments larger than this aren't copied into the media cache.
+    #[serde(default = "default_media_max_bytes")]
+    pub(crate) media_max_bytes: u64,
+    /// Days a cached attachment copy is kept.
+    #[serde(default = "default_media_cache_days")]
+    pub(crate) media_cache_days: u64,
+}
+
+fn default_media_dir() -> String {
+    "media".to_string()
+}
+
+fn default_media_max_bytes() -> u64 {
+    25 * 1024 * 1024
+}
+
+fn default_media_cache_days() -> u64 {
+    30
+}
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn text(status: &'static str, body: &str) -> Response {
+        Response {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    fn file(content_type: &'static str, body: Vec<u8>) -> Response {
+        Response {
+            status: "200 OK",
+            content_type,
+            body,
+        }
+    }
+}
+
+/// A deliberately small HTTP/1.1 server: one GET (or HEAD) request per
+/// connection, no keep-alive, no request bodies.
+pub(crate) struct HttpServer {
+    listen: String,
+    pastes: Arc<PasteService>,
+    media: Arc<MediaStore>,
+}
+
+impl HttpServer {
+    pub fn new(
+        config: &HttpConfig,
+        pastes: Arc<PasteService>,
+        media: Arc<MediaStore>,
+    ) -> HttpServer {
+        HttpServer {
+            listen: config.listen.clone(),
+            pastes,
+            media,
+        }
+    }
+
+    pub async fn run(self: Arc<Self>) -> anyhow::Result<()> {
+        let listener = TcpListener::bind(&self.listen).await?;
+
+        eprintln!("HTTP server listening on {}", self.listen);
+
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            let server = self.clone();
+
+            tokio::spawn(async move {
+                if let Err(e) = server.handle_connection(stream).await {
+                    eprintln!("HTTP error from {}: {:#}", peer, e);
+                }
+            });
+        }
+    }
+
+    async fn handle_connection(&self, mut stream: TcpStream) -> anyhow::Result<()> {
+        let request = timeout(REQUEST_TIMEOUT, HttpServer::read_request(&mut stream))
+            .await
+            .map_err(|_| anyhow!("Request timed out"))??;
+        let mut request_line = request.lines().next().unwrap_or("").split_whitespace();
+        let method = request_line.next().unwrap_or("");
+        let path = request_line.next().unwrap_or("");
+        let response = match method {
+            "GET" | "HEAD" => self.route(path).await,
+            _ => Response::text("405 Method Not Allowed", "Method not allowed\n"),
+        };
+        let head = format!(
+            "HTTP/1.1 {}\r\n\
+             Content-Type: {}\r\n\
+             Content-Length: {}\r\n\
+             X-Content-Type-Options: nosniff\r\n\
+             Connection: close\r\n\r\n",
+            response.status,
+            response.content_type,
+            response.body.len()
+        );
+
+        stream.write_all(head.as_bytes()).await?;
+        if method != "HEAD" {
+            stream.write_all(&response.body).await?;
+        }
+        stream.shutdown().await?;
+
+        Ok(())
+    }
+
+    async fn read_request(stream: &mut TcpStream) -> anyhow::Result<String> {
+        let mut buf = Vec::new();
+        let mut chunk = [0; 1024];
+
+        while !buf.windows(4).any(|window| window == b"\r\n\r\n") {
+            let read = stream.read(&mut chunk).await?;
+
+            if read == 0 {
+                break;
+            }
+
+            buf.extend_from_slice(&chunk[..read]);
+
+            if buf.len() > MAX_REQUEST_BYTES {
+                return Err(anyhow!("Request too large"));
+            }
+        }
+
+        Ok(String::from_utf8_lossy(&buf).into_owned())
+    }
+
+    async fn route(&self, path: &str) -> Response {
+        let path = path.split(['?', '#']).next().unwrap_or("");
+
+        if let Some(id) = path.strip_prefix("/paste/") {
+            if !id.is_empty() && id.chars().all(|ch| ch.is_ascii_hexdigit()) {
+                return match self.pastes.get(id).await {
+                    Ok(Some(content)) => Response::text("200 OK", &content),
+                    Ok(None) => Response::text("404 Not Found", "Paste not found\n"),
+                    Err(e) => {
+                        eprintln!("Failed to load paste {}: {:#}", id, e);
+
+                        Response::text("500 Internal Server Error", "Internal error\n")
+                    }
+                };
+            }
+        }
+
+        if let Some(file) = path.strip_prefix("/media/") {
+            if let Some((body, content_type)) = self.media.read(file).await {
+                return Response::file(content_type, body);
+            }
+        }
+
+        if let Some(seed) = path
+            .strip_prefix("/identicon/")
+            .and_then(|file| file.strip_suffix(".png"))
+        {
+            if let Some(seed) = HttpServer::decode_hex(seed) {
+                return Response::file("image/png", identicon::png(&seed));
+            }
+        }
+
+        Response::text("404 Not Found", "Not found\n")
+    }
+
+    fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+        if hex.is_empty()
+            || hex.len() > MAX_IDENTICON_SEED_LEN
+            || !hex.len().is_multiple_of(2)
+            || !hex.chars().all(|ch| ch.is_ascii_hexdigit())
+        {
+            return None;
+        }
+
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+            .collect()
+    }
+}
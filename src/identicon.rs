@@ -0,0 +1,157 @@
+const GRID: usize = 5;
+const CELL: usize = 24;
+const MARGIN: usize = 12;
+const SIZE: usize = GRID * CELL + 2 * MARGIN;
+const BACKGROUND: [u8; 3] = [240, 240, 240];
+// Longest run a stored deflate block can hold.
+const STORED_BLOCK_LEN: usize = 65535;
+
+/// Renders small GitHub-style identicons as PNG, so that users without an
+/// avatar of their own still get a distinct picture. The image depends on
+/// nothing but `seed`, so it can be rendered again whenever it's asked for
+/// instead of being stored.
+pub(crate) fn png(seed: &[u8]) -> Vec<u8> {
+    let byte = |index: usize| seed.get(index % seed.len().max(1)).copied().unwrap_or(0);
+    let colour = hsl_to_rgb(u16::from_be_bytes([byte(0), byte(1)]) % 360, 0.5, 0.55);
+    // The left columns are drawn from the seed and mirrored to the right.
+    let half = GRID.div_ceil(2);
+    let filled = |row: usize, column: usize| {
+        let column = column.min(GRID - 1 - column);
+        let bit = row * half + column;
+
+        (byte(2 + bit / 8) >> (bit % 8)) & 1 == 1
+    };
+    let mut pixels = Vec::with_capacity(SIZE * (1 + SIZE * 3));
+
+    for y in 0..SIZE {
+        // Filter type "none" for every scanline.
+        pixels.push(0);
+
+        for x in 0..SIZE {
+            let in_grid = (MARGIN..MARGIN + GRID * CELL).contains(&x)
+                && (MARGIN..MARGIN + GRID * CELL).contains(&y);
+            let rgb = if in_grid && filled((y - MARGIN) / CELL, (x - MARGIN) / CELL) {
+                colour
+            } else {
+                BACKGROUND
+            };
+
+            pixels.extend_from_slice(&rgb);
+        }
+    }
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(SIZE as u32).to_be_bytes());
+    header.extend_from_slice(&(SIZE as u32).to_be_bytes());
+    // 8-bit RGB, default compression and filtering, no interlacing.
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+    write_chunk(&mut png, b"IHDR", &header);
+    write_chunk(&mut png, b"IDAT", &zlib_stored(&pixels));
+    write_chunk(&mut png, b"IEND", &[]);
+
+    png
+}
+
+fn hsl_to_rgb(hue: u16, saturation: f32, lightness: f32) -> [u8; 3] {
+    let chroma = (1.0 - (2.0 * lightness - 1.0).abs()) * saturation;
+    let sector = hue as f32 / 60.0;
+    let x = chroma * (1.0 - (sector % 2.0 - 1.0).abs());
+    let (r, g, b) = match hue / 60 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    let m = lightness - chroma / 2.0;
+
+    [r, g, b].map(|value| ((value + m) * 255.0).round() as u8)
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// Wraps `data` in a zlib stream of uncompressed blocks. Identicons are
+/// tiny, so compressing them isn't worth a dependency.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = data.chunks(STORED_BLOCK_LEN).peekable();
+
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+
+    while let Some(block) = blocks.next() {
+        let len = block.len() as u16;
+
+        out.push(blocks.peek().is_none() as u8);
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+
+    out
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+
+    for byte in data {
+        crc ^= *byte as u32;
+
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+
+    for byte in data {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksums_match_reference_values() {
+        assert_eq!(crc32(b"IEND"), 0xae42_6082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+    }
+
+    #[test]
+    fn identicons_are_stable_and_distinct() {
+        let alice = png(b"alice");
+
+        assert!(alice.starts_with(b"\x89PNG\r\n\x1a\n"));
+        assert!(alice.ends_with(b"IEND\xae\x42\x60\x82"));
+        assert_eq!(alice, png(b"alice"));
+        assert_ne!(alice, png(b"bob"));
+    }
+}
@@ -1,34 +1,84 @@
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{HashMap, VecDeque},
     sync::{Arc, Mutex},
     time::{Duration, Instant},
 };
 
+use base64::prelude::{Engine, BASE64_STANDARD};
+use chrono::{DateTime, TimeZone, Utc};
 use deadpool_sqlite::Pool;
 use irc::{
-    client::prelude::{Client, Command, Config, Prefix},
+    client::{
+        data::AccessLevel,
+        prelude::{Client, Command, Config, NegotiationVersion, Prefix, Response},
+    },
     proto::{caps::Capability, command::CapSubCommand, message::Tag, Message as IrcMessage},
 };
 use lazy_static::lazy_static;
 use regex::Regex;
-use rusqlite::params;
+use rusqlite::{params, OptionalExtension};
 use serde::Deserialize;
-use tokio::sync::broadcast;
+use tokio::{sync::broadcast, time};
 use tokio_stream::{wrappers::BroadcastStream, StreamMap};
 
-use crate::{Attachment, Message, ThreadRef};
+use crate::{
+    archive::Archive, direct::DirectBridge, paste::PasteService, threads::ThreadMap, Attachment,
+    IrcConfig, Late, Message, ReplyRef, Services, ThreadRef,
+};
 use anyhow::anyhow;
 
+mod accounts;
+mod avatar;
+mod backfill;
+mod notice;
+mod presence;
+mod send_queue;
+mod tls;
+mod topic;
+
+use accounts::{AccountLookup, Pending};
+use avatar::AvatarCache;
+use backfill::{HistoryBackfill, PendingCursors};
+pub(crate) use notice::IrcNoticeConfig;
+use notice::{NoticeKind, NoticeRendering};
+use presence::PresenceTracker;
+use send_queue::{EditGroup, Priority, SendQueue};
+pub(crate) use tls::IrcTlsConfig;
+use topic::TopicTracker;
+
 const TRANSPORT_NAME: &'static str = "IRC";
 const DEFAULT_THREAD_EXCERPT_LEN: usize = 120;
 const REPLY_TOKEN_TTL: Duration = Duration::from_secs(60 * 60 * 6);
+const REPLY_TOKEN_ATTEMPTS: u32 = 64;
 const THREAD_LIST_LIMIT: usize = 8;
+const THREAD_HISTORY_DEFAULT: usize = 10;
+const THREAD_HISTORY_MAX: usize = 50;
+// Text bytes per history NOTICE, leaving room for our hostmask and the
+// requesting nick.
+const THREAD_HISTORY_LINE_BYTES: usize = 400;
+const RELAYMSG_NICK_LEN: usize = 24;
+const SASL_CHUNK_LEN: usize = 400;
+// How long a sent line waits for its echo-message before we give up on
+// learning its server msgid.
+const PENDING_ECHO_TTL: Duration = Duration::from_secs(60 * 5);
+const RECENT_AUTHOR_TTL: Duration = Duration::from_secs(60 * 60 * 6);
+const DEFAULT_MAX_MESSAGE_LINES: usize = 10;
+const IRC_LINE_MAX_BYTES: usize = 512;
+// "!" + USERLEN (10, including a "~") + "@" + HOSTLEN (63), used until the
+// server tells us our real hostmask.
+const WORST_CASE_USERHOST_LEN: usize = 1 + 10 + 1 + 63;
+const RELAYMSG_NICK_SPECIAL_CHARS: &str = "[]\\`_^{|}-";
+const DEFAULT_TLS_PORT: u16 = 6697;
+// Pause after every configured server failed to connect in a row.
+const CONNECT_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+// Lines sent but not yet echoed back, keyed by (channel, text).
+type PendingEchoes = HashMap<(String, String), VecDeque<(i64, Instant)>>;
 
 #[derive(Clone, Debug)]
 struct ReplyTokenEntry {
     thread_ref: ThreadRef,
     nickname: Option<String>,
-    created_at: Instant,
 }
 
 #[derive(Clone, Copy, Debug, Default, Deserialize)]
@@ -57,6 +107,186 @@ pub(crate) enum ThreadContextRepeat {
     Never,
 }
 
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum RelaymsgMode {
+    #[default]
+    Auto,
+    PlaintextOnly,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ReactionMode {
+    /// Only send reactions as IRCv3 `+draft/react` tags.
+    #[default]
+    TagsOnly,
+    /// Use tags when possible, otherwise a plaintext line.
+    Auto,
+    PlaintextOnly,
+    Off,
+}
+
+/// What an IRC channel is mapped to: just a bus name, or a bus with
+/// per-channel settings.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub(crate) enum IrcChannelMapping {
+    Bus(Arc<String>),
+    Channel(IrcChannelConfig),
+}
+
+impl IrcChannelMapping {
+    fn bus(&self) -> &Arc<String> {
+        match self {
+            IrcChannelMapping::Bus(bus) => bus,
+            IrcChannelMapping::Channel(channel) => &channel.bus,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct IrcChannelConfig {
+    bus: Arc<String>,
+    /// Channel key sent with JOIN, for +k channels.
+    #[serde(default)]
+    key: Option<String>,
+    #[serde(default)]
+    rejoin_on_kick: bool,
+    #[serde(default = "default_rejoin_delay_secs")]
+    rejoin_delay_secs: u64,
+    /// Join when invited, e.g. to get back into an invite-only channel.
+    #[serde(default)]
+    join_on_invite: bool,
+    // Overrides of the transport-wide thread presentation options.
+    #[serde(default)]
+    thread_presentation_mode: Option<ThreadPresentationMode>,
+    #[serde(default)]
+    thread_fallback_style: Option<ThreadFallbackStyle>,
+    #[serde(default)]
+    thread_context_repeat: Option<ThreadContextRepeat>,
+    #[serde(default)]
+    thread_excerpt_len: Option<usize>,
+    #[serde(default)]
+    show_thread_root_marker: Option<bool>,
+}
+
+fn default_rejoin_delay_secs() -> u64 {
+    10
+}
+
+#[derive(Clone, Copy, Debug)]
+struct ThreadOptions {
+    presentation_mode: ThreadPresentationMode,
+    fallback_style: ThreadFallbackStyle,
+    context_repeat: ThreadContextRepeat,
+    excerpt_len: usize,
+    show_root_marker: bool,
+}
+
+impl ThreadOptions {
+    fn with_overrides(self, channel: &IrcChannelConfig) -> ThreadOptions {
+        ThreadOptions {
+            presentation_mode: channel
+                .thread_presentation_mode
+                .unwrap_or(self.presentation_mode),
+            fallback_style: channel.thread_fallback_style.unwrap_or(self.fallback_style),
+            context_repeat: channel.thread_context_repeat.unwrap_or(self.context_repeat),
+            excerpt_len: match channel.thread_excerpt_len {
+                Some(0) => DEFAULT_THREAD_EXCERPT_LEN,
+                Some(excerpt_len) => excerpt_len,
+                None => self.excerpt_len,
+            },
+            show_root_marker: channel
+                .show_thread_root_marker
+                .unwrap_or(self.show_root_marker),
+        }
+    }
+}
+
+/// Settings of a mapped channel, with the transport-wide defaults filled
+/// in.
+#[derive(Clone, Debug)]
+struct ChannelOptions {
+    key: Option<String>,
+    rejoin_delay: Option<Duration>,
+    join_on_invite: bool,
+    thread: ThreadOptions,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum PresenceRelay {
+    #[default]
+    None,
+    /// Only relay users joining the channel.
+    Joins,
+    /// Relay joins, parts, quits, kicks and nick changes.
+    All,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub(crate) struct IrcAuthConfig {
+    /// Server password sent with PASS during registration.
+    #[serde(default)]
+    pub(crate) server_password: Option<String>,
+    /// Nicks tried in order when the configured nickname is in use.
+    #[serde(default)]
+    pub(crate) alt_nicks: Vec<String>,
+    #[serde(default)]
+    pub(crate) sasl: Option<SaslConfig>,
+    /// Used for NickServ IDENTIFY when SASL is not configured or fails,
+    /// and for nick recovery.
+    #[serde(default)]
+    pub(crate) nickserv_password: Option<String>,
+    #[serde(default)]
+    pub(crate) nick_recovery: NickRecovery,
+    /// PKCS#12 client certificate, required for SASL EXTERNAL (CertFP).
+    #[serde(default)]
+    pub(crate) client_cert_path: Option<String>,
+    #[serde(default)]
+    pub(crate) client_cert_pass: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "mechanism", rename_all = "snake_case")]
+pub(crate) enum SaslConfig {
+    Plain {
+        /// Account name; defaults to the configured nickname.
+        #[serde(default)]
+        username: Option<String>,
+        password: String,
+    },
+    External,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum NickRecovery {
+    #[default]
+    None,
+    Ghost,
+    Regain,
+    Recover,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum SaslProgress {
+    #[default]
+    NotStarted,
+    Requested,
+    Authenticating,
+    Succeeded,
+    Failed,
+}
+
+#[derive(Clone, Debug, Default)]
+struct IrcAuthState {
+    sasl: SaslProgress,
+    nickserv_identify_pending: bool,
+    channels_joined: bool,
+}
+
 #[derive(Debug)]
 struct ThreadPresentation {
     reply_target: Option<String>,
@@ -67,99 +297,331 @@ struct ThreadPresentation {
 pub(crate) struct IRC {
     transport_id: usize,
     config: Config,
-    img_root: String,
+    // Tried in turn, moving on whenever connecting fails.
+    servers: Vec<String>,
+    current_server: usize,
+    tls: Option<IrcTlsConfig>,
+    avatars: AvatarCache,
     channels: HashMap<String, broadcast::Sender<Message>>,
+    // Bus id of each channel, by channel key, for looking messages up in
+    // the archive.
+    buses: HashMap<String, String>,
     pool: Pool,
     pipo_id: Arc<Mutex<i64>>,
     capabilities: IrcCapabilityState,
-    thread_presentation_mode: ThreadPresentationMode,
-    thread_fallback_style: ThreadFallbackStyle,
-    thread_context_repeat: ThreadContextRepeat,
-    thread_excerpt_len: usize,
-    show_thread_root_marker: bool,
-    seen_thread_tokens: Arc<Mutex<HashMap<String, HashSet<String>>>>,
-    reply_tokens: Arc<Mutex<HashMap<(String, String), ReplyTokenEntry>>>,
+    thread_options: ThreadOptions,
+    channel_options: HashMap<String, ChannelOptions>,
+    relaymsg_mode: RelaymsgMode,
+    max_message_lines: usize,
+    pastes: Option<Arc<PasteService>>,
+    send_burst: u32,
+    send_interval: Duration,
+    send_queue: Option<SendQueue>,
+    hostmask: Option<String>,
+    // From the prefix of the server's welcome and ISUPPORT replies.
+    server_name: Option<String>,
+    auth: IrcAuthConfig,
+    auth_state: IrcAuthState,
+    deleted_message_notice: bool,
+    reaction_mode: ReactionMode,
+    presence: PresenceTracker,
+    history_backfill: HistoryBackfill,
+    history_cursors: Mutex<PendingCursors>,
+    direct: Option<Arc<DirectBridge>>,
+    accounts: AccountLookup,
+    notices: IrcNoticeConfig,
+    topic_sync: bool,
+    // Last known topic of each channel, so that a topic we set ourselves
+    // isn't relayed back when the server echoes it.
+    topics: TopicTracker,
+    archive: Arc<Archive>,
+    threads: Arc<ThreadMap>,
+    pending_echoes: Arc<Mutex<PendingEchoes>>,
+    recent_authors: Arc<Mutex<HashMap<i64, (String, Instant)>>>,
 }
 
 #[derive(Clone, Debug, Default)]
 struct IrcCapabilityState {
     supports_message_tags: bool,
     supports_reply_tags: bool,
+    supports_relaymsg: bool,
+    supports_echo_message: bool,
+    supports_redaction: bool,
+    supports_chathistory: bool,
+    supports_account_tag: bool,
+    relaymsg_separators: Option<String>,
 }
 
 impl IRC {
     pub async fn new(
-        bus_map: &HashMap<String, broadcast::Sender<Message>>,
-        pipo_id: Arc<Mutex<i64>>,
-        pool: Pool,
-        nickname: String,
-        server: String,
-        use_tls: bool,
-        img_root: &str,
-        channel_mapping: &HashMap<Arc<String>, Arc<String>>,
-        thread_presentation_mode: ThreadPresentationMode,
-        thread_fallback_style: ThreadFallbackStyle,
-        thread_context_repeat: ThreadContextRepeat,
-        thread_excerpt_len: usize,
-        show_thread_root_marker: bool,
         transport_id: usize,
+        bus_map: &HashMap<String, broadcast::Sender<Message>>,
+        irc: &IrcConfig,
+        services: &Services,
+        direct: Option<Arc<DirectBridge>>,
     ) -> anyhow::Result<IRC> {
+        let channel_mapping = &irc.channel_mapping;
+        let servers: Vec<String> = irc
+            .server
+            .iter()
+            .map(|server| server.to_string())
+            .chain(irc.servers.iter().cloned())
+            .collect();
+        let auth = irc.auth.clone();
         let channels = channel_mapping
             .iter()
-            .filter_map(|(channelname, busname)| {
-                if let Some(sender) = bus_map.get(busname.as_ref()) {
+            .filter_map(|(channelname, mapping)| {
+                if let Some(sender) = bus_map.get(mapping.bus().as_ref()) {
                     Some((channelname.as_ref().clone(), sender.clone()))
                 } else {
-                    eprintln!("No bus named '{}' in configuration file.", busname);
+                    eprintln!("No bus named '{}' in configuration file.", mapping.bus());
                     None
                 }
             })
             .collect();
-        // Can this be done without an if/else?
-        let config = if let Some((server_addr, server_port)) = server.rsplit_once(':') {
-            Config {
-                nickname: Some(nickname.clone()).to_owned(),
-                server: Some(server_addr.to_string()).to_owned(),
-                port: Some(server_port.parse()?).to_owned(),
-                use_tls: Some(use_tls).to_owned(),
-                ..Config::default()
-            }
-        } else {
-            Config {
-                nickname: Some(nickname.clone()).to_owned(),
-                server: Some(server.clone()).to_owned(),
-                use_tls: Some(use_tls).to_owned(),
-                ..Config::default()
-            }
+        let buses = channel_mapping
+            .iter()
+            .map(|(channelname, mapping)| {
+                (IRC::channel_key(channelname), mapping.bus().to_string())
+            })
+            .collect();
+        let thread_options = ThreadOptions {
+            presentation_mode: irc.thread_presentation_mode,
+            fallback_style: irc.thread_fallback_style,
+            context_repeat: irc.thread_context_repeat,
+            excerpt_len: if irc.thread_excerpt_len == 0 {
+                DEFAULT_THREAD_EXCERPT_LEN
+            } else {
+                irc.thread_excerpt_len
+            },
+            show_root_marker: irc.show_thread_root_marker,
+        };
+        let channel_options = channel_mapping
+            .iter()
+            .map(|(channelname, mapping)| {
+                let options = match mapping {
+                    IrcChannelMapping::Bus(_) => ChannelOptions {
+                        key: None,
+                        rejoin_delay: None,
+                        join_on_invite: false,
+                        thread: thread_options,
+                    },
+                    IrcChannelMapping::Channel(channel) => ChannelOptions {
+                        key: channel.key.clone(),
+                        rejoin_delay: channel
+                            .rejoin_on_kick
+                            .then(|| Duration::from_secs(channel.rejoin_delay_secs)),
+                        join_on_invite: channel.join_on_invite,
+                        thread: thread_options.with_overrides(channel),
+                    },
+                };
+
+                (IRC::channel_key(channelname), options)
+            })
+            .collect();
+        if servers.is_empty() {
+            return Err(anyhow!("IRC transport {} has no server", transport_id));
+        }
+        for server in servers.iter() {
+            IRC::split_server(server)?;
+        }
+
+        // The server is filled in on every connection attempt.
+        let mut config = Config {
+            nickname: Some(irc.nickname.to_string()),
+            use_tls: Some(irc.use_tls),
+            ..Config::default()
+        };
+        // NickServ IDENTIFY and nick recovery are handled by us rather than
+        // the irc crate so that they can be skipped after a SASL login.
+        config.password = auth.server_password.clone();
+        config.alt_nicks = auth.alt_nicks.clone();
+        config.client_cert_path = auth.client_cert_path.clone();
+        config.client_cert_pass = auth.client_cert_pass.clone();
+
+        let has_client_cert = match irc.tls.as_ref() {
+            Some(tls) => tls.has_client_cert(),
+            None => auth.client_cert_path.is_some(),
         };
 
+        if matches!(auth.sasl, Some(SaslConfig::External)) && !has_client_cert {
+            eprintln!(
+                "IRC transport {} uses SASL EXTERNAL without a client certificate",
+                transport_id
+            );
+        }
+        if irc.tls.is_some() && auth.client_cert_path.is_some() {
+            eprintln!(
+                "IRC transport {} ignores client_cert_path, use tls.client_cert_path instead",
+                transport_id
+            );
+        }
+
+        services
+            .pool
+            .get()
+            .await?
+            .interact(|conn| -> anyhow::Result<()> {
+                // Server msgids of every line we relayed, so that the whole
+                // message can be redacted later.
+                conn.execute_batch(
+                    "CREATE TABLE IF NOT EXISTS irc_sent_messages (
+                                     transport_id INTEGER NOT NULL,
+                                     channel      TEXT NOT NULL,
+                                     msgid        TEXT NOT NULL,
+                                     pipo_id      INTEGER NOT NULL,
+                                     PRIMARY KEY (transport_id, msgid)
+                                     );
+                     CREATE INDEX IF NOT EXISTS irc_sent_messages_pipo_id
+                       ON irc_sent_messages (transport_id, channel, pipo_id);",
+                )?;
+                // Reply tokens handed out in plaintext thread markers, and
+                // which of them already had their full context shown, so
+                // that >>TOKEN replies keep working across restarts.
+                conn.execute_batch(
+                    "CREATE TABLE IF NOT EXISTS irc_reply_tokens (
+                                     transport_id     INTEGER NOT NULL,
+                                     channel          TEXT NOT NULL,
+                                     token            TEXT NOT NULL,
+                                     thread_key       TEXT NOT NULL,
+                                     origin_transport TEXT NOT NULL,
+                                     thread_root_id   TEXT,
+                                     reply_target_id  INTEGER,
+                                     root_pipo_id     INTEGER,
+                                     root_author      TEXT,
+                                     root_excerpt     TEXT,
+                                     nickname         TEXT,
+                                     created          INTEGER NOT NULL,
+                                     PRIMARY KEY (transport_id, channel, token)
+                                     );
+                     CREATE INDEX IF NOT EXISTS irc_reply_tokens_thread_key
+                       ON irc_reply_tokens (transport_id, channel, thread_key);
+                     CREATE TABLE IF NOT EXISTS irc_seen_thread_tokens (
+                                     transport_id INTEGER NOT NULL,
+                                     channel      TEXT NOT NULL,
+                                     token        TEXT NOT NULL,
+                                     created      INTEGER NOT NULL,
+                                     PRIMARY KEY (transport_id, channel, token)
+                                     );",
+                )?;
+
+                let root_pipo_id_exists = conn
+                    .prepare("PRAGMA table_info(irc_reply_tokens)")?
+                    .query_map([], |row| row.get::<usize, String>(1))?
+                    .filter_map(Result::ok)
+                    .any(|column| column == "root_pipo_id");
+                if !root_pipo_id_exists {
+                    conn.execute(
+                        "ALTER TABLE irc_reply_tokens ADD COLUMN root_pipo_id INTEGER",
+                        [],
+                    )?;
+                }
+
+                // The newest msgid seen in each channel, where CHATHISTORY
+                // picks up after a reconnect or restart.
+                conn.execute_batch(
+                    "CREATE TABLE IF NOT EXISTS irc_history_cursors (
+                                     transport_id INTEGER NOT NULL,
+                                     channel      TEXT NOT NULL,
+                                     msgid        TEXT NOT NULL,
+                                     time         TEXT NOT NULL,
+                                     PRIMARY KEY (transport_id, channel)
+                                     );",
+                )?;
+
+                Ok(())
+            })
+            .await
+            .unwrap_or_else(|_| Err(anyhow!("Interact Error")))?;
+
         Ok(IRC {
             config,
-            img_root: img_root.to_string(),
+            servers,
+            current_server: 0,
+            tls: irc.tls.clone(),
+            avatars: AvatarCache::new(
+                irc.img_root.as_deref().map(String::as_str),
+                services.media.clone(),
+                Duration::from_secs(irc.avatar_cache_ttl_secs),
+            ),
             channels,
+            buses,
             transport_id,
-            pool,
-            pipo_id,
+            pool: services.pool.clone(),
+            pipo_id: services.pipo_id.clone(),
             capabilities: IrcCapabilityState::default(),
-            thread_presentation_mode,
-            thread_fallback_style,
-            thread_context_repeat,
-            thread_excerpt_len: if thread_excerpt_len == 0 {
-                DEFAULT_THREAD_EXCERPT_LEN
+            thread_options,
+            channel_options,
+            relaymsg_mode: irc.relaymsg_mode,
+            max_message_lines: if irc.max_message_lines == 0 {
+                DEFAULT_MAX_MESSAGE_LINES
             } else {
-                thread_excerpt_len
+                irc.max_message_lines
             },
-            show_thread_root_marker,
-            seen_thread_tokens: Arc::new(Mutex::new(HashMap::new())),
-            reply_tokens: Arc::new(Mutex::new(HashMap::new())),
+            pastes: services.pastes.clone(),
+            send_burst: irc.send_burst,
+            send_interval: Duration::from_millis(irc.send_interval_ms),
+            send_queue: None,
+            hostmask: None,
+            server_name: None,
+            auth,
+            auth_state: IrcAuthState::default(),
+            deleted_message_notice: irc.deleted_message_notice,
+            reaction_mode: irc.reaction_mode,
+            presence: PresenceTracker::new(irc.presence_relay),
+            history_backfill: HistoryBackfill::new(irc.history_backfill_limit),
+            history_cursors: Mutex::new(PendingCursors::new()),
+            direct,
+            accounts: AccountLookup::new(),
+            notices: irc.notices.clone(),
+            topic_sync: irc.topic_sync,
+            topics: TopicTracker::new(),
+            archive: services.archive.clone(),
+            threads: services.threads.clone(),
+            pending_echoes: Arc::new(Mutex::new(HashMap::new())),
+            recent_authors: Arc::new(Mutex::new(HashMap::new())),
         })
     }
 
     pub async fn connect(&mut self) -> anyhow::Result<()> {
+        let mut failures = 0;
+
         loop {
-            let (client, mut irc_stream, mut input_buses) = self.connect_irc().await?;
+            let (client, mut irc_stream, mut input_buses) = match self.connect_irc().await {
+                Ok(connection) => {
+                    failures = 0;
+                    connection
+                }
+                Err(e) => {
+                    eprintln!(
+                        "Couldn't connect to IRC server {}: {:#}",
+                        self.servers[self.current_server], e
+                    );
+                    self.current_server = (self.current_server + 1) % self.servers.len();
+                    failures += 1;
+
+                    if failures % self.servers.len() == 0 {
+                        time::sleep(CONNECT_RETRY_DELAY).await;
+                    }
+
+                    continue;
+                }
+            };
+            let mut direct_messages = StreamMap::new();
+
+            if let Some(direct) = self.direct.as_ref() {
+                direct_messages.insert((), direct.subscribe());
+            }
 
             loop {
+                let presence_flush = self.presence.next_flush().map(time::Instant::from_std);
+                let cursor_flush = self
+                    .history_cursors
+                    .lock()
+                    .unwrap()
+                    .next_flush()
+                    .map(time::Instant::from_std);
+
                 // stupid sexy infinite loop
                 tokio::select! {
                 Some((channel, message))
@@ -173,10 +635,12 @@ impl IRC {
                         username,
                         avatar_url: _,
                         thread,
+                        reply_to,
                         message,
                         attachments,
                         is_edit,
                         irc_flag,
+                        late,
                         } => {
                         if sender != self.transport_id {
                             self.handle_action_message(&client,
@@ -185,7 +649,8 @@ impl IRC {
                                            transport,
                                            username,
                                            thread,
-                                           message,
+                                           reply_to,
+                                           Late::mark(late, message),
                                            attachments,
                                            is_edit,
                                            irc_flag).await;
@@ -205,14 +670,20 @@ impl IRC {
                                         transport,
                                         message,
                                         attachments,
-                                        is_edit);
+                                        is_edit).await;
                         }
                         },
                         Message::Delete {
-                        sender: _,
-                        pipo_id: _,
+                        sender,
+                        pipo_id,
                         transport: _,
                         } => {
+                        if sender != self.transport_id {
+                            self.handle_delete_message(&channel,
+                                           pipo_id).await;
+                        }
+                        },
+                        Message::Direct { .. } => {
                         continue
                         },
                         Message::Names {
@@ -235,18 +706,22 @@ impl IRC {
                         } => {
                         continue
                         },
+                        Message::Presence { .. } => {
+                        continue
+                        },
+                        Message::Notice { .. } => {
+                        continue
+                        },
                         Message::Reaction {
-                        sender: _,
-                        pipo_id: _,
-                        transport: _,
-                        emoji: _,
-                        remove: _,
-                        username: _,
-                        avatar_url: _,
-                        thread: _,
-                        } => {
+                        sender,
+                        ..
+                        } if sender == self.transport_id => {
                         continue
                         },
+                        reaction @ Message::Reaction { .. } => {
+                        self.handle_reaction_message(&channel,
+                                         reaction).await;
+                        },
                         Message::Text {
                         sender,
                         pipo_id,
@@ -254,10 +729,12 @@ impl IRC {
                         username,
                         avatar_url: _,
                         thread,
+                        reply_to,
                         message,
                         attachments,
                         is_edit,
                         irc_flag,
+                        late,
                         } => {
                         if sender != self.transport_id {
                             self.handle_text_message(&client,
@@ -266,14 +743,58 @@ impl IRC {
                                          transport,
                                          username,
                                          thread,
-                                         message,
+                                         reply_to,
+                                         Late::mark(late, message),
                                          attachments,
                                          is_edit,
                                          irc_flag).await;
                         }
                         },
+                        Message::Topic {
+                        sender,
+                        transport: _,
+                        username: _,
+                        topic,
+                        } => {
+                        if sender != self.transport_id {
+                            self.handle_topic_message(&client, &channel, topic);
+                        }
+                        },
+                    }
                     }
+                Some(((), message))
+                    = tokio_stream::StreamExt::next(&mut direct_messages) => {
+                    match message {
+                        Ok(Message::Direct {
+                        sender,
+                        transport,
+                        username,
+                        recipient,
+                        recipient_transport,
+                        message,
+                        }) => {
+                        self.handle_direct_message(&client,
+                                       sender,
+                                       transport,
+                                       username,
+                                       recipient,
+                                       recipient_transport,
+                                       message).await;
+                        },
+                        Ok(_) => continue,
+                        Err(e) => eprintln!("DM bus error: {}", e),
                     }
+                    }
+                _ = time::sleep_until(presence_flush.unwrap_or_else(time::Instant::now)),
+                    if presence_flush.is_some() => {
+                    let lines = self.presence.flush(Instant::now());
+
+                    self.publish_presence(lines);
+                }
+                _ = time::sleep_until(cursor_flush.unwrap_or_else(time::Instant::now)),
+                    if cursor_flush.is_some() => {
+                    self.flush_history_cursors().await;
+                }
                 Some(message)
                     = tokio_stream::StreamExt::next(&mut irc_stream) => {
                     if let Err(e) = message {
@@ -288,27 +809,136 @@ impl IRC {
                         None => "".to_string(),
                     };
                     self.update_capabilities_from_message(&message);
+                    self.handle_standard_replies(&message);
+                    self.history_backfill.update_from_isupport(&message);
+                    self.accounts.update_from_isupport(&message);
+                    self.topics.update_from_isupport(&message);
+                    self.update_server_name(&message);
+
+                    if let Some((nickname, account, pending)) = self.accounts.handle_reply(&message) {
+                        for message in pending {
+                            match message {
+                                Pending::Received(text) => {
+                                    if let Err(e) = self.handle_direct_privmsg(&nickname,
+                                                                               account.as_deref(),
+                                                                               &text).await {
+                                        eprintln!("Error handling private PRIVMSG: {:#}", e);
+                                    }
+                                },
+                                Pending::Bridged { sender, transport, username, message } => {
+                                    self.deliver_direct_message(&client,
+                                                                &nickname,
+                                                                account.as_deref(),
+                                                                sender,
+                                                                transport,
+                                                                username,
+                                                                message).await;
+                                },
+                            }
+                        }
+
+                        continue
+                    }
+
+                    if self.history_backfill.handle_batch(&message) {
+                        continue
+                    }
+                    if let Some(channel) = self.history_backfill.batch_channel(&message) {
+                        let channel = channel.to_string();
+
+                        if let Err(e) = self.handle_backfill_message(&client, &channel, message).await {
+                            eprintln!("Error handling CHATHISTORY message: {:#}", e);
+                        }
+
+                        continue
+                    }
+
+                    if let Err(e) = self.handle_registration_message(&client, &message) {
+                        eprintln!("IRC registration error: {:#}", e);
+                    }
+                    self.update_hostmask_from_message(&client, &message);
+
+                    if let Command::NICK(ref new_nick) = message.command {
+                        self.avatars.invalidate(&nickname);
+                        self.avatars.invalidate(new_nick);
+                    }
+
+                    let lines = self.presence.handle(&message,
+                                                     client.current_nickname(),
+                                                     Instant::now());
+
+                    self.publish_presence(lines);
+                    self.update_topic_from_message(&client, &message, &nickname);
+                    self.handle_kick_and_invite(&client, &message);
+
+                    if let Command::JOIN(ref channel, _, _) = message.command {
+                        if nickname == client.current_nickname() {
+                            self.request_channel_modes(channel);
+                            self.request_history(channel).await;
+                        }
+                    }
+
+                    self.advance_history_cursor(&message).await;
+
+                    if self.is_own_echo(&client, &message) {
+                        self.record_own_echo(&message).await;
+
+                        continue
+                    }
 
                     let irc_message_id = IRC::parse_message_id_tag(&message);
 
+                    if let Command::Raw(ref command, ref args) = message.command {
+                        if command == "REDACT"
+                            && message.source_nickname() != Some(client.current_nickname()) {
+                            if let Err(e) = self.handle_redact(args).await {
+                                eprintln!("Error handling REDACT: {}", e);
+                            }
+                        }
+                        else if command == "TAGMSG"
+                            && message.source_nickname() != Some(client.current_nickname()) {
+                            if let Err(e) = self.handle_tagmsg(&nickname, args, &message).await {
+                                eprintln!("Error handling TAGMSG: {}", e);
+                            }
+                        }
+                    }
+
+                    if let Command::PRIVMSG(ref target, ref text) = message.command {
+                        if self.direct.is_some()
+                            && IRC::channel_key(target) == IRC::channel_key(client.current_nickname()) {
+                            if let Err(e) = self.handle_private_privmsg(&message, &nickname, text).await {
+                                eprintln!("Error handling private PRIVMSG: {:#}", e);
+                            }
+
+                            continue
+                        }
+                    }
+
                     if let Command::PRIVMSG(channel, message)
                         = message.command {
-                        if let Err(e) = self.handle_priv_msg(&client,
-                                             nickname,
-                                             channel,
-                                             message,
-                                             irc_message_id)
+                        if let Err(e) = self.handle_priv_msg(nickname,
+                                                             channel,
+                                                             message,
+                                                             irc_message_id,
+                                                             None)
                             .await {
                             eprintln!("Error handling PRIVMSG: {}",
                                   e);
                             }
                         }
-                    else if let Command::NOTICE(channel, message)
+                    else if let Command::NOTICE(channel, text)
                         = message.command {
-                        if let Err(e) = self.handle_notice(nickname,
+                        let kind = self.notices.classify(message.prefix.as_ref(),
+                                                         &channel,
+                                                         client.current_nickname(),
+                                                         self.server_name.as_deref());
+
+                        if let Err(e) = self.handle_notice(kind,
+                                           nickname,
                                            channel,
-                                           message,
-                                           irc_message_id)
+                                           text,
+                                           irc_message_id,
+                                           None)
                             .await {
                             eprintln!("Error handling NOTICE: {}",
                                   e);
@@ -318,6 +948,8 @@ impl IRC {
                    else => break
                 }
             }
+
+            self.flush_history_cursors().await;
         }
     }
 
@@ -329,82 +961,107 @@ impl IRC {
         transport: String,
         username: String,
         thread: Option<crate::ThreadRef>,
+        reply_to: Option<ReplyRef>,
         message: Option<String>,
         attachments: Option<Vec<Attachment>>,
         is_edit: bool,
         irc_flag: bool,
     ) {
         let irc_message_id = self.ensure_ircid_for_pipo_id(pipo_id).await;
-        let mut message = message;
+        // IRC can't carry files, so they're bridged as links.
+        let (mut message, attachments) = Attachment::inline_file_links(message, attachments);
 
         if irc_flag && is_edit {
             message = None
         }
         if let Some(message) = message {
-            let mut is_edit = is_edit;
             let thread_presentation = self
-                .resolve_thread_presentation(channel, pipo_id, &thread)
+                .resolve_thread_presentation(channel, pipo_id, &thread, &reply_to)
                 .await;
 
             if thread.is_some() {
                 self.log_thread_presentation(channel, pipo_id, &thread_presentation);
             }
 
-            if let Some(prefix) = thread_presentation.plaintext_prefix.as_ref() {
-                let prefix_message = format!(
+            let relay_nick = self.relay_nick(&transport, &username);
+            let edit_group = self.edit_group(channel, pipo_id, is_edit);
+
+            self.record_author(pipo_id, &username);
+
+            let format_line = |line: &str| match relay_nick {
+                Some(_) => format!("\x01ACTION {}\x01", line),
+                None => format!(
                     "\x01ACTION \x02* \x02{}!\x02{}\x02 {}\x01",
                     &transport[..1].to_uppercase(),
                     username,
-                    prefix
-                );
+                    line
+                ),
+            };
 
-                if let Err(e) = self
-                    .send_privmsg_with_tags(
+            if let Some(prefix) = thread_presentation.plaintext_prefix.as_ref() {
+                for prefix_message in self
+                    .wrap_outbound_lines(
                         client,
                         channel,
-                        prefix_message.clone(),
-                        thread_presentation.reply_target.as_deref(),
-                        irc_message_id.as_deref(),
+                        relay_nick.as_deref(),
+                        std::slice::from_ref(prefix),
+                        None,
+                        &format_line,
                     )
                     .await
                 {
-                    eprintln!(
-                        "Failed to send message '{}' channel {}: {:#}",
-                        prefix_message, channel, e
-                    );
+                    if let Err(e) = self
+                        .send_privmsg_with_tags(
+                            channel,
+                            pipo_id,
+                            prefix_message.clone(),
+                            relay_nick.as_deref(),
+                            thread_presentation.reply_target.as_deref(),
+                            irc_message_id.as_deref(),
+                            edit_group.as_ref(),
+                        )
+                        .await
+                    {
+                        eprintln!(
+                            "Failed to send message '{}' channel {}: {:#}",
+                            prefix_message, channel, e
+                        );
+                    }
                 }
             }
 
-            for msg in message.split("\n") {
-                if msg == "" {
-                    continue;
-                }
-
-                let message = if is_edit {
-                    is_edit = false;
+            let mut lines = message
+                .split('\n')
+                .filter(|line| !line.is_empty())
+                .map(str::to_string)
+                .collect::<Vec<_>>();
 
-                    format!(
-                        "\x01ACTION \x02* \x02{}!\x02{}\x02 {}*\x01",
-                        &transport[..1].to_uppercase(),
-                        username,
-                        msg
-                    )
-                } else {
-                    format!(
-                        "\x01ACTION \x02* \x02{}!\x02{}\x02 {}\x01",
-                        &transport[..1].to_uppercase(),
-                        username,
-                        msg
-                    )
-                };
+            if is_edit {
+                if let Some(first) = lines.first_mut() {
+                    *first = format!("{}*", first);
+                }
+            }
 
+            for message in self
+                .wrap_outbound_lines(
+                    client,
+                    channel,
+                    relay_nick.as_deref(),
+                    &lines,
+                    Some(&message),
+                    &format_line,
+                )
+                .await
+            {
                 if let Err(e) = self
                     .send_privmsg_with_tags(
-                        client,
                         channel,
+                        pipo_id,
                         message.clone(),
+                        relay_nick.as_deref(),
                         thread_presentation.reply_target.as_deref(),
                         irc_message_id.as_deref(),
+                        edit_group.as_ref(),
                     )
                     .await
                 {
@@ -417,11 +1074,11 @@ impl IRC {
         }
 
         if let Some(attachments) = attachments {
-            IRC::handle_attachments(client, channel, attachments);
+            self.handle_attachments(client, channel, attachments).await;
         }
     }
 
-    fn handle_bot_message(
+    async fn handle_bot_message(
         &self,
         client: &Client,
         channel: &str,
@@ -435,7 +1092,7 @@ impl IRC {
         }
 
         if let Some(attachment) = attachments {
-            IRC::handle_attachments(client, channel, attachment);
+            self.handle_attachments(client, channel, attachment).await;
         }
     }
 
@@ -459,7 +1116,7 @@ impl IRC {
                     message: Some(serde_json::json!(users).to_string()),
                 };
 
-                if let Some(sender) = self.channels.get(channel) {
+                if let Some(sender) = self.channel_sender(channel) {
                     eprintln!("Sending message: {:#}", message);
                     if let Err(e) = sender.send(message) {
                         eprintln!("Couldn't send message: {:#}", e);
@@ -469,90 +1126,401 @@ impl IRC {
         }
     }
 
-    async fn handle_text_message(
-        &self,
+    /// Looks up which services account `recipient` is logged in to before
+    /// a DM from another transport is delivered to it, provided that nick
+    /// is on the allowlist.
+    async fn handle_direct_message(
+        &mut self,
         client: &Client,
-        channel: &str,
-        pipo_id: i64,
+        sender: usize,
         transport: String,
         username: String,
-        thread: Option<crate::ThreadRef>,
-        message: Option<String>,
-        attachments: Option<Vec<Attachment>>,
-        is_edit: bool,
-        irc_flag: bool,
+        recipient: String,
+        recipient_transport: Option<usize>,
+        message: String,
     ) {
-        let irc_message_id = self.ensure_ircid_for_pipo_id(pipo_id).await;
-        let mut message = message;
+        let Some(direct) = self.direct.as_ref() else {
+            return;
+        };
 
-        if irc_flag && is_edit {
-            message = None
+        if !direct.is_for(sender, recipient_transport) || !direct.allows(&[&recipient]) {
+            return;
         }
-        if let Some(message) = message {
-            let mut is_edit = is_edit;
-            let thread_presentation = self
-                .resolve_thread_presentation(channel, pipo_id, &thread)
-                .await;
 
-            if thread.is_some() {
-                self.log_thread_presentation(channel, pipo_id, &thread_presentation);
-            }
+        if !self.accounts.supports_whox() {
+            self.deliver_direct_message(
+                client, &recipient, None, sender, transport, username, message,
+            )
+            .await;
 
-            if let Some(prefix) = thread_presentation.plaintext_prefix.as_ref() {
-                let prefix_message = format!(
-                    "\x01ACTION <{}!\x02{}\x02> {}\x01",
-                    &transport[..1].to_uppercase(),
-                    username,
-                    prefix
-                );
+            return;
+        }
 
-                if let Err(e) = self
-                    .send_privmsg_with_tags(
-                        client,
-                        channel,
-                        prefix_message.clone(),
-                        thread_presentation.reply_target.as_deref(),
-                        irc_message_id.as_deref(),
-                    )
-                    .await
-                {
-                    eprintln!(
-                        "Failed to send message '{}' channel {}: {:#}",
-                        prefix_message, channel, e
-                    );
-                }
+        let pending = Pending::Bridged {
+            sender,
+            transport,
+            username,
+            message,
+        };
+
+        if let Some(query) = self.accounts.queue(&recipient, pending) {
+            if let Err(e) = self
+                .send_queue()
+                .and_then(|queue| queue.send(query, Priority::Control))
+            {
+                eprintln!("Failed to look up the account of {}: {:#}", recipient, e);
             }
+        }
+    }
 
-            for msg in message.split("\n") {
-                if msg == "" {
-                    continue;
-                }
+    /// Delivers a DM from another transport as a PRIVMSG to `recipient`,
+    /// provided it's logged in to an allowlisted `account`. Anyone can
+    /// take an allowlisted nick that isn't protected by services, so the
+    /// sender is told instead when it isn't.
+    async fn deliver_direct_message(
+        &self,
+        client: &Client,
+        recipient: &str,
+        account: Option<&str>,
+        sender: usize,
+        transport: String,
+        username: String,
+        message: String,
+    ) {
+        let Some(direct) = self.direct.as_ref() else {
+            return;
+        };
 
-                let message = if is_edit {
-                    is_edit = false;
+        if !account.is_some_and(|account| direct.allows(&[account])) {
+            let notice = format!(
+                "{} isn't identified with NickServ on IRC, so your message wasn't delivered there.",
+                recipient
+            );
 
-                    format!(
-                        "\x01ACTION <{}!\x02{}\x02> \x02EDIT:\x02 {}\x01",
-                        &transport[..1].to_uppercase(),
-                        username,
-                        msg
-                    )
-                } else {
-                    format!(
-                        "\x01ACTION <{}!\x02{}\x02> {}\x01",
-                        &transport[..1].to_uppercase(),
-                        username,
-                        msg
+            if let Err(e) = direct.send(
+                TRANSPORT_NAME,
+                client.current_nickname(),
+                &username,
+                Some(sender),
+                notice,
+            ) {
+                eprintln!(
+                    "Failed to tell {} about an undelivered DM: {:#}",
+                    username, e
+                );
+            }
+
+            return;
+        }
+
+        let format_line = |line: &str| {
+            format!(
+                "\x01ACTION <{}!\x02{}\x02> {}\x01",
+                &transport[..1].to_uppercase(),
+                username,
+                line
+            )
+        };
+        let lines = message
+            .split('\n')
+            .filter(|line| !line.is_empty())
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+
+        for line in self
+            .wrap_outbound_lines(
+                client,
+                recipient,
+                None,
+                &lines,
+                // Private conversations never go to the public paste
+                // service.
+                None,
+                &format_line,
+            )
+            .await
+        {
+            if let Err(e) = self.send_queue().and_then(|queue| {
+                queue.send(
+                    IrcMessage::from(Command::PRIVMSG(recipient.to_string(), line)),
+                    Priority::Normal,
+                )
+            }) {
+                eprintln!("Failed to send DM to {}: {:#}", recipient, e);
+
+                return;
+            }
+        }
+
+        if let Err(e) = direct.remember(recipient, Some(sender), &username).await {
+            eprintln!(
+                "Failed to remember DM conversation of {}: {:#}",
+                recipient, e
+            );
+        }
+    }
+
+    /// Finds out which services account the sender of a PRIVMSG to the
+    /// bot is logged in to, right away from its `account` tag or else
+    /// with WHOX, and then bridges it.
+    async fn handle_private_privmsg(
+        &mut self,
+        message: &IrcMessage,
+        nickname: &str,
+        text: &str,
+    ) -> anyhow::Result<()> {
+        // CTCP requests such as VERSION are answered by the irc crate.
+        if text.starts_with('\x01') {
+            return Ok(());
+        }
+
+        if self.capabilities.supports_account_tag {
+            return self
+                .handle_direct_privmsg(nickname, accounts::account_tag(message), text)
+                .await;
+        }
+
+        if !self.accounts.supports_whox() {
+            return self.send_notice(
+                nickname,
+                "This network can't tell whether you're identified, so your direct messages aren't bridged.",
+            );
+        }
+
+        match self
+            .accounts
+            .queue(nickname, Pending::Received(text.to_string()))
+        {
+            Some(query) => self.send_queue()?.send(query, Priority::Control),
+            None => Ok(()),
+        }
+    }
+
+    /// Bridges a PRIVMSG sent to the bot itself by `nickname`, logged in
+    /// to `account`. "@alice hello" starts a conversation with alice;
+    /// anything else goes to whoever the nick last talked to.
+    async fn handle_direct_privmsg(
+        &self,
+        nickname: &str,
+        account: Option<&str>,
+        message: &str,
+    ) -> anyhow::Result<()> {
+        let Some(direct) = self.direct.as_ref() else {
+            return Ok(());
+        };
+
+        if !direct.allows(&[nickname]) {
+            return self.send_notice(nickname, "Bridged direct messages aren't enabled for you.");
+        }
+
+        // Anyone can take an allowlisted nick that isn't protected by
+        // services, so the account has to be on the allowlist as well.
+        if !account.is_some_and(|account| direct.allows(&[account])) {
+            return self.send_notice(
+                nickname,
+                "Identify with NickServ to send bridged direct messages.",
+            );
+        }
+
+        if let Some((recipient, text)) = DirectBridge::parse_recipient(message) {
+            direct.send(TRANSPORT_NAME, nickname, recipient, None, text.to_string())?;
+
+            return direct.remember(nickname, None, recipient).await;
+        }
+
+        match direct.peer(nickname).await? {
+            Some((peer_transport, peer)) => direct.send(
+                TRANSPORT_NAME,
+                nickname,
+                &peer,
+                peer_transport,
+                message.to_string(),
+            ),
+            None => self.send_notice(
+                nickname,
+                "Start a conversation with: @name your message. Replies then go to the last person you wrote to.",
+            ),
+        }
+    }
+
+    fn publish_presence(&self, lines: Vec<(String, String)>) {
+        for (channel, line) in lines {
+            let Some(sender) = self.channel_sender(&channel) else {
+                continue;
+            };
+            let message = Message::Presence {
+                sender: self.transport_id,
+                transport: TRANSPORT_NAME.to_string(),
+                message: line,
+            };
+
+            if let Err(e) = sender.send(message) {
+                eprintln!("Couldn't send message: {:#}", e);
+            }
+        }
+    }
+
+    /// Keeps track of channel topics, and relays topic changes made by IRC
+    /// users when `topic_sync` is enabled.
+    fn update_topic_from_message(&mut self, client: &Client, message: &IrcMessage, nickname: &str) {
+        let Some((channel, topic)) = self.topics.handle(message) else {
+            return;
+        };
+
+        if !self.topic_sync || nickname == client.current_nickname() {
+            return;
+        }
+
+        let Some(sender) = self.channel_sender(&channel) else {
+            return;
+        };
+        let message = Message::Topic {
+            sender: self.transport_id,
+            transport: TRANSPORT_NAME.to_string(),
+            username: Some(nickname.to_string()),
+            topic,
+        };
+
+        if let Err(e) = sender.send(message) {
+            eprintln!("Couldn't send message: {:#}", e);
+        }
+    }
+
+    fn handle_topic_message(&mut self, client: &Client, channel: &str, topic: String) {
+        if !self.topic_sync {
+            return;
+        }
+
+        // Without ops on a +t channel the server would only refuse it.
+        if self.topics.is_protected(channel) && !IRC::is_channel_operator(client, channel) {
+            eprintln!("Not setting the topic of {}, which needs ops", channel);
+
+            return;
+        }
+
+        let Some(topic) = self.topics.set(channel, &topic) else {
+            return;
+        };
+
+        if let Err(e) = self.send_queue().and_then(|queue| {
+            queue.send(
+                IrcMessage::from(Command::TOPIC(channel.to_string(), Some(topic))),
+                Priority::Normal,
+            )
+        }) {
+            eprintln!("Failed to set topic of {}: {:#}", channel, e);
+        }
+    }
+
+    async fn handle_text_message(
+        &self,
+        client: &Client,
+        channel: &str,
+        pipo_id: i64,
+        transport: String,
+        username: String,
+        thread: Option<crate::ThreadRef>,
+        reply_to: Option<ReplyRef>,
+        message: Option<String>,
+        attachments: Option<Vec<Attachment>>,
+        is_edit: bool,
+        irc_flag: bool,
+    ) {
+        let irc_message_id = self.ensure_ircid_for_pipo_id(pipo_id).await;
+        // IRC can't carry files, so they're bridged as links.
+        let (mut message, attachments) = Attachment::inline_file_links(message, attachments);
+
+        if irc_flag && is_edit {
+            message = None
+        }
+        if let Some(message) = message {
+            let thread_presentation = self
+                .resolve_thread_presentation(channel, pipo_id, &thread, &reply_to)
+                .await;
+
+            if thread.is_some() {
+                self.log_thread_presentation(channel, pipo_id, &thread_presentation);
+            }
+
+            let relay_nick = self.relay_nick(&transport, &username);
+            let edit_group = self.edit_group(channel, pipo_id, is_edit);
+
+            self.record_author(pipo_id, &username);
+
+            let format_line = |line: &str| match relay_nick {
+                Some(_) => line.to_string(),
+                None => format!(
+                    "\x01ACTION <{}!\x02{}\x02> {}\x01",
+                    &transport[..1].to_uppercase(),
+                    username,
+                    line
+                ),
+            };
+
+            if let Some(prefix) = thread_presentation.plaintext_prefix.as_ref() {
+                for prefix_message in self
+                    .wrap_outbound_lines(
+                        client,
+                        channel,
+                        relay_nick.as_deref(),
+                        std::slice::from_ref(prefix),
+                        None,
+                        &format_line,
                     )
-                };
+                    .await
+                {
+                    if let Err(e) = self
+                        .send_privmsg_with_tags(
+                            channel,
+                            pipo_id,
+                            prefix_message.clone(),
+                            relay_nick.as_deref(),
+                            thread_presentation.reply_target.as_deref(),
+                            irc_message_id.as_deref(),
+                            edit_group.as_ref(),
+                        )
+                        .await
+                    {
+                        eprintln!(
+                            "Failed to send message '{}' channel {}: {:#}",
+                            prefix_message, channel, e
+                        );
+                    }
+                }
+            }
+
+            let mut lines = message
+                .split('\n')
+                .filter(|line| !line.is_empty())
+                .map(str::to_string)
+                .collect::<Vec<_>>();
+
+            if is_edit {
+                if let Some(first) = lines.first_mut() {
+                    *first = format!("\x02EDIT:\x02 {}", first);
+                }
+            }
 
+            for message in self
+                .wrap_outbound_lines(
+                    client,
+                    channel,
+                    relay_nick.as_deref(),
+                    &lines,
+                    Some(&message),
+                    &format_line,
+                )
+                .await
+            {
                 if let Err(e) = self
                     .send_privmsg_with_tags(
-                        client,
                         channel,
+                        pipo_id,
                         message.clone(),
+                        relay_nick.as_deref(),
                         thread_presentation.reply_target.as_deref(),
                         irc_message_id.as_deref(),
+                        edit_group.as_ref(),
                     )
                     .await
                 {
@@ -565,11 +1533,16 @@ impl IRC {
         }
 
         if let Some(attachment) = attachments {
-            IRC::handle_attachments(client, channel, attachment);
+            self.handle_attachments(client, channel, attachment).await;
         }
     }
 
-    fn handle_attachments(client: &Client, channel: &str, attachments: Vec<Attachment>) {
+    async fn handle_attachments(
+        &self,
+        client: &Client,
+        channel: &str,
+        attachments: Vec<Attachment>,
+    ) {
         for attachment in attachments {
             let has_text = attachment.text.is_some();
             let has_fallback = attachment.fallback.is_some();
@@ -593,16 +1566,8 @@ impl IRC {
                 continue;
             }
 
-            let mut line_counter = 0;
-
-            for msg in text.split("\n") {
-                line_counter += 1;
-
-                if msg == "" {
-                    continue;
-                }
-
-                let message = if author_name.is_empty() {
+            let format_line = |msg: &str| {
+                if author_name.is_empty() {
                     format!("\x01ACTION [\x02{}\x02] {}\x01", service_name, msg)
                 } else {
                     format!(
@@ -611,20 +1576,74 @@ impl IRC {
                         author_name,
                         msg
                     )
-                };
-
-                if let Err(e) = client.send_privmsg(channel.clone(), message.clone()) {
+                }
+            };
+            let lines = text
+                .split('\n')
+                .filter(|line| !line.is_empty())
+                .map(str::to_string)
+                .collect::<Vec<_>>();
+
+            for message in self
+                .wrap_outbound_lines(client, channel, None, &lines, Some(&text), &format_line)
+                .await
+            {
+                if let Err(e) = self.send_queue().and_then(|queue| {
+                    queue.send(
+                        IrcMessage::from(Command::PRIVMSG(channel.to_string(), message.clone())),
+                        Priority::Normal,
+                    )
+                }) {
                     eprintln!(
                         "Failed to send message '{}' channel {}: {:#}",
                         message, channel, e
                     );
                 }
+            }
+        }
+    }
 
-                if line_counter > 6 {
-                    break;
-                }
+    /// Splits "host:port" into its parts; the port is optional.
+    fn split_server(server: &str) -> anyhow::Result<(String, Option<u16>)> {
+        match server.rsplit_once(':') {
+            Some((host, port)) => Ok((
+                host.to_string(),
+                Some(
+                    port.parse()
+                        .map_err(|e| anyhow!("Invalid port in IRC server {}: {}", server, e))?,
+                ),
+            )),
+            None => Ok((server.to_string(), None)),
+        }
+    }
+
+    /// The irc crate configuration for connecting to the current server.
+    /// With `tls` set we do TLS ourselves, and point the irc crate at our
+    /// end of the connection.
+    async fn server_config(&self) -> anyhow::Result<Config> {
+        let server = &self.servers[self.current_server];
+        let (host, port) = IRC::split_server(server)?;
+        let mut config = self.config.clone();
+
+        match self.tls.as_ref() {
+            Some(tls) => {
+                let relay = tls::relay(
+                    &format!("{}:{}", host, port.unwrap_or(DEFAULT_TLS_PORT)),
+                    tls,
+                )
+                .await?;
+
+                config.server = Some(relay.ip().to_string());
+                config.port = Some(relay.port());
+                config.use_tls = Some(false);
+            }
+            None => {
+                config.server = Some(host);
+                config.port = port;
             }
         }
+
+        Ok(config)
     }
 
     async fn connect_irc(
@@ -634,18 +1653,56 @@ impl IRC {
         irc::client::ClientStream,
         StreamMap<String, BroadcastStream<Message>>,
     )> {
-        let mut client = Client::from_config(self.config.clone()).await?;
+        let mut client = Client::from_config(self.server_config().await?).await?;
 
         self.capabilities = IrcCapabilityState::default();
 
-        client.send_cap_req(&[
+        // Request capabilities one at a time so that a server rejecting
+        // one of them (CAP REQ is all-or-nothing) doesn't cost us the rest.
+        client.send_cap_ls(NegotiationVersion::V302)?;
+        for capability in [
             Capability::MultiPrefix,
             Capability::Custom("message-tags"),
             Capability::Custom("draft/reply"),
+            Capability::Custom("draft/relaymsg"),
+            Capability::Custom("draft/message-redaction"),
             Capability::ServerTime,
             Capability::EchoMessage,
-        ])?;
-        client.identify()?;
+            Capability::Custom("batch"),
+            Capability::Custom("draft/chathistory"),
+            Capability::AccountTag,
+        ] {
+            client.send_cap_req(&[capability])?;
+        }
+
+        self.auth_state = IrcAuthState::default();
+        self.hostmask = None;
+        self.history_backfill.reset();
+        self.accounts.reset();
+        self.topics.reset();
+        self.send_queue = Some(SendQueue::spawn(
+            client.sender(),
+            self.send_burst,
+            self.send_interval,
+        ));
+
+        if self.auth.sasl.is_some() {
+            // Registration stays suspended until CAP END, which is sent
+            // once SASL has succeeded or failed.
+            if let Some(password) = self.auth.server_password.as_ref() {
+                client.send(Command::PASS(password.clone()))?;
+            }
+            client.send(Command::NICK(client.current_nickname().to_string()))?;
+            client.send(Command::USER(
+                self.config.username().to_string(),
+                "0".to_string(),
+                self.config.real_name().to_string(),
+            ))?;
+            client.send_cap_req(&[Capability::Sasl])?;
+            self.auth_state.sasl = SaslProgress::Requested;
+        } else {
+            client.identify()?;
+        }
 
         let irc_stream = client.stream()?;
         let mut input_buses = StreamMap::new();
@@ -654,51 +1711,1029 @@ impl IRC {
                 channel_name.clone(),
                 BroadcastStream::new(channel.subscribe()),
             );
-            if let Err(e) = client.send_join(channel_name) {
+        }
+
+        Ok((client, irc_stream, input_buses))
+    }
+
+    fn join_channels(&mut self, client: &Client) {
+        if self.auth_state.channels_joined {
+            return;
+        }
+
+        self.auth_state.channels_joined = true;
+
+        for channel_name in self.channels.keys() {
+            if let Err(e) = client.send(self.join_command(channel_name)) {
                 eprintln!("Failed to join channel {}: {:#}", channel_name, e);
             }
         }
+    }
+
+    /// A channel name or nick lowercased with rfc1459 casemapping. Every
+    /// map of channels or nicks is keyed by it, so that any spelling the
+    /// server or a user comes up with finds the same entry.
+    pub(crate) fn channel_key(name: &str) -> String {
+        name.chars()
+            .map(|ch| match ch {
+                '[' => '{',
+                ']' => '}',
+                '\\' => '|',
+                '~' => '^',
+                _ => ch.to_ascii_lowercase(),
+            })
+            .collect()
+    }
+
+    /// The bus of `channel`, however the server spells it. `channels` is
+    /// keyed by the configured names, which are what we join and send to.
+    fn channel_sender(&self, channel: &str) -> Option<&broadcast::Sender<Message>> {
+        let key = IRC::channel_key(channel);
+
+        self.channels
+            .iter()
+            .find(|(name, _)| IRC::channel_key(name) == key)
+            .map(|(_, sender)| sender)
+    }
+
+    /// Whether we may change the topic of `channel` while it has +t.
+    fn is_channel_operator(client: &Client, channel: &str) -> bool {
+        let key = IRC::channel_key(channel);
+        let Some(users) = client
+            .list_channels()
+            .unwrap_or_default()
+            .into_iter()
+            .find(|name| IRC::channel_key(name) == key)
+            .and_then(|name| client.list_users(&name))
+        else {
+            return false;
+        };
+
+        users
+            .iter()
+            .find(|user| user.get_nickname() == client.current_nickname())
+            .is_some_and(|user| {
+                user.access_levels().iter().any(|level| {
+                    matches!(
+                        level,
+                        AccessLevel::Owner
+                            | AccessLevel::Admin
+                            | AccessLevel::Oper
+                            | AccessLevel::HalfOp
+                    )
+                })
+            })
+    }
+
+    fn channel_options(&self, channel: &str) -> Option<&ChannelOptions> {
+        self.channel_options.get(&IRC::channel_key(channel))
+    }
+
+    fn join_command(&self, channel: &str) -> Command {
+        let key = self
+            .channel_options(channel)
+            .and_then(|options| options.key.clone());
+
+        Command::JOIN(channel.to_string(), key, None)
+    }
+
+    fn thread_options(&self, channel: &str) -> ThreadOptions {
+        self.channel_options(channel)
+            .map_or(self.thread_options, |options| options.thread)
+    }
+
+    /// Rejoins mapped channels we were kicked from, after the channel's
+    /// delay, and joins those we're invited to, if their settings say so.
+    fn handle_kick_and_invite(&self, client: &Client, message: &IrcMessage) {
+        match &message.command {
+            Command::KICK(channel, nick, _) if nick == client.current_nickname() => {
+                let Some(delay) = self
+                    .channel_options(channel)
+                    .and_then(|options| options.rejoin_delay)
+                else {
+                    return;
+                };
+                let sender = client.sender();
+                let join = self.join_command(channel);
+                let channel = channel.clone();
+
+                eprintln!("Kicked from {}, rejoining in {}s", channel, delay.as_secs());
+
+                tokio::spawn(async move {
+                    time::sleep(delay).await;
+
+                    if let Err(e) = sender.send(join) {
+                        eprintln!("Failed to rejoin channel {}: {:#}", channel, e);
+                    }
+                });
+            }
+            Command::INVITE(nick, channel) if nick == client.current_nickname() => {
+                if !self
+                    .channel_options(channel)
+                    .is_some_and(|options| options.join_on_invite)
+                {
+                    eprintln!(
+                        "Ignoring invite to {} from {}",
+                        channel,
+                        message.source_nickname().unwrap_or("unknown")
+                    );
+                    return;
+                }
+
+                if let Err(e) = client.send(self.join_command(channel)) {
+                    eprintln!("Failed to join channel {}: {:#}", channel, e);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn handle_registration_message(
+        &mut self,
+        client: &Client,
+        message: &IrcMessage,
+    ) -> anyhow::Result<()> {
+        match &message.command {
+            Command::CAP(_, subcommand, _, Some(extensions))
+                if self.auth_state.sasl == SaslProgress::Requested
+                    && extensions.split_whitespace().any(|cap| cap == "sasl") =>
+            {
+                match subcommand {
+                    CapSubCommand::ACK => {
+                        self.auth_state.sasl = SaslProgress::Authenticating;
+
+                        match self.auth.sasl {
+                            Some(SaslConfig::External) => client.send_sasl_external()?,
+                            _ => client.send_sasl_plain()?,
+                        }
+                    }
+                    CapSubCommand::NAK => {
+                        self.fail_sasl(client, "server does not support SASL")?;
+                    }
+                    _ => (),
+                }
+            }
+            Command::AUTHENTICATE(data)
+                if data == "+" && self.auth_state.sasl == SaslProgress::Authenticating =>
+            {
+                for chunk in IRC::sasl_response_chunks(&self.sasl_response(client)) {
+                    client.send_sasl(chunk)?;
+                }
+            }
+            Command::Response(Response::RPL_SASLSUCCESS, _)
+            | Command::Response(Response::ERR_SASLALREADY, _) => {
+                self.auth_state.sasl = SaslProgress::Succeeded;
+                client.send(Command::CAP(None, CapSubCommand::END, None, None))?;
+            }
+            Command::Response(Response::ERR_NICKLOCKED, args)
+            | Command::Response(Response::ERR_SASLFAIL, args)
+            | Command::Response(Response::ERR_SASLTOOLONG, args)
+            | Command::Response(Response::ERR_SASLABORT, args)
+                if self.auth_state.sasl == SaslProgress::Authenticating =>
+            {
+                let reason = args.last().map(String::as_str).unwrap_or("unknown");
+
+                self.fail_sasl(client, reason)?;
+            }
+            Command::Response(Response::RPL_ENDOFMOTD, _)
+            | Command::Response(Response::ERR_NOMOTD, _) => {
+                self.recover_nick(client)?;
+
+                let nickserv_password = self.auth.nickserv_password.clone();
+
+                match nickserv_password {
+                    Some(password) if self.auth_state.sasl != SaslProgress::Succeeded => {
+                        // Wait for services to answer before joining so
+                        // that +r channels accept us.
+                        let account = self
+                            .config
+                            .nickname()
+                            .unwrap_or(client.current_nickname())
+                            .to_string();
+
+                        client.send(Command::NICKSERV(vec![
+                            "IDENTIFY".to_string(),
+                            account,
+                            password,
+                        ]))?;
+                        self.auth_state.nickserv_identify_pending = true;
+                    }
+                    _ => self.join_channels(client),
+                }
+            }
+            Command::Response(Response::RPL_LOGGEDIN, _)
+                if self.auth_state.nickserv_identify_pending =>
+            {
+                self.auth_state.nickserv_identify_pending = false;
+                self.join_channels(client);
+            }
+            Command::NOTICE(_, _)
+                if self.auth_state.nickserv_identify_pending
+                    && message
+                        .source_nickname()
+                        .is_some_and(|nick| nick.eq_ignore_ascii_case("NickServ")) =>
+            {
+                // Services that don't send 900 still answer IDENTIFY with a
+                // notice, successful or not.
+                self.auth_state.nickserv_identify_pending = false;
+                self.join_channels(client);
+            }
+            _ => (),
+        }
+
+        Ok(())
+    }
+
+    fn fail_sasl(&mut self, client: &Client, reason: &str) -> anyhow::Result<()> {
+        eprintln!(
+            "IRC SASL authentication failed for transport {}: {}",
+            self.transport_id, reason
+        );
+        self.auth_state.sasl = SaslProgress::Failed;
+        client.send(Command::CAP(None, CapSubCommand::END, None, None))?;
+
+        Ok(())
+    }
+
+    fn sasl_response(&self, client: &Client) -> String {
+        match self.auth.sasl.as_ref() {
+            Some(SaslConfig::Plain { username, password }) => {
+                let username = username
+                    .as_deref()
+                    .unwrap_or_else(|| self.config.nickname().unwrap_or(client.current_nickname()));
+
+                BASE64_STANDARD.encode(format!("{}\0{}\0{}", username, username, password))
+            }
+            Some(SaslConfig::External) | None => String::new(),
+        }
+    }
+
+    /// Splits a base64 SASL response into AUTHENTICATE payloads of at most
+    /// 400 bytes; an empty or exactly-divisible response ends with "+".
+    fn sasl_response_chunks(response: &str) -> Vec<String> {
+        let mut chunks = response
+            .as_bytes()
+            .chunks(SASL_CHUNK_LEN)
+            .map(|chunk| String::from_utf8_lossy(chunk).into_owned())
+            .collect::<Vec<_>>();
+
+        if response.len().is_multiple_of(SASL_CHUNK_LEN) {
+            chunks.push("+".to_string());
+        }
+
+        chunks
+    }
+
+    fn recover_nick(&self, client: &Client) -> anyhow::Result<()> {
+        let Ok(nickname) = self.config.nickname() else {
+            return Ok(());
+        };
+
+        if self.auth.nick_recovery == NickRecovery::None
+            || client.current_nickname().eq_ignore_ascii_case(nickname)
+        {
+            return Ok(());
+        }
+
+        let password = self.auth.nickserv_password.clone().or_else(|| {
+            if let Some(SaslConfig::Plain { password, .. }) = self.auth.sasl.as_ref() {
+                Some(password.clone())
+            } else {
+                None
+            }
+        });
+        let command = match self.auth.nick_recovery {
+            NickRecovery::Ghost => "GHOST",
+            NickRecovery::Regain => "REGAIN",
+            NickRecovery::Recover => "RECOVER",
+            NickRecovery::None => return Ok(()),
+        };
+        let mut args = vec![command.to_string(), nickname.to_string()];
+
+        args.extend(password);
+        client.send(Command::NICKSERV(args))?;
+
+        // REGAIN and RECOVER switch our nick themselves; GHOST only frees
+        // it up.
+        if self.auth.nick_recovery == NickRecovery::Ghost {
+            client.send(Command::NICK(nickname.to_string()))?;
+        }
+
+        Ok(())
+    }
+
+    fn update_capabilities_from_message(&mut self, message: &IrcMessage) {
+        let Command::CAP(_, subcommand, _, Some(extensions)) = &message.command else {
+            return;
+        };
+
+        if *subcommand == CapSubCommand::LS {
+            // `draft/relaymsg=<separators>` advertises which characters
+            // relayed nicks have to contain.
+            for capability in extensions.split_whitespace() {
+                if let Some(("draft/relaymsg", separators)) = capability.split_once('=') {
+                    self.capabilities.relaymsg_separators = Some(separators.to_string());
+                }
+            }
+
+            return;
+        }
+
+        if *subcommand != CapSubCommand::ACK {
+            return;
+        }
+
+        for capability in extensions.split_whitespace() {
+            match capability {
+                "message-tags" => self.capabilities.supports_message_tags = true,
+                "draft/reply" | "reply" => self.capabilities.supports_reply_tags = true,
+                "draft/relaymsg" => self.capabilities.supports_relaymsg = true,
+                "echo-message" => self.capabilities.supports_echo_message = true,
+                "draft/message-redaction" => self.capabilities.supports_redaction = true,
+                "draft/chathistory" | "chathistory" => {
+                    self.capabilities.supports_chathistory = true
+                }
+                "account-tag" => self.capabilities.supports_account_tag = true,
+                _ => continue,
+            }
+        }
+    }
+
+    fn handle_standard_replies(&mut self, message: &IrcMessage) {
+        let Command::Raw(command, args) = &message.command else {
+            return;
+        };
+
+        if command != "FAIL" {
+            return;
+        }
+
+        if args.first().map(String::as_str) == Some("CHATHISTORY") {
+            eprintln!(
+                "IRC CHATHISTORY request failed: transport_id={} reply={:?}",
+                self.transport_id, args
+            );
+
+            return;
+        }
+
+        if args.first().map(String::as_str) != Some("RELAYMSG") {
+            return;
+        }
+
+        // Usually means we lack the privileges to relay in this channel;
+        // fall back to plaintext formatting for the rest of the session.
+        eprintln!(
+            "IRC RELAYMSG rejected, falling back to plaintext: transport_id={} reply={:?}",
+            self.transport_id, args
+        );
+        self.capabilities.supports_relaymsg = false;
+    }
+
+    fn is_own_echo(&self, client: &Client, message: &IrcMessage) -> bool {
+        if !matches!(
+            message.command,
+            Command::PRIVMSG(_, _) | Command::NOTICE(_, _)
+        ) {
+            return false;
+        }
+
+        let current_nickname = client.current_nickname();
+
+        if message.source_nickname() == Some(current_nickname) {
+            return true;
+        }
+
+        IRC::parse_relaymsg_tag(message)
+            .is_some_and(|relayer| relayer.eq_ignore_ascii_case(current_nickname))
+    }
+
+    /// Remembers the name of the server we're connected to, by which
+    /// services are recognised when no `services_hosts` are configured.
+    fn update_server_name(&mut self, message: &IrcMessage) {
+        if let (
+            Command::Response(Response::RPL_WELCOME | Response::RPL_ISUPPORT, _),
+            Some(Prefix::ServerName(server)),
+        ) = (&message.command, &message.prefix)
+        {
+            self.server_name = Some(server.clone());
+        }
+    }
+
+    fn update_hostmask_from_message(&mut self, client: &Client, message: &IrcMessage) {
+        match &message.command {
+            Command::Response(Response::RPL_WELCOME, args) => {
+                // Most servers end the welcome text with our full hostmask.
+                if let Some(hostmask) = args
+                    .last()
+                    .and_then(|text| text.split_whitespace().last())
+                    .filter(|word| word.contains('!') && word.contains('@'))
+                {
+                    self.hostmask = Some(hostmask.to_string());
+                }
+            }
+            Command::JOIN(_, _, _) => {
+                if let Some(Prefix::Nickname(nick, user, host)) = &message.prefix {
+                    if nick == client.current_nickname() && !user.is_empty() && !host.is_empty() {
+                        self.hostmask = Some(format!("{}!{}@{}", nick, user, host));
+                    }
+                }
+            }
+            Command::NICK(new_nick) => {
+                let Some((nick, userhost)) = self
+                    .hostmask
+                    .as_deref()
+                    .and_then(|hostmask| hostmask.split_once('!'))
+                else {
+                    return;
+                };
+
+                if message.source_nickname() == Some(nick) {
+                    self.hostmask = Some(format!("{}!{}", new_nick, userhost));
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Bytes left for the text of a PRIVMSG to `target` once the server
+    /// has prepended our source and framed the line. IRCv3 tags don't
+    /// count here, they have a separate 4094-byte budget of their own.
+    fn line_byte_budget(&self, client: &Client, target: &str, relay_nick: Option<&str>) -> usize {
+        let source_len = match (relay_nick, self.hostmask.as_ref()) {
+            (Some(relay_nick), _) => relay_nick.len() + WORST_CASE_USERHOST_LEN,
+            (None, Some(hostmask)) => hostmask.len(),
+            (None, None) => client.current_nickname().len() + WORST_CASE_USERHOST_LEN,
+        };
+        // ":<source> PRIVMSG <target> :<text>\r\n"
+        let overhead = 1 + source_len + " PRIVMSG ".len() + target.len() + " :".len() + 2;
+
+        IRC_LINE_MAX_BYTES.saturating_sub(overhead)
+    }
+
+    /// Formats `lines` with `format_line`, splitting any that would push
+    /// the relayed line past 512 bytes, and caps the result at
+    /// `max_message_lines`. When a paste service is configured, long
+    /// messages and code blocks are replaced by a preview and a link to
+    /// `full_text`.
+    async fn wrap_outbound_lines(
+        &self,
+        client: &Client,
+        target: &str,
+        relay_nick: Option<&str>,
+        lines: &[String],
+        full_text: Option<&str>,
+        format_line: &(dyn Fn(&str) -> String + Sync),
+    ) -> Vec<String> {
+        let budget = self
+            .line_byte_budget(client, target, relay_nick)
+            .saturating_sub(format_line("").len());
+        let mut chunks = lines
+            .iter()
+            .flat_map(|line| IRC::split_at_byte_limit(line, budget))
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+
+        if let (Some(pastes), Some(full_text)) = (self.pastes.as_ref(), full_text) {
+            if PasteService::should_paste(full_text, chunks.len(), self.max_message_lines) {
+                match pastes.create(full_text).await {
+                    Ok(url) => {
+                        let total = full_text.lines().count();
+
+                        chunks = PasteService::preview(&chunks);
+                        chunks.push(format!("(full text, {} lines: {})", total, url));
+                    }
+                    Err(e) => eprintln!("Failed to create paste: {:#}", e),
+                }
+            }
+        }
+
+        IRC::cap_lines(chunks, self.max_message_lines)
+            .iter()
+            .map(|line| format_line(line))
+            .collect()
+    }
+
+    /// Splits `line` into chunks of at most `max_bytes` bytes, preferring
+    /// to break on spaces and never cutting through a UTF-8 sequence.
+    fn split_at_byte_limit(line: &str, max_bytes: usize) -> Vec<&str> {
+        let mut chunks = Vec::new();
+        let mut rest = line;
+
+        while rest.len() > max_bytes {
+            let mut cut = max_bytes;
+
+            while !rest.is_char_boundary(cut) {
+                cut -= 1;
+            }
+
+            if cut == 0 {
+                // Not even one character fits; send it anyway rather than
+                // looping forever.
+                cut = rest.chars().next().map_or(rest.len(), char::len_utf8);
+            }
+
+            // The fallback above can leave `cut` at the very end.
+            let search_end = if rest.as_bytes().get(cut) == Some(&b' ') {
+                cut + 1
+            } else {
+                cut
+            };
+
+            match rest[..search_end].rfind(' ') {
+                Some(space) if space > 0 => {
+                    chunks.push(&rest[..space]);
+                    rest = &rest[space + 1..];
+                }
+                _ => {
+                    chunks.push(&rest[..cut]);
+                    rest = &rest[cut..];
+                }
+            }
+        }
+
+        if !rest.is_empty() || chunks.is_empty() {
+            chunks.push(rest);
+        }
+
+        chunks
+    }
+
+    fn cap_lines(mut lines: Vec<String>, max_lines: usize) -> Vec<String> {
+        if lines.len() <= max_lines {
+            return lines;
+        }
+
+        // With room for a single line, the count goes at the end of it.
+        if max_lines <= 1 {
+            let hidden = lines.len() - 1;
+
+            lines.truncate(1);
+            lines[0].push_str(&format!(" ({} more lines)", hidden));
+
+            return lines;
+        }
+
+        let keep = max_lines - 1;
+        let hidden = lines.len() - keep;
+
+        lines.truncate(keep);
+        lines.push(format!("({} more lines)", hidden));
+
+        lines
+    }
+
+    fn relay_nick(&self, transport: &str, username: &str) -> Option<String> {
+        if self.relaymsg_mode == RelaymsgMode::PlaintextOnly || !self.capabilities.supports_relaymsg
+        {
+            return None;
+        }
+
+        let separator = self
+            .capabilities
+            .relaymsg_separators
+            .as_deref()
+            .and_then(|separators| separators.chars().next())
+            .unwrap_or('/');
+        let mut nick = username
+            .chars()
+            .filter(|ch| ch.is_ascii_alphanumeric() || RELAYMSG_NICK_SPECIAL_CHARS.contains(*ch))
+            .take(RELAYMSG_NICK_LEN)
+            .collect::<String>();
+
+        if nick.is_empty() {
+            return None;
+        }
+
+        if nick.starts_with(|ch: char| ch.is_ascii_digit() || ch == '-') {
+            nick.insert(0, '_');
+        }
+
+        Some(format!(
+            "{}{}{}",
+            nick,
+            separator,
+            &transport[..1].to_lowercase()
+        ))
+    }
+
+    async fn send_privmsg_with_tags(
+        &self,
+        channel: &str,
+        pipo_id: i64,
+        message: String,
+        relay_nick: Option<&str>,
+        reply_target: Option<&str>,
+        irc_message_id: Option<&str>,
+        edit_group: Option<&EditGroup>,
+    ) -> anyhow::Result<()> {
+        let tags = self.tags_for_outbound_message(reply_target, irc_message_id);
+
+        self.expect_echo(channel, &message, pipo_id);
+        let command = match relay_nick {
+            Some(relay_nick) => Command::Raw(
+                "RELAYMSG".to_string(),
+                vec![channel.to_string(), relay_nick.to_string(), message],
+            ),
+            None => Command::PRIVMSG(channel.to_string(), message),
+        };
+        let message = IrcMessage {
+            tags,
+            prefix: None,
+            command,
+        };
+
+        match edit_group {
+            Some(edit_group) => self.send_queue()?.send_edit(message, edit_group),
+            None => self.send_queue()?.send(message, Priority::Normal),
+        }
+    }
+
+    /// Remembers a line we are about to send so that its echo can be
+    /// matched back to `pipo_id`.
+    fn expect_echo(&self, channel: &str, text: &str, pipo_id: i64) {
+        if !self.capabilities.supports_echo_message {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut pending_echoes = self.pending_echoes.lock().unwrap();
+
+        pending_echoes.retain(|_, entries| {
+            entries.retain(|(_, sent_at)| now.duration_since(*sent_at) <= PENDING_ECHO_TTL);
+            !entries.is_empty()
+        });
+        pending_echoes
+            .entry((IRC::channel_key(channel), text.to_string()))
+            .or_default()
+            .push_back((pipo_id, now));
+    }
+
+    async fn record_own_echo(&self, message: &IrcMessage) {
+        let Command::PRIVMSG(target, text) = &message.command else {
+            return;
+        };
+        let Some(msgid) = IRC::parse_message_id_tag(message) else {
+            return;
+        };
+        let channel = IRC::channel_key(target);
+        let pipo_id = {
+            let mut pending_echoes = self.pending_echoes.lock().unwrap();
+            let key = (channel.clone(), text.clone());
+            let pipo_id = pending_echoes
+                .get_mut(&key)
+                .and_then(|entries| entries.pop_front())
+                .map(|(pipo_id, _)| pipo_id);
+
+            if pending_echoes.get(&key).is_some_and(VecDeque::is_empty) {
+                pending_echoes.remove(&key);
+            }
+
+            pipo_id
+        };
+        let Some(pipo_id) = pipo_id else {
+            return;
+        };
+
+        if let Err(e) = self
+            .insert_into_irc_sent_messages(&channel, &msgid, pipo_id)
+            .await
+        {
+            eprintln!("Failed to record IRC msgid {}: {:#}", msgid, e);
+        }
+
+        // Replace the placeholder id with the real msgid, so that IRC
+        // replies to this line can be matched to the message.
+        let placeholder = IRC::generated_irc_message_id(pipo_id);
+
+        if self.select_ircid_from_messages(pipo_id).await.as_deref() == Some(placeholder.as_str()) {
+            if let Err(e) = self.update_messages_ircid(pipo_id, Some(msgid)).await {
+                eprintln!("Failed to update ircid for {}: {:#}", pipo_id, e);
+            }
+        }
+    }
+
+    fn record_author(&self, pipo_id: i64, username: &str) {
+        let now = Instant::now();
+        let mut recent_authors = self.recent_authors.lock().unwrap();
+
+        recent_authors.retain(|_, (_, seen_at)| now.duration_since(*seen_at) <= RECENT_AUTHOR_TTL);
+        recent_authors.insert(pipo_id, (username.to_string(), now));
+    }
+
+    /// Redacts every line we relayed for `pipo_id` in `channel`. Returns
+    /// false when redaction isn't available or nothing was known to redact.
+    async fn redact_sent_lines(&self, channel: &str, pipo_id: i64) -> bool {
+        if !self.capabilities.supports_redaction {
+            return false;
+        }
+
+        let channel = IRC::channel_key(channel);
+        let msgids = match self.select_sent_msgids(&channel, pipo_id).await {
+            Ok(msgids) => msgids,
+            Err(e) => {
+                eprintln!("Failed to look up IRC msgids for {}: {:#}", pipo_id, e);
+
+                return false;
+            }
+        };
+
+        if msgids.is_empty() {
+            return false;
+        }
+
+        for msgid in msgids.iter() {
+            let redact = IrcMessage::from(Command::Raw(
+                "REDACT".to_string(),
+                vec![channel.clone(), msgid.clone()],
+            ));
+
+            if let Err(e) = self
+                .send_queue()
+                .and_then(|queue| queue.send(redact, Priority::Normal))
+            {
+                eprintln!("Failed to redact {} in {}: {:#}", msgid, channel, e);
+            }
+        }
+
+        if let Err(e) = self.delete_sent_msgids(&channel, pipo_id).await {
+            eprintln!("Failed to forget IRC msgids for {}: {:#}", pipo_id, e);
+        }
+
+        true
+    }
+
+    async fn handle_delete_message(&self, channel: &str, pipo_id: i64) {
+        if self.redact_sent_lines(channel, pipo_id).await || !self.deleted_message_notice {
+            return;
+        }
+
+        let author = self
+            .recent_authors
+            .lock()
+            .unwrap()
+            .get(&pipo_id)
+            .map(|(author, _)| author.clone());
+
+        if let Some(author) = author {
+            if let Err(e) = self.send_notice(channel, format!("[deleted message from {}]", author))
+            {
+                eprintln!("Failed to send deletion notice to {}: {:#}", channel, e);
+            }
+        }
+    }
+
+    async fn handle_redact(&self, args: &[String]) -> anyhow::Result<()> {
+        let [channel, msgid, ..] = args else {
+            return Err(anyhow!("Malformed REDACT: {:?}", args));
+        };
+        let sender = self
+            .channel_sender(channel)
+            .ok_or_else(|| anyhow!("Could not get sender for channel {}", channel))?;
+
+        // Only messages written on IRC are deleted elsewhere. Our own
+        // relayed lines are redacted when their message is deleted, or
+        // by channel operators tidying up IRC, which shouldn't reach back
+        // to the transport the message came from.
+        if self.select_sent_pipo_id(msgid).await.is_some() {
+            return Ok(());
+        }
+
+        let Some(pipo_id) = self.select_id_by_ircid(msgid.to_string()).await else {
+            return Ok(());
+        };
+
+        sender
+            .send(Message::Delete {
+                sender: self.transport_id,
+                pipo_id,
+                transport: TRANSPORT_NAME.to_string(),
+            })
+            .map_err(|e| anyhow!("Couldn't send message: {:#}", e))?;
+
+        Ok(())
+    }
+
+    async fn handle_reaction_message(&self, channel: &str, reaction: Message) {
+        let Message::Reaction {
+            pipo_id,
+            transport,
+            emoji,
+            remove,
+            username,
+            thread,
+            ..
+        } = reaction
+        else {
+            return;
+        };
+
+        if self.reaction_mode == ReactionMode::Off {
+            return;
+        }
+
+        let emoji = IRC::unicode_emoji(&emoji);
+        // A placeholder id was never seen by the server, so clients
+        // couldn't attach a reaction to it.
+        let reply_target = self
+            .select_ircid_from_messages(pipo_id)
+            .await
+            .filter(|ircid| *ircid != IRC::generated_irc_message_id(pipo_id));
+        let can_use_tags = self.capabilities.supports_message_tags
+            && self.reaction_mode != ReactionMode::PlaintextOnly;
+
+        if let (true, Some(reply_target)) = (can_use_tags, reply_target) {
+            let react_tag = if remove {
+                "+draft/unreact"
+            } else {
+                "+draft/react"
+            };
+            let tagmsg = IrcMessage {
+                tags: Some(vec![
+                    Tag(react_tag.to_string(), Some(emoji.clone())),
+                    Tag("+draft/reply".to_string(), Some(reply_target)),
+                ]),
+                prefix: None,
+                command: Command::Raw("TAGMSG".to_string(), vec![channel.to_string()]),
+            };
+
+            if let Err(e) = self
+                .send_queue()
+                .and_then(|queue| queue.send(tagmsg, Priority::Normal))
+            {
+                eprintln!("Failed to send reaction to {}: {:#}", channel, e);
+            }
+
+            return;
+        }
+
+        if self.reaction_mode == ReactionMode::TagsOnly {
+            return;
+        }
+
+        let username = username.unwrap_or_else(|| "someone".to_string());
+        let thread = match thread {
+            Some(thread) => Some(thread),
+            None => self.thread_ref_for_reaction(pipo_id).await,
+        };
+        let target = match thread.as_ref() {
+            Some(_) => match self.remember_reply_token(channel, &thread, None).await {
+                Some(token) => format!(" to [t:{}]", token),
+                None => String::new(),
+            },
+            None => String::new(),
+        };
+        let line = if remove {
+            format!(
+                "\x01ACTION \x02* \x02{}!\x02{}\x02 removed their {} reaction{}\x01",
+                &transport[..1].to_uppercase(),
+                username,
+                emoji,
+                target
+            )
+        } else {
+            format!(
+                "\x01ACTION \x02* \x02{}!\x02{}\x02 reacted {}{}\x01",
+                &transport[..1].to_uppercase(),
+                username,
+                emoji,
+                target
+            )
+        };
+
+        if let Err(e) = self.send_queue().and_then(|queue| {
+            queue.send(
+                IrcMessage::from(Command::PRIVMSG(channel.to_string(), line)),
+                Priority::Normal,
+            )
+        }) {
+            eprintln!("Failed to send reaction to {}: {:#}", channel, e);
+        }
+    }
+
+    /// A thread reference for the reacted-to message, so the plaintext
+    /// fallback can carry a [t:TOKEN] that IRC users can reply to. A reply
+    /// points at the thread it was posted in; anything else starts a
+    /// thread of its own, named by its ids on the transport it came from.
+    async fn thread_ref_for_reaction(&self, pipo_id: i64) -> Option<ThreadRef> {
+        let archived = match self.archive.select(pipo_id).await {
+            Ok(archived) => archived?,
+            Err(e) => {
+                eprintln!("Failed to look up archived message {}: {:#}", pipo_id, e);
+                return None;
+            }
+        };
+
+        if archived.thread_root_id.is_some() || archived.reply_target_id.is_some() {
+            return Some(ThreadRef {
+                origin_transport: archived.transport,
+                thread_root_id: archived.thread_root_id,
+                reply_target_id: archived.reply_target_id,
+                ..Default::default()
+            });
+        }
+
+        Some(ThreadRef {
+            origin_transport: archived.transport,
+            thread_root_id: self.select_slackid_from_messages(pipo_id).await,
+            reply_target_id: self.select_discordid_from_messages(pipo_id).await,
+            root_pipo_id: Some(pipo_id),
+            root_author: Some(archived.username),
+            root_excerpt: ThreadRef::excerpt(archived.message.as_deref()),
+        })
+    }
+
+    async fn handle_tagmsg(
+        &self,
+        nickname: &str,
+        args: &[String],
+        message: &IrcMessage,
+    ) -> anyhow::Result<()> {
+        let Some(channel) = args.first() else {
+            return Err(anyhow!("Malformed TAGMSG: {:?}", args));
+        };
+        let Some(tags) = message.tags.as_ref() else {
+            return Ok(());
+        };
+        let tag_value = |name: &str| {
+            tags.iter().find_map(
+                |Tag(key, value)| {
+                    if key == name {
+                        value.clone()
+                    } else {
+                        None
+                    }
+                },
+            )
+        };
+        let (emoji, remove) = match (tag_value("+draft/react"), tag_value("+draft/unreact")) {
+            (Some(emoji), _) => (emoji, false),
+            (None, Some(emoji)) => (emoji, true),
+            (None, None) => return Ok(()),
+        };
+        let Some(reply_target) = tag_value("+draft/reply") else {
+            return Ok(());
+        };
+        let Some(sender) = self.channel_sender(channel) else {
+            return Err(anyhow!("Could not get sender for channel {}", channel));
+        };
+        let Some(pipo_id) = self.select_pipo_id_for_msgid(&reply_target).await else {
+            return Ok(());
+        };
+        // The rest of pipo passes emoji around as shortcodes.
+        let emoji = emojis::get(&emoji)
+            .and_then(|emoji| emoji.shortcode())
+            .map(str::to_string)
+            .unwrap_or(emoji);
+
+        sender
+            .send(Message::Reaction {
+                sender: self.transport_id,
+                pipo_id,
+                transport: TRANSPORT_NAME.to_string(),
+                emoji,
+                remove,
+                username: Some(nickname.to_string()),
+                avatar_url: self.avatars.url(nickname).await,
+                thread: None,
+            })
+            .map_err(|e| anyhow!("Couldn't send message: {:#}", e))?;
 
-        Ok((client, irc_stream, input_buses))
+        Ok(())
     }
 
-    fn update_capabilities_from_message(&mut self, message: &IrcMessage) {
-        let Command::CAP(_, subcommand, _, Some(extensions)) = &message.command else {
-            return;
-        };
-
-        if *subcommand != CapSubCommand::ACK {
-            return;
+    fn unicode_emoji(emoji: &str) -> String {
+        if emojis::get(emoji).is_some() {
+            return emoji.to_string();
         }
 
-        for capability in extensions.split_whitespace() {
-            match capability {
-                "message-tags" => self.capabilities.supports_message_tags = true,
-                "draft/reply" | "reply" => self.capabilities.supports_reply_tags = true,
-                _ => continue,
-            }
+        let shortcode = emoji.trim_matches(':');
+
+        match emojis::get_by_shortcode(shortcode) {
+            Some(emoji) => emoji.as_str().to_string(),
+            None => format!(":{}:", shortcode),
         }
     }
 
-    async fn send_privmsg_with_tags(
-        &self,
-        client: &Client,
-        channel: &str,
-        message: String,
-        reply_target: Option<&str>,
-        irc_message_id: Option<&str>,
-    ) -> irc::error::Result<()> {
-        let tags = self.tags_for_outbound_message(reply_target, irc_message_id);
+    fn send_queue(&self) -> anyhow::Result<&SendQueue> {
+        self.send_queue
+            .as_ref()
+            .ok_or_else(|| anyhow!("IRC send queue is not running"))
+    }
 
-        if let Some(tags) = tags {
-            return client.send(IrcMessage {
-                tags: Some(tags),
-                prefix: None,
-                command: Command::PRIVMSG(channel.to_string(), message),
-            });
+    fn edit_group(&self, channel: &str, pipo_id: i64, is_edit: bool) -> Option<EditGroup> {
+        if !is_edit {
+            return None;
         }
 
-        client.send_privmsg(channel, message)
+        Some(self.send_queue.as_ref()?.edit_group(channel, pipo_id))
+    }
+
+    fn send_notice(&self, target: &str, notice: impl Into<String>) -> anyhow::Result<()> {
+        self.send_queue()?.send(
+            IrcMessage::from(Command::NOTICE(target.to_string(), notice.into())),
+            Priority::Control,
+        )
     }
 
     fn tags_for_outbound_message(
@@ -742,30 +2777,32 @@ impl IRC {
         channel: &str,
         pipo_id: i64,
         thread: &Option<ThreadRef>,
+        reply_to: &Option<ReplyRef>,
     ) -> ThreadPresentation {
         if thread.is_none() {
-            return ThreadPresentation {
-                reply_target: None,
-                plaintext_prefix: None,
-                mode_used: "none",
+            return match reply_to {
+                Some(reply_to) => self.resolve_reply_presentation(channel, reply_to).await,
+                None => ThreadPresentation {
+                    reply_target: None,
+                    plaintext_prefix: None,
+                    mode_used: "none",
+                },
             };
         }
 
-        self.remember_reply_token(channel, thread, None);
+        self.remember_reply_token(channel, thread, None).await;
 
+        let presentation_mode = self.thread_options(channel).presentation_mode;
         let can_use_reply_tags = self.capabilities.supports_message_tags
             && self.capabilities.supports_reply_tags
-            && !matches!(
-                self.thread_presentation_mode,
-                ThreadPresentationMode::PlaintextOnly
-            );
+            && !matches!(presentation_mode, ThreadPresentationMode::PlaintextOnly);
         let reply_target = if can_use_reply_tags {
-            self.resolve_irc_reply_target(thread).await
+            self.resolve_irc_reply_target(channel, thread).await
         } else {
             None
         };
 
-        match self.thread_presentation_mode {
+        match presentation_mode {
             ThreadPresentationMode::Auto => {
                 if reply_target.is_some() {
                     ThreadPresentation {
@@ -820,9 +2857,53 @@ impl IRC {
         );
     }
 
-    async fn resolve_irc_reply_target(&self, thread: &Option<ThreadRef>) -> Option<String> {
+    /// The msgid replies in `thread` are tagged with in `channel`. Once
+    /// found, it's kept in the thread map, so later replies skip the
+    /// lookups.
+    async fn resolve_irc_reply_target(
+        &self,
+        channel: &str,
+        thread: &Option<ThreadRef>,
+    ) -> Option<String> {
         let thread_ref = thread.as_ref()?;
 
+        if let Some(root_pipo_id) = thread_ref.root_pipo_id {
+            match self
+                .threads
+                .select_thread(root_pipo_id, self.transport_id)
+                .await
+            {
+                Ok(Some(native))
+                    if IRC::channel_key(&native.channel) == IRC::channel_key(channel) =>
+                {
+                    return Some(native.thread_id)
+                }
+                Ok(_) => {}
+                Err(e) => eprintln!("Failed to look up thread of {}: {:#}", root_pipo_id, e),
+            }
+        }
+
+        let ircid = self.find_irc_reply_target(thread_ref).await?;
+
+        if let Some(root_pipo_id) = thread_ref.root_pipo_id {
+            if let Err(e) = self
+                .threads
+                .insert(root_pipo_id, self.transport_id, &ircid, channel)
+                .await
+            {
+                eprintln!("Failed to store thread of {}: {:#}", root_pipo_id, e);
+            }
+        }
+
+        Some(ircid)
+    }
+
+    async fn find_irc_reply_target(&self, thread_ref: &ThreadRef) -> Option<String> {
+        if let Some(root_pipo_id) = thread_ref.root_pipo_id {
+            if let Some(ircid) = self.select_ircid_from_messages(root_pipo_id).await {
+                return Some(ircid);
+            }
+        }
         if let Some(thread_root_id) = thread_ref.thread_root_id.clone() {
             if let Some(ircid) = self.select_ircid_by_slackid(thread_root_id.clone()).await {
                 return Some(ircid);
@@ -846,6 +2927,59 @@ impl IRC {
         None
     }
 
+    /// Inline replies outside of threads point at the message they answer
+    /// with `+draft/reply` when it was seen on IRC, and quote it otherwise.
+    async fn resolve_reply_presentation(
+        &self,
+        channel: &str,
+        reply_to: &ReplyRef,
+    ) -> ThreadPresentation {
+        let options = self.thread_options(channel);
+        let can_use_reply_tags = self.capabilities.supports_message_tags
+            && self.capabilities.supports_reply_tags
+            && !matches!(
+                options.presentation_mode,
+                ThreadPresentationMode::PlaintextOnly
+            );
+        let reply_target = match reply_to.pipo_id {
+            Some(pipo_id) if can_use_reply_tags => self.select_ircid_from_messages(pipo_id).await,
+            _ => None,
+        };
+
+        if reply_target.is_some() {
+            return ThreadPresentation {
+                reply_target,
+                plaintext_prefix: None,
+                mode_used: "ircv3_tag",
+            };
+        }
+        if matches!(options.presentation_mode, ThreadPresentationMode::Ircv3Only) {
+            return ThreadPresentation {
+                reply_target: None,
+                plaintext_prefix: None,
+                mode_used: "ircv3_unavailable",
+            };
+        }
+
+        let author = IRC::sanitize_thread_context_text(reply_to.author.as_deref())
+            .unwrap_or_else(|| "unknown".to_string());
+        let plaintext_prefix = match IRC::sanitize_thread_context_text(reply_to.excerpt.as_deref())
+        {
+            Some(excerpt) => format!(
+                "↪ {}: {}",
+                author,
+                IRC::truncate_with_ellipsis(excerpt, options.excerpt_len)
+            ),
+            None => format!("↪ {}", author),
+        };
+
+        ThreadPresentation {
+            reply_target: None,
+            plaintext_prefix: Some(plaintext_prefix),
+            mode_used: "plaintext_fallback",
+        }
+    }
+
     async fn outbound_thread_fallback_prefix(
         &self,
         channel: &str,
@@ -853,9 +2987,10 @@ impl IRC {
         thread: &Option<ThreadRef>,
     ) -> Option<String> {
         let thread_ref = thread.as_ref()?;
+        let options = self.thread_options(channel);
 
         if self.is_thread_root_message(pipo_id, thread_ref).await {
-            return if self.show_thread_root_marker {
+            return if options.show_root_marker {
                 Some("[thread]".to_string())
             } else {
                 None
@@ -867,20 +3002,22 @@ impl IRC {
             .unwrap_or_else(|| "unknown".to_string());
         let root_excerpt = IRC::sanitize_thread_context_text(thread_ref.root_excerpt.as_deref())
             .filter(|excerpt| !excerpt.is_empty())
-            .map(|excerpt| IRC::truncate_with_ellipsis(excerpt, self.thread_excerpt_len))
+            .map(|excerpt| IRC::truncate_with_ellipsis(excerpt, options.excerpt_len))
             .unwrap_or_else(|| "…".to_string());
-        let thread_token = IRC::thread_token(thread_ref);
+        let thread_token = self.remember_reply_token(channel, thread, None).await?;
         let compact_prefix = format!("↪ [t:{}] {}", thread_token, root_author);
         let expanded_prefix = format!("↪ [t:{}] {}: {}", thread_token, root_author, root_excerpt);
 
-        let emit_expanded = match self.thread_context_repeat {
+        let emit_expanded = match options.context_repeat {
             ThreadContextRepeat::Always => true,
             ThreadContextRepeat::Never => false,
-            ThreadContextRepeat::FirstSeen => self.mark_thread_token_seen(channel, &thread_token),
+            ThreadContextRepeat::FirstSeen => {
+                self.mark_thread_token_seen(channel, &thread_token).await
+            }
         };
 
         if emit_expanded {
-            if self.thread_context_repeat == ThreadContextRepeat::FirstSeen {
+            if options.context_repeat == ThreadContextRepeat::FirstSeen {
                 Some(format!(
                     "{} (reply with: >>{} <message>)",
                     expanded_prefix, thread_token
@@ -888,58 +3025,170 @@ impl IRC {
             } else {
                 Some(expanded_prefix)
             }
-        } else if self.thread_fallback_style == ThreadFallbackStyle::Verbose {
+        } else if options.fallback_style == ThreadFallbackStyle::Verbose {
             Some(expanded_prefix)
         } else {
             Some(compact_prefix)
         }
     }
 
-    fn mark_thread_token_seen(&self, channel: &str, token: &str) -> bool {
-        let mut seen = self.seen_thread_tokens.lock().unwrap();
-        let channel_seen = seen.entry(channel.to_string()).or_default();
-        channel_seen.insert(token.to_string())
+    async fn mark_thread_token_seen(&self, channel: &str, token: &str) -> bool {
+        let conn = self.pool.get().await.unwrap();
+        let transport_id = self.transport_id;
+        let channel = channel.to_string();
+        let token = token.to_string();
+
+        conn.interact(move |conn| -> anyhow::Result<bool> {
+            IRC::cleanup_expired_reply_tokens(conn, transport_id)?;
+
+            Ok(conn.execute(
+                "INSERT OR IGNORE INTO irc_seen_thread_tokens
+                   (transport_id, channel, token, created)
+                 VALUES (?1, ?2, ?3, CAST(strftime('%s', 'now') AS INTEGER))",
+                params![transport_id, channel, token],
+            )? == 1)
+        })
+        .await
+        .unwrap_or_else(|_| Err(anyhow!("Interact Error")))
+        .unwrap_or_else(|e| {
+            eprintln!("Failed to record seen thread token: {:#}", e);
+            true
+        })
     }
 
-    fn remember_reply_token(
+    /// Assigns (or refreshes) the reply token for `thread` in `channel` and
+    /// returns it. A thread keeps its token for as long as it stays active;
+    /// if the token it hashes to is held by another live thread, salted
+    /// hashes are tried until a free one turns up.
+    async fn remember_reply_token(
         &self,
         channel: &str,
         thread: &Option<ThreadRef>,
         nickname: Option<&str>,
-    ) {
-        let Some(thread_ref) = thread.as_ref() else {
-            return;
-        };
+    ) -> Option<String> {
+        let thread_ref = thread.clone()?;
+        let thread_key = IRC::thread_key(&thread_ref);
+        let conn = self.pool.get().await.unwrap();
+        let transport_id = self.transport_id;
+        let token_channel = channel.to_string();
+        let nickname = nickname.map(str::to_string);
+        let fallback_token = IRC::thread_token(&thread_key, 0);
+
+        conn.interact(move |conn| -> anyhow::Result<String> {
+            IRC::cleanup_expired_reply_tokens(conn, transport_id)?;
+
+            let existing = conn
+                .query_row(
+                    "SELECT token FROM irc_reply_tokens
+                     WHERE transport_id = ?1 AND channel = ?2 AND thread_key = ?3",
+                    params![transport_id, token_channel, thread_key],
+                    |row| row.get::<_, String>(0),
+                )
+                .optional()?;
+            let token = match existing {
+                Some(token) => token,
+                None => {
+                    let mut free_token = None;
+
+                    for attempt in 0..REPLY_TOKEN_ATTEMPTS {
+                        let candidate = IRC::thread_token(&thread_key, attempt);
+                        let holder = conn
+                            .query_row(
+                                "SELECT thread_key FROM irc_reply_tokens
+                                 WHERE transport_id = ?1 AND channel = ?2 AND token = ?3",
+                                params![transport_id, token_channel, candidate],
+                                |row| row.get::<_, String>(0),
+                            )
+                            .optional()?;
+
+                        match holder {
+                            Some(holder) => eprintln!(
+                                "Reply token {} in {} is held by thread {}, not {}",
+                                candidate, token_channel, holder, thread_key
+                            ),
+                            None => {
+                                free_token = Some(candidate);
+                                break;
+                            }
+                        }
+                    }
 
-        let token = IRC::thread_token(thread_ref);
-        let mut tokens = self.reply_tokens.lock().unwrap();
-        IRC::cleanup_expired_reply_tokens(&mut tokens);
-        tokens.insert(
-            (channel.to_string(), token),
-            ReplyTokenEntry {
-                thread_ref: thread_ref.clone(),
-                nickname: nickname.map(str::to_string),
-                created_at: Instant::now(),
-            },
-        );
+                    free_token
+                        .ok_or_else(|| anyhow!("No free reply token for thread {}", thread_key))?
+                }
+            };
+
+            conn.execute(
+                "INSERT OR REPLACE INTO irc_reply_tokens
+                   (transport_id, channel, token, thread_key, origin_transport,
+                    thread_root_id, reply_target_id, root_pipo_id, root_author,
+                    root_excerpt, nickname, created)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11,
+                         CAST(strftime('%s', 'now') AS INTEGER))",
+                params![
+                    transport_id,
+                    token_channel,
+                    token,
+                    thread_key,
+                    thread_ref.origin_transport,
+                    thread_ref.thread_root_id,
+                    thread_ref.reply_target_id.map(|id| id as i64),
+                    thread_ref.root_pipo_id,
+                    thread_ref.root_author,
+                    thread_ref.root_excerpt,
+                    nickname,
+                ],
+            )?;
+
+            Ok(token)
+        })
+        .await
+        .unwrap_or_else(|_| Err(anyhow!("Interact Error")))
+        .map_err(|e| {
+            eprintln!("Failed to store reply token for {}: {:#}", channel, e);
+        })
+        .ok()
+        .or(Some(fallback_token))
     }
 
-    fn cleanup_expired_reply_tokens(tokens: &mut HashMap<(String, String), ReplyTokenEntry>) {
-        let now = Instant::now();
-        tokens.retain(|_, entry| now.duration_since(entry.created_at) < REPLY_TOKEN_TTL);
+    /// Drops reply tokens that haven't been used within `REPLY_TOKEN_TTL`,
+    /// along with the seen-state of tokens that no longer exist.
+    fn cleanup_expired_reply_tokens(
+        conn: &rusqlite::Connection,
+        transport_id: usize,
+    ) -> anyhow::Result<()> {
+        conn.execute(
+            "DELETE FROM irc_reply_tokens
+             WHERE transport_id = ?1
+               AND created < CAST(strftime('%s', 'now') AS INTEGER) - ?2",
+            params![transport_id, REPLY_TOKEN_TTL.as_secs() as i64],
+        )?;
+        conn.execute(
+            "DELETE FROM irc_seen_thread_tokens
+             WHERE transport_id = ?1
+               AND NOT EXISTS (SELECT 1 FROM irc_reply_tokens
+                               WHERE irc_reply_tokens.transport_id = irc_seen_thread_tokens.transport_id
+                                 AND irc_reply_tokens.channel = irc_seen_thread_tokens.channel
+                                 AND irc_reply_tokens.token = irc_seen_thread_tokens.token)",
+            params![transport_id],
+        )?;
+
+        Ok(())
     }
 
-    fn resolve_reply_token(
+    async fn resolve_reply_token(
         &self,
         channel: &str,
         nickname: Option<&str>,
         token: &str,
     ) -> Option<ThreadRef> {
-        let mut tokens = self.reply_tokens.lock().unwrap();
-        IRC::cleanup_expired_reply_tokens(&mut tokens);
-
-        let key = (channel.to_string(), token.to_uppercase());
-        let entry = tokens.get(&key)?;
+        let token = token.to_uppercase();
+        let entry = self
+            .active_reply_token_entries_for_channel(channel)
+            .await
+            .into_iter()
+            .find(|(entry_token, _)| *entry_token == token)
+            .map(|(_, entry)| entry)?;
 
         if let (Some(expected), Some(actual)) = (entry.nickname.as_deref(), nickname) {
             if !expected.eq_ignore_ascii_case(actual) {
@@ -947,35 +3196,67 @@ impl IRC {
             }
         }
 
-        Some(entry.thread_ref.clone())
+        Some(entry.thread_ref)
     }
 
-    fn active_reply_tokens_for_channel(&self, channel: &str) -> Vec<String> {
-        let mut tokens = self.reply_tokens.lock().unwrap();
-        IRC::cleanup_expired_reply_tokens(&mut tokens);
-
-        tokens
-            .keys()
-            .filter(|(token_channel, _)| token_channel == channel)
-            .map(|(_, token)| token.clone())
+    async fn active_reply_tokens_for_channel(&self, channel: &str) -> Vec<String> {
+        self.active_reply_token_entries_for_channel(channel)
+            .await
+            .into_iter()
+            .map(|(token, _)| token)
             .collect()
     }
 
-    fn active_reply_token_entries_for_channel(
+    /// Live reply tokens of `channel`, most recently used first.
+    async fn active_reply_token_entries_for_channel(
         &self,
         channel: &str,
     ) -> Vec<(String, ReplyTokenEntry)> {
-        let mut tokens = self.reply_tokens.lock().unwrap();
-        IRC::cleanup_expired_reply_tokens(&mut tokens);
-
-        let mut entries = tokens
-            .iter()
-            .filter(|((token_channel, _), _)| token_channel == channel)
-            .map(|((_, token), entry)| (token.clone(), entry.clone()))
-            .collect::<Vec<_>>();
-
-        entries.sort_by(|a, b| b.1.created_at.cmp(&a.1.created_at));
-        entries
+        let conn = self.pool.get().await.unwrap();
+        let transport_id = self.transport_id;
+        let token_channel = channel.to_string();
+
+        conn.interact(
+            move |conn| -> anyhow::Result<Vec<(String, ReplyTokenEntry)>> {
+                IRC::cleanup_expired_reply_tokens(conn, transport_id)?;
+
+                let mut stmt = conn.prepare(
+                    "SELECT token, origin_transport, thread_root_id, reply_target_id,
+                        root_pipo_id, root_author, root_excerpt, nickname
+                 FROM irc_reply_tokens
+                 WHERE transport_id = ?1 AND channel = ?2
+                 ORDER BY created DESC, rowid DESC",
+                )?;
+                let entries = stmt
+                    .query_map(params![transport_id, token_channel], |row| {
+                        Ok((
+                            row.get(0)?,
+                            ReplyTokenEntry {
+                                thread_ref: ThreadRef {
+                                    origin_transport: row.get(1)?,
+                                    thread_root_id: row.get(2)?,
+                                    reply_target_id: row
+                                        .get::<_, Option<i64>>(3)?
+                                        .map(|id| id as u64),
+                                    root_pipo_id: row.get(4)?,
+                                    root_author: row.get(5)?,
+                                    root_excerpt: row.get(6)?,
+                                },
+                                nickname: row.get(7)?,
+                            },
+                        ))
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                Ok(entries)
+            },
+        )
+        .await
+        .unwrap_or_else(|_| Err(anyhow!("Interact Error")))
+        .unwrap_or_else(|e| {
+            eprintln!("Failed to load reply tokens for {}: {:#}", channel, e);
+            Vec::new()
+        })
     }
 
     fn thread_root_summary(&self, thread_ref: &ThreadRef) -> String {
@@ -992,14 +3273,34 @@ impl IRC {
 
     async fn handle_local_thread_command(
         &self,
-        client: &Client,
         channel: &str,
+        nickname: &str,
         message: &str,
     ) -> anyhow::Result<bool> {
         let trimmed = message.trim();
+        let mut words = trimmed.split_whitespace();
+
+        if words
+            .next()
+            .is_some_and(|command| command.eq_ignore_ascii_case("/thread"))
+        {
+            let Some(token) = words.next() else {
+                self.send_notice(nickname, "Usage: /thread TOKEN [count]")?;
+                return Ok(true);
+            };
+            let limit = words
+                .next()
+                .and_then(|count| count.parse::<usize>().ok())
+                .unwrap_or(THREAD_HISTORY_DEFAULT)
+                .clamp(1, THREAD_HISTORY_MAX);
+
+            self.send_thread_history(channel, nickname, token, limit)
+                .await?;
+            return Ok(true);
+        }
 
         if trimmed.eq_ignore_ascii_case("/threads") {
-            let entries = self.active_reply_token_entries_for_channel(channel);
+            let entries = self.active_reply_token_entries_for_channel(channel).await;
             let rendered = if entries.is_empty() {
                 "none cached yet".to_string()
             } else {
@@ -1017,14 +3318,14 @@ impl IRC {
                 "Recent thread tokens (latest {}): {}",
                 THREAD_LIST_LIMIT, rendered
             );
-            client.send_notice(channel, notice)?;
+            self.send_notice(channel, notice)?;
             return Ok(true);
         }
 
         if trimmed.eq_ignore_ascii_case("/threadhelp") || trimmed.eq_ignore_ascii_case("/help") {
-            client.send_notice(
+            self.send_notice(
                 channel,
-                "Thread replies: >>TOKEN your reply (example: >>K7F2 thanks) or /reply TOKEN your reply. Use /threads to list recent tokens.",
+                "Thread replies: >>TOKEN your reply (example: >>K7F2 thanks) or /reply TOKEN your reply. Use /threads to list recent tokens and /thread TOKEN [count] to catch up on a thread.",
             )?;
             return Ok(true);
         }
@@ -1032,6 +3333,83 @@ impl IRC {
         Ok(false)
     }
 
+    /// Sends `nickname` the last `limit` archived messages of the thread
+    /// behind `token` as private NOTICEs.
+    async fn send_thread_history(
+        &self,
+        channel: &str,
+        nickname: &str,
+        token: &str,
+        limit: usize,
+    ) -> anyhow::Result<()> {
+        let token = token.to_uppercase();
+        let Some(thread_ref) = self
+            .resolve_reply_token(channel, Some(nickname), &token)
+            .await
+        else {
+            return self.send_notice(
+                nickname,
+                format!(
+                    "Unknown or expired thread token {}. Use /threads to list recent tokens.",
+                    token
+                ),
+            );
+        };
+        let Some(bus) = self.buses.get(&IRC::channel_key(channel)) else {
+            return Ok(());
+        };
+        let history = self.archive.thread_history(bus, &thread_ref, limit).await?;
+        let summary = self.thread_root_summary(&thread_ref);
+
+        if history.is_empty() {
+            return self.send_notice(
+                nickname,
+                format!("No archived messages for thread {} ({}).", token, summary),
+            );
+        }
+
+        self.send_notice(
+            nickname,
+            format!(
+                "Thread {} ({}), last {} messages:",
+                token,
+                summary,
+                history.len()
+            ),
+        )?;
+
+        for entry in history {
+            let time = Utc
+                .timestamp_opt(entry.created, 0)
+                .single()
+                .map(|time| time.format("%H:%M").to_string())
+                .unwrap_or_else(|| "--:--".to_string());
+            let text = IRC::sanitize_thread_context_text(entry.message.as_deref())
+                .unwrap_or_else(|| "(no text)".to_string());
+            let source = format!(
+                "{}!{}",
+                entry.transport.get(..1).unwrap_or("?").to_uppercase(),
+                entry.username
+            );
+            let line = if entry.is_action {
+                format!("[{}] * {} {}", time, source, text)
+            } else {
+                format!("[{}] <{}> {}", time, source, text)
+            };
+
+            // History goes through the normal queue so that a long thread
+            // can't starve replies to other users.
+            for chunk in IRC::split_at_byte_limit(&line, THREAD_HISTORY_LINE_BYTES) {
+                self.send_queue()?.send(
+                    IrcMessage::from(Command::NOTICE(nickname.to_string(), chunk.to_string())),
+                    Priority::Normal,
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+
     fn parse_reply_command(message: &str) -> Option<(String, String)> {
         let trimmed = message.trim_start();
         let (token, remaining) = if let Some(command) = trimmed.strip_prefix(">>") {
@@ -1060,8 +3438,8 @@ impl IRC {
         Some((token.to_uppercase(), remaining.to_string()))
     }
 
-    async fn send_reply_token_usage_notice(&self, client: &Client, channel: &str) {
-        let mut active = self.active_reply_tokens_for_channel(channel);
+    async fn send_reply_token_usage_notice(&self, channel: &str) {
+        let mut active = self.active_reply_tokens_for_channel(channel).await;
         active.sort();
         let sample = if active.is_empty() {
             "none currently cached".to_string()
@@ -1074,7 +3452,7 @@ impl IRC {
             sample
         );
 
-        if let Err(e) = client.send_notice(channel, notice) {
+        if let Err(e) = self.send_notice(channel, notice) {
             eprintln!(
                 "Failed to send reply-token usage notice to {}: {:#}",
                 channel, e
@@ -1082,15 +3460,30 @@ impl IRC {
         }
     }
 
-    fn thread_token(thread_ref: &ThreadRef) -> String {
-        let token_input = thread_ref
+    /// What identifies a thread across messages: its root id where the
+    /// origin has one, otherwise the best stand-in available.
+    fn thread_key(thread_ref: &ThreadRef) -> String {
+        thread_ref
             .thread_root_id
             .as_deref()
             .filter(|value| !value.is_empty())
             .map(|value| value.to_string())
             .or_else(|| thread_ref.reply_target_id.map(|id| id.to_string()))
+            // Threads without an id of their own still have a bridged root,
+            // which tells them apart better than its author's name.
+            .or_else(|| thread_ref.root_pipo_id.map(|id| format!("pipo:{}", id)))
             .or_else(|| IRC::sanitize_thread_context_text(thread_ref.root_author.as_deref()))
-            .unwrap_or_else(|| "thread".to_string());
+            .unwrap_or_else(|| "thread".to_string())
+    }
+
+    /// The reply token candidate for `thread_key`; `attempt` salts the hash
+    /// when earlier candidates are already taken.
+    fn thread_token(thread_key: &str, attempt: u32) -> String {
+        let token_input = if attempt == 0 {
+            thread_key.to_string()
+        } else {
+            format!("{}#{}", thread_key, attempt)
+        };
 
         let mut hash: u32 = 0x811c9dc5;
         for byte in token_input.as_bytes() {
@@ -1119,90 +3512,230 @@ impl IRC {
             return false;
         };
 
-        if let Some(slackid) = self.select_slackid_from_messages(pipo_id).await {
-            if thread_root_id == slackid {
-                return true;
-            }
+        if let Some(slackid) = self.select_slackid_from_messages(pipo_id).await {
+            if thread_root_id == slackid {
+                return true;
+            }
+        }
+
+        false
+    }
+
+    fn sanitize_thread_context_text(value: Option<&str>) -> Option<String> {
+        let value = value?;
+        let collapsed = value
+            .chars()
+            .map(|ch| if ch.is_ascii_control() { ' ' } else { ch })
+            .collect::<String>();
+
+        let collapsed = collapsed
+            .split_whitespace()
+            .collect::<Vec<&str>>()
+            .join(" ");
+
+        if collapsed.is_empty() {
+            None
+        } else {
+            Some(collapsed)
+        }
+    }
+
+    fn truncate_with_ellipsis(input: String, max_len: usize) -> String {
+        let char_count = input.chars().count();
+        if char_count <= max_len {
+            return input;
+        }
+
+        let truncated: String = input.chars().take(max_len.saturating_sub(1)).collect();
+        format!("{}…", truncated)
+    }
+
+    fn parse_message_id_tag(message: &IrcMessage) -> Option<String> {
+        let tags = message.tags.as_ref()?;
+
+        tags.iter().find_map(|Tag(key, value)| {
+            if key == "msgid" || key == "+draft/msgid" {
+                value.clone()
+            } else {
+                None
+            }
+        })
+    }
+
+    fn parse_relaymsg_tag(message: &IrcMessage) -> Option<&str> {
+        let tags = message.tags.as_ref()?;
+
+        tags.iter().find_map(|Tag(key, value)| {
+            if key == "draft/relaymsg" || key == "relaymsg" {
+                value.as_deref()
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Asks for whatever was said in `channel` since the newest message we
+    /// know of there. Nothing is requested for channels we've never seen a
+    /// msgid in, so a fresh setup doesn't replay old history.
+    /// Asks for the modes of a channel we joined, which tell whether its
+    /// topic can only be set by operators.
+    fn request_channel_modes(&self, channel: &str) {
+        if !self.topic_sync {
+            return;
+        }
+
+        if let Err(e) = self.send_queue().and_then(|queue| {
+            queue.send(
+                IrcMessage::from(Command::Raw("MODE".to_string(), vec![channel.to_string()])),
+                Priority::Control,
+            )
+        }) {
+            eprintln!("Failed to request modes of {}: {:#}", channel, e);
+        }
+    }
+
+    async fn request_history(&self, channel: &str) {
+        if !self.capabilities.supports_chathistory
+            || !self.history_backfill.enabled()
+            || self.channel_sender(channel).is_none()
+        {
+            return;
+        }
+
+        let pending = self
+            .history_cursors
+            .lock()
+            .unwrap()
+            .get(&IRC::channel_key(channel))
+            .map(str::to_string);
+        let stored = match pending {
+            Some(msgid) => Ok(Some(msgid)),
+            None => self.select_history_cursor(channel).await,
+        };
+        let msgid = match stored {
+            Ok(Some(msgid)) => msgid,
+            Ok(None) => return,
+            Err(e) => {
+                eprintln!("Failed to look up history cursor for {}: {:#}", channel, e);
+
+                return;
+            }
+        };
+
+        if let Err(e) = self.send_queue().and_then(|queue| {
+            queue.send(
+                self.history_backfill.request(channel, &msgid),
+                Priority::Control,
+            )
+        }) {
+            eprintln!("Failed to request history of {}: {:#}", channel, e);
+        }
+    }
+
+    /// Remembers the msgid of a PRIVMSG or NOTICE in a mapped channel as
+    /// the newest one seen there, unless a newer one is already known.
+    async fn advance_history_cursor(&self, message: &IrcMessage) {
+        let (Command::PRIVMSG(target, _) | Command::NOTICE(target, _)) = &message.command else {
+            return;
+        };
+        let Some(msgid) = IRC::parse_message_id_tag(message) else {
+            return;
+        };
+
+        if self.channel_sender(target).is_none() {
+            return;
         }
 
-        false
-    }
+        let time = backfill::server_time(message).unwrap_or_else(Utc::now);
 
-    fn sanitize_thread_context_text(value: Option<&str>) -> Option<String> {
-        let value = value?;
-        let collapsed = value
-            .chars()
-            .map(|ch| if ch.is_ascii_control() { ' ' } else { ch })
-            .collect::<String>();
+        self.history_cursors.lock().unwrap().advance(
+            &IRC::channel_key(target),
+            &msgid,
+            time,
+            Instant::now(),
+        );
+    }
 
-        let collapsed = collapsed
-            .split_whitespace()
-            .collect::<Vec<&str>>()
-            .join(" ");
+    /// Writes the history cursors that advanced since the last flush.
+    async fn flush_history_cursors(&self) {
+        let cursors = self.history_cursors.lock().unwrap().take();
 
-        if collapsed.is_empty() {
-            None
-        } else {
-            Some(collapsed)
+        if cursors.is_empty() {
+            return;
         }
-    }
 
-    fn truncate_with_ellipsis(input: String, max_len: usize) -> String {
-        let char_count = input.chars().count();
-        if char_count <= max_len {
-            return input;
+        if let Err(e) = self.update_history_cursors(cursors).await {
+            eprintln!("Failed to update history cursors: {:#}", e);
         }
-
-        let truncated: String = input.chars().take(max_len.saturating_sub(1)).collect();
-        format!("{}…", truncated)
     }
 
-    fn parse_message_id_tag(message: &IrcMessage) -> Option<String> {
-        let tags = message.tags.as_ref()?;
+    /// Relays a message from a CHATHISTORY batch, unless it is one of our
+    /// own or was already bridged before the connection dropped.
+    async fn handle_backfill_message(
+        &self,
+        client: &Client,
+        channel: &str,
+        message: IrcMessage,
+    ) -> anyhow::Result<()> {
+        if self.is_own_echo(client, &message) {
+            return Ok(());
+        }
 
-        tags.iter().find_map(|Tag(key, value)| {
-            if key == "msgid" || key == "+draft/msgid" {
-                value.clone()
-            } else {
-                None
-            }
-        })
-    }
+        let Some(msgid) = IRC::parse_message_id_tag(&message) else {
+            return Ok(());
+        };
 
-    async fn get_avatar_url(&self, nickname: &str) -> String {
-        let client = reqwest::Client::new();
-        let url = format!("{}/{}.png", self.img_root, nickname);
+        if self.select_pipo_id_for_msgid(&msgid).await.is_some() {
+            return Ok(());
+        }
 
-        let response = match client.head(url).send().await {
-            Ok(response) => response,
-            Err(_) => return format!("{}/irc.png", self.img_root),
-        };
+        self.advance_history_cursor(&message).await;
+
+        let late = Some(Late {
+            sent_at: backfill::server_time(&message),
+        });
+        let nickname = message.source_nickname().unwrap_or("").to_string();
+        // Notices from history are classified the same way as live ones.
+        let kind = self.notices.classify(
+            message.prefix.as_ref(),
+            channel,
+            client.current_nickname(),
+            self.server_name.as_deref(),
+        );
 
-        if let Some(etag) = response.headers().get(reqwest::header::ETAG) {
-            if let Ok(etag) = etag.to_str() {
-                return format!("{}/{}.png?{}", self.img_root, nickname, etag);
+        match message.command {
+            Command::PRIVMSG(_, text) => {
+                self.handle_priv_msg(nickname, channel.to_string(), text, Some(msgid), late)
+                    .await
+            }
+            Command::NOTICE(_, text) => {
+                self.handle_notice(kind, nickname, channel.to_string(), text, Some(msgid), late)
+                    .await
             }
+            _ => Ok(()),
         }
-
-        return format!("{}/{}.png", self.img_root, nickname);
     }
 
     async fn handle_priv_msg(
         &self,
-        client: &Client,
         nickname: String,
         channel: String,
         message: String,
         irc_message_id: Option<String>,
+        late: Option<Late>,
     ) -> anyhow::Result<()> {
-        if self
-            .handle_local_thread_command(client, &channel, &message)
-            .await?
+        let is_backfill = late.is_some();
+
+        // Commands in history were answered, or not, while we were away.
+        if !is_backfill
+            && self
+                .handle_local_thread_command(&channel, &nickname, &message)
+                .await?
         {
             return Ok(());
         }
 
-        if let Some(sender) = self.channels.get(&channel) {
+        if let Some(sender) = self.channel_sender(&channel) {
             lazy_static! {
                 static ref RE: Regex = Regex::new("^\x01ACTION (.*)\x01\r?$").unwrap();
             }
@@ -1212,7 +3745,7 @@ impl IRC {
                     .await?;
             }
 
-            let avatar_url = self.get_avatar_url(&nickname).await;
+            let avatar_url = self.avatars.url(&nickname).await;
 
             eprintln!("IRC PIPO ID: {}", pipo_id);
 
@@ -1222,13 +3755,14 @@ impl IRC {
                 let mut content = message.to_string();
 
                 if let Some((token, parsed_message)) = IRC::parse_reply_command(message) {
-                    if let Some(thread_ref) =
-                        self.resolve_reply_token(&channel, Some(&nickname), &token)
+                    if let Some(thread_ref) = self
+                        .resolve_reply_token(&channel, Some(&nickname), &token)
+                        .await
                     {
                         thread = Some(thread_ref);
                         content = parsed_message;
-                    } else {
-                        self.send_reply_token_usage_notice(client, &channel).await;
+                    } else if !is_backfill {
+                        self.send_reply_token_usage_notice(&channel).await;
                         return Ok(());
                     }
                 }
@@ -1238,12 +3772,14 @@ impl IRC {
                     pipo_id,
                     transport: TRANSPORT_NAME.to_string(),
                     username: nickname.clone(),
-                    avatar_url: Some(avatar_url),
+                    avatar_url,
                     thread,
+                    reply_to: None,
                     message: Some(content),
                     attachments: None,
                     is_edit: false,
                     irc_flag: false,
+                    late,
                 };
                 return match sender.send(message) {
                     Ok(_) => Ok(()),
@@ -1254,13 +3790,14 @@ impl IRC {
                 let mut content = message.to_string();
 
                 if let Some((token, parsed_message)) = IRC::parse_reply_command(&message) {
-                    if let Some(thread_ref) =
-                        self.resolve_reply_token(&channel, Some(&nickname), &token)
+                    if let Some(thread_ref) = self
+                        .resolve_reply_token(&channel, Some(&nickname), &token)
+                        .await
                     {
                         thread = Some(thread_ref);
                         content = parsed_message;
-                    } else {
-                        self.send_reply_token_usage_notice(client, &channel).await;
+                    } else if !is_backfill {
+                        self.send_reply_token_usage_notice(&channel).await;
                         return Ok(());
                     }
                 }
@@ -1270,12 +3807,14 @@ impl IRC {
                     pipo_id,
                     transport: TRANSPORT_NAME.to_string(),
                     username: nickname.clone(),
-                    avatar_url: Some(avatar_url),
+                    avatar_url,
                     thread,
+                    reply_to: None,
                     message: Some(content),
                     attachments: None,
                     is_edit: false,
                     irc_flag: false,
+                    late,
                 };
                 return match sender.send(message) {
                     Ok(_) => Ok(()),
@@ -1420,6 +3959,162 @@ impl IRC {
         .flatten()
     }
 
+    async fn select_id_by_ircid(&self, ircid: String) -> Option<i64> {
+        let conn = self.pool.get().await.unwrap();
+
+        conn.interact(move |conn| -> anyhow::Result<i64> {
+            Ok(conn.query_row(
+                "SELECT id FROM messages WHERE ircid = ?1",
+                params![ircid],
+                |row| row.get(0),
+            )?)
+        })
+        .await
+        .unwrap_or_else(|_| Err(anyhow!("Interact Error")))
+        .ok()
+    }
+
+    /// The message one of our own relayed lines belongs to. Lines relayed
+    /// through relaymsg, or split off a longer message, are only known in
+    /// irc_sent_messages.
+    async fn select_sent_pipo_id(&self, msgid: &str) -> Option<i64> {
+        let conn = self.pool.get().await.unwrap();
+        let transport_id = self.transport_id;
+        let msgid = msgid.to_string();
+
+        conn.interact(move |conn| -> anyhow::Result<i64> {
+            Ok(conn.query_row(
+                "SELECT pipo_id FROM irc_sent_messages
+                 WHERE transport_id = ?1 AND msgid = ?2",
+                params![transport_id, msgid],
+                |row| row.get(0),
+            )?)
+        })
+        .await
+        .unwrap_or_else(|_| Err(anyhow!("Interact Error")))
+        .ok()
+    }
+
+    async fn select_pipo_id_for_msgid(&self, msgid: &str) -> Option<i64> {
+        match self.select_sent_pipo_id(msgid).await {
+            Some(pipo_id) => Some(pipo_id),
+            None => self.select_id_by_ircid(msgid.to_string()).await,
+        }
+    }
+
+    async fn insert_into_irc_sent_messages(
+        &self,
+        channel: &str,
+        msgid: &str,
+        pipo_id: i64,
+    ) -> anyhow::Result<()> {
+        let conn = self.pool.get().await.unwrap();
+        let transport_id = self.transport_id;
+        let channel = channel.to_string();
+        let msgid = msgid.to_string();
+
+        conn.interact(move |conn| -> anyhow::Result<usize> {
+            Ok(conn.execute(
+                "INSERT OR REPLACE INTO irc_sent_messages
+                   (transport_id, channel, msgid, pipo_id)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![transport_id, channel, msgid, pipo_id],
+            )?)
+        })
+        .await
+        .unwrap_or_else(|_| Err(anyhow!("Interact Error")))?;
+
+        Ok(())
+    }
+
+    async fn select_sent_msgids(&self, channel: &str, pipo_id: i64) -> anyhow::Result<Vec<String>> {
+        let conn = self.pool.get().await.unwrap();
+        let transport_id = self.transport_id;
+        let channel = channel.to_string();
+
+        conn.interact(move |conn| -> anyhow::Result<Vec<String>> {
+            let mut statement = conn.prepare(
+                "SELECT msgid FROM irc_sent_messages
+                 WHERE transport_id = ?1 AND channel = ?2 AND pipo_id = ?3",
+            )?;
+            let msgids = statement
+                .query_map(params![transport_id, channel, pipo_id], |row| row.get(0))?
+                .collect::<Result<Vec<String>, _>>()?;
+
+            Ok(msgids)
+        })
+        .await
+        .unwrap_or_else(|_| Err(anyhow!("Interact Error")))
+    }
+
+    async fn delete_sent_msgids(&self, channel: &str, pipo_id: i64) -> anyhow::Result<()> {
+        let conn = self.pool.get().await.unwrap();
+        let transport_id = self.transport_id;
+        let channel = channel.to_string();
+
+        conn.interact(move |conn| -> anyhow::Result<usize> {
+            Ok(conn.execute(
+                "DELETE FROM irc_sent_messages
+                 WHERE transport_id = ?1 AND channel = ?2 AND pipo_id = ?3",
+                params![transport_id, channel, pipo_id],
+            )?)
+        })
+        .await
+        .unwrap_or_else(|_| Err(anyhow!("Interact Error")))?;
+
+        Ok(())
+    }
+
+    async fn select_history_cursor(&self, channel: &str) -> anyhow::Result<Option<String>> {
+        let conn = self.pool.get().await.unwrap();
+        let transport_id = self.transport_id;
+        let channel = IRC::channel_key(channel);
+
+        conn.interact(move |conn| -> anyhow::Result<Option<String>> {
+            Ok(conn
+                .query_row(
+                    "SELECT msgid FROM irc_history_cursors
+                     WHERE transport_id = ?1 AND channel = ?2",
+                    params![transport_id, channel],
+                    |row| row.get(0),
+                )
+                .optional()?)
+        })
+        .await
+        .unwrap_or_else(|_| Err(anyhow!("Interact Error")))
+    }
+
+    /// Stores `(channel, msgid, time)` cursors in one transaction.
+    async fn update_history_cursors(
+        &self,
+        cursors: Vec<(String, String, DateTime<Utc>)>,
+    ) -> anyhow::Result<()> {
+        let conn = self.pool.get().await.unwrap();
+        let transport_id = self.transport_id;
+
+        conn.interact(move |conn| -> anyhow::Result<()> {
+            let transaction = conn.transaction()?;
+
+            for (channel, msgid, time) in cursors {
+                // Fixed-width UTC timestamps compare correctly as text.
+                let time = time.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string();
+
+                transaction.execute(
+                    "INSERT INTO irc_history_cursors (transport_id, channel, msgid, time)
+                     VALUES (?1, ?2, ?3, ?4)
+                     ON CONFLICT (transport_id, channel) DO UPDATE
+                       SET msgid = excluded.msgid, time = excluded.time
+                       WHERE excluded.time >= irc_history_cursors.time",
+                    params![transport_id, channel, msgid, time],
+                )?;
+            }
+
+            Ok(transaction.commit()?)
+        })
+        .await
+        .unwrap_or_else(|_| Err(anyhow!("Interact Error")))
+    }
+
     async fn select_slackid_from_messages(&self, pipo_id: i64) -> Option<String> {
         let conn = self.pool.get().await.unwrap();
 
@@ -1436,63 +4131,115 @@ impl IRC {
         .flatten()
     }
 
+    async fn select_discordid_from_messages(&self, pipo_id: i64) -> Option<u64> {
+        let conn = self.pool.get().await.unwrap();
+
+        conn.interact(move |conn| -> anyhow::Result<Option<u64>> {
+            Ok(conn.query_row(
+                "SELECT discordid FROM messages WHERE id = ?1",
+                params![pipo_id],
+                |row| row.get(0),
+            )?)
+        })
+        .await
+        .unwrap_or_else(|_| Err(anyhow!("Interact Error")))
+        .ok()
+        .flatten()
+    }
+
+    /// Relays a NOTICE as configured for its kind. Notices that weren't
+    /// sent to a channel go to the bus of `notices.private_channel`.
     async fn handle_notice(
         &self,
+        kind: NoticeKind,
         nickname: String,
         channel: String,
         message: String,
         irc_message_id: Option<String>,
+        late: Option<Late>,
     ) -> anyhow::Result<()> {
-        if let Some(sender) = self.channels.get(&channel) {
-            lazy_static! {
-                static ref RE: Regex = Regex::new("^\x01ACTION (.*)\x01\r?$").unwrap();
-            }
-            let pipo_id = self.insert_into_messages_table().await?;
-            if let Some(irc_message_id) = irc_message_id {
-                self.update_messages_ircid(pipo_id, Some(irc_message_id))
-                    .await?;
-            }
+        lazy_static! {
+            static ref RE: Regex = Regex::new("^\x01ACTION (.*)\x01\r?$").unwrap();
+        }
+        let rendering = self.notices.rendering(kind);
+        let target = match kind {
+            NoticeKind::Channel => Some(&channel),
+            _ if self.channel_sender(&channel).is_some() => Some(&channel),
+            _ => self.notices.private_channel.as_ref(),
+        };
+        let sender = match target.and_then(|target| self.channel_sender(target)) {
+            Some(sender) if rendering != NoticeRendering::Drop => sender,
+            _ => return Ok(()),
+        };
+        let (is_action, text) = match RE.captures(&message) {
+            Some(captures) => (true, captures.get(1).unwrap().as_str().to_string()),
+            None => (false, message),
+        };
 
-            let avatar_url = self.get_avatar_url(&nickname).await;
+        if rendering == NoticeRendering::System {
+            return match sender.send(Message::Notice {
+                sender: self.transport_id,
+                transport: TRANSPORT_NAME.to_string(),
+                username: nickname,
+                message: if is_action {
+                    format!("* {}", text)
+                } else {
+                    text
+                },
+                late,
+            }) {
+                Ok(_) => Ok(()),
+                Err(e) => Err(anyhow!("Couldn't send message: {:#}", e)),
+            };
+        }
 
-            if let Some(message) = RE.captures(&message) {
-                let message = format!("```{}```", message.get(1).unwrap().as_str());
-                let message = Message::Action {
-                    sender: self.transport_id,
-                    pipo_id,
-                    transport: TRANSPORT_NAME.to_string(),
-                    username: nickname.clone(),
-                    avatar_url: Some(avatar_url),
-                    thread: None,
-                    message: Some(message.to_string()),
-                    attachments: None,
-                    is_edit: false,
-                    irc_flag: false,
-                };
-                return match sender.send(message) {
-                    Ok(_) => Ok(()),
-                    Err(e) => Err(anyhow!("Couldn't send message: {:#}", e)),
-                };
-            } else {
-                let message = Message::Text {
-                    sender: self.transport_id,
-                    pipo_id,
-                    transport: TRANSPORT_NAME.to_string(),
-                    username: nickname.clone(),
-                    avatar_url: Some(avatar_url),
-                    thread: None,
-                    message: Some(format!("```{}```", message.to_string())),
-                    attachments: None,
-                    is_edit: false,
-                    irc_flag: false,
-                };
-                return match sender.send(message) {
-                    Ok(_) => Ok(()),
-                    Err(e) => Err(anyhow!("Couldn't send message: {:#}", e)),
-                };
+        let pipo_id = self.insert_into_messages_table().await?;
+        if let Some(irc_message_id) = irc_message_id {
+            self.update_messages_ircid(pipo_id, Some(irc_message_id))
+                .await?;
+        }
+
+        let avatar_url = self.avatars.url(&nickname).await;
+        let text = if rendering == NoticeRendering::Code {
+            format!("```{}```", text)
+        } else {
+            text
+        };
+        let message = if is_action || rendering == NoticeRendering::Italic {
+            Message::Action {
+                sender: self.transport_id,
+                pipo_id,
+                transport: TRANSPORT_NAME.to_string(),
+                username: nickname,
+                avatar_url,
+                thread: None,
+                reply_to: None,
+                message: Some(text),
+                attachments: None,
+                is_edit: false,
+                irc_flag: false,
+                late,
             }
         } else {
-            return Err(anyhow!("Could not get sender for channel {}", channel));
+            Message::Text {
+                sender: self.transport_id,
+                pipo_id,
+                transport: TRANSPORT_NAME.to_string(),
+                username: nickname,
+                avatar_url,
+                thread: None,
+                reply_to: None,
+                message: Some(text),
+                attachments: None,
+                is_edit: false,
+                irc_flag: false,
+                late,
+            }
+        };
+
+        match sender.send(message) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(anyhow!("Couldn't send message: {:#}", e)),
         }
     }
 }
@@ -1506,3 +4253,168 @@ impl Default for ThreadPresentation {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn channel_mapping_accepts_bus_names_and_settings() {
+        let mapping: HashMap<String, IrcChannelMapping> = serde_json::from_str(
+            r##"{
+                "#pipo": "main",
+                "#staff": {
+                    "bus": "staff",
+                    "key": "hunter2",
+                    "rejoin_on_kick": true,
+                    "thread_presentation_mode": "plaintext_only"
+                }
+            }"##,
+        )
+        .unwrap();
+
+        assert_eq!(mapping["#pipo"].bus().as_str(), "main");
+
+        let IrcChannelMapping::Channel(staff) = &mapping["#staff"] else {
+            panic!("expected channel settings");
+        };
+        let defaults = ThreadOptions {
+            presentation_mode: ThreadPresentationMode::Auto,
+            fallback_style: ThreadFallbackStyle::Compact,
+            context_repeat: ThreadContextRepeat::FirstSeen,
+            excerpt_len: DEFAULT_THREAD_EXCERPT_LEN,
+            show_root_marker: true,
+        };
+        let options = defaults.with_overrides(staff);
+
+        assert_eq!(staff.bus.as_str(), "staff");
+        assert_eq!(staff.key.as_deref(), Some("hunter2"));
+        assert_eq!(staff.rejoin_delay_secs, 10);
+        assert!(matches!(
+            options.presentation_mode,
+            ThreadPresentationMode::PlaintextOnly
+        ));
+        assert_eq!(options.fallback_style, ThreadFallbackStyle::Compact);
+    }
+
+    #[test]
+    fn channel_keys_use_rfc1459_casemapping() {
+        assert_eq!(IRC::channel_key("#Pipo[Dev]\\~"), "#pipo{dev}|^");
+        assert_eq!(IRC::channel_key("#pipo"), IRC::channel_key("#PIPO"));
+        assert_eq!(IRC::channel_key("Alice[m]"), IRC::channel_key("alice{M}"));
+    }
+
+    #[test]
+    fn split_server_takes_an_optional_port() {
+        assert_eq!(
+            IRC::split_server("irc.example.net:6697").unwrap(),
+            ("irc.example.net".to_string(), Some(6697))
+        );
+        assert_eq!(
+            IRC::split_server("irc.example.net").unwrap(),
+            ("irc.example.net".to_string(), None)
+        );
+        assert!(IRC::split_server("irc.example.net:tls").is_err());
+    }
+
+    #[test]
+    fn split_at_byte_limit_keeps_short_lines_whole() {
+        assert_eq!(
+            IRC::split_at_byte_limit("hello world", 20),
+            vec!["hello world"]
+        );
+        assert_eq!(IRC::split_at_byte_limit("", 20), vec![""]);
+    }
+
+    #[test]
+    fn split_at_byte_limit_breaks_on_word_boundaries() {
+        let chunks = IRC::split_at_byte_limit("the quick brown fox jumps", 10);
+
+        assert_eq!(chunks, vec!["the quick", "brown fox", "jumps"]);
+        assert!(chunks.iter().all(|chunk| chunk.len() <= 10));
+    }
+
+    #[test]
+    fn split_at_byte_limit_never_cuts_utf8_sequences() {
+        let line = "ééééé🙂🙂🙂";
+        let chunks = IRC::split_at_byte_limit(line, 5);
+
+        assert!(chunks.iter().all(|chunk| chunk.len() <= 5));
+        assert_eq!(chunks.concat(), line);
+    }
+
+    #[test]
+    fn split_at_byte_limit_sends_characters_wider_than_the_limit() {
+        for max_bytes in 1..=3 {
+            assert_eq!(IRC::split_at_byte_limit("🙂", max_bytes), vec!["🙂"]);
+            assert_eq!(
+                IRC::split_at_byte_limit("🙂🙂 a", max_bytes).concat(),
+                "🙂🙂a"
+            );
+        }
+    }
+
+    #[test]
+    fn split_at_byte_limit_hard_splits_long_words() {
+        let chunks = IRC::split_at_byte_limit("abcdefghij klm", 4);
+
+        assert_eq!(chunks, vec!["abcd", "efgh", "ij", "klm"]);
+    }
+
+    #[test]
+    fn unicode_emoji_converts_shortcodes() {
+        assert_eq!(IRC::unicode_emoji("thumbsup"), "👍");
+        assert_eq!(IRC::unicode_emoji(":thumbsup:"), "👍");
+        assert_eq!(IRC::unicode_emoji("👍"), "👍");
+        assert_eq!(IRC::unicode_emoji("partyparrot"), ":partyparrot:");
+    }
+
+    #[test]
+    fn cap_lines_appends_more_lines_marker() {
+        let lines = (1..=5).map(|n| n.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(IRC::cap_lines(lines.clone(), 5), lines);
+        assert_eq!(
+            IRC::cap_lines(lines.clone(), 3),
+            vec![
+                "1".to_string(),
+                "2".to_string(),
+                "(3 more lines)".to_string()
+            ]
+        );
+        assert_eq!(
+            IRC::cap_lines(lines, 1),
+            vec!["1 (4 more lines)".to_string()]
+        );
+    }
+
+    #[test]
+    fn thread_key_prefers_root_message_to_author() {
+        let thread_ref = ThreadRef {
+            root_pipo_id: Some(42),
+            root_author: Some("alice".to_string()),
+            ..ThreadRef::default()
+        };
+
+        assert_eq!(IRC::thread_key(&thread_ref), "pipo:42");
+        assert_eq!(
+            IRC::thread_key(&ThreadRef {
+                root_pipo_id: None,
+                ..thread_ref
+            }),
+            "alice"
+        );
+    }
+
+    #[test]
+    fn thread_token_salts_retries() {
+        let first = IRC::thread_token("1700000000.000100", 0);
+
+        assert_eq!(first, IRC::thread_token("1700000000.000100", 0));
+        assert_ne!(first, IRC::thread_token("1700000000.000100", 1));
+        assert!(first.len() <= 4);
+        assert!(first
+            .chars()
+            .all(|ch| ch.is_ascii_digit() || ch.is_ascii_uppercase()));
+    }
+}
@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+
+use irc::{
+    client::prelude::{Command, Response},
+    proto::{message::Tag, Message as IrcMessage},
+};
+
+use super::IRC;
+
+// Marks our WHOX queries, so that their replies can be told apart from
+// anyone else's WHO.
+const WHOX_TOKEN: &str = "152";
+// Messages kept per nick while its account is looked up.
+const MAX_PENDING: usize = 10;
+
+/// A message waiting for the account of the nick it's from or for.
+#[derive(Debug, PartialEq)]
+pub(super) enum Pending {
+    /// A private message the nick sent to the bot.
+    Received(String),
+    /// A DM from another transport to the nick.
+    Bridged {
+        sender: usize,
+        transport: String,
+        username: String,
+        message: String,
+    },
+}
+
+/// The services account the sender of `message` is logged in to, from the
+/// `account` tag of `account-tag`.
+pub(super) fn account_tag(message: &IrcMessage) -> Option<&str> {
+    message.tags.as_ref()?.iter().find_map(|Tag(key, value)| {
+        if key == "account" {
+            value.as_deref().filter(|account| !account.is_empty())
+        } else {
+            None
+        }
+    })
+}
+
+/// Looks up services accounts with WHOX. Private messages to the bot wait
+/// here until the sender's account is known on servers without
+/// `account-tag`, and bridged DMs until the recipient's is.
+pub(super) struct AccountLookup {
+    supports_whox: bool,
+    // Waiting messages, by nick key.
+    pending: HashMap<String, Vec<Pending>>,
+}
+
+impl AccountLookup {
+    pub fn new() -> AccountLookup {
+        AccountLookup {
+            supports_whox: false,
+            pending: HashMap::new(),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.supports_whox = false;
+        self.pending.clear();
+    }
+
+    pub fn supports_whox(&self) -> bool {
+        self.supports_whox
+    }
+
+    pub fn update_from_isupport(&mut self, message: &IrcMessage) {
+        let Command::Response(Response::RPL_ISUPPORT, args) = &message.command else {
+            return;
+        };
+
+        if args.iter().skip(1).any(|token| token == "WHOX") {
+            self.supports_whox = true;
+        }
+    }
+
+    /// Holds `message` until the account of `nickname` is known. Returns
+    /// the WHO query to send when none is in flight for the nick yet.
+    pub fn queue(&mut self, nickname: &str, message: Pending) -> Option<IrcMessage> {
+        let pending = self.pending.entry(IRC::channel_key(nickname)).or_default();
+        let first = pending.is_empty();
+
+        if pending.len() < MAX_PENDING {
+            pending.push(message);
+        }
+
+        first.then(|| {
+            IrcMessage::from(Command::Raw(
+                "WHO".to_string(),
+                vec![nickname.to_string(), format!("%tna,{}", WHOX_TOKEN)],
+            ))
+        })
+    }
+
+    /// Handles the reply to one of our queries: the nick, the account it's
+    /// logged in to, and the messages that waited for it. A nick that ends
+    /// its WHO list without an account isn't logged in, or has left.
+    pub fn handle_reply(
+        &mut self,
+        message: &IrcMessage,
+    ) -> Option<(String, Option<String>, Vec<Pending>)> {
+        let (nickname, account) = match &message.command {
+            Command::Raw(command, args) if command == "354" => match args.as_slice() {
+                [_, token, nickname, account, ..] if token == WHOX_TOKEN => {
+                    (nickname, Some(account).filter(|account| *account != "0"))
+                }
+                _ => return None,
+            },
+            Command::Response(Response::RPL_ENDOFWHO, args) => (args.get(1)?, None),
+            _ => return None,
+        };
+        let messages = self.pending.remove(&IRC::channel_key(nickname))?;
+
+        Some((nickname.clone(), account.cloned(), messages))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(line: &str) -> IrcMessage {
+        line.parse().expect("valid IRC line")
+    }
+
+    #[test]
+    fn account_tag_ignores_missing_accounts() {
+        assert_eq!(
+            account_tag(&message("@account=alice :alice!a@host PRIVMSG pipo :hi")),
+            Some("alice")
+        );
+        assert_eq!(
+            account_tag(&message(":alice!a@host PRIVMSG pipo :hi")),
+            None
+        );
+    }
+
+    #[test]
+    fn whox_replies_release_waiting_messages() {
+        let mut lookup = AccountLookup::new();
+
+        lookup.update_from_isupport(&message(
+            ":irc.example.net 005 pipo WHOX CHANTYPES=# :are supported by this server",
+        ));
+        assert!(lookup.supports_whox());
+
+        let Some(query) = lookup.queue("Alice", Pending::Received("hi".to_string())) else {
+            panic!("expected a WHO query");
+        };
+        assert_eq!(query.to_string(), "WHO Alice %tna,152\r\n");
+        assert!(lookup
+            .queue("Alice", Pending::Received("there".to_string()))
+            .is_none());
+        assert!(lookup
+            .queue("bob", Pending::Received("hey".to_string()))
+            .is_some());
+
+        assert_eq!(
+            lookup.handle_reply(&message(":irc.example.net 354 pipo 152 Alice alice")),
+            Some((
+                "Alice".to_string(),
+                Some("alice".to_string()),
+                vec![
+                    Pending::Received("hi".to_string()),
+                    Pending::Received("there".to_string())
+                ]
+            ))
+        );
+        assert_eq!(
+            lookup.handle_reply(&message(":irc.example.net 315 pipo Alice :End of WHO list")),
+            None
+        );
+        assert_eq!(
+            lookup.handle_reply(&message(":irc.example.net 354 pipo 152 bob 0")),
+            Some((
+                "bob".to_string(),
+                None,
+                vec![Pending::Received("hey".to_string())]
+            ))
+        );
+    }
+
+    #[test]
+    fn bridged_dms_wait_for_the_recipients_account() {
+        let mut lookup = AccountLookup::new();
+        let dm = || Pending::Bridged {
+            sender: 1,
+            transport: "Slack".to_string(),
+            username: "carol".to_string(),
+            message: "hello".to_string(),
+        };
+
+        assert!(lookup.queue("dave", dm()).is_some());
+        assert_eq!(
+            lookup.handle_reply(&message(":irc.example.net 315 pipo dave :End of WHO list")),
+            Some(("dave".to_string(), None, vec![dm()]))
+        );
+    }
+}
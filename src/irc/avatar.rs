@@ -0,0 +1,167 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use reqwest::{header, StatusCode};
+
+use super::IRC;
+use crate::media::MediaStore;
+
+struct CachedAvatar {
+    url: Option<String>,
+    etag: Option<String>,
+    fetched: Instant,
+}
+
+/// Avatar URLs of IRC users, looked up at most once per `ttl` for each
+/// nick. Avatars uploaded to the media store come first, then `img_root`
+/// is asked for `<nick>.png`, revalidating with the ETag it gave us last
+/// time. Nicks without either get an identicon from the built-in HTTP
+/// server, or `irc.png` when that isn't running.
+pub(super) struct AvatarCache {
+    img_root: Option<String>,
+    media: Option<Arc<MediaStore>>,
+    ttl: Duration,
+    client: reqwest::Client,
+    // By nick key.
+    avatars: Mutex<HashMap<String, CachedAvatar>>,
+}
+
+impl AvatarCache {
+    pub fn new(
+        img_root: Option<&str>,
+        media: Option<Arc<MediaStore>>,
+        ttl: Duration,
+    ) -> AvatarCache {
+        AvatarCache {
+            img_root: img_root.map(|img_root| img_root.trim_end_matches('/').to_string()),
+            media,
+            ttl,
+            client: reqwest::Client::new(),
+            avatars: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn url(&self, nickname: &str) -> Option<String> {
+        let key = IRC::channel_key(nickname);
+        let cached = match self.avatars.lock().unwrap().get(&key) {
+            Some(avatar) if avatar.fetched.elapsed() < self.ttl => return avatar.url.clone(),
+            Some(avatar) => Some((avatar.url.clone(), avatar.etag.clone())),
+            None => None,
+        };
+        let uploaded = match self.media.as_ref() {
+            Some(media) => media.avatar_url(nickname).await,
+            None => None,
+        };
+        let (url, etag) = match (uploaded, self.img_root.as_ref()) {
+            (Some(uploaded), _) => (Some(uploaded), None),
+            (None, Some(img_root)) => {
+                match self.look_up(img_root, nickname, cached.clone()).await {
+                    Some(found) => found,
+                    // Not cached, so that the next message tries again.
+                    None => {
+                        return cached
+                            .and_then(|(url, _)| url)
+                            .or_else(|| self.fallback(nickname))
+                    }
+                }
+            }
+            (None, None) => (self.fallback(nickname), None),
+        };
+
+        self.avatars.lock().unwrap().insert(
+            key,
+            CachedAvatar {
+                url: url.clone(),
+                etag,
+                fetched: Instant::now(),
+            },
+        );
+
+        url
+    }
+
+    /// Asks `img_root` for the avatar of `nickname`. Returns `None` when
+    /// that failed, in which case nothing should be cached.
+    async fn look_up(
+        &self,
+        img_root: &str,
+        nickname: &str,
+        cached: Option<(Option<String>, Option<String>)>,
+    ) -> Option<(Option<String>, Option<String>)> {
+        let url = format!("{}/{}.png", img_root, nickname);
+        let mut request = self.client.head(&url);
+
+        if let Some(etag) = cached.as_ref().and_then(|(_, etag)| etag.as_ref()) {
+            request = request.header(header::IF_NONE_MATCH, etag);
+        }
+
+        let response = match request.send().await {
+            Ok(response) => response,
+            Err(e) => {
+                eprintln!("Failed to look up avatar of {}: {:#}", nickname, e);
+
+                return None;
+            }
+        };
+
+        Some(match (response.status(), cached) {
+            (StatusCode::NOT_MODIFIED, Some(cached)) => cached,
+            (status, _) if status.is_success() => {
+                let etag = response
+                    .headers()
+                    .get(header::ETAG)
+                    .and_then(|etag| etag.to_str().ok())
+                    .map(str::to_string);
+
+                match etag {
+                    Some(etag) => (Some(format!("{}?{}", url, etag)), Some(etag)),
+                    None => (Some(url), None),
+                }
+            }
+            _ => (self.fallback(nickname), None),
+        })
+    }
+
+    /// Forgets the avatar of `nickname`, e.g. once someone else may be
+    /// using it.
+    pub fn invalidate(&self, nickname: &str) {
+        self.avatars
+            .lock()
+            .unwrap()
+            .remove(&IRC::channel_key(nickname));
+    }
+
+    fn fallback(&self, nickname: &str) -> Option<String> {
+        match (self.media.as_ref(), self.img_root.as_ref()) {
+            (Some(media), _) => Some(media.identicon_url(nickname)),
+            (None, Some(img_root)) => Some(format!("{}/irc.png", img_root)),
+            (None, None) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fallback_without_media_store() {
+        let avatars = AvatarCache::new(
+            Some("https://img.example.net/"),
+            None,
+            Duration::from_secs(60),
+        );
+
+        assert_eq!(
+            avatars.fallback("alice").as_deref(),
+            Some("https://img.example.net/irc.png")
+        );
+        assert_eq!(
+            AvatarCache::new(None, None, Duration::from_secs(60)).fallback("alice"),
+            None
+        );
+    }
+}
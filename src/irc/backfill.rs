@@ -0,0 +1,247 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use irc::{
+    client::prelude::{Command, Response},
+    proto::{command::BatchSubCommand, message::Tag, Message as IrcMessage},
+};
+
+/// Follows `draft/chathistory` requests made after (re)joining a channel,
+/// and which of the server's `BATCH`es carry their replies, so that the
+/// messages inside can be told apart from live traffic.
+pub(super) struct HistoryBackfill {
+    limit: usize,
+    // Most messages the server hands out per request, from ISUPPORT
+    // CHATHISTORY=<n>, where zero means no limit.
+    server_limit: Option<usize>,
+    // Open chathistory batches, by reference tag, and their channel.
+    batches: HashMap<String, String>,
+}
+
+impl HistoryBackfill {
+    pub fn new(limit: usize) -> HistoryBackfill {
+        HistoryBackfill {
+            limit,
+            server_limit: None,
+            batches: HashMap::new(),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.server_limit = None;
+        self.batches.clear();
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.limit > 0
+    }
+
+    pub fn update_from_isupport(&mut self, message: &IrcMessage) {
+        let Command::Response(Response::RPL_ISUPPORT, args) = &message.command else {
+            return;
+        };
+
+        for token in args.iter().skip(1) {
+            if let Some(("CHATHISTORY", value)) = token.split_once('=') {
+                self.server_limit = value.parse().ok();
+            }
+        }
+    }
+
+    /// The `CHATHISTORY AFTER` request for everything in `channel` since
+    /// `msgid`, capped by both our and the server's limit.
+    pub fn request(&self, channel: &str, msgid: &str) -> IrcMessage {
+        let limit = match self.server_limit {
+            Some(server_limit) if server_limit > 0 => self.limit.min(server_limit),
+            _ => self.limit,
+        };
+
+        IrcMessage::from(Command::Raw(
+            "CHATHISTORY".to_string(),
+            vec![
+                "AFTER".to_string(),
+                channel.to_string(),
+                format!("msgid={}", msgid),
+                limit.to_string(),
+            ],
+        ))
+    }
+
+    /// Keeps track of chathistory batches opening and closing. Returns true
+    /// for `BATCH` lines, which need no further handling.
+    pub fn handle_batch(&mut self, message: &IrcMessage) -> bool {
+        let Command::BATCH(reference, kind, params) = &message.command else {
+            return false;
+        };
+
+        if let Some(reference) = reference.strip_prefix('+') {
+            let is_chathistory = matches!(
+                kind,
+                Some(BatchSubCommand::CUSTOM(kind)) if kind.eq_ignore_ascii_case("chathistory")
+            );
+
+            if let (true, Some(channel)) = (is_chathistory, params.as_ref().and_then(|p| p.first()))
+            {
+                self.batches.insert(reference.to_string(), channel.clone());
+            }
+        } else if let Some(reference) = reference.strip_prefix('-') {
+            self.batches.remove(reference);
+        }
+
+        true
+    }
+
+    /// The channel whose history `message` was sent as part of, if any.
+    pub fn batch_channel(&self, message: &IrcMessage) -> Option<&str> {
+        let reference = message.tags.as_ref()?.iter().find_map(|Tag(key, value)| {
+            if key == "batch" {
+                value.as_deref()
+            } else {
+                None
+            }
+        })?;
+
+        self.batches.get(reference).map(String::as_str)
+    }
+}
+
+/// How long the newest msgid of a channel may wait before it is written to
+/// the database.
+const CURSOR_FLUSH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The newest msgid seen in each channel since the cursors were last
+/// written, so that busy channels don't cost a database write per message.
+pub(super) struct PendingCursors {
+    // By channel, the msgid and its server time.
+    cursors: HashMap<String, (String, DateTime<Utc>)>,
+    // When the oldest unwritten cursor came in.
+    since: Option<Instant>,
+}
+
+impl PendingCursors {
+    pub fn new() -> PendingCursors {
+        PendingCursors {
+            cursors: HashMap::new(),
+            since: None,
+        }
+    }
+
+    /// Notes `msgid` as the newest in `channel`, unless a newer one is
+    /// already waiting.
+    pub fn advance(&mut self, channel: &str, msgid: &str, time: DateTime<Utc>, now: Instant) {
+        match self.cursors.get(channel) {
+            Some((_, newest)) if *newest > time => (),
+            _ => {
+                self.cursors
+                    .insert(channel.to_string(), (msgid.to_string(), time));
+            }
+        }
+
+        self.since.get_or_insert(now);
+    }
+
+    /// The waiting msgid of `channel`, which is newer than the stored one.
+    pub fn get(&self, channel: &str) -> Option<&str> {
+        self.cursors.get(channel).map(|(msgid, _)| msgid.as_str())
+    }
+
+    /// When the waiting cursors are due to be written, if any wait.
+    pub fn next_flush(&self) -> Option<Instant> {
+        self.since.map(|since| since + CURSOR_FLUSH_INTERVAL)
+    }
+
+    pub fn take(&mut self) -> Vec<(String, String, DateTime<Utc>)> {
+        self.since = None;
+        self.cursors
+            .drain()
+            .map(|(channel, (msgid, time))| (channel, msgid, time))
+            .collect()
+    }
+}
+
+/// The `server-time` of `message`, if the server sent one.
+pub(super) fn server_time(message: &IrcMessage) -> Option<DateTime<Utc>> {
+    let time = message.tags.as_ref()?.iter().find_map(|Tag(key, value)| {
+        if key == "time" {
+            value.as_deref()
+        } else {
+            None
+        }
+    })?;
+
+    DateTime::parse_from_rfc3339(time)
+        .ok()
+        .map(|time| time.with_timezone(&Utc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(line: &str) -> IrcMessage {
+        line.parse().expect("valid IRC line")
+    }
+
+    #[test]
+    fn messages_are_matched_to_open_chathistory_batches() {
+        let mut backfill = HistoryBackfill::new(100);
+
+        assert!(backfill.handle_batch(&message(":irc.example.net BATCH +h1 chathistory #pipo")));
+        assert!(backfill.handle_batch(&message(":irc.example.net BATCH +n1 netsplit a b")));
+
+        let history = message("@batch=h1;msgid=abc :alice!a@host PRIVMSG #pipo :hi");
+        let netsplit = message("@batch=n1 :alice!a@host QUIT :a b");
+
+        assert_eq!(backfill.batch_channel(&history), Some("#pipo"));
+        assert_eq!(backfill.batch_channel(&netsplit), None);
+        assert_eq!(
+            backfill.batch_channel(&message(":alice!a@host PRIVMSG #pipo :live")),
+            None
+        );
+
+        backfill.handle_batch(&message(":irc.example.net BATCH -h1"));
+
+        assert_eq!(backfill.batch_channel(&history), None);
+    }
+
+    #[test]
+    fn request_respects_server_limit() {
+        let mut backfill = HistoryBackfill::new(100);
+
+        backfill.update_from_isupport(&message(
+            ":irc.example.net 005 pipo CHATHISTORY=50 MSGREFTYPES=msgid :are supported by this server",
+        ));
+
+        let Command::Raw(command, args) = backfill.request("#pipo", "abc").command else {
+            panic!("expected a raw CHATHISTORY command");
+        };
+
+        assert_eq!(command, "CHATHISTORY");
+        assert_eq!(args, vec!["AFTER", "#pipo", "msgid=abc", "50"]);
+    }
+
+    #[test]
+    fn pending_cursors_keep_the_newest_msgid() {
+        let mut cursors = PendingCursors::new();
+        let now = Instant::now();
+        let time = |line| server_time(&message(line)).expect("server time");
+        let earlier = time("@time=2026-10-18T09:05:00.000Z :a!a@host PRIVMSG #pipo :hi");
+        let later = time("@time=2026-10-18T09:06:00.000Z :a!a@host PRIVMSG #pipo :hi");
+
+        assert_eq!(cursors.next_flush(), None);
+
+        cursors.advance("#pipo", "b", later, now);
+        cursors.advance("#pipo", "a", earlier, now + Duration::from_secs(1));
+
+        assert_eq!(cursors.get("#pipo"), Some("b"));
+        assert_eq!(cursors.next_flush(), Some(now + CURSOR_FLUSH_INTERVAL));
+        assert_eq!(
+            cursors.take(),
+            vec![("#pipo".to_string(), "b".to_string(), later)]
+        );
+        assert_eq!(cursors.next_flush(), None);
+    }
+}
@@ -0,0 +1,178 @@
+use irc::client::prelude::Prefix;
+use serde::Deserialize;
+
+use super::IRC;
+
+/// How a NOTICE is relayed to the bus.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum NoticeRendering {
+    Drop,
+    /// A message from the sender wrapped in a code block.
+    Code,
+    /// A message from the sender, shown like an action.
+    Italic,
+    /// A line from the bridge itself, like joins and parts.
+    System,
+}
+
+/// Per-kind rendering of NOTICEs, see `NoticeKind`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub(crate) struct IrcNoticeConfig {
+    pub(crate) server: NoticeRendering,
+    pub(crate) services: NoticeRendering,
+    pub(crate) direct: NoticeRendering,
+    pub(crate) channel: NoticeRendering,
+    /// Mapped channel whose bus receives notices that weren't sent to a
+    /// channel. Without one, those are dropped.
+    pub(crate) private_channel: Option<String>,
+    /// Nicks treated as network services, compared case-insensitively.
+    pub(crate) services_nicks: Vec<String>,
+    /// Hosts services send from. Without any, a services nick must come
+    /// from a `services.` host, the server's own name or its domain.
+    pub(crate) services_hosts: Vec<String>,
+}
+
+impl Default for IrcNoticeConfig {
+    fn default() -> IrcNoticeConfig {
+        IrcNoticeConfig {
+            server: NoticeRendering::Drop,
+            services: NoticeRendering::Drop,
+            direct: NoticeRendering::Drop,
+            channel: NoticeRendering::Italic,
+            private_channel: None,
+            services_nicks: [
+                "NickServ", "ChanServ", "MemoServ", "OperServ", "HostServ", "BotServ", "SaslServ",
+                "Global",
+            ]
+            .iter()
+            .map(|nick| nick.to_string())
+            .collect(),
+            services_hosts: Vec::new(),
+        }
+    }
+}
+
+impl IrcNoticeConfig {
+    pub fn rendering(&self, kind: NoticeKind) -> NoticeRendering {
+        match kind {
+            NoticeKind::Server => self.server,
+            NoticeKind::Services => self.services,
+            NoticeKind::Direct => self.direct,
+            NoticeKind::Channel => self.channel,
+        }
+    }
+
+    /// Tells apart who sent a NOTICE to `target`. Server and services
+    /// notices are recognised by their sender, wherever they were sent;
+    /// everything else by whether it was sent to us or to a channel.
+    /// `server_name` is the name of the server we're connected to.
+    pub fn classify(
+        &self,
+        prefix: Option<&Prefix>,
+        target: &str,
+        current_nickname: &str,
+        server_name: Option<&str>,
+    ) -> NoticeKind {
+        let (nickname, host) = match prefix {
+            Some(Prefix::Nickname(nickname, _, host)) if !nickname.is_empty() => (nickname, host),
+            _ => return NoticeKind::Server,
+        };
+
+        if self
+            .services_nicks
+            .iter()
+            .any(|services| services.eq_ignore_ascii_case(nickname))
+            && self.is_services_host(host, server_name)
+        {
+            NoticeKind::Services
+        } else if IRC::channel_key(target) == IRC::channel_key(current_nickname) {
+            NoticeKind::Direct
+        } else {
+            NoticeKind::Channel
+        }
+    }
+
+    /// Whether `host` belongs to services rather than to a user who took
+    /// a services nick, e.g. while services are down.
+    fn is_services_host(&self, host: &str, server_name: Option<&str>) -> bool {
+        if !self.services_hosts.is_empty() {
+            return self
+                .services_hosts
+                .iter()
+                .any(|services| services.eq_ignore_ascii_case(host));
+        }
+
+        let host = host.to_ascii_lowercase();
+
+        host.starts_with("services.")
+            || server_name.is_some_and(|server| {
+                let server = server.to_ascii_lowercase();
+                let domain = server
+                    .split_once('.')
+                    .map_or(server.as_str(), |(_, domain)| domain);
+
+                host == server || host == domain
+            })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum NoticeKind {
+    /// From the server itself, e.g. connection notices.
+    Server,
+    /// From a services bot such as NickServ or ChanServ.
+    Services,
+    /// From a user, sent to us rather than to a channel.
+    Direct,
+    /// From a user, sent to a channel.
+    Channel,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn notices_are_classified_by_sender_and_target() {
+        let config = IrcNoticeConfig::default();
+        let user = Prefix::Nickname("alice".into(), "alice".into(), "example.net".into());
+        let services = Prefix::Nickname("ChanServ".into(), "services".into(), "services.".into());
+        let server = Prefix::ServerName("irc.example.net".into());
+        let classify = |prefix, target| config.classify(prefix, target, "pipo", None);
+
+        assert_eq!(classify(Some(&server), "*"), NoticeKind::Server);
+        assert_eq!(classify(None, "pipo"), NoticeKind::Server);
+        assert_eq!(classify(Some(&services), "#pipo"), NoticeKind::Services);
+        assert_eq!(classify(Some(&user), "PIPO"), NoticeKind::Direct);
+        assert_eq!(classify(Some(&user), "#pipo"), NoticeKind::Channel);
+    }
+
+    #[test]
+    fn services_nicks_must_come_from_a_services_host() {
+        let mut config = IrcNoticeConfig::default();
+        let network = Prefix::Nickname("NickServ".into(), "service".into(), "Rizon.net".into());
+        let impostor = Prefix::Nickname("Global".into(), "mallory".into(), "user/mallory".into());
+
+        assert_eq!(
+            config.classify(Some(&network), "pipo", "pipo", Some("irc.rizon.net")),
+            NoticeKind::Services
+        );
+        assert_eq!(
+            config.classify(Some(&impostor), "pipo", "pipo", Some("irc.rizon.net")),
+            NoticeKind::Direct
+        );
+
+        config.services_hosts = vec!["user/mallory".to_string()];
+
+        assert_eq!(
+            config.classify(Some(&network), "pipo", "pipo", Some("irc.rizon.net")),
+            NoticeKind::Direct
+        );
+        assert_eq!(
+            config.classify(Some(&impostor), "#pipo", "pipo", None),
+            NoticeKind::Services
+        );
+    }
+}
@@ -0,0 +1,410 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+use irc::{
+    client::prelude::{Command, Response},
+    proto::Message as IrcMessage,
+};
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use super::{PresenceRelay, IRC};
+
+// How long netsplit quits (and the joins once it heals) are collected
+// before they are reported.
+const NETSPLIT_BATCH_WINDOW: Duration = Duration::from_secs(5);
+// Joins this long after a netsplit quit are no longer treated as the split
+// healing.
+const NETJOIN_WINDOW: Duration = Duration::from_secs(60 * 15);
+const NETSPLIT_NICK_LIMIT: usize = 20;
+const NICK_PREFIXES: [char; 5] = ['~', '&', '@', '%', '+'];
+
+struct Netsplit {
+    servers: String,
+    started: Instant,
+    quits: BTreeMap<String, Vec<String>>,
+}
+
+struct Netjoin {
+    started: Instant,
+    joins: BTreeMap<String, Vec<String>>,
+}
+
+/// Follows channel membership, since the irc crate forgets a user before
+/// we get to see their QUIT, and turns joins, parts, quits, kicks and nick
+/// changes into `(channel, line)` pairs for the bus. Netsplit quits, and
+/// the joins when the split heals, are collected for
+/// `NETSPLIT_BATCH_WINDOW` and reported as one line per channel.
+pub(super) struct PresenceTracker {
+    relay: PresenceRelay,
+    // Keys of the nicks in each channel, by channel key.
+    members: HashMap<String, HashSet<String>>,
+    splits: Vec<Netsplit>,
+    split_nicks: HashMap<String, Instant>,
+    netjoin: Option<Netjoin>,
+}
+
+impl PresenceTracker {
+    pub fn new(relay: PresenceRelay) -> PresenceTracker {
+        PresenceTracker {
+            relay,
+            members: HashMap::new(),
+            splits: Vec::new(),
+            split_nicks: HashMap::new(),
+            netjoin: None,
+        }
+    }
+
+    pub fn handle(
+        &mut self,
+        message: &IrcMessage,
+        own_nick: &str,
+        now: Instant,
+    ) -> Vec<(String, String)> {
+        let nick = message.source_nickname().unwrap_or("");
+
+        match &message.command {
+            Command::Response(Response::RPL_NAMREPLY, args) => {
+                if let (Some(channel), Some(names)) = (args.get(2), args.get(3)) {
+                    self.members
+                        .entry(IRC::channel_key(channel))
+                        .or_default()
+                        .extend(
+                            names.split_whitespace().map(|name| {
+                                IRC::channel_key(name.trim_start_matches(NICK_PREFIXES))
+                            }),
+                        );
+                }
+
+                Vec::new()
+            }
+            Command::JOIN(channel, _, _) => {
+                if nick == own_nick {
+                    self.members
+                        .insert(IRC::channel_key(channel), HashSet::new());
+
+                    return Vec::new();
+                }
+
+                self.members
+                    .entry(IRC::channel_key(channel))
+                    .or_default()
+                    .insert(IRC::channel_key(nick));
+
+                let healed_split = self
+                    .split_nicks
+                    .get(&IRC::channel_key(nick))
+                    .is_some_and(|quit| now.duration_since(*quit) < NETJOIN_WINDOW);
+
+                if healed_split {
+                    self.netjoin
+                        .get_or_insert_with(|| Netjoin {
+                            started: now,
+                            joins: BTreeMap::new(),
+                        })
+                        .joins
+                        .entry(channel.clone())
+                        .or_default()
+                        .push(nick.to_string());
+
+                    return Vec::new();
+                }
+
+                self.line(true, channel, format!("{} joined", nick))
+            }
+            Command::PART(channel, reason) => {
+                if nick == own_nick {
+                    self.members.remove(&IRC::channel_key(channel));
+
+                    return Vec::new();
+                }
+
+                self.remove_member(channel, nick);
+                self.line(
+                    false,
+                    channel,
+                    format!("{} left{}", nick, PresenceTracker::reason(reason)),
+                )
+            }
+            Command::KICK(channel, target, reason) => {
+                if target == own_nick {
+                    self.members.remove(&IRC::channel_key(channel));
+
+                    return Vec::new();
+                }
+
+                self.remove_member(channel, target);
+                self.line(
+                    false,
+                    channel,
+                    format!(
+                        "{} was kicked by {}{}",
+                        target,
+                        nick,
+                        PresenceTracker::reason(reason)
+                    ),
+                )
+            }
+            Command::QUIT(reason) => {
+                let channels = self.channels_of(nick);
+
+                for channel in channels.iter() {
+                    self.remove_member(channel, nick);
+                }
+
+                if let Some(servers) = reason
+                    .as_deref()
+                    .filter(|r| PresenceTracker::is_netsplit(r))
+                {
+                    self.split_nicks.insert(IRC::channel_key(nick), now);
+
+                    let split = match self.splits.iter().position(|s| s.servers == servers) {
+                        Some(index) => &mut self.splits[index],
+                        None => {
+                            self.splits.push(Netsplit {
+                                servers: servers.to_string(),
+                                started: now,
+                                quits: BTreeMap::new(),
+                            });
+                            self.splits.last_mut().unwrap()
+                        }
+                    };
+
+                    for channel in channels {
+                        split
+                            .quits
+                            .entry(channel)
+                            .or_default()
+                            .push(nick.to_string());
+                    }
+
+                    return Vec::new();
+                }
+
+                let line = format!("{} quit{}", nick, PresenceTracker::reason(reason));
+
+                channels
+                    .iter()
+                    .flat_map(|channel| self.line(false, channel, line.clone()))
+                    .collect()
+            }
+            Command::NICK(new_nick) => {
+                let channels = self.channels_of(nick);
+
+                for channel in channels.iter() {
+                    self.remove_member(channel, nick);
+                    self.members
+                        .entry(channel.clone())
+                        .or_default()
+                        .insert(IRC::channel_key(new_nick));
+                }
+
+                if nick == own_nick || new_nick == own_nick {
+                    return Vec::new();
+                }
+
+                let line = format!("{} is now known as {}", nick, new_nick);
+
+                channels
+                    .iter()
+                    .flat_map(|channel| self.line(false, channel, line.clone()))
+                    .collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// When the next batched netsplit line is due, if any.
+    pub fn next_flush(&self) -> Option<Instant> {
+        self.splits
+            .iter()
+            .map(|split| split.started)
+            .chain(self.netjoin.as_ref().map(|netjoin| netjoin.started))
+            .min()
+            .map(|started| started + NETSPLIT_BATCH_WINDOW)
+    }
+
+    /// Reports the netsplits and netjoins whose batching window is over.
+    pub fn flush(&mut self, now: Instant) -> Vec<(String, String)> {
+        let mut lines = Vec::new();
+        let (due, pending) = self
+            .splits
+            .drain(..)
+            .partition::<Vec<_>, _>(|split| now >= split.started + NETSPLIT_BATCH_WINDOW);
+
+        self.splits = pending;
+
+        for split in due {
+            for (channel, nicks) in split.quits {
+                lines.extend(self.line(
+                    false,
+                    &channel,
+                    format!(
+                        "Netsplit ({}): {} quit",
+                        split.servers,
+                        PresenceTracker::nick_list(&nicks)
+                    ),
+                ));
+            }
+        }
+
+        if self
+            .netjoin
+            .as_ref()
+            .is_some_and(|netjoin| now >= netjoin.started + NETSPLIT_BATCH_WINDOW)
+        {
+            for (channel, nicks) in self.netjoin.take().unwrap().joins {
+                lines.extend(self.line(
+                    false,
+                    &channel,
+                    format!(
+                        "Netsplit over: {} rejoined",
+                        PresenceTracker::nick_list(&nicks)
+                    ),
+                ));
+            }
+        }
+
+        self.split_nicks
+            .retain(|_, quit| now.duration_since(*quit) < NETJOIN_WINDOW);
+
+        lines
+    }
+
+    fn line(&self, is_join: bool, channel: &str, line: String) -> Vec<(String, String)> {
+        let relayed = match self.relay {
+            PresenceRelay::None => false,
+            PresenceRelay::Joins => is_join,
+            PresenceRelay::All => true,
+        };
+
+        if relayed {
+            vec![(channel.to_string(), line)]
+        } else {
+            Vec::new()
+        }
+    }
+
+    fn channels_of(&self, nick: &str) -> Vec<String> {
+        let mut channels = self
+            .members
+            .iter()
+            .filter(|(_, members)| members.contains(&IRC::channel_key(nick)))
+            .map(|(channel, _)| channel.clone())
+            .collect::<Vec<_>>();
+
+        channels.sort();
+        channels
+    }
+
+    fn remove_member(&mut self, channel: &str, nick: &str) {
+        if let Some(members) = self.members.get_mut(&IRC::channel_key(channel)) {
+            members.remove(&IRC::channel_key(nick));
+        }
+    }
+
+    fn reason(reason: &Option<String>) -> String {
+        match reason.as_deref().map(str::trim) {
+            Some(reason) if !reason.is_empty() => format!(" ({})", reason),
+            _ => String::new(),
+        }
+    }
+
+    fn nick_list(nicks: &[String]) -> String {
+        if nicks.len() <= NETSPLIT_NICK_LIMIT {
+            return nicks.join(", ");
+        }
+
+        format!(
+            "{} and {} more",
+            nicks[..NETSPLIT_NICK_LIMIT].join(", "),
+            nicks.len() - NETSPLIT_NICK_LIMIT
+        )
+    }
+
+    /// Servers announce a netsplit as a QUIT whose reason is the names of
+    /// the two servers that lost each other, e.g. "hub.example.net
+    /// leaf.example.net". Users can't send a reason that looks like that.
+    fn is_netsplit(reason: &str) -> bool {
+        lazy_static! {
+            static ref RE: Regex =
+                Regex::new(r"^[A-Za-z0-9-]+(\.[A-Za-z0-9-]+)+ [A-Za-z0-9-]+(\.[A-Za-z0-9-]+)+$")
+                    .unwrap();
+        }
+
+        RE.is_match(reason)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(line: &str) -> IrcMessage {
+        line.parse().expect("valid IRC line")
+    }
+
+    #[test]
+    fn joins_mode_only_relays_joins() {
+        let mut tracker = PresenceTracker::new(PresenceRelay::Joins);
+        let now = Instant::now();
+
+        tracker.handle(
+            &message(":irc.example.net 353 pipo = #pipo :pipo @alice +bob"),
+            "pipo",
+            now,
+        );
+
+        assert_eq!(
+            tracker.handle(&message(":carol!c@host JOIN #pipo"), "pipo", now),
+            vec![("#pipo".to_string(), "carol joined".to_string())]
+        );
+        assert!(tracker
+            .handle(&message(":alice!a@host QUIT :bye"), "pipo", now)
+            .is_empty());
+    }
+
+    #[test]
+    fn netsplits_are_batched_per_channel() {
+        let mut tracker = PresenceTracker::new(PresenceRelay::All);
+        let now = Instant::now();
+
+        tracker.handle(
+            &message(":irc.example.net 353 pipo = #Pipo :pipo alice bob carol"),
+            "pipo",
+            now,
+        );
+        tracker.handle(&message(":carol!c@host NICK dave"), "pipo", now);
+
+        for nick in ["alice", "bob"] {
+            let quit = format!(":{}!u@host QUIT :hub.example.net leaf.example.net", nick);
+
+            assert!(tracker.handle(&message(&quit), "pipo", now).is_empty());
+        }
+
+        assert_eq!(
+            tracker.handle(&message(":dave!c@host QUIT :Leaving"), "pipo", now),
+            vec![("#pipo".to_string(), "dave quit (Leaving)".to_string())]
+        );
+        assert!(tracker.flush(now).is_empty());
+        assert_eq!(
+            tracker.flush(tracker.next_flush().expect("pending netsplit")),
+            vec![(
+                "#pipo".to_string(),
+                "Netsplit (hub.example.net leaf.example.net): alice, bob quit".to_string()
+            )]
+        );
+        assert!(tracker
+            .handle(&message(":alice!u@host JOIN #pipo"), "pipo", now)
+            .is_empty());
+        assert_eq!(
+            tracker.flush(now + NETSPLIT_BATCH_WINDOW),
+            vec![(
+                "#pipo".to_string(),
+                "Netsplit over: alice rejoined".to_string()
+            )]
+        );
+    }
+}
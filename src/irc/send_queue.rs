@@ -0,0 +1,227 @@
+use std::{
+    collections::VecDeque,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use anyhow::anyhow;
+use irc::{client::Sender, proto::Message as IrcMessage};
+use tokio::{
+    sync::mpsc,
+    time::{self, Duration, Instant},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum Priority {
+    /// Replies to users and other protocol chatter that shouldn't wait
+    /// behind a backlog of bridged lines.
+    Control,
+    Normal,
+}
+
+/// Identifies the lines of one bridged edit. Queuing a newer edit of the
+/// same message drops the lines of older edits that haven't gone out yet.
+#[derive(Clone, Debug)]
+pub(super) struct EditGroup {
+    channel: String,
+    pipo_id: i64,
+    generation: u64,
+}
+
+#[derive(Debug)]
+struct QueuedMessage {
+    message: IrcMessage,
+    priority: Priority,
+    edit: Option<EditGroup>,
+}
+
+/// Token-bucket rate limiter in front of an IRC connection. Up to `burst`
+/// lines go out back to back, after which one line is sent per
+/// `interval`. Messages the irc crate sends on its own (PONG, CAP, JOIN,
+/// registration) bypass the queue entirely.
+pub(super) struct SendQueue {
+    tx: mpsc::UnboundedSender<QueuedMessage>,
+    next_generation: AtomicU64,
+}
+
+impl SendQueue {
+    pub fn spawn(sender: Sender, burst: u32, interval: Duration) -> SendQueue {
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        tokio::spawn(SendQueue::run(sender, rx, burst.max(1), interval));
+
+        SendQueue {
+            tx,
+            next_generation: AtomicU64::new(0),
+        }
+    }
+
+    pub fn send(&self, message: IrcMessage, priority: Priority) -> anyhow::Result<()> {
+        self.push(QueuedMessage {
+            message,
+            priority,
+            edit: None,
+        })
+    }
+
+    pub fn send_edit(&self, message: IrcMessage, edit: &EditGroup) -> anyhow::Result<()> {
+        self.push(QueuedMessage {
+            message,
+            priority: Priority::Normal,
+            edit: Some(edit.clone()),
+        })
+    }
+
+    pub fn edit_group(&self, channel: &str, pipo_id: i64) -> EditGroup {
+        EditGroup {
+            channel: channel.to_string(),
+            pipo_id,
+            generation: self.next_generation.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    fn push(&self, message: QueuedMessage) -> anyhow::Result<()> {
+        self.tx
+            .send(message)
+            .map_err(|_| anyhow!("IRC send queue is closed"))
+    }
+
+    async fn run(
+        sender: Sender,
+        mut rx: mpsc::UnboundedReceiver<QueuedMessage>,
+        burst: u32,
+        interval: Duration,
+    ) {
+        let mut control = VecDeque::new();
+        let mut normal = VecDeque::new();
+        let mut tokens = burst as f64;
+        let mut last_refill = Instant::now();
+
+        loop {
+            if control.is_empty() && normal.is_empty() {
+                match rx.recv().await {
+                    Some(message) => SendQueue::enqueue(&mut control, &mut normal, message),
+                    None => return,
+                }
+            }
+
+            while let Ok(message) = rx.try_recv() {
+                SendQueue::enqueue(&mut control, &mut normal, message);
+            }
+
+            let now = Instant::now();
+
+            tokens = (tokens
+                + now.duration_since(last_refill).as_secs_f64() / interval.as_secs_f64())
+            .min(burst as f64);
+            last_refill = now;
+
+            if tokens < 1.0 {
+                let wait = interval.mul_f64(1.0 - tokens);
+
+                tokio::select! {
+                    message = rx.recv() => match message {
+                        Some(message) => SendQueue::enqueue(&mut control, &mut normal, message),
+                        None => return,
+                    },
+                    _ = time::sleep(wait) => (),
+                }
+
+                continue;
+            }
+
+            let Some(queued) = control.pop_front().or_else(|| normal.pop_front()) else {
+                continue;
+            };
+
+            tokens -= 1.0;
+
+            if let Err(e) = sender.send(queued.message) {
+                eprintln!("Failed to send queued IRC message: {:#}", e);
+            }
+        }
+    }
+
+    fn enqueue(
+        control: &mut VecDeque<QueuedMessage>,
+        normal: &mut VecDeque<QueuedMessage>,
+        message: QueuedMessage,
+    ) {
+        if let Some(edit) = message.edit.as_ref() {
+            normal.retain(|queued| match queued.edit.as_ref() {
+                Some(queued_edit) => {
+                    queued_edit.channel != edit.channel
+                        || queued_edit.pipo_id != edit.pipo_id
+                        || queued_edit.generation >= edit.generation
+                }
+                None => true,
+            });
+        }
+
+        match message.priority {
+            Priority::Control => control.push_back(message),
+            Priority::Normal => normal.push_back(message),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use irc::client::prelude::Command;
+
+    fn queued(text: &str, priority: Priority, edit: Option<&EditGroup>) -> QueuedMessage {
+        QueuedMessage {
+            message: IrcMessage::from(Command::PRIVMSG("#pipo".to_string(), text.to_string())),
+            priority,
+            edit: edit.cloned(),
+        }
+    }
+
+    fn texts(queue: &VecDeque<QueuedMessage>) -> Vec<String> {
+        queue
+            .iter()
+            .map(|queued| match &queued.message.command {
+                Command::PRIVMSG(_, text) => text.clone(),
+                _ => unreachable!(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn enqueue_drops_lines_of_superseded_edits() {
+        let mut control = VecDeque::new();
+        let mut normal = VecDeque::new();
+        let first = EditGroup {
+            channel: "#pipo".to_string(),
+            pipo_id: 7,
+            generation: 0,
+        };
+        let second = EditGroup {
+            generation: 1,
+            ..first.clone()
+        };
+        let other = EditGroup {
+            pipo_id: 8,
+            generation: 2,
+            ..first.clone()
+        };
+
+        for message in [
+            queued("plain", Priority::Normal, None),
+            queued("edit 1a", Priority::Normal, Some(&first)),
+            queued("edit 1b", Priority::Normal, Some(&first)),
+            queued("other edit", Priority::Normal, Some(&other)),
+            queued("edit 2a", Priority::Normal, Some(&second)),
+            queued("edit 2b", Priority::Normal, Some(&second)),
+            queued("notice", Priority::Control, None),
+        ] {
+            SendQueue::enqueue(&mut control, &mut normal, message);
+        }
+
+        assert_eq!(
+            texts(&normal),
+            vec!["plain", "other edit", "edit 2a", "edit 2b"]
+        );
+        assert_eq!(texts(&control), vec!["notice"]);
+    }
+}
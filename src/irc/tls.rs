@@ -0,0 +1,216 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use anyhow::anyhow;
+use serde::Deserialize;
+use tokio::{
+    io::copy_bidirectional,
+    net::{TcpListener, TcpStream},
+    time::timeout,
+};
+use tokio_rustls::{
+    rustls::{
+        client::WebPkiServerVerifier,
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName},
+        ClientConfig, RootCertStore,
+    },
+    TlsConnector,
+};
+
+use crate::cert_verifier::PinnedCertVerifier;
+
+// How long the relay waits for the irc crate to connect before it gives up
+// and closes the server connection.
+const RELAY_ACCEPT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// TLS settings that pipo handles itself instead of leaving TLS to the irc
+/// crate, which can neither load a CA bundle nor pin certificates.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub(crate) struct IrcTlsConfig {
+    /// PEM bundle of CAs trusted in addition to the built-in roots.
+    #[serde(default)]
+    pub(crate) ca_path: Option<String>,
+    /// SHA-256 fingerprints of server certificates that are accepted even
+    /// when they don't chain to a trusted CA, e.g. self-signed ones.
+    #[serde(default)]
+    pub(crate) fingerprints: Vec<String>,
+    /// PEM certificate chain and private key presented to the server, for
+    /// CertFP.
+    #[serde(default)]
+    pub(crate) client_cert_path: Option<String>,
+    #[serde(default)]
+    pub(crate) client_key_path: Option<String>,
+}
+
+impl IrcTlsConfig {
+    pub fn has_client_cert(&self) -> bool {
+        self.client_cert_path.is_some() && self.client_key_path.is_some()
+    }
+
+    fn client_config(&self) -> anyhow::Result<ClientConfig> {
+        let mut roots = RootCertStore::empty();
+
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+
+        if let Some(ca_path) = self.ca_path.as_ref() {
+            for cert in CertificateDer::pem_file_iter(ca_path)? {
+                roots.add(cert?)?;
+            }
+        }
+
+        let verifier = Arc::new(PinnedCertVerifier::with_fingerprints(
+            self.fingerprints.clone(),
+            Some(WebPkiServerVerifier::builder(Arc::new(roots)).build()?),
+        ));
+        let builder = ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(verifier);
+
+        Ok(match (&self.client_cert_path, &self.client_key_path) {
+            (Some(cert_path), Some(key_path)) => {
+                let chain =
+                    CertificateDer::pem_file_iter(cert_path)?.collect::<Result<Vec<_>, _>>()?;
+                let key = PrivateKeyDer::from_pem_file(key_path)?;
+
+                builder.with_client_auth_cert(chain, key)?
+            }
+            (None, None) => builder.with_no_client_auth(),
+            _ => {
+                return Err(anyhow!(
+                    "client_cert_path and client_key_path must be set together"
+                ))
+            }
+        })
+    }
+}
+
+/// Connects to `server` over TLS and returns a local address that carries
+/// the connection in plaintext, for the irc crate to connect to instead.
+/// The irc crate can't be handed a stream of ours, so the relay takes the
+/// first connection that comes from this process and refuses all others.
+/// Without one in time, the server connection is closed again.
+pub(super) async fn relay(server: &str, tls: &IrcTlsConfig) -> anyhow::Result<SocketAddr> {
+    let connector = TlsConnector::from(Arc::new(tls.client_config()?));
+    let hostname = server
+        .rsplit_once(':')
+        .map_or(server, |(hostname, _)| hostname);
+    let socket = TcpStream::connect(server).await?;
+    let mut stream = connector
+        .connect(ServerName::try_from(hostname.to_string())?, socket)
+        .await?;
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+
+    tokio::spawn(async move {
+        let accept = async {
+            loop {
+                match listener.accept().await {
+                    Ok((local, peer)) if is_own_connection(addr.port(), peer.port()) => {
+                        return Some(local)
+                    }
+                    Ok((_, peer)) => {
+                        eprintln!("IRC TLS relay refused a connection from {}", peer)
+                    }
+                    Err(e) => {
+                        eprintln!("IRC TLS relay failed to accept: {:#}", e);
+                        return None;
+                    }
+                }
+            }
+        };
+        let mut local = match timeout(RELAY_ACCEPT_TIMEOUT, accept).await {
+            Ok(Some(local)) => local,
+            Ok(None) => return,
+            Err(_) => {
+                eprintln!("IRC TLS relay gave up waiting for a connection");
+                return;
+            }
+        };
+
+        drop(listener);
+
+        if let Err(e) = copy_bidirectional(&mut local, &mut stream).await {
+            eprintln!("IRC TLS relay closed: {:#}", e);
+        }
+    });
+
+    Ok(addr)
+}
+
+/// Whether the connection from local port `peer_port` to the relay on
+/// `relay_port` was opened by this process, so that other local users
+/// can't take over the session. Looks the connecting socket up in
+/// /proc/net/tcp and among our own file descriptors.
+#[cfg(target_os = "linux")]
+fn is_own_connection(relay_port: u16, peer_port: u16) -> bool {
+    let Ok(table) = std::fs::read_to_string("/proc/net/tcp") else {
+        return false;
+    };
+    let Some(inode) = socket_inode(&table, peer_port, relay_port) else {
+        return false;
+    };
+    let Ok(fds) = std::fs::read_dir("/proc/self/fd") else {
+        return false;
+    };
+    let socket = format!("socket:[{}]", inode);
+
+    fds.filter_map(Result::ok).any(|fd| {
+        std::fs::read_link(fd.path()).is_ok_and(|link| link.as_os_str() == socket.as_str())
+    })
+}
+
+/// Other platforms have no cheap way to tell who owns a socket, so any
+/// first connection is taken.
+#[cfg(not(target_os = "linux"))]
+fn is_own_connection(_relay_port: u16, _peer_port: u16) -> bool {
+    true
+}
+
+/// The inode of the socket in a /proc/net/tcp `table` that connects from
+/// `local_port` to `remote_port`.
+#[cfg(any(target_os = "linux", test))]
+fn socket_inode(table: &str, local_port: u16, remote_port: u16) -> Option<&str> {
+    let port = |address: &str| {
+        address
+            .rsplit_once(':')
+            .and_then(|(_, port)| u16::from_str_radix(port, 16).ok())
+    };
+
+    table.lines().skip(1).find_map(|line| {
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+
+        (fields.len() > 9
+            && port(fields[1]) == Some(local_port)
+            && port(fields[2]) == Some(remote_port))
+        .then(|| fields[9])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn socket_inode_matches_the_connecting_side() {
+        let table = "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+   0: 0100007F:A411 0100007F:9C40 01 00000000:00000000 00:00000000 00000000  1000        0 4242 1 0000000000000000 20 4 30 10 -1
+   1: 0100007F:9C40 0100007F:A411 01 00000000:00000000 00:00000000 00000000  1000        0 4343 1 0000000000000000 20 4 30 10 -1";
+
+        assert_eq!(socket_inode(table, 0xa411, 0x9c40), Some("4242"));
+        assert_eq!(socket_inode(table, 0x9c40, 0xa411), Some("4343"));
+        assert_eq!(socket_inode(table, 0xa412, 0x9c40), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn own_connections_are_recognised() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("listener");
+        let relay_port = listener.local_addr().expect("address").port();
+        let _client = TcpStream::connect(("127.0.0.1", relay_port))
+            .await
+            .expect("connect");
+        let (_, peer) = listener.accept().await.expect("accept");
+
+        assert!(is_own_connection(relay_port, peer.port()));
+        assert!(!is_own_connection(relay_port, peer.port().wrapping_add(1)));
+    }
+}
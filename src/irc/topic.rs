@@ -0,0 +1,161 @@
+use std::collections::{HashMap, HashSet};
+
+use irc::{
+    client::prelude::{ChannelMode, Command, Mode, Response},
+    proto::Message as IrcMessage,
+};
+
+use super::IRC;
+
+/// Follows channel topics, which channels only let operators change them
+/// (+t), and how long a topic the server takes, so that bridged topics are
+/// only sent when they would change something and can be set.
+pub(super) struct TopicTracker {
+    // Current topics, by channel key.
+    topics: HashMap<String, String>,
+    // Channels with +t, by channel key.
+    protected: HashSet<String>,
+    // Longest topic in bytes, from ISUPPORT TOPICLEN=<n>.
+    topic_len: Option<usize>,
+}
+
+impl TopicTracker {
+    pub fn new() -> TopicTracker {
+        TopicTracker {
+            topics: HashMap::new(),
+            protected: HashSet::new(),
+            topic_len: None,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.topics.clear();
+        self.protected.clear();
+        self.topic_len = None;
+    }
+
+    pub fn update_from_isupport(&mut self, message: &IrcMessage) {
+        let Command::Response(Response::RPL_ISUPPORT, args) = &message.command else {
+            return;
+        };
+
+        for token in args.iter().skip(1) {
+            if let Some(("TOPICLEN", value)) = token.split_once('=') {
+                self.topic_len = value.parse().ok().filter(|len| *len > 0);
+            }
+        }
+    }
+
+    /// Keeps track of topics and +t. Returns the channel and topic of a
+    /// `TOPIC` that changed the channel's topic.
+    pub fn handle(&mut self, message: &IrcMessage) -> Option<(String, String)> {
+        match &message.command {
+            Command::Response(Response::RPL_TOPIC, args) if args.len() >= 3 => {
+                self.topics
+                    .insert(IRC::channel_key(&args[1]), args[2].clone());
+
+                None
+            }
+            Command::Response(Response::RPL_CHANNELMODEIS, args) if args.len() >= 3 => {
+                let key = IRC::channel_key(&args[1]);
+
+                if args[2].trim_start_matches('+').contains('t') {
+                    self.protected.insert(key);
+                } else {
+                    self.protected.remove(&key);
+                }
+
+                None
+            }
+            Command::ChannelMODE(channel, modes) => {
+                for mode in modes {
+                    match mode {
+                        Mode::Plus(ChannelMode::ProtectedTopic, _) => {
+                            self.protected.insert(IRC::channel_key(channel));
+                        }
+                        Mode::Minus(ChannelMode::ProtectedTopic, _) => {
+                            self.protected.remove(&IRC::channel_key(channel));
+                        }
+                        _ => (),
+                    }
+                }
+
+                None
+            }
+            Command::TOPIC(channel, Some(topic)) => {
+                let previous = self.topics.insert(IRC::channel_key(channel), topic.clone());
+
+                (previous.as_ref() != Some(topic)).then(|| (channel.clone(), topic.clone()))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn is_protected(&self, channel: &str) -> bool {
+        self.protected.contains(&IRC::channel_key(channel))
+    }
+
+    /// `topic` cut down to what the server takes. Returns it when it
+    /// differs from the channel's topic, which is then taken to be set.
+    pub fn set(&mut self, channel: &str, topic: &str) -> Option<String> {
+        let topic = self.truncate(topic);
+        let key = IRC::channel_key(channel);
+
+        if self.topics.get(&key) == Some(&topic) {
+            return None;
+        }
+
+        self.topics.insert(key, topic.clone());
+
+        Some(topic)
+    }
+
+    fn truncate(&self, topic: &str) -> String {
+        let Some(mut len) = self.topic_len.filter(|len| topic.len() > *len) else {
+            return topic.to_string();
+        };
+
+        while !topic.is_char_boundary(len) {
+            len -= 1;
+        }
+
+        topic[..len].to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(line: &str) -> IrcMessage {
+        line.parse().expect("valid IRC line")
+    }
+
+    #[test]
+    fn topics_are_cut_to_topiclen_before_comparing() {
+        let mut topics = TopicTracker::new();
+
+        topics.update_from_isupport(&message(
+            ":irc.example.net 005 pipo TOPICLEN=5 :are supported by this server",
+        ));
+        topics.handle(&message(":irc.example.net 332 pipo #Pipo :hello"));
+
+        assert_eq!(topics.set("#pipo", "hello world"), None);
+        assert_eq!(topics.set("#PIPO", "abcdé"), Some("abcd".to_string()));
+        assert_eq!(topics.set("#pipo", "abcdé!"), None);
+    }
+
+    #[test]
+    fn protected_topics_follow_channel_modes() {
+        let mut topics = TopicTracker::new();
+
+        topics.handle(&message(":irc.example.net 324 pipo #pipo +nt"));
+        assert!(topics.is_protected("#Pipo"));
+
+        topics.handle(&message(":op!o@host MODE #pipo -t"));
+        assert!(!topics.is_protected("#pipo"));
+
+        topics.handle(&message(":op!o@host MODE #pipo +to alice"));
+        assert!(topics.is_protected("#pipo"));
+    }
+}
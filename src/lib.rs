@@ -5,6 +5,7 @@ use std::{
 };
 
 use anyhow::{anyhow, Context};
+use chrono::{DateTime, Utc};
 use deadpool_sqlite::{Config, Runtime};
 use regex::bytes::Regex;
 use rusqlite::Error::QueryReturnedNoRows;
@@ -12,18 +13,35 @@ use serde::Deserialize;
 use serde_json;
 use tokio::{fs::File, io::AsyncReadExt, sync::broadcast};
 
+mod archive;
+mod cert_verifier;
+mod direct;
 mod discord;
+mod http;
+mod identicon;
 mod irc;
+mod media;
 mod mumble;
+mod paste;
 pub(crate) mod protos;
 mod rachni;
 pub mod slack;
+mod threads;
 
+use crate::archive::Archive;
+use crate::direct::DirectBridge;
 use crate::discord::Discord;
-use crate::irc::{IRC, ThreadContextRepeat, ThreadFallbackStyle, ThreadPresentationMode};
+use crate::http::{HttpConfig, HttpServer};
+use crate::irc::{
+    IrcAuthConfig, IrcChannelMapping, IrcNoticeConfig, IrcTlsConfig, PresenceRelay, ReactionMode,
+    RelaymsgMode, ThreadContextRepeat, ThreadFallbackStyle, ThreadPresentationMode, IRC,
+};
+use crate::media::MediaStore;
 use crate::mumble::Mumble;
+use crate::paste::PasteService;
 use crate::rachni::Rachni;
 use crate::slack::Slack;
+use crate::threads::ThreadMap;
 
 pub use crate::slack::objects;
 
@@ -36,10 +54,12 @@ enum Message {
         username: String,
         avatar_url: Option<String>,
         thread: Option<ThreadRef>,
+        reply_to: Option<ReplyRef>,
         message: Option<String>,
         attachments: Option<Vec<Attachment>>,
         is_edit: bool,
         irc_flag: bool,
+        late: Option<Late>,
     },
     Bot {
         sender: usize,
@@ -54,6 +74,17 @@ enum Message {
         pipo_id: i64,
         transport: String,
     },
+    /// A private message from `username` to `recipient`, carried on the
+    /// DM bus. Without a `recipient_transport`, every transport that knows
+    /// the recipient delivers it.
+    Direct {
+        sender: usize,
+        transport: String,
+        username: String,
+        recipient: String,
+        recipient_transport: Option<usize>,
+        message: String,
+    },
     Names {
         sender: usize,
         transport: String,
@@ -65,6 +96,22 @@ enum Message {
         pipo_id: i64,
         remove: bool,
     },
+    /// Someone joining, leaving or renaming, phrased for display, e.g.
+    /// "alice joined" or a batched netsplit line.
+    Presence {
+        sender: usize,
+        transport: String,
+        message: String,
+    },
+    /// A NOTICE relayed as a line from the bridge rather than from its
+    /// sender, e.g. a welcome from ChanServ.
+    Notice {
+        sender: usize,
+        transport: String,
+        username: String,
+        message: String,
+        late: Option<Late>,
+    },
     Reaction {
         sender: usize,
         pipo_id: i64,
@@ -82,10 +129,18 @@ enum Message {
         username: String,
         avatar_url: Option<String>,
         thread: Option<ThreadRef>,
+        reply_to: Option<ReplyRef>,
         message: Option<String>,
         attachments: Option<Vec<Attachment>>,
         is_edit: bool,
         irc_flag: bool,
+        late: Option<Late>,
+    },
+    Topic {
+        sender: usize,
+        transport: String,
+        username: Option<String>,
+        topic: String,
     },
 }
 
@@ -94,10 +149,76 @@ pub(crate) struct ThreadRef {
     origin_transport: String,
     thread_root_id: Option<String>,
     reply_target_id: Option<u64>,
+    // The thread's first message, when it was bridged.
+    root_pipo_id: Option<i64>,
     root_author: Option<String>,
     root_excerpt: Option<String>,
 }
 
+impl ThreadRef {
+    /// The start of `message`, for showing which thread a reply belongs
+    /// to.
+    fn excerpt(message: Option<&str>) -> Option<String> {
+        let message = message?.trim();
+        if message.is_empty() {
+            return None;
+        }
+
+        let excerpt = message.chars().take(120).collect::<String>();
+        if message.chars().count() > 120 {
+            Some(format!("{}…", excerpt))
+        } else {
+            Some(excerpt)
+        }
+    }
+}
+
+/// The message a message is an inline reply to, quoted where there's no
+/// native reply to map it to.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct ReplyRef {
+    // None when the message replied to was never bridged.
+    pipo_id: Option<i64>,
+    author: Option<String>,
+    excerpt: Option<String>,
+}
+
+impl ReplyRef {
+    /// "author: excerpt", or whichever of the two is known.
+    fn quote(&self) -> Option<String> {
+        match (&self.author, &self.excerpt) {
+            (Some(author), Some(excerpt)) => Some(format!("{}: {}", author, excerpt)),
+            (Some(author), None) => Some(author.clone()),
+            (None, Some(excerpt)) => Some(excerpt.clone()),
+            (None, None) => None,
+        }
+    }
+}
+
+/// Set on messages relayed well after they were written, such as IRC
+/// history caught up after a reconnect.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Late {
+    // When the message was written, if the origin said.
+    sent_at: Option<DateTime<Utc>>,
+}
+
+impl Late {
+    /// Prefixes the text of a late message with when it was written, since
+    /// it's shown among messages written long after it.
+    fn mark(late: Option<Late>, message: Option<String>) -> Option<String> {
+        let Some(late) = late else {
+            return message;
+        };
+        let marker = match late.sent_at {
+            Some(sent_at) => format!("[late, {}]", sent_at.format("%H:%M UTC")),
+            None => "[late]".to_string(),
+        };
+
+        message.map(|message| format!("{} {}", marker, message))
+    }
+}
+
 #[derive(Clone, Debug, Default)]
 struct Attachment {
     id: u64,
@@ -121,6 +242,51 @@ struct Attachment {
     image_height: Option<u64>,
     image_width: Option<u64>,
     fallback: Option<String>,
+    // The file itself, for transports that can't download it on their own.
+    data: Option<FileData>,
+}
+
+/// The contents of a bridged file, shared by every transport that
+/// receives it.
+#[derive(Clone)]
+struct FileData(Arc<[u8]>);
+
+impl fmt::Debug for FileData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "FileData({} bytes)", self.0.len())
+    }
+}
+
+impl Attachment {
+    /// Whether this is a file someone uploaded, reachable at `from_url`,
+    /// rather than a preview or a quoted message.
+    fn is_file(&self) -> bool {
+        self.filename.is_some() && self.from_url.is_some()
+    }
+
+    /// Moves the links of file attachments to the end of `message`, for
+    /// transports that can't carry files, and returns what's left.
+    fn inline_file_links(
+        message: Option<String>,
+        attachments: Option<Vec<Attachment>>,
+    ) -> (Option<String>, Option<Vec<Attachment>>) {
+        let Some(attachments) = attachments else {
+            return (message, None);
+        };
+        let (files, others): (Vec<_>, Vec<_>) =
+            attachments.into_iter().partition(Attachment::is_file);
+        let mut lines = message
+            .into_iter()
+            .filter(|message| !message.is_empty())
+            .collect::<Vec<_>>();
+
+        lines.extend(files.into_iter().filter_map(|file| file.from_url));
+
+        let message = Some(lines.join("\n")).filter(|message| !message.is_empty());
+        let others = Some(others).filter(|others| !others.is_empty());
+
+        (message, others)
+    }
 }
 
 impl fmt::Display for Message {
@@ -133,10 +299,12 @@ impl fmt::Display for Message {
                 username: _,
                 avatar_url: _,
                 thread: _,
+                reply_to: _,
                 message,
                 attachments: _,
                 is_edit: _,
                 irc_flag: _,
+                late: _,
             } => match message {
                 Some(message) => write!(f, "{}", message),
                 None => write!(f, "Empty message"),
@@ -157,6 +325,14 @@ impl fmt::Display for Message {
                 pipo_id: _,
                 transport: _,
             } => write!(f, "Delete message"),
+            Message::Direct {
+                sender: _,
+                transport: _,
+                username: _,
+                recipient: _,
+                recipient_transport: _,
+                message,
+            } => write!(f, "{}", message),
             Message::Names {
                 sender: _,
                 transport: _,
@@ -171,6 +347,18 @@ impl fmt::Display for Message {
                 pipo_id: _,
                 remove: _,
             } => write!(f, "Pin message"),
+            Message::Presence {
+                sender: _,
+                transport: _,
+                message,
+            } => write!(f, "{}", message),
+            Message::Notice {
+                sender: _,
+                transport: _,
+                username,
+                message,
+                late: _,
+            } => write!(f, "-{}- {}", username, message),
             Message::Reaction {
                 sender: _,
                 pipo_id: _,
@@ -188,14 +376,22 @@ impl fmt::Display for Message {
                 username: _,
                 avatar_url: _,
                 thread: _,
+                reply_to: _,
                 message,
                 attachments: _,
                 is_edit: _,
                 irc_flag: _,
+                late: _,
             } => match message {
                 Some(message) => write!(f, "{}", message),
                 None => write!(f, "Empty Message"),
             },
+            Message::Topic {
+                sender: _,
+                transport: _,
+                username: _,
+                topic,
+            } => write!(f, "Topic: {}", topic),
         }
     }
 }
@@ -208,33 +404,9 @@ struct ConfigBus {
 #[derive(Deserialize, Debug)]
 #[serde(tag = "transport")]
 enum ConfigTransport {
-    IRC {
-        nickname: Arc<String>,
-        server: Arc<String>,
-        use_tls: bool,
-        img_root: Arc<String>,
-        channel_mapping: HashMap<Arc<String>, Arc<String>>,
-        #[serde(default)]
-        thread_presentation_mode: ThreadPresentationMode,
-        #[serde(default)]
-        thread_fallback_style: ThreadFallbackStyle,
-        #[serde(default)]
-        thread_context_repeat: ThreadContextRepeat,
-        #[serde(default = "default_thread_excerpt_len")]
-        thread_excerpt_len: usize,
-        #[serde(default = "default_show_thread_root_marker")]
-        show_thread_root_marker: bool,
-    },
-    Discord {
-        token: Arc<String>,
-        guild_id: u64,
-        channel_mapping: HashMap<Arc<String>, Arc<String>>,
-    },
-    Slack {
-        token: Arc<String>,
-        bot_token: Arc<String>,
-        channel_mapping: HashMap<Arc<String>, Arc<String>>,
-    },
+    IRC(IrcConfig),
+    Discord(DiscordConfig),
+    Slack(SlackConfig),
     Minecraft {
         username: Arc<String>,
         buses: Vec<Arc<String>>,
@@ -257,20 +429,181 @@ enum ConfigTransport {
     },
 }
 
+#[derive(Deserialize, Debug)]
+struct IrcConfig {
+    nickname: Arc<String>,
+    #[serde(default)]
+    server: Option<Arc<String>>,
+    /// Further servers tried in turn when connecting fails.
+    #[serde(default)]
+    servers: Vec<String>,
+    use_tls: bool,
+    #[serde(default)]
+    tls: Option<IrcTlsConfig>,
+    #[serde(default)]
+    img_root: Option<Arc<String>>,
+    #[serde(default = "default_avatar_cache_ttl_secs")]
+    avatar_cache_ttl_secs: u64,
+    channel_mapping: HashMap<Arc<String>, IrcChannelMapping>,
+    #[serde(default)]
+    thread_presentation_mode: ThreadPresentationMode,
+    #[serde(default)]
+    thread_fallback_style: ThreadFallbackStyle,
+    #[serde(default)]
+    thread_context_repeat: ThreadContextRepeat,
+    #[serde(default = "default_thread_excerpt_len")]
+    thread_excerpt_len: usize,
+    #[serde(default = "default_show_thread_root_marker")]
+    show_thread_root_marker: bool,
+    #[serde(default)]
+    relaymsg_mode: RelaymsgMode,
+    #[serde(default = "default_max_message_lines")]
+    max_message_lines: usize,
+    #[serde(default = "default_send_burst")]
+    send_burst: u32,
+    #[serde(default = "default_send_interval_ms")]
+    send_interval_ms: u64,
+    #[serde(default)]
+    deleted_message_notice: bool,
+    #[serde(default)]
+    reaction_mode: ReactionMode,
+    #[serde(default)]
+    presence_relay: PresenceRelay,
+    #[serde(default)]
+    topic_sync: bool,
+    #[serde(default = "default_history_backfill_limit")]
+    history_backfill_limit: usize,
+    #[serde(default)]
+    dm_bus: Option<String>,
+    #[serde(default)]
+    dm_allowlist: Vec<String>,
+    #[serde(default)]
+    notices: IrcNoticeConfig,
+    #[serde(flatten)]
+    auth: IrcAuthConfig,
+}
+
+#[derive(Deserialize, Debug)]
+struct DiscordConfig {
+    token: Arc<String>,
+    guild_id: u64,
+    channel_mapping: HashMap<Arc<String>, Arc<String>>,
+    #[serde(default)]
+    topic_sync: bool,
+    #[serde(default)]
+    dm_bus: Option<String>,
+    #[serde(default)]
+    dm_allowlist: Vec<String>,
+    #[serde(default = "default_discord_upload_max_bytes")]
+    upload_max_bytes: u64,
+    #[serde(default)]
+    ignored_ids: Vec<u64>,
+}
+
+#[derive(Deserialize, Debug)]
+struct SlackConfig {
+    token: Arc<String>,
+    bot_token: Arc<String>,
+    channel_mapping: HashMap<Arc<String>, Arc<String>>,
+    #[serde(default)]
+    topic_sync: bool,
+    #[serde(default)]
+    dm_bus: Option<String>,
+    #[serde(default)]
+    dm_allowlist: Vec<String>,
+    #[serde(default = "default_slack_upload_max_bytes")]
+    upload_max_bytes: u64,
+}
+
+/// What every transport shares: the database, the id of the next bridged
+/// message and the services built on top of them.
+struct Services {
+    pool: deadpool_sqlite::Pool,
+    pipo_id: Arc<Mutex<i64>>,
+    archive: Arc<Archive>,
+    threads: Arc<ThreadMap>,
+    media: Option<Arc<MediaStore>>,
+    pastes: Option<Arc<PasteService>>,
+}
+
 #[derive(Deserialize, Debug)]
 struct ParsedConfig {
     buses: Vec<ConfigBus>,
     transports: Vec<ConfigTransport>,
+    #[serde(default)]
+    http: Option<HttpConfig>,
+    #[serde(default = "default_archive_days")]
+    archive_days: u32,
+}
+
+/// Sets up DM bridging for a transport that names a `dm_bus`.
+async fn direct_bridge(
+    transport_id: usize,
+    bus_map: &HashMap<String, broadcast::Sender<Message>>,
+    dm_bus: Option<&str>,
+    dm_allowlist: &[String],
+    casemapping: fn(&str) -> String,
+    pool: &deadpool_sqlite::Pool,
+) -> anyhow::Result<Option<Arc<DirectBridge>>> {
+    let Some(dm_bus) = dm_bus else {
+        return Ok(None);
+    };
+    let bus = bus_map
+        .get(dm_bus)
+        .ok_or_else(|| anyhow!("No bus named '{}' in configuration file.", dm_bus))?;
+
+    Ok(Some(Arc::new(
+        DirectBridge::new(
+            transport_id,
+            bus.clone(),
+            dm_allowlist,
+            casemapping,
+            pool.clone(),
+        )
+        .await?,
+    )))
 }
 
 fn default_thread_excerpt_len() -> usize {
     120
 }
 
+fn default_max_message_lines() -> usize {
+    10
+}
+
+fn default_send_burst() -> u32 {
+    5
+}
+
+fn default_send_interval_ms() -> u64 {
+    500
+}
+
+fn default_history_backfill_limit() -> usize {
+    100
+}
+
+fn default_avatar_cache_ttl_secs() -> u64 {
+    60 * 60
+}
+
+fn default_slack_upload_max_bytes() -> u64 {
+    25 * 1024 * 1024
+}
+
+fn default_discord_upload_max_bytes() -> u64 {
+    10 * 1024 * 1024
+}
+
 fn default_show_thread_root_marker() -> bool {
     true
 }
 
+fn default_archive_days() -> u32 {
+    30
+}
+
 pub async fn inner_main() -> anyhow::Result<()> {
     let args: Vec<String> = env::args().collect();
     let config_path = args.get(1).cloned().or(env::var("CONFIG_PATH").ok());
@@ -403,38 +736,81 @@ pub async fn inner_main() -> anyhow::Result<()> {
 
     // all_transport_tasks.push(handle);
 
+    let archive = Arc::new(Archive::new(db_pool.clone(), config_json.archive_days).await?);
+    all_transport_tasks.push(archive.clone().spawn(&bus_map));
+    let threads = Arc::new(ThreadMap::new(db_pool.clone()).await?);
+
+    // The paste service and media store need somewhere to serve files
+    // from, so they are only enabled together with the HTTP server.
+    let (paste_service, media) = match config_json.http.as_ref() {
+        Some(http_config) => {
+            let paste_service =
+                Arc::new(PasteService::new(db_pool.clone(), &http_config.public_url).await?);
+            let media = Arc::new(
+                MediaStore::new(
+                    &http_config.media_dir,
+                    &http_config.public_url,
+                    http_config.media_max_bytes,
+                    http_config.media_cache_days,
+                )
+                .await?,
+            );
+            let server = Arc::new(HttpServer::new(
+                http_config,
+                paste_service.clone(),
+                media.clone(),
+            ));
+            let handle = tokio::spawn(async move {
+                match server.run().await {
+                    Ok(_) => eprintln!("HttpServer::run() exited Ok"),
+                    Err(e) => eprintln!("HttpServer::run() exited with Error: {:#}", e),
+                }
+            });
+            all_transport_tasks.push(handle);
+
+            (Some(paste_service), Some(media))
+        }
+        None => (None, None),
+    };
+
+    // Only Discord attaches the files themselves; the others link to them.
+    // Transports that receive files note, by bus, the largest file Discord
+    // takes there.
+    let mut attach_max_bytes: HashMap<String, u64> = HashMap::new();
+
+    for transport in config_json.transports.iter() {
+        if let ConfigTransport::Discord(discord) = transport {
+            for bus in discord.channel_mapping.values() {
+                let max_bytes = attach_max_bytes.entry(bus.to_string()).or_default();
+
+                *max_bytes = (*max_bytes).max(discord.upload_max_bytes);
+            }
+        }
+    }
+
+    let services = Services {
+        pool: db_pool.clone(),
+        pipo_id: pipo_id.clone(),
+        archive,
+        threads,
+        media: media.clone(),
+        pastes: paste_service.clone(),
+    };
+
     for transport_id in 0..config_json.transports.len() {
         match &config_json.transports[transport_id] {
-            ConfigTransport::IRC {
-                nickname,
-                server,
-                use_tls,
-                img_root,
-                channel_mapping,
-                thread_presentation_mode,
-                thread_fallback_style,
-                thread_context_repeat,
-                thread_excerpt_len,
-                show_thread_root_marker,
-            } => {
-                // tokio::spawn maybe?
-                let mut instance = IRC::new(
-                    &bus_map,
-                    pipo_id.clone(),
-                    db_pool.clone(),
-                    nickname.to_string(),
-                    server.to_string(),
-                    *use_tls,
-                    &img_root,
-                    &channel_mapping,
-                    *thread_presentation_mode,
-                    *thread_fallback_style,
-                    *thread_context_repeat,
-                    *thread_excerpt_len,
-                    *show_thread_root_marker,
+            ConfigTransport::IRC(irc) => {
+                let direct = direct_bridge(
                     transport_id,
+                    &bus_map,
+                    irc.dm_bus.as_deref(),
+                    &irc.dm_allowlist,
+                    IRC::channel_key,
+                    &db_pool,
                 )
                 .await?;
+                // tokio::spawn maybe?
+                let mut instance = IRC::new(transport_id, &bus_map, irc, &services, direct).await?;
                 // you should push enough state to connect the spawned
                 // transport to all its buses... you don't need to push
                 // the task itself, tokio will track that
@@ -452,21 +828,18 @@ pub async fn inner_main() -> anyhow::Result<()> {
                 });
                 all_transport_tasks.push(handle);
             }
-            ConfigTransport::Discord {
-                token,
-                guild_id,
-                channel_mapping,
-            } => {
-                let mut instance = Discord::new(
+            ConfigTransport::Discord(discord) => {
+                let direct = direct_bridge(
                     transport_id,
                     &bus_map,
-                    pipo_id.clone(),
-                    db_pool.clone(),
-                    token.to_string(),
-                    *guild_id,
-                    &channel_mapping,
+                    discord.dm_bus.as_deref(),
+                    &discord.dm_allowlist,
+                    str::to_lowercase,
+                    &db_pool,
                 )
                 .await?;
+                let mut instance =
+                    Discord::new(transport_id, &bus_map, discord, &services, direct).await?;
                 let handle = tokio::spawn(async move {
                     match instance.connect().await {
                         Ok(_) => eprintln!("Discord::connect() exited Ok"),
@@ -481,19 +854,23 @@ pub async fn inner_main() -> anyhow::Result<()> {
                 });
                 all_transport_tasks.push(handle);
             }
-            ConfigTransport::Slack {
-                token,
-                bot_token,
-                channel_mapping,
-            } => {
+            ConfigTransport::Slack(slack) => {
+                let direct = direct_bridge(
+                    transport_id,
+                    &bus_map,
+                    slack.dm_bus.as_deref(),
+                    &slack.dm_allowlist,
+                    str::to_lowercase,
+                    &db_pool,
+                )
+                .await?;
                 let mut instance = Slack::new(
                     transport_id,
                     &bus_map,
-                    pipo_id.clone(),
-                    db_pool.clone(),
-                    token.to_string(),
-                    bot_token.to_string(),
-                    &channel_mapping,
+                    slack,
+                    &services,
+                    direct,
+                    &attach_max_bytes,
                 )
                 .await?;
                 let handle = tokio::spawn(async move {
@@ -537,6 +914,7 @@ pub async fn inner_main() -> anyhow::Result<()> {
                     &voice_channel_mapping,
                     pipo_id.clone(),
                     db_pool.clone(),
+                    paste_service.clone(),
                 )
                 .await?;
                 let handle = tokio::spawn(async move {
@@ -568,6 +946,7 @@ pub async fn inner_main() -> anyhow::Result<()> {
                     &buses,
                     db_pool.clone(),
                     pipo_id.clone(),
+                    media.clone(),
                 )
                 .await?;
                 let handle = tokio::spawn(async move {
@@ -596,3 +975,58 @@ pub async fn inner_main() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn late_messages_are_marked_with_when_they_were_sent() {
+        let sent_at = DateTime::parse_from_rfc3339("2026-10-18T09:05:00.000Z")
+            .ok()
+            .map(|time| time.with_timezone(&Utc));
+
+        assert_eq!(
+            Late::mark(Some(Late { sent_at }), Some("hi".to_string())).as_deref(),
+            Some("[late, 09:05 UTC] hi")
+        );
+        assert_eq!(
+            Late::mark(Some(Late { sent_at: None }), Some("hi".to_string())).as_deref(),
+            Some("[late] hi")
+        );
+        assert_eq!(
+            Late::mark(None, Some("hi".to_string())).as_deref(),
+            Some("hi")
+        );
+    }
+
+    #[test]
+    fn file_links_are_appended_to_the_message() {
+        let file = Attachment {
+            filename: Some("cat.png".to_string()),
+            from_url: Some("https://example.net/cat.png".to_string()),
+            ..Default::default()
+        };
+        let preview = Attachment {
+            text: Some("A quoted message".to_string()),
+            ..Default::default()
+        };
+
+        let (message, attachments) = Attachment::inline_file_links(
+            Some("look".to_string()),
+            Some(vec![file.clone(), preview]),
+        );
+
+        assert_eq!(
+            message.as_deref(),
+            Some("look\nhttps://example.net/cat.png")
+        );
+        assert_eq!(attachments.map(|a| a.len()), Some(1));
+
+        let (message, attachments) =
+            Attachment::inline_file_links(Some(String::new()), Some(vec![file]));
+
+        assert_eq!(message.as_deref(), Some("https://example.net/cat.png"));
+        assert!(attachments.is_none());
+    }
+}
@@ -0,0 +1,354 @@
+use std::{
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::anyhow;
+use reqwest::header;
+use sha2::{Digest, Sha256};
+use tokio::fs;
+
+const AVATAR_DIR: &str = "avatars";
+const CACHE_DIR: &str = "cache";
+// Bytes of a hash that make up cache file names and identicon seeds.
+const HASH_PREFIX_LEN: usize = 8;
+const MAX_FILENAME_LEN: usize = 64;
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Files served by the built-in HTTP server under `/media/`: avatars put
+/// into `<media_dir>/avatars/` by the operator, and copies of attachments
+/// downloaded into `<media_dir>/cache/` so that links handed to other
+/// transports don't expire with the original. Generated identicons live
+/// alongside under `/identicon/`.
+pub(crate) struct MediaStore {
+    dir: PathBuf,
+    public_url: String,
+    max_bytes: u64,
+    cache_ttl: Duration,
+    http: reqwest::Client,
+    last_prune: Mutex<Option<Instant>>,
+}
+
+impl MediaStore {
+    pub async fn new(
+        dir: &str,
+        public_url: &str,
+        max_bytes: u64,
+        cache_days: u64,
+    ) -> anyhow::Result<MediaStore> {
+        let dir = PathBuf::from(dir);
+
+        fs::create_dir_all(dir.join(AVATAR_DIR)).await?;
+        fs::create_dir_all(dir.join(CACHE_DIR)).await?;
+
+        Ok(MediaStore {
+            dir,
+            public_url: public_url.trim_end_matches('/').to_string(),
+            max_bytes,
+            cache_ttl: Duration::from_secs(cache_days * 24 * 60 * 60),
+            http: reqwest::Client::new(),
+            last_prune: Mutex::new(None),
+        })
+    }
+
+    /// The URL of the avatar uploaded for `name`, if there is one. The
+    /// modification time is appended so that replacing the file changes
+    /// the URL, and with it what chat clients have cached.
+    pub async fn avatar_url(&self, name: &str) -> Option<String> {
+        let filename = format!("{}.png", MediaStore::sanitize(&name.to_lowercase()));
+        let modified = fs::metadata(self.dir.join(AVATAR_DIR).join(&filename))
+            .await
+            .and_then(|metadata| metadata.modified())
+            .ok()?;
+        let modified = modified.duration_since(UNIX_EPOCH).ok()?.as_secs();
+
+        Some(format!(
+            "{}/media/{}/{}?{}",
+            self.public_url, AVATAR_DIR, filename, modified
+        ))
+    }
+
+    pub fn identicon_url(&self, name: &str) -> String {
+        format!(
+            "{}/identicon/{}.png",
+            self.public_url,
+            MediaStore::hash_prefix(&name.to_lowercase())
+        )
+    }
+
+    /// Downloads `url` into the cache, unless it's there already, and
+    /// returns the URL of the copy. `token` is sent as a bearer token, for
+    /// files that are only available to the bot.
+    pub async fn cache(
+        &self,
+        url: &str,
+        filename: &str,
+        token: Option<&str>,
+    ) -> anyhow::Result<String> {
+        self.prune().await;
+
+        let filename = MediaStore::cache_filename(url, filename);
+        let path = self.dir.join(CACHE_DIR).join(&filename);
+        let public_url = format!("{}/media/{}/{}", self.public_url, CACHE_DIR, filename);
+
+        if fs::try_exists(&path).await? {
+            return Ok(public_url);
+        }
+
+        let body = MediaStore::download(&self.http, url, token, self.max_bytes).await?;
+
+        self.write_cached(&filename, &body).await?;
+
+        Ok(public_url)
+    }
+
+    /// Puts `body`, already downloaded from `url`, into the cache unless
+    /// it's there already, and returns the URL of the copy.
+    pub async fn store(&self, url: &str, filename: &str, body: &[u8]) -> anyhow::Result<String> {
+        self.prune().await;
+
+        let filename = MediaStore::cache_filename(url, filename);
+        let public_url = format!("{}/media/{}/{}", self.public_url, CACHE_DIR, filename);
+
+        if body.len() as u64 > self.max_bytes {
+            return Err(anyhow!("File is larger than {} bytes", self.max_bytes));
+        }
+        if !fs::try_exists(self.dir.join(CACHE_DIR).join(&filename)).await? {
+            self.write_cached(&filename, body).await?;
+        }
+
+        Ok(public_url)
+    }
+
+    /// The largest file the cache takes.
+    pub fn max_bytes(&self) -> u64 {
+        self.max_bytes
+    }
+
+    async fn write_cached(&self, filename: &str, body: &[u8]) -> anyhow::Result<()> {
+        let path = self.dir.join(CACHE_DIR).join(filename);
+        // Written under a temporary name first, so that a half-written
+        // file is never served.
+        let partial = path.with_extension("partial");
+
+        fs::write(&partial, body).await?;
+        fs::rename(&partial, &path).await?;
+
+        Ok(())
+    }
+
+    /// The cached copy of `url`, if `cache` has stored one.
+    pub async fn cached(&self, url: &str, filename: &str) -> Option<Vec<u8>> {
+        let filename = MediaStore::cache_filename(url, filename);
+
+        fs::read(self.dir.join(CACHE_DIR).join(filename)).await.ok()
+    }
+
+    fn cache_filename(url: &str, filename: &str) -> String {
+        // Signed URLs carry an ever-changing query, which doesn't make it a
+        // different file.
+        let source = url.split('?').next().unwrap_or(url);
+
+        format!(
+            "{}-{}",
+            MediaStore::hash_prefix(source),
+            MediaStore::sanitize(filename)
+        )
+    }
+
+    /// Downloads `url`, giving up as soon as it turns out to be larger
+    /// than `max_bytes`. `token` is sent as a bearer token.
+    pub async fn download(
+        http: &reqwest::Client,
+        url: &str,
+        token: Option<&str>,
+        max_bytes: u64,
+    ) -> anyhow::Result<Vec<u8>> {
+        let mut request = http.get(url);
+
+        if let Some(token) = token {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+
+        let mut response = request.send().await?.error_for_status()?;
+
+        if response
+            .content_length()
+            .is_some_and(|length| length > max_bytes)
+        {
+            return Err(anyhow!("{} is larger than {} bytes", url, max_bytes));
+        }
+
+        let mut body = Vec::new();
+
+        while let Some(chunk) = response.chunk().await? {
+            body.extend_from_slice(&chunk);
+
+            if body.len() as u64 > max_bytes {
+                return Err(anyhow!("{} is larger than {} bytes", url, max_bytes));
+            }
+        }
+
+        Ok(body)
+    }
+
+    /// Reads the file at `path`, relative to `/media/`, along with its
+    /// content type.
+    pub async fn read(&self, path: &str) -> Option<(Vec<u8>, &'static str)> {
+        let (directory, filename) = path.split_once('/')?;
+
+        if ![AVATAR_DIR, CACHE_DIR].contains(&directory)
+            || filename.is_empty()
+            || filename.starts_with('.')
+            || filename.ends_with(".partial")
+            || !filename.chars().all(MediaStore::is_safe_char)
+        {
+            return None;
+        }
+
+        let body = fs::read(self.dir.join(directory).join(filename))
+            .await
+            .ok()?;
+
+        Some((body, MediaStore::content_type(filename)))
+    }
+
+    /// Removes cached copies older than `media_cache_days`, at most once
+    /// an hour.
+    async fn prune(&self) {
+        {
+            let mut last_prune = self.last_prune.lock().unwrap();
+
+            if last_prune.is_some_and(|last_prune| last_prune.elapsed() < PRUNE_INTERVAL) {
+                return;
+            }
+
+            *last_prune = Some(Instant::now());
+        }
+
+        let Ok(mut entries) = fs::read_dir(self.dir.join(CACHE_DIR)).await else {
+            return;
+        };
+        let cutoff = SystemTime::now() - self.cache_ttl;
+
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let expired = entry
+                .metadata()
+                .await
+                .and_then(|metadata| metadata.modified())
+                .is_ok_and(|modified| modified < cutoff);
+
+            if expired {
+                if let Err(e) = fs::remove_file(entry.path()).await {
+                    eprintln!("Failed to remove {:?}: {:#}", entry.path(), e);
+                }
+            }
+        }
+    }
+
+    fn hash_prefix(text: &str) -> String {
+        Sha256::digest(text.as_bytes())
+            .iter()
+            .take(HASH_PREFIX_LEN)
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    /// Makes `filename` safe to use on disk and in URLs.
+    fn sanitize(filename: &str) -> String {
+        let filename = filename
+            .chars()
+            .map(|ch| {
+                if MediaStore::is_safe_char(ch) {
+                    ch
+                } else {
+                    '_'
+                }
+            })
+            .take(MAX_FILENAME_LEN)
+            .collect::<String>();
+
+        match filename.trim_start_matches('.') {
+            "" => "file".to_string(),
+            filename => filename.to_string(),
+        }
+    }
+
+    fn is_safe_char(ch: char) -> bool {
+        ch.is_ascii_alphanumeric() || ['.', '-', '_'].contains(&ch)
+    }
+
+    fn content_type(filename: &str) -> &'static str {
+        let extension = filename
+            .rsplit_once('.')
+            .map(|(_, extension)| extension.to_ascii_lowercase());
+
+        // Anything that a browser could run as a page is served as a
+        // download.
+        match extension.as_deref() {
+            Some("png") => "image/png",
+            Some("jpg" | "jpeg") => "image/jpeg",
+            Some("gif") => "image/gif",
+            Some("webp") => "image/webp",
+            Some("mp4") => "video/mp4",
+            Some("webm") => "video/webm",
+            Some("mp3") => "audio/mpeg",
+            Some("ogg") => "audio/ogg",
+            Some("pdf") => "application/pdf",
+            Some("txt" | "log") => "text/plain; charset=utf-8",
+            _ => "application/octet-stream",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitize_keeps_names_inside_the_media_dir() {
+        assert_eq!(MediaStore::sanitize("cat picture.png"), "cat_picture.png");
+        assert_eq!(MediaStore::sanitize("../../etc/passwd"), "_.._etc_passwd");
+        assert_eq!(MediaStore::sanitize(".."), "file");
+        assert_eq!(MediaStore::sanitize(""), "file");
+        assert_eq!(
+            MediaStore::sanitize(&"a".repeat(100)).len(),
+            MAX_FILENAME_LEN
+        );
+    }
+
+    #[tokio::test]
+    async fn stored_files_are_cached_once_within_max_bytes() {
+        let dir = std::env::temp_dir().join(format!("pipo-media-{}", std::process::id()));
+        let media = MediaStore::new(dir.to_str().unwrap(), "https://pipo.example.net/", 4, 1)
+            .await
+            .expect("media store");
+        let url = "https://files.slack.com/files-pri/T01-F01/notes.txt?t=1";
+
+        let stored = media.store(url, "notes.txt", b"abcd").await.expect("store");
+
+        assert!(stored.starts_with("https://pipo.example.net/media/cache/"));
+        assert!(stored.ends_with("-notes.txt"));
+        assert_eq!(
+            media.cached(url, "notes.txt").await.as_deref(),
+            Some(&b"abcd"[..])
+        );
+        assert!(media.store(url, "big.txt", b"abcde").await.is_err());
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn pages_are_not_served_inline() {
+        assert_eq!(MediaStore::content_type("cat.PNG"), "image/png");
+        assert_eq!(
+            MediaStore::content_type("page.html"),
+            "application/octet-stream"
+        );
+        assert_eq!(
+            MediaStore::content_type("noextension"),
+            "application/octet-stream"
+        );
+    }
+}
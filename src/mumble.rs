@@ -29,18 +29,19 @@ use tokio_rustls::{
 use tokio_stream::{wrappers::BroadcastStream, StreamMap};
 use webpki_roots;
 
-use crate::{Attachment, Message};
+use crate::{
+    cert_verifier::PinnedCertVerifier, paste::PasteService, Attachment, Late, Message, ReplyRef,
+};
 
-mod cert_verifier;
 mod protocol;
 
 use crate::protos::Mumble as mumble;
-use cert_verifier::MumbleCertVerifier;
 
 const TRANSPORT_NAME: &'static str = "Mumble";
 
 const MAX_PAYLOAD: usize = 8 * 1024 * 1024 + 6;
 const MUMBLE_VERSION: u32 = 1 << 16 | 4 << 8 | 0;
+const MAX_INLINE_LINES: usize = 10;
 
 enum Payload {
     Version,
@@ -92,6 +93,7 @@ pub(crate) struct Mumble {
     pipo_id: Arc<Mutex<i64>>,
     pool: Pool,
     actor_id: Option<u32>,
+    pastes: Option<Arc<PasteService>>,
 }
 
 impl Mumble {
@@ -108,6 +110,7 @@ impl Mumble {
         _voice_channel_mapping: &HashMap<Arc<String>, Arc<String>>,
         pipo_id: Arc<Mutex<i64>>,
         pool: Pool,
+        pastes: Option<Arc<PasteService>>,
     ) -> anyhow::Result<Self> {
         let comment = comment.map(|s| s.to_string());
         let stream = None;
@@ -142,6 +145,7 @@ impl Mumble {
             pipo_id,
             pool,
             actor_id,
+            pastes,
         })
     }
 
@@ -254,7 +258,7 @@ impl Mumble {
             root_store.add(cert.clone())?;
             config = ClientConfig::builder()
                 .dangerous()
-                .with_custom_certificate_verifier(Arc::new(MumbleCertVerifier::new(cert)))
+                .with_custom_certificate_verifier(Arc::new(PinnedCertVerifier::new(&cert)))
                 .with_no_client_auth();
         } else {
             config = ClientConfig::builder()
@@ -545,12 +549,14 @@ impl Mumble {
                         username,
                         avatar_url: None,
                         thread: None,
+                        reply_to: None,
                         message: Some(
                             html_escape::decode_html_entities(message.message()).to_string(),
                         ),
                         attachments: None,
                         is_edit: false,
                         irc_flag: false,
+                        late: None,
                     };
 
                     bus.1
@@ -581,9 +587,11 @@ impl Mumble {
                 sender,
                 transport,
                 username,
+                reply_to,
                 message,
                 attachments,
                 is_edit,
+                late,
                 ..
             } => {
                 if sender != self.transport_id {
@@ -591,7 +599,8 @@ impl Mumble {
                         &channel,
                         &transport,
                         &username,
-                        message.as_deref(),
+                        reply_to.as_ref(),
+                        Late::mark(late, message).as_deref(),
                         attachments,
                         is_edit,
                     )
@@ -609,6 +618,10 @@ impl Mumble {
                 // Not handled
                 Ok(())
             }
+            Message::Direct { .. } => {
+                // Not handled
+                Ok(())
+            }
             Message::Names {
                 sender,
                 transport: _,
@@ -622,6 +635,14 @@ impl Mumble {
                 // Not handled
                 Ok(())
             }
+            Message::Presence { .. } => {
+                // Not handled
+                Ok(())
+            }
+            Message::Notice { .. } => {
+                // Not handled
+                Ok(())
+            }
             Message::Reaction { .. } => {
                 // Not handled
                 Ok(())
@@ -630,9 +651,11 @@ impl Mumble {
                 sender,
                 transport,
                 username,
+                reply_to,
                 message,
                 attachments,
                 is_edit,
+                late,
                 ..
             } => {
                 if sender != self.transport_id {
@@ -640,7 +663,8 @@ impl Mumble {
                         &channel,
                         &transport,
                         &username,
-                        message.as_deref(),
+                        reply_to.as_ref(),
+                        Late::mark(late, message).as_deref(),
                         attachments,
                         is_edit,
                     )
@@ -650,6 +674,10 @@ impl Mumble {
 
                 Ok(())
             }
+            Message::Topic { .. } => {
+                // Not handled
+                Ok(())
+            }
         }
     }
 
@@ -658,16 +686,25 @@ impl Mumble {
         channel: &str,
         transport: &str,
         username: &str,
+        reply_to: Option<&ReplyRef>,
         message: Option<&str>,
-        _attachments: Option<Vec<Attachment>>,
+        attachments: Option<Vec<Attachment>>,
         is_edit: bool,
     ) -> anyhow::Result<()> {
+        let files = Mumble::format_file_links(attachments.as_deref());
+        let body = match message {
+            Some(message) => self.format_message_body(message).await,
+            None if !files.is_empty() => String::new(),
+            None => return Err(anyhow!("Action Message contains no message")),
+        };
         let message_text = format!(
-            "{}<i>*{}!<font color=\"#3ae\"><b>{}</b></font> {}</i>",
+            "{}{}<i>*{}!<font color=\"#3ae\"><b>{}</b></font> {}</i>{}",
+            Mumble::format_reply_quote(reply_to),
             if is_edit { "<b>EDIT:</b> " } else { "" },
             html_escape::encode_text(&transport[..1].to_uppercase()),
             html_escape::encode_text(username),
-            html_escape::encode_text(message.ok_or(anyhow!("Action Message contains no message"))?)
+            body,
+            files
         );
         let actor_id = self
             .actor_id
@@ -695,16 +732,25 @@ impl Mumble {
         channel: &str,
         transport: &str,
         username: &str,
+        reply_to: Option<&ReplyRef>,
         message: Option<&str>,
-        _attachments: Option<Vec<Attachment>>,
+        attachments: Option<Vec<Attachment>>,
         is_edit: bool,
     ) -> anyhow::Result<()> {
+        let files = Mumble::format_file_links(attachments.as_deref());
+        let body = match message {
+            Some(message) => self.format_message_body(message).await,
+            None if !files.is_empty() => String::new(),
+            None => return Err(anyhow!("TextMessage contains no message")),
+        };
         let message_text = format!(
-            "{}!<font color=\"#3ae\"><b>{}</b></font>: {}{}",
+            "{}{}!<font color=\"#3ae\"><b>{}</b></font>: {}{}{}",
+            Mumble::format_reply_quote(reply_to),
             html_escape::encode_text(&transport[..1].to_uppercase()),
             html_escape::encode_text(username),
             if is_edit { "<b>EDIT:</b> " } else { "" },
-            html_escape::encode_text(message.ok_or(anyhow!("TextMessage contains no message"))?)
+            body,
+            files
         );
         let actor_id = self
             .actor_id
@@ -727,6 +773,67 @@ impl Mumble {
         Err(anyhow!("Couldn't find channel ID for channel {}", channel))
     }
 
+    /// A blockquote of the message `reply_to` answers, to go above the
+    /// reply.
+    fn format_reply_quote(reply_to: Option<&ReplyRef>) -> String {
+        match reply_to.and_then(ReplyRef::quote) {
+            Some(quote) => format!(
+                "<blockquote>{}</blockquote>",
+                html_escape::encode_text(&quote)
+            ),
+            None => String::new(),
+        }
+    }
+
+    /// Links to the files among `attachments`, one per line, since Mumble
+    /// can't carry them.
+    fn format_file_links(attachments: Option<&[Attachment]>) -> String {
+        attachments
+            .unwrap_or_default()
+            .iter()
+            .filter(|attachment| attachment.is_file())
+            .map(|attachment| {
+                format!(
+                    "<br><a href=\"{}\">{}</a>",
+                    html_escape::encode_double_quoted_attribute(
+                        attachment.from_url.as_deref().unwrap_or_default()
+                    ),
+                    html_escape::encode_text(attachment.filename.as_deref().unwrap_or_default())
+                )
+            })
+            .collect()
+    }
+
+    /// Escapes `message` for Mumble's HTML text messages, replacing long
+    /// messages and code blocks with a preview and a link to a paste.
+    async fn format_message_body(&self, message: &str) -> String {
+        let lines = message.lines().map(str::to_string).collect::<Vec<_>>();
+
+        if let Some(pastes) = self.pastes.as_ref() {
+            if PasteService::should_paste(message, lines.len(), MAX_INLINE_LINES) {
+                match pastes.create(message).await {
+                    Ok(url) => {
+                        let preview = PasteService::preview(&lines)
+                            .iter()
+                            .map(|line| html_escape::encode_text(line).to_string())
+                            .collect::<Vec<_>>()
+                            .join("<br>");
+
+                        return format!(
+                            "{}<br><a href=\"{}\">full text ({} lines)</a>",
+                            preview,
+                            html_escape::encode_double_quoted_attribute(&url),
+                            lines.len()
+                        );
+                    }
+                    Err(e) => eprintln!("Failed to create paste: {:#}", e),
+                }
+            }
+        }
+
+        html_escape::encode_text(message).to_string()
+    }
+
     async fn insert_into_messages_table(&self) -> anyhow::Result<i64> {
         let conn = self.pool.get().await.unwrap();
         let pipo_id = *self.pipo_id.lock().unwrap();
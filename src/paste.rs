@@ -0,0 +1,159 @@
+use anyhow::anyhow;
+use deadpool_sqlite::Pool;
+use rand::{distributions::Alphanumeric, Rng};
+use rusqlite::{params, OptionalExtension};
+
+/// Number of lines shown inline before the link to the full paste.
+pub(crate) const PASTE_PREVIEW_LINES: usize = 3;
+const PASTE_ID_LEN: usize = 12;
+const PASTE_TTL_DAYS: u32 = 30;
+
+/// Stores overflowing message text in SQLite so transports with small
+/// message limits can link to it instead of flooding the channel. Pastes
+/// are served by the built-in HTTP endpoint under `/paste/<id>`.
+pub(crate) struct PasteService {
+    pool: Pool,
+    public_url: String,
+}
+
+impl PasteService {
+    pub async fn new(pool: Pool, public_url: &str) -> anyhow::Result<PasteService> {
+        let conn = pool.get().await?;
+
+        conn.interact(|conn| -> anyhow::Result<()> {
+            conn.execute_batch(
+                "CREATE TABLE IF NOT EXISTS pastes (
+                                 id      TEXT PRIMARY KEY,
+                                 content TEXT NOT NULL,
+                                 created INTEGER DEFAULT (CAST(strftime('%s', 'now') AS INTEGER))
+                                 );",
+            )?;
+
+            Ok(())
+        })
+        .await
+        .unwrap_or_else(|_| Err(anyhow!("Interact Error")))?;
+
+        Ok(PasteService {
+            pool,
+            public_url: public_url.trim_end_matches('/').to_string(),
+        })
+    }
+
+    /// Stores `content` and returns the public URL it can be read from.
+    /// Paste ids are random, so that they can't be guessed from the text.
+    pub async fn create(&self, content: &str) -> anyhow::Result<String> {
+        let id = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(PASTE_ID_LEN)
+            .map(char::from)
+            .collect::<String>();
+        let conn = self.pool.get().await?;
+        let content = content.to_string();
+        let paste_id = id.clone();
+
+        conn.interact(move |conn| -> anyhow::Result<()> {
+            conn.execute(
+                "DELETE FROM pastes
+                 WHERE created < CAST(strftime('%s', 'now', ?1) AS INTEGER)",
+                params![format!("-{} days", PASTE_TTL_DAYS)],
+            )?;
+            conn.execute(
+                "INSERT INTO pastes (id, content)
+                 VALUES (?1, ?2)",
+                params![paste_id, content],
+            )?;
+
+            Ok(())
+        })
+        .await
+        .unwrap_or_else(|_| Err(anyhow!("Interact Error")))?;
+
+        Ok(format!("{}/paste/{}", self.public_url, id))
+    }
+
+    pub async fn get(&self, id: &str) -> anyhow::Result<Option<String>> {
+        let conn = self.pool.get().await?;
+        let id = id.to_string();
+
+        conn.interact(move |conn| -> anyhow::Result<Option<String>> {
+            Ok(conn
+                .query_row(
+                    "SELECT content FROM pastes WHERE id = ?1",
+                    params![id],
+                    |row| row.get(0),
+                )
+                .optional()?)
+        })
+        .await
+        .unwrap_or_else(|_| Err(anyhow!("Interact Error")))
+    }
+
+    /// Whether a message should go to a paste instead of being sent inline:
+    /// it is longer than `max_lines`, or it contains a fenced code block
+    /// that doesn't fit in the preview.
+    pub fn should_paste(text: &str, line_count: usize, max_lines: usize) -> bool {
+        line_count > max_lines || (text.contains("```") && line_count > PASTE_PREVIEW_LINES)
+    }
+
+    /// The first few non-empty lines of `lines`, skipping code fence
+    /// markers.
+    pub fn preview(lines: &[String]) -> Vec<String> {
+        lines
+            .iter()
+            .filter(|line| !line.trim().is_empty() && !line.trim_start().starts_with("```"))
+            .take(PASTE_PREVIEW_LINES)
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use deadpool_sqlite::{Config, Runtime};
+
+    #[tokio::test]
+    async fn create_and_get_round_trip() {
+        let pool = Config::new(":memory:")
+            // Every connection to ":memory:" opens its own database.
+            .builder(Runtime::Tokio1)
+            .expect("pool config")
+            .max_size(1)
+            .build()
+            .expect("pool");
+        let pastes = PasteService::new(pool, "https://pipo.example/")
+            .await
+            .expect("paste service");
+        let url = pastes.create("line one\nline two").await.expect("create");
+        let id = url
+            .strip_prefix("https://pipo.example/paste/")
+            .expect("public url prefix");
+
+        assert_eq!(id.len(), PASTE_ID_LEN);
+        assert_eq!(
+            pastes.get(id).await.expect("get"),
+            Some("line one\nline two".to_string())
+        );
+        assert_eq!(pastes.get("000000000000").await.expect("get"), None);
+    }
+
+    #[test]
+    fn preview_skips_code_fences() {
+        let lines = ["```rust", "fn main() {", "", "    run();", "}", "```"]
+            .iter()
+            .map(|line| line.to_string())
+            .collect::<Vec<_>>();
+
+        assert!(PasteService::should_paste(
+            &lines.join("\n"),
+            lines.len(),
+            10
+        ));
+        assert!(!PasteService::should_paste("one\ntwo", 2, 10));
+        assert_eq!(
+            PasteService::preview(&lines),
+            vec!["fn main() {", "    run();", "}"]
+        );
+    }
+}
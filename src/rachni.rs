@@ -13,7 +13,7 @@ use tokio::{
     time::{self, Duration},
 };
 
-use crate::Message;
+use crate::{media::MediaStore, Message};
 
 const TRANSPORT_NAME: &'static str = "Rachni";
 
@@ -25,6 +25,7 @@ pub(crate) struct Rachni {
     bus_map: HashMap<String, broadcast::Sender<Message>>,
     pool: Pool,
     pipo_id: Arc<Mutex<i64>>,
+    media: Option<Arc<MediaStore>>,
 }
 
 impl Rachni {
@@ -37,6 +38,7 @@ impl Rachni {
         buses: &Vec<String>,
         pool: Pool,
         pipo_id: Arc<Mutex<i64>>,
+        media: Option<Arc<MediaStore>>,
     ) -> anyhow::Result<Rachni> {
         let server = String::from(server);
         let api_key = String::from(api_key);
@@ -53,6 +55,7 @@ impl Rachni {
             bus_map,
             pool,
             pipo_id,
+            media,
         })
     }
 
@@ -121,10 +124,7 @@ impl Rachni {
 
     async fn send_message(&self, username: &str, message: &str) -> anyhow::Result<()> {
         let pipo_id = self.insert_into_messages_table().await?;
-        let avatar_url = Some(format!(
-            "http://{}/profiles/default/profile_default.png",
-            self.server
-        ));
+        let avatar_url = Some(self.avatar_url(username).await);
         let message = Message::Action {
             sender: self.transport_id,
             pipo_id,
@@ -132,10 +132,12 @@ impl Rachni {
             username: username.to_string(),
             avatar_url,
             thread: None,
+            reply_to: None,
             message: Some(message.to_string()),
             attachments: None,
             is_edit: false,
             irc_flag: false,
+            late: None,
         };
 
         for (_, sender) in self.bus_map.iter() {
@@ -145,6 +147,22 @@ impl Rachni {
         Ok(())
     }
 
+    /// An avatar uploaded to the media store for the streamer, else their
+    /// identicon. Without a media store, Rachni's default profile picture.
+    async fn avatar_url(&self, username: &str) -> String {
+        let Some(media) = self.media.as_ref() else {
+            return format!(
+                "http://{}/profiles/default/profile_default.png",
+                self.server
+            );
+        };
+
+        match media.avatar_url(username).await {
+            Some(url) => url,
+            None => media.identicon_url(username),
+        }
+    }
+
     async fn insert_into_messages_table(&self) -> anyhow::Result<i64> {
         let conn = self.pool.get().await.unwrap();
         let pipo_id = *self.pipo_id.lock().unwrap();
@@ -1,6 +1,7 @@
 use std::{
     collections::{HashMap, VecDeque},
     sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
 use anyhow::anyhow;
@@ -14,16 +15,22 @@ use lazy_static::lazy_static;
 use regex::{Captures, Regex};
 use reqwest::{
     header::{self, HeaderMap},
-    multipart::Form,
+    multipart::{Form, Part},
     Client as HttpClient, Method,
 };
 use rusqlite::params;
 use serde_json::Value;
-use tokio::{net::TcpStream, sync::broadcast};
+use tokio::{
+    net::TcpStream,
+    sync::{broadcast, mpsc},
+};
 use tokio_stream::{wrappers::BroadcastStream, StreamExt, StreamMap};
 use tokio_tungstenite::*;
 
-use crate::{Message, ThreadRef};
+use crate::{
+    direct::DirectBridge, media::MediaStore, threads::ThreadMap, Late, Message, ReplyRef, Services,
+    SlackConfig, ThreadRef,
+};
 
 pub mod objects;
 use objects::{Message as SlackMessage, *};
@@ -31,6 +38,9 @@ use objects::{Message as SlackMessage, *};
 //use parse::*;
 
 const TRANSPORT_NAME: &'static str = "Slack";
+const SLACK_TOPIC_MAX_CHARS: usize = 250;
+// How long answers to a /names are passed on to whoever asked.
+const NAMES_REQUEST_TTL: Duration = Duration::from_secs(15 * 60);
 
 pub(crate) struct Slack {
     transport_id: usize,
@@ -46,6 +56,24 @@ pub(crate) struct Slack {
     users: HashMap<String, User>,
     thread_metadata_cache: HashMap<String, SlackThreadMetadata>,
     seen_event_ids: VecDeque<String>,
+    topic_sync: bool,
+    direct: Option<Arc<DirectBridge>>,
+    media: Option<Arc<MediaStore>>,
+    // Larger files are linked instead of uploaded here.
+    upload_max_bytes: u64,
+    // The largest file that a transport on each channel's bus attaches
+    // itself, by channel name. Files are only downloaded for those.
+    attach_max_bytes: HashMap<String, u64>,
+    threads: Arc<ThreadMap>,
+    // Last known topic of each channel, by name. Topics we set ourselves
+    // come back as channel_topic messages and must not be relayed again.
+    topics: HashMap<String, String>,
+    // When each user last asked for /names. Answers go to everyone who
+    // asked on any transport, and only ours are posted here.
+    names_requests: HashMap<String, Instant>,
+    // Messages with files come back here once the files are fetched.
+    file_share_sender: mpsc::UnboundedSender<FileShare>,
+    file_shares: mpsc::UnboundedReceiver<FileShare>,
 }
 
 #[derive(Clone, Debug, Default)]
@@ -54,6 +82,115 @@ struct SlackThreadMetadata {
     root_excerpt: Option<String>,
 }
 
+/// A message with files, waiting for them to be fetched.
+struct FileShare {
+    ts: Option<String>,
+    thread_ts: Option<String>,
+    channel_name: String,
+    channel_id: String,
+    user: Option<String>,
+    message: Option<String>,
+    attachments: Option<Vec<Attachment>>,
+    files: Vec<crate::Attachment>,
+    is_edit: bool,
+}
+
+/// Fetches files shared on Slack for the other transports, away from the
+/// event loop so that a large file doesn't hold up everything else. Each
+/// file is downloaded at most once, for both the media store and the
+/// transports that attach files themselves.
+#[derive(Clone)]
+struct FileFetcher {
+    http: HttpClient,
+    bot_token: String,
+    media: Option<Arc<MediaStore>>,
+}
+
+impl FileFetcher {
+    /// `file` for the other transports: a link, and its contents when
+    /// they are at most `attach_max_bytes`.
+    async fn bridge_file(
+        &self,
+        id: u64,
+        file: &File,
+        attach_max_bytes: Option<u64>,
+    ) -> crate::Attachment {
+        let attach_max_bytes = attach_max_bytes.filter(|max_bytes| file.size <= *max_bytes);
+        let data = self.file_data(file, attach_max_bytes).await;
+        let url = self.file_url(file, data.as_deref()).await;
+        let data = data
+            .filter(|data| attach_max_bytes.is_some_and(|max_bytes| data.len() as u64 <= max_bytes))
+            .map(|data| crate::FileData(data.into()));
+
+        crate::Attachment {
+            id,
+            filename: Some(file.name.clone()),
+            content_type: Some(file.mimetype.clone()),
+            size: Some(file.size),
+            image_width: Some(file.original_w).filter(|width| *width > 0),
+            image_height: Some(file.original_h).filter(|height| *height > 0),
+            from_url: Some(url.clone()),
+            original_url: Some(file.url_private_download.clone()),
+            fallback: Some(url),
+            data,
+            ..Default::default()
+        }
+    }
+
+    /// The contents of `file`, when the media store or another transport
+    /// takes a file that large: the media store's copy when there is one,
+    /// or else downloaded with the bot token.
+    async fn file_data(&self, file: &File, attach_max_bytes: Option<u64>) -> Option<Vec<u8>> {
+        let media_max_bytes = self
+            .media
+            .as_ref()
+            .map(|media| media.max_bytes())
+            .filter(|max_bytes| file.size <= *max_bytes);
+        let max_bytes = attach_max_bytes.max(media_max_bytes)?;
+
+        if let Some(media) = self.media.as_ref() {
+            if let Some(data) = media.cached(&file.url_private_download, &file.name).await {
+                return Some(data);
+            }
+        }
+
+        match MediaStore::download(
+            &self.http,
+            &file.url_private_download,
+            Some(&self.bot_token),
+            max_bytes,
+        )
+        .await
+        {
+            Ok(data) => Some(data),
+            Err(e) => {
+                eprintln!("Failed to download file {}: {:#}", file.id, e);
+                None
+            }
+        }
+    }
+
+    /// Where other transports should link to `file`. With a media store
+    /// that's a copy of our own made from `data`, as the file itself needs
+    /// the bot token; otherwise only members of the workspace can open it.
+    async fn file_url(&self, file: &File, data: Option<&[u8]>) -> String {
+        let (Some(media), Some(data)) = (self.media.as_ref(), data) else {
+            return file.permalink.clone();
+        };
+
+        match media
+            .store(&file.url_private_download, &file.name, data)
+            .await
+        {
+            Ok(url) => url,
+            Err(e) => {
+                eprintln!("Failed to cache file {}: {:#}", file.id, e);
+                file.permalink.clone()
+            }
+        }
+    }
+}
+
 struct WebSocket {
     endpoint: Option<reqwest::Url>,
     ws_sink: Option<SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, tungstenite::Message>>,
@@ -65,13 +202,13 @@ impl Slack {
     pub async fn new(
         transport_id: usize,
         bus_map: &HashMap<String, broadcast::Sender<Message>>,
-        pipo_id: Arc<Mutex<i64>>,
-        pool: Pool,
-        token: String,
-        bot_token: String,
-        channel_mapping: &HashMap<Arc<String>, Arc<String>>,
+        slack: &SlackConfig,
+        services: &Services,
+        direct: Option<Arc<DirectBridge>>,
+        attach_max_bytes: &HashMap<String, u64>,
     ) -> anyhow::Result<Slack> {
-        let channels = channel_mapping
+        let channels = slack
+            .channel_mapping
             .iter()
             .filter_map(|(channelname, busname)| {
                 if let Some(sender) = bus_map.get(busname.as_ref()) {
@@ -82,6 +219,42 @@ impl Slack {
                 }
             })
             .collect();
+        let attach_max_bytes = slack
+            .channel_mapping
+            .iter()
+            .filter_map(|(channelname, busname)| {
+                Some((
+                    channelname.to_string(),
+                    *attach_max_bytes.get(busname.as_str())?,
+                ))
+            })
+            .collect();
+
+        services
+            .pool
+            .get()
+            .await?
+            .interact(|conn| -> anyhow::Result<()> {
+                // Files we uploaded for a bridged message, so that they go
+                // away with it.
+                conn.execute_batch(
+                    "CREATE TABLE IF NOT EXISTS slack_uploads (
+                                     pipo_id    INTEGER NOT NULL,
+                                     channel_id TEXT NOT NULL,
+                                     file_id    TEXT NOT NULL,
+                                     filename   TEXT NOT NULL,
+                                     PRIMARY KEY (file_id)
+                                     );
+                     CREATE INDEX IF NOT EXISTS slack_uploads_pipo_id
+                       ON slack_uploads (pipo_id);",
+                )?;
+
+                Ok(())
+            })
+            .await
+            .unwrap_or_else(|_| Err(anyhow!("Interact Error")))?;
+
+        let (file_share_sender, file_shares) = mpsc::unbounded_channel();
 
         Ok(Slack {
             transport_id,
@@ -92,16 +265,26 @@ impl Slack {
                 ws_stream: StreamMap::new(),
                 next_connection_id: 0,
             },
-            token,
-            bot_token,
+            token: slack.token.to_string(),
+            bot_token: slack.bot_token.to_string(),
             channels,
-            pool,
-            pipo_id,
+            pool: services.pool.clone(),
+            pipo_id: services.pipo_id.clone(),
             channel_map: HashMap::new(),
             id_map: HashMap::new(),
             users: HashMap::new(),
             thread_metadata_cache: HashMap::new(),
             seen_event_ids: VecDeque::with_capacity(50),
+            topic_sync: slack.topic_sync,
+            direct,
+            media: services.media.clone(),
+            upload_max_bytes: slack.upload_max_bytes,
+            attach_max_bytes,
+            threads: services.threads.clone(),
+            topics: HashMap::new(),
+            names_requests: HashMap::new(),
+            file_share_sender,
+            file_shares,
         })
     }
 
@@ -130,6 +313,9 @@ impl Slack {
         for channel in json["channels"].as_array().unwrap().iter() {
             let name = format!("#{}", channel.get("name").unwrap().as_str().unwrap());
             let id = channel.get("id").unwrap().as_str().unwrap().to_string();
+            if let Some(topic) = channel["topic"]["value"].as_str() {
+                self.topics.insert(name.clone(), topic.to_string());
+            }
             self.channel_map.insert(name.clone(), id.clone());
             self.id_map.insert(id, name);
         }
@@ -143,10 +329,38 @@ impl Slack {
             );
         }
 
+        let mut direct_messages = StreamMap::new();
+
+        if let Some(direct) = self.direct.as_ref() {
+            direct_messages.insert((), direct.subscribe());
+        }
+
         self.get_users_list().await?;
 
         loop {
             tokio::select! {
+            Some(((), message))
+                = StreamExt::next(&mut direct_messages) => {
+                if let Ok(Message::Direct {
+                    sender,
+                    transport,
+                    username,
+                    recipient,
+                    recipient_transport,
+                    message,
+                }) = message {
+                    if let Err(e)
+                    = self.post_direct_message(sender,
+                                   transport,
+                                   username,
+                                   recipient,
+                                   recipient_transport,
+                                   message)
+                    .await {
+                        eprintln!("Failed to post direct message: {}", e);
+                    }
+                }
+                }
             Some((channel, message))
                 = StreamExt::next(&mut input_buses) => {
                 let message = message.unwrap();
@@ -158,10 +372,12 @@ impl Slack {
                     username,
                     avatar_url,
                     thread,
+                    reply_to,
                     message,
                     attachments,
                     is_edit,
                     irc_flag: _,
+                    late,
                     } => {
                     if sender != self.transport_id {
                         if let Err(e)
@@ -171,7 +387,8 @@ impl Slack {
                                        username,
                                        avatar_url,
                                        thread,
-                                       message,
+                                       reply_to,
+                                       Late::mark(late, message),
                                        attachments,
                                        is_edit)
                         .await {
@@ -215,6 +432,7 @@ impl Slack {
                         }
                     }
                     },
+                    Message::Direct { .. } => (),
                     Message::Names {
                     sender,
                     transport,
@@ -233,6 +451,42 @@ impl Slack {
                         }
                     }
                     },
+                    Message::Presence {
+                    sender,
+                    transport,
+                    message,
+                    } => {
+                    if sender != self.transport_id {
+                        if let Err(e)
+                        = self.post_presence_message(&channel,
+                                         transport,
+                                         message)
+                        .await {
+                            eprintln!("Failed to post message:\
+                                   {}", e);
+                        }
+                    }
+                    },
+                    Message::Notice {
+                    sender,
+                    transport,
+                    username,
+                    message,
+                    late,
+                    } => {
+                    if sender != self.transport_id {
+                        let line = format!("-{}- {}", username, message);
+
+                        if let Err(e)
+                        = self.post_presence_message(&channel,
+                                         transport,
+                                         Late::mark(late, Some(line)).unwrap_or_default())
+                        .await {
+                            eprintln!("Failed to post message:\
+                                   {}", e);
+                        }
+                    }
+                    },
                     Message::Pin {
                     sender,
                     pipo_id,
@@ -300,10 +554,12 @@ impl Slack {
                     username,
                     avatar_url,
                     thread,
+                    reply_to,
                     message,
                     attachments,
                     is_edit,
                     irc_flag: _,
+                    late,
                     } => {
                     if sender != self.transport_id {
                         if let Err(e)
@@ -313,7 +569,8 @@ impl Slack {
                                      username,
                                      avatar_url,
                                      thread,
-                                     message,
+                                     reply_to,
+                                     Late::mark(late, message),
                                      attachments,
                                      is_edit)
                         .await {
@@ -322,6 +579,23 @@ impl Slack {
                         }
                     }
                     }
+                    Message::Topic {
+                    sender,
+                    transport: _,
+                    username: _,
+                    topic,
+                    } => {
+                    if sender != self.transport_id {
+                        if let Err(e) = self.set_topic(&channel, topic).await {
+                            eprintln!("Failed to set topic: {}", e);
+                        }
+                    }
+                    }
+                }
+                }
+            Some(share) = self.file_shares.recv() => {
+                if let Err(e) = self.handle_fetched_file_share(share).await {
+                    eprintln!("Failed to bridge shared files: {:#}", e);
                 }
                 }
             message
@@ -531,7 +805,7 @@ impl Slack {
             ));
         }
 
-        Ok(())
+        self.delete_uploads(pipo_id, channel, &[]).await
     }
 
     async fn pins_add(&mut self, channel: &str, pipo_id: i64) -> anyhow::Result<()> {
@@ -628,15 +902,13 @@ impl Slack {
         username: String,
         avatar_url: Option<String>,
         thread: Option<ThreadRef>,
+        reply_to: Option<ReplyRef>,
         message: Option<String>,
         attachments: Option<Vec<crate::Attachment>>,
         is_edit: bool,
     ) -> anyhow::Result<()> {
-        let thread_ts = match thread {
-            Some(thread_ref) => thread_ref.thread_root_id,
-            None => None,
-        };
-        let message = message.map(|s| format!("_{}_", s));
+        let thread_ts = self.thread_ts(thread).await;
+        let message = Slack::quote_reply(reply_to, message.map(|s| format!("_{}_", s)));
 
         if is_edit {
             if let Some(ts) = self.select_slackid_from_messages(pipo_id).await? {
@@ -647,6 +919,7 @@ impl Slack {
                         transport,
                         username,
                         avatar_url,
+                        pipo_id,
                         message,
                         attachments,
                     )
@@ -710,6 +983,14 @@ impl Slack {
                 )),
             };
         } else {
+            let requested = self
+                .names_requests
+                .get(&username)
+                .is_some_and(|asked| asked.elapsed() < NAMES_REQUEST_TTL);
+            if !requested {
+                return Ok(());
+            }
+
             let mut headers = HeaderMap::new();
             let json: Value = serde_json::from_str(&message)?;
             let username = match self.users.get(&username) {
@@ -804,6 +1085,224 @@ impl Slack {
         }
     }
 
+    /// Delivers a DM from another transport to the Slack user called
+    /// `recipient`, provided they are on the allowlist.
+    async fn post_direct_message(
+        &mut self,
+        sender: usize,
+        transport: String,
+        username: String,
+        recipient: String,
+        recipient_transport: Option<usize>,
+        message: String,
+    ) -> anyhow::Result<()> {
+        let Some(direct) = self.direct.clone() else {
+            return Ok(());
+        };
+
+        if !direct.is_for(sender, recipient_transport) {
+            return Ok(());
+        }
+
+        let user = self.users.iter().find(|(name, user)| {
+            name.eq_ignore_ascii_case(&recipient)
+                || Slack::get_username(user)
+                    .is_ok_and(|username| username.eq_ignore_ascii_case(&recipient))
+        });
+        let Some((name, user_id)) =
+            user.and_then(|(name, user)| Some((name.clone(), user.id.clone()?)))
+        else {
+            return Ok(());
+        };
+
+        if !direct.allows(&[&name, &user_id]) {
+            return Ok(());
+        }
+
+        let channel = self.open_direct_channel(&user_id).await?;
+        let message = self.insert_user_names(message);
+
+        self.post_direct_text(
+            &channel,
+            &message,
+            Some(&format!("{} ({})", username, transport)),
+        )
+        .await?;
+
+        direct.remember(&user_id, Some(sender), &username).await
+    }
+
+    /// Bridges a DM sent to the bot. "@bob hello" starts a conversation
+    /// with bob; anything else goes to whoever the author last talked to.
+    async fn handle_direct_message(
+        &mut self,
+        channel: &str,
+        user: Option<String>,
+        text: Option<String>,
+    ) -> anyhow::Result<()> {
+        let (Some(direct), Some(user), Some(text)) = (self.direct.clone(), user, text) else {
+            return Ok(());
+        };
+        let name = self
+            .users
+            .iter()
+            .find(|(_, cached)| cached.id.as_deref() == Some(user.as_str()))
+            .map(|(name, _)| name.clone())
+            .unwrap_or_default();
+        let username = self.get_username_from_cache(&user).unwrap_or(user.clone());
+
+        if !direct.allows(&[&name, &user]) {
+            return self
+                .post_direct_text(
+                    channel,
+                    "Bridged direct messages aren't enabled for you.",
+                    None,
+                )
+                .await;
+        }
+
+        let text = self.parse_usernames(&text).await?;
+
+        if let Some((recipient, message)) = DirectBridge::parse_recipient(&text) {
+            direct.send(
+                TRANSPORT_NAME,
+                &username,
+                recipient,
+                None,
+                message.to_string(),
+            )?;
+
+            return direct.remember(&user, None, recipient).await;
+        }
+
+        match direct.peer(&user).await? {
+            Some((peer_transport, peer)) => {
+                direct.send(TRANSPORT_NAME, &username, &peer, peer_transport, text)
+            }
+            None => {
+                self.post_direct_text(
+                    channel,
+                    "Start a conversation with: @name your message. Replies then go to the last person you wrote to.",
+                    None,
+                )
+                .await
+            }
+        }
+    }
+
+    async fn open_direct_channel(&self, user_id: &str) -> anyhow::Result<String> {
+        let mut headers = HeaderMap::new();
+
+        headers.insert(header::CONTENT_TYPE, "application/json".parse()?);
+        headers.insert(
+            header::AUTHORIZATION,
+            format!("Bearer {}", self.bot_token).parse()?,
+        );
+
+        let response = self
+            .http
+            .request(Method::POST, "https://slack.com/api/conversations.open")
+            .headers(headers)
+            .body(serde_json::json!({ "users": user_id }).to_string())
+            .send()
+            .await?;
+        let json: Value = serde_json::from_str(response.text().await?.as_str())?;
+
+        if json["ok"] == false {
+            return Err(anyhow!(
+                "E: slack.rs:Slack::open_direct_channel(): {}",
+                json["error"]
+            ));
+        }
+
+        json["channel"]["id"]
+            .as_str()
+            .map(str::to_string)
+            .ok_or_else(|| anyhow!("conversations.open returned no channel id"))
+    }
+
+    async fn post_direct_text(
+        &self,
+        channel: &str,
+        text: &str,
+        username: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let mut headers = HeaderMap::new();
+        let body = serde_json::json!({
+            "channel":channel,
+            "text":text,
+            "username":username
+        })
+        .to_string();
+
+        headers.insert(header::CONTENT_TYPE, "application/json".parse()?);
+        headers.insert(
+            header::AUTHORIZATION,
+            format!("Bearer {}", self.bot_token).parse()?,
+        );
+
+        let response = self
+            .http
+            .request(Method::POST, "https://slack.com/api/chat.postMessage")
+            .headers(headers)
+            .body(body)
+            .send()
+            .await?;
+        let json: Value = serde_json::from_str(response.text().await?.as_str())?;
+
+        if json["ok"] == false {
+            return Err(anyhow!(
+                "E: slack.rs:Slack::post_direct_text(): {}",
+                json["error"]
+            ));
+        }
+
+        Ok(())
+    }
+
+    async fn post_presence_message(
+        &mut self,
+        channel: &str,
+        transport: String,
+        message: String,
+    ) -> anyhow::Result<()> {
+        let mut headers = HeaderMap::new();
+        let channel = match self.channel_map.get(channel) {
+            Some(s) => s,
+            None => return Err(anyhow!("Could not find id for channel {}", channel)),
+        };
+        let body = serde_json::json!({
+            "channel":channel,
+            "text":format!("_{}_", message),
+            "username":transport
+        })
+        .to_string();
+
+        headers.insert(header::CONTENT_TYPE, "application/json".parse()?);
+        headers.insert(
+            header::AUTHORIZATION,
+            format!("Bearer {}", self.bot_token).parse()?,
+        );
+
+        let response = self
+            .http
+            .request(Method::POST, "https://slack.com/api/chat.postMessage")
+            .headers(headers)
+            .body(body)
+            .send()
+            .await?;
+        let json: Value = serde_json::from_str(response.text().await?.as_str())?;
+
+        if json["ok"] == false {
+            return Err(anyhow!(
+                "E: slack.rs:Slack::post_presence_message(): {}",
+                json["error"]
+            ));
+        }
+
+        Ok(())
+    }
+
     async fn post_text_message(
         &mut self,
         pipo_id: i64,
@@ -812,17 +1311,13 @@ impl Slack {
         username: String,
         avatar_url: Option<String>,
         thread: Option<ThreadRef>,
+        reply_to: Option<ReplyRef>,
         message: Option<String>,
         attachments: Option<Vec<crate::Attachment>>,
         is_edit: bool,
     ) -> anyhow::Result<()> {
-        let thread_ts = match thread {
-            Some(thread_ref) => match thread_ref.reply_target_id {
-                Some(reply_target_id) => self.get_slackid_from_discordid(reply_target_id).await?,
-                None => thread_ref.thread_root_id,
-            },
-            None => None,
-        };
+        let thread_ts = self.thread_ts(thread).await;
+        let message = Slack::quote_reply(reply_to, message);
 
         if is_edit {
             if let Some(ts) = self.select_slackid_from_messages(pipo_id).await? {
@@ -833,6 +1328,7 @@ impl Slack {
                         transport,
                         username,
                         avatar_url,
+                        pipo_id,
                         message,
                         attachments,
                     )
@@ -870,14 +1366,56 @@ impl Slack {
             Some(s) => s.clone(),
             None => return Err(anyhow!("Could not find id for channel {}", channel)),
         };
-        let message = message.map(|s| self.insert_user_names(s));
+        let mut message = message.map(|s| self.insert_user_names(s));
         let icon_url = Slack::get_avatar_url(avatar_url);
         let username = format!("{} ({})", &username, transport);
-        let attachments = match attachments {
-            Some(a) => Some(self.prepare_attachments_for_slack(&channel, a).await),
-            None => None,
+        let (files, attachments): (Vec<_>, Vec<_>) = attachments
+            .unwrap_or_default()
+            .into_iter()
+            .partition(crate::Attachment::is_file);
+        let attachments = if attachments.is_empty() {
+            None
+        } else {
+            Some(
+                self.prepare_attachments_for_slack(&channel, attachments)
+                    .await,
+            )
         };
-        let body = match thread_ts {
+        let mut uploads = Vec::new();
+
+        // Files that can't be downloaded are linked instead.
+        for file in files {
+            match self.download_file(&file).await {
+                Ok(bytes) => uploads.push((file, bytes)),
+                Err(e) => {
+                    eprintln!("Couldn't upload a file to Slack, linking it: {:#}", e);
+
+                    let link = format!(
+                        "<{}|{}>",
+                        file.from_url.unwrap_or_default(),
+                        file.filename.unwrap_or_default()
+                    );
+
+                    message = Some(match message {
+                        Some(message) if !message.is_empty() => format!("{}\n{}", message, link),
+                        _ => link,
+                    });
+                }
+            }
+        }
+
+        if message.as_deref().map_or(true, str::is_empty) && !uploads.is_empty() {
+            message = Some(
+                uploads
+                    .iter()
+                    .filter_map(|(file, _)| file.filename.as_deref())
+                    .map(|filename| format!("_{}_", filename))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            );
+        }
+
+        let body = match &thread_ts {
             Some(thread_ts) => serde_json::json!({
         "channel":channel,
         "text":message,
@@ -920,9 +1458,290 @@ impl Slack {
             self.update_messages(pipo_id, ts.to_string()).await?;
         }
 
+        // Slack shows uploads as messages of the bot's own, right after
+        // the text they came with.
+        for (file, bytes) in uploads {
+            let result = match self
+                .upload_file(&channel, thread_ts.as_deref(), &file, bytes)
+                .await
+            {
+                Ok(file_id) => self.insert_upload(pipo_id, &channel, &file_id, &file).await,
+                Err(e) => Err(e),
+            };
+
+            if let Err(e) = result {
+                eprintln!("Couldn't upload {:?} to Slack: {:#}", file.filename, e);
+            }
+        }
+
         Ok(())
     }
 
+    /// Downloads a bridged file so it can be uploaded to Slack, as long as
+    /// it's no larger than `upload_max_bytes`. Files that come with their
+    /// contents aren't downloaded again.
+    async fn download_file(&self, file: &crate::Attachment) -> anyhow::Result<Vec<u8>> {
+        let size = file
+            .data
+            .as_ref()
+            .map(|data| data.0.len() as u64)
+            .or(file.size);
+
+        if size.is_some_and(|size| size > self.upload_max_bytes) {
+            return Err(anyhow!(
+                "{:?} is larger than {} bytes",
+                file.filename,
+                self.upload_max_bytes
+            ));
+        }
+
+        if let Some(data) = file.data.as_ref() {
+            return Ok(data.0.to_vec());
+        }
+
+        let url = file
+            .original_url
+            .as_ref()
+            .or(file.from_url.as_ref())
+            .ok_or_else(|| anyhow!("{:?} has no URL", file.filename))?;
+
+        MediaStore::download(&self.http, url, None, self.upload_max_bytes).await
+    }
+
+    /// Uploads `bytes` to `channel_id` the way `files.uploadV2` does: ask
+    /// for an upload URL, send the file there, then share it. Returns the
+    /// id of the new file.
+    async fn upload_file(
+        &self,
+        channel_id: &str,
+        thread_ts: Option<&str>,
+        file: &crate::Attachment,
+        bytes: Vec<u8>,
+    ) -> anyhow::Result<String> {
+        let filename = file.filename.clone().unwrap_or_else(|| "file".to_string());
+        let url = reqwest::Url::parse_with_params(
+            "https://slack.com/api/files.getUploadURLExternal",
+            &[
+                ("filename", filename.as_str()),
+                ("length", bytes.len().to_string().as_str()),
+            ],
+        )?;
+        let response = self
+            .http
+            .request(Method::GET, url)
+            .header(header::AUTHORIZATION, format!("Bearer {}", self.bot_token))
+            .send()
+            .await?;
+        let json: Value = serde_json::from_str(response.text().await?.as_str())?;
+
+        if json["ok"] == false {
+            return Err(anyhow!(
+                "E: slack.rs:Slack::upload_file(): {}",
+                json["error"]
+            ));
+        }
+
+        let (Some(upload_url), Some(file_id)) =
+            (json["upload_url"].as_str(), json["file_id"].as_str())
+        else {
+            return Err(anyhow!("files.getUploadURLExternal returned no upload URL"));
+        };
+        let mut part = Part::bytes(bytes).file_name(filename.clone());
+
+        if let Some(content_type) = file.content_type.as_deref() {
+            part = part.mime_str(content_type)?;
+        }
+
+        self.http
+            .request(Method::POST, upload_url)
+            .multipart(Form::new().part("file", part))
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let body = serde_json::json!({
+        "files":[{"id":file_id, "title":filename}],
+        "channel_id":channel_id,
+        "thread_ts":thread_ts})
+        .to_string();
+        let response = self
+            .http
+            .request(
+                Method::POST,
+                "https://slack.com/api/files.completeUploadExternal",
+            )
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::AUTHORIZATION, format!("Bearer {}", self.bot_token))
+            .body(body)
+            .send()
+            .await?;
+        let json: Value = serde_json::from_str(response.text().await?.as_str())?;
+
+        if json["ok"] == false {
+            return Err(anyhow!(
+                "E: slack.rs:Slack::upload_file(): {}",
+                json["error"]
+            ));
+        }
+
+        Ok(file_id.to_string())
+    }
+
+    async fn insert_upload(
+        &self,
+        pipo_id: i64,
+        channel_id: &str,
+        file_id: &str,
+        file: &crate::Attachment,
+    ) -> anyhow::Result<()> {
+        let conn = self.pool.get().await?;
+        let channel_id = channel_id.to_string();
+        let file_id = file_id.to_string();
+        let filename = file.filename.clone().unwrap_or_default();
+
+        conn.interact(move |conn| -> anyhow::Result<()> {
+            conn.execute(
+                "INSERT OR REPLACE INTO slack_uploads (pipo_id, channel_id, file_id, filename)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![pipo_id, channel_id, file_id, filename],
+            )?;
+
+            Ok(())
+        })
+        .await
+        .unwrap_or_else(|_| Err(anyhow!("Interact Error")))
+    }
+
+    /// Deletes the files uploaded for `pipo_id` in `channel_id`, except
+    /// those named in `keep`.
+    async fn delete_uploads(
+        &self,
+        pipo_id: i64,
+        channel_id: &str,
+        keep: &[String],
+    ) -> anyhow::Result<()> {
+        let conn = self.pool.get().await?;
+        let select_channel_id = channel_id.to_string();
+        let uploads = conn
+            .interact(move |conn| -> anyhow::Result<Vec<(String, String)>> {
+                let mut stmt = conn.prepare(
+                    "SELECT file_id, filename FROM slack_uploads
+                     WHERE pipo_id = ?1 AND channel_id = ?2",
+                )?;
+                let uploads = stmt
+                    .query_map(params![pipo_id, select_channel_id], |row| {
+                        Ok((row.get(0)?, row.get(1)?))
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                Ok(uploads)
+            })
+            .await
+            .unwrap_or_else(|_| Err(anyhow!("Interact Error")))?;
+
+        for (file_id, filename) in uploads {
+            if keep.contains(&filename) {
+                continue;
+            }
+
+            let body = serde_json::json!({ "file": file_id }).to_string();
+            let response = self
+                .http
+                .request(Method::POST, "https://slack.com/api/files.delete")
+                .header(header::CONTENT_TYPE, "application/json")
+                .header(header::AUTHORIZATION, format!("Bearer {}", self.bot_token))
+                .body(body)
+                .send()
+                .await?;
+            let json: Value = serde_json::from_str(response.text().await?.as_str())?;
+
+            // A file someone deleted by hand is gone all the same.
+            if json["ok"] == false
+                && json["error"] != "file_deleted"
+                && json["error"] != "file_not_found"
+            {
+                return Err(anyhow!(
+                    "E: slack.rs:Slack::delete_uploads(): {}",
+                    json["error"]
+                ));
+            }
+
+            let conn = self.pool.get().await?;
+
+            conn.interact(move |conn| -> anyhow::Result<()> {
+                conn.execute(
+                    "DELETE FROM slack_uploads WHERE file_id = ?1",
+                    params![file_id],
+                )?;
+
+                Ok(())
+            })
+            .await
+            .unwrap_or_else(|_| Err(anyhow!("Interact Error")))?;
+        }
+
+        Ok(())
+    }
+
+    async fn set_topic(&mut self, channel: &str, topic: String) -> anyhow::Result<()> {
+        let topic = topic
+            .chars()
+            .take(SLACK_TOPIC_MAX_CHARS)
+            .collect::<String>();
+
+        if !self.topic_sync || self.topics.get(channel) == Some(&topic) {
+            return Ok(());
+        }
+
+        let mut headers = HeaderMap::new();
+        let channel_id = match self.channel_map.get(channel) {
+            Some(s) => s,
+            None => return Err(anyhow!("Could not find id for channel {}", channel)),
+        };
+        let body = serde_json::json!({
+            "channel":channel_id,
+            "topic":topic
+        })
+        .to_string();
+
+        headers.insert(header::CONTENT_TYPE, "application/json".parse()?);
+        headers.insert(
+            header::AUTHORIZATION,
+            format!("Bearer {}", self.bot_token).parse()?,
+        );
+
+        // The truncated topic is what Slack reports back, and what has to
+        // be recognised as ours; otherwise it would be relayed to the other
+        // transports, which would send the full topic back again.
+        let previous = self.topics.insert(channel.to_string(), topic);
+        let result = async {
+            let response = self
+                .http
+                .request(Method::POST, "https://slack.com/api/conversations.setTopic")
+                .headers(headers)
+                .body(body)
+                .send()
+                .await?;
+            let json: Value = serde_json::from_str(response.text().await?.as_str())?;
+
+            if json["ok"] == false {
+                return Err(anyhow!("E: slack.rs:Slack::set_topic(): {}", json["error"]));
+            }
+
+            Ok(())
+        }
+        .await;
+
+        if result.is_err() {
+            match previous {
+                Some(previous) => self.topics.insert(channel.to_string(), previous),
+                None => self.topics.remove(channel),
+            };
+        }
+
+        result
+    }
+
     async fn remove_reaction(
         &mut self,
         pipo_id: i64,
@@ -983,14 +1802,28 @@ impl Slack {
         transport: String,
         username: String,
         avatar_url: Option<String>,
+        pipo_id: i64,
         message: Option<String>,
-        _attachments: Option<Vec<crate::Attachment>>,
+        attachments: Option<Vec<crate::Attachment>>,
     ) -> anyhow::Result<()> {
         let mut headers = HeaderMap::new();
         let channel = match self.channel_map.get(channel) {
-            Some(s) => s,
+            Some(s) => s.clone(),
             None => return Err(anyhow!("Could not find id for channel {}", channel)),
         };
+        // Edits can only take files away; the uploads of those that are
+        // gone are deleted.
+        let keep = attachments
+            .unwrap_or_default()
+            .into_iter()
+            .filter(crate::Attachment::is_file)
+            .filter_map(|file| file.filename)
+            .collect::<Vec<_>>();
+
+        if let Err(e) = self.delete_uploads(pipo_id, &channel, &keep).await {
+            eprintln!("Couldn't delete files removed from {}: {:#}", pipo_id, e);
+        }
+
         let message = message.map(|s| self.insert_user_names(s));
         let icon_url = Slack::get_avatar_url(avatar_url);
         let username = format!("{} ({})", &username, transport);
@@ -1096,6 +1929,28 @@ impl Slack {
         }
     }
 
+    /// Puts a quote of the message `reply_to` answers above `message`,
+    /// Slack having no inline replies.
+    fn quote_reply(reply_to: Option<ReplyRef>, message: Option<String>) -> Option<String> {
+        let quote = match reply_to {
+            Some(ReplyRef {
+                author: Some(author),
+                excerpt: Some(excerpt),
+                ..
+            }) => format!("> *{}*: {}", author, excerpt.replace('\n', " ")),
+            Some(reply_to) => match reply_to.quote() {
+                Some(quote) => format!("> {}", quote.replace('\n', " ")),
+                None => return message,
+            },
+            None => return message,
+        };
+
+        match message {
+            Some(message) => Some(format!("{}\n{}", quote, message)),
+            None => Some(quote),
+        }
+    }
+
     async fn get_user_display_name(&mut self, user: Option<String>) -> anyhow::Result<String> {
         let display_name = match user {
             Some(user) => {
@@ -1308,6 +2163,11 @@ impl Slack {
         match payload.command.as_str() {
             "/names" => {
                 if accepts_response {
+                    self.names_requests
+                        .retain(|_, asked| asked.elapsed() < NAMES_REQUEST_TTL);
+                    self.names_requests
+                        .insert(payload.user_name.clone(), Instant::now());
+
                     let message = Message::Names {
                         sender: self.transport_id,
                         transport: TRANSPORT_NAME.to_string(),
@@ -1351,8 +2211,9 @@ impl Slack {
                 previous_message: prev_message,
                 event_ts: _,
                 thread_ts,
-                channel_type: _,
+                channel_type,
                 edited,
+                topic,
             }) => {
                 let irc_flag = match edited {
                     Some(_) => false,
@@ -1371,6 +2232,16 @@ impl Slack {
                         ))
                     }
                 };
+
+                if channel_type.as_deref() == Some("im") {
+                    // Edits, and the bot's own posts, aren't bridged.
+                    if subtype.is_some() || is_edit {
+                        return Ok(());
+                    }
+
+                    return self.handle_direct_message(&channel, user, text).await;
+                }
+
                 let (channel_name, channel_id) = self.resolve_channel_name_and_id(channel)?;
 
                 let rich_text = if let Some(blocks) = blocks {
@@ -1403,6 +2274,9 @@ impl Slack {
                 match subtype {
                     Some(subtype) => match subtype.as_str() {
                         "bot_add" => return Ok(()),
+                        "channel_topic" => {
+                            return self.handle_channel_topic(&channel_name, user, topic).await
+                        }
                         "bot_message" => {
                             return self
                                 .handle_bot_message(
@@ -1469,6 +2343,7 @@ impl Slack {
                                     user,
                                     rich_text,
                                     attachments,
+                                    Vec::new(),
                                     is_edit,
                                     irc_flag,
                                 )
@@ -1485,6 +2360,7 @@ impl Slack {
                                 user,
                                 rich_text,
                                 attachments,
+                                Vec::new(),
                                 is_edit,
                                 irc_flag,
                             )
@@ -1540,20 +2416,6 @@ impl Slack {
         Ok((channel_name, channel_id))
     }
 
-    fn build_thread_excerpt(message: Option<&str>) -> Option<String> {
-        let message = message?.trim();
-        if message.is_empty() {
-            return None;
-        }
-
-        let excerpt = message.chars().take(120).collect::<String>();
-        if message.chars().count() > 120 {
-            Some(format!("{}…", excerpt))
-        } else {
-            Some(excerpt)
-        }
-    }
-
     fn get_username_from_cache(&self, user_id: &str) -> Option<String> {
         self.users
             .values()
@@ -1584,10 +2446,38 @@ impl Slack {
 
         Ok(SlackThreadMetadata {
             root_author,
-            root_excerpt: Slack::build_thread_excerpt(root_text.as_deref()),
+            root_excerpt: ThreadRef::excerpt(root_text.as_deref()),
         })
     }
 
+    /// The `thread_ts` to post a message in `thread` under: the Slack copy
+    /// of the thread's first message, wherever the thread was started.
+    async fn thread_ts(&self, thread: Option<ThreadRef>) -> Option<String> {
+        let thread_ref = thread?;
+
+        if let Some(root_pipo_id) = thread_ref.root_pipo_id {
+            match self
+                .threads
+                .select_thread(root_pipo_id, self.transport_id)
+                .await
+            {
+                Ok(Some(native)) => return Some(native.thread_id),
+                Ok(None) => {}
+                Err(e) => eprintln!("Failed to look up thread of {}: {:#}", root_pipo_id, e),
+            }
+            if let Ok(Some(ts)) = self.select_slackid_from_messages(root_pipo_id).await {
+                return Some(ts);
+            }
+        }
+        if let Some(reply_target_id) = thread_ref.reply_target_id {
+            if let Ok(Some(ts)) = self.get_slackid_from_discordid(reply_target_id).await {
+                return Some(ts);
+            }
+        }
+
+        thread_ref.thread_root_id
+    }
+
     async fn build_slack_thread_ref(
         &mut self,
         thread_ts: Option<String>,
@@ -1600,17 +2490,15 @@ impl Slack {
             return Ok(None);
         };
 
-        match self.select_id_from_messages(&thread_root_id).await {
-            Some(_) => {}
-            None => {
-                self.insert_into_messages_table(&thread_root_id).await?;
-            }
-        }
+        let root_pipo_id = match self.select_id_from_messages(&thread_root_id).await {
+            Some(id) => id,
+            None => self.insert_into_messages_table(&thread_root_id).await?,
+        };
 
         let metadata = if message_ts == Some(thread_root_id.as_str()) {
             SlackThreadMetadata {
                 root_author: local_author.map(String::from),
-                root_excerpt: Slack::build_thread_excerpt(local_message),
+                root_excerpt: ThreadRef::excerpt(local_message),
             }
         } else if let Some(metadata) = self.thread_metadata_cache.get(&thread_root_id) {
             metadata.clone()
@@ -1622,15 +2510,23 @@ impl Slack {
         self.thread_metadata_cache
             .insert(thread_root_id.clone(), metadata.clone());
 
+        if let Err(e) = self
+            .threads
+            .insert(root_pipo_id, self.transport_id, &thread_root_id, channel_id)
+            .await
+        {
+            eprintln!("Failed to store thread of {}: {:#}", root_pipo_id, e);
+        }
+
         Ok(Some(ThreadRef {
             origin_transport: TRANSPORT_NAME.to_string(),
             reply_target_id: self
                 .select_discordid_from_messages(thread_root_id.clone())
                 .await?,
             thread_root_id: Some(thread_root_id),
+            root_pipo_id: Some(root_pipo_id),
             root_author: metadata.root_author,
             root_excerpt: metadata.root_excerpt,
-            ..Default::default()
         }))
     }
 
@@ -1678,6 +2574,36 @@ impl Slack {
         return self.send_message(channel_name, message).await;
     }
 
+    async fn handle_channel_topic(
+        &mut self,
+        channel_name: &str,
+        user: Option<String>,
+        topic: Option<String>,
+    ) -> anyhow::Result<()> {
+        let topic = topic.unwrap_or_default();
+
+        if self.topics.get(channel_name) == Some(&topic) {
+            return Ok(());
+        }
+
+        self.topics.insert(channel_name.to_string(), topic.clone());
+
+        if !self.topic_sync {
+            return Ok(());
+        }
+
+        let message = Message::Topic {
+            sender: self.transport_id,
+            transport: TRANSPORT_NAME.to_string(),
+            username: self.get_user_display_name(user).await.ok(),
+            topic,
+        };
+
+        self.send_message(channel_name, message).await
+    }
+
+    /// Bridges a message with files once they are fetched, which happens
+    /// in the background.
     async fn handle_file_share(
         &mut self,
         ts: Option<String>,
@@ -1690,37 +2616,51 @@ impl Slack {
         attachments: Option<Vec<Attachment>>,
         is_edit: bool,
     ) -> anyhow::Result<()> {
-        let mut message = message;
-        let mut file_urls = String::new();
-        let mut is_first_line = true;
-
-        if let Some(files) = files {
-            for file in files {
-                let file_url = file.permalink_public;
-
-                if is_first_line {
-                    file_urls.push_str(&format!("{}", file_url));
-                    is_first_line = false;
-                } else {
-                    file_urls.push_str(&format!("\n{}", file_url));
-                }
-            }
-
-            message = Some(match message {
-                Some(s) => format!("{}\n{}", s, file_urls),
-                None => file_urls,
-            });
-        }
-
-        self.handle_message(
+        let fetcher = FileFetcher {
+            http: self.http.clone(),
+            bot_token: self.bot_token.clone(),
+            media: self.media.clone(),
+        };
+        let attach_max_bytes = self.attach_max_bytes.get(channel_name).copied();
+        let sender = self.file_share_sender.clone();
+        let mut share = FileShare {
             ts,
             thread_ts,
-            channel_name,
-            channel_id,
+            channel_name: channel_name.to_string(),
+            channel_id: channel_id.to_string(),
             user,
             message,
             attachments,
+            files: Vec::new(),
             is_edit,
+        };
+
+        tokio::spawn(async move {
+            for (id, file) in files.unwrap_or_default().into_iter().enumerate() {
+                let file = fetcher
+                    .bridge_file(id as u64, &file, attach_max_bytes)
+                    .await;
+                share.files.push(file);
+            }
+
+            // Only fails once we're gone, along with the message.
+            let _ = sender.send(share);
+        });
+
+        Ok(())
+    }
+
+    async fn handle_fetched_file_share(&mut self, share: FileShare) -> anyhow::Result<()> {
+        self.handle_message(
+            share.ts,
+            share.thread_ts,
+            &share.channel_name,
+            &share.channel_id,
+            share.user,
+            share.message,
+            share.attachments,
+            share.files,
+            share.is_edit,
             false,
         )
         .await
@@ -1793,10 +2733,12 @@ impl Slack {
             username,
             avatar_url,
             thread: None,
+            reply_to: None,
             message: message,
             attachments: None,
             is_edit,
             irc_flag,
+            late: None,
         };
 
         return self.send_message(channel, message).await;
@@ -1844,6 +2786,7 @@ impl Slack {
                 thread_ts,
                 channel_type,
                 edited,
+                topic,
             }) => Event::Message(SlackMessage {
                 channel: Some(String::from(channel)),
                 hidden: Some(hidden),
@@ -1866,6 +2809,7 @@ impl Slack {
                 thread_ts,
                 channel_type,
                 edited,
+                topic,
             }),
             _ => return Err(anyhow!("message not an Event::Message")),
         };
@@ -2060,11 +3004,12 @@ impl Slack {
         user: Option<String>,
         message: Option<String>,
         attachments: Option<Vec<Attachment>>,
+        files: Vec<crate::Attachment>,
         mut is_edit: bool,
         mut irc_flag: bool,
     ) -> anyhow::Result<()> {
         let has_message = message.is_some();
-        let has_attachments = attachments.is_some();
+        let has_attachments = attachments.is_some() || !files.is_empty();
         let ts = ts.ok_or_else(|| anyhow!("Message has no timestamp."))?;
         let pipo_id = match self.select_id_from_messages(&ts).await {
             Some(id) => id,
@@ -2090,17 +3035,21 @@ impl Slack {
             )
             .await?;
         let avatar_url = Slack::get_avatar_url_for_user(&user)?;
-        let attachments = match attachments {
-            Some(attachments) => Some(self.handle_attachments(attachments).await),
-            None => None,
+        let mut attachments = match attachments {
+            Some(attachments) => self.handle_attachments(attachments).await,
+            None => Vec::new(),
         };
 
+        attachments.extend(files);
+
+        let attachments = Some(attachments).filter(|attachments| !attachments.is_empty());
+
         if has_message || has_attachments {
             self.thread_metadata_cache.insert(
                 ts.clone(),
                 SlackThreadMetadata {
                     root_author: Some(username.clone()),
-                    root_excerpt: Slack::build_thread_excerpt(message.as_deref()),
+                    root_excerpt: ThreadRef::excerpt(message.as_deref()),
                 },
             );
 
@@ -2111,10 +3060,12 @@ impl Slack {
                 username,
                 avatar_url,
                 thread,
+                reply_to: None,
                 message: message,
                 attachments,
                 is_edit,
                 irc_flag,
+                late: None,
             };
 
             return self.send_message(channel_name, message).await;
@@ -2157,6 +3108,7 @@ impl Slack {
                     thread_ts: _,
                     channel_type: _,
                     edited: _,
+                    topic: _,
                 }) => {
                     let channel = match self.id_map.get(&channel) {
                         Some(c) => c.to_string(),
@@ -2230,6 +3182,7 @@ impl Slack {
                 thread_ts: _,
                 channel_type: _,
                 edited: _,
+                topic: _,
             }) => {
                 let channel = match channel {
                     Some(c) => match self.id_map.get(&c) {
@@ -2432,33 +3385,6 @@ impl Slack {
         Ok(())
     }
 
-    #[allow(dead_code)]
-    async fn make_file_public(&self, file_id: &str) -> anyhow::Result<()> {
-        let mut headers = HeaderMap::new();
-
-        headers.insert(header::CONTENT_TYPE, "application/json".parse()?);
-        headers.insert(
-            header::AUTHORIZATION,
-            ("Bearer ".to_owned() + &self.bot_token).parse()?,
-        );
-
-        let body = serde_json::json!({"file":file_id}).to_string();
-
-        let response = self
-            .http
-            .request(Method::POST, "https://slack.com/api/files.sharedPublicURL")
-            .headers(headers)
-            .body(body)
-            .send()
-            .await?;
-        let json: Value = serde_json::from_str(response.text().await?.as_str())?;
-        if json["ok"] == false {
-            return Err(anyhow!("get_user_display_name() {:?}", json));
-        }
-
-        Ok(())
-    }
-
     #[async_recursion]
     async fn convert_element_to_string(&mut self, element: &Element) -> anyhow::Result<String> {
         match element {
@@ -106,6 +106,8 @@ pub struct Message {
     pub thread_ts: Option<String>,
     pub channel_type: Option<String>,
     pub edited: Option<Edited>,
+    /// The new topic, on `channel_topic` messages.
+    pub topic: Option<String>,
 }
 
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
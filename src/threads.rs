@@ -0,0 +1,252 @@
+use anyhow::anyhow;
+use deadpool_sqlite::Pool;
+use rusqlite::{params, OptionalExtension};
+
+/// A thread as one transport knows it: its native id (a Discord thread
+/// channel, a Slack `thread_ts` or an IRC `msgid`) and the channel it's in.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct NativeThread {
+    pub(crate) thread_id: String,
+    pub(crate) channel: String,
+}
+
+/// Links the first message of every bridged thread to the threads that
+/// carry it on each transport, so that replies keep landing in the same
+/// thread across restarts, including threads that have been archived
+/// since.
+pub(crate) struct ThreadMap {
+    pool: Pool,
+}
+
+impl ThreadMap {
+    pub async fn new(pool: Pool) -> anyhow::Result<ThreadMap> {
+        pool.get()
+            .await?
+            .interact(|conn| -> anyhow::Result<()> {
+                conn.execute_batch(
+                    "CREATE TABLE IF NOT EXISTS threads (
+                                     root_pipo_id INTEGER NOT NULL,
+                                     transport_id INTEGER NOT NULL,
+                                     thread_id    TEXT NOT NULL,
+                                     channel      TEXT NOT NULL,
+                                     PRIMARY KEY (root_pipo_id, transport_id)
+                                     );
+                     CREATE INDEX IF NOT EXISTS threads_thread_id
+                       ON threads (transport_id, thread_id);
+                     -- pipo ids wrap around, and a reused id no longer
+                     -- starts the thread it used to.
+                     CREATE TRIGGER IF NOT EXISTS threads_forget_reused_root
+                     AFTER INSERT ON messages
+                     BEGIN
+                       DELETE FROM threads WHERE root_pipo_id = new.id;
+                     END;",
+                )?;
+
+                Ok(())
+            })
+            .await
+            .unwrap_or_else(|_| Err(anyhow!("Interact Error")))?;
+
+        Ok(ThreadMap { pool })
+    }
+
+    /// Records that `transport_id` carries the thread of `root_pipo_id` as
+    /// `thread_id` in `channel`.
+    pub async fn insert(
+        &self,
+        root_pipo_id: i64,
+        transport_id: usize,
+        thread_id: &str,
+        channel: &str,
+    ) -> anyhow::Result<()> {
+        let thread_id = thread_id.to_string();
+        let channel = channel.to_string();
+
+        self.pool
+            .get()
+            .await?
+            .interact(move |conn| -> anyhow::Result<()> {
+                conn.execute(
+                    "INSERT OR REPLACE INTO threads
+                       (root_pipo_id, transport_id, thread_id, channel)
+                     VALUES (?1, ?2, ?3, ?4)",
+                    params![root_pipo_id, transport_id, thread_id, channel],
+                )?;
+
+                Ok(())
+            })
+            .await
+            .unwrap_or_else(|_| Err(anyhow!("Interact Error")))
+    }
+
+    /// Forgets the thread `transport_id` carries the thread of
+    /// `root_pipo_id` in, e.g. once it has been deleted.
+    pub async fn delete(&self, root_pipo_id: i64, transport_id: usize) -> anyhow::Result<()> {
+        self.pool
+            .get()
+            .await?
+            .interact(move |conn| -> anyhow::Result<()> {
+                conn.execute(
+                    "DELETE FROM threads WHERE root_pipo_id = ?1 AND transport_id = ?2",
+                    params![root_pipo_id, transport_id],
+                )?;
+
+                Ok(())
+            })
+            .await
+            .unwrap_or_else(|_| Err(anyhow!("Interact Error")))
+    }
+
+    /// The thread `transport_id` carries the thread of `root_pipo_id` in.
+    pub async fn select_thread(
+        &self,
+        root_pipo_id: i64,
+        transport_id: usize,
+    ) -> anyhow::Result<Option<NativeThread>> {
+        self.pool
+            .get()
+            .await?
+            .interact(move |conn| -> anyhow::Result<Option<NativeThread>> {
+                Ok(conn
+                    .query_row(
+                        "SELECT thread_id, channel FROM threads
+                         WHERE root_pipo_id = ?1 AND transport_id = ?2",
+                        params![root_pipo_id, transport_id],
+                        |row| {
+                            Ok(NativeThread {
+                                thread_id: row.get(0)?,
+                                channel: row.get(1)?,
+                            })
+                        },
+                    )
+                    .optional()?)
+            })
+            .await
+            .unwrap_or_else(|_| Err(anyhow!("Interact Error")))
+    }
+
+    /// The first message of the thread `transport_id` knows as
+    /// `thread_id`.
+    pub async fn select_root(
+        &self,
+        transport_id: usize,
+        thread_id: &str,
+    ) -> anyhow::Result<Option<i64>> {
+        let thread_id = thread_id.to_string();
+
+        self.pool
+            .get()
+            .await?
+            .interact(move |conn| -> anyhow::Result<Option<i64>> {
+                Ok(conn
+                    .query_row(
+                        "SELECT root_pipo_id FROM threads
+                         WHERE transport_id = ?1 AND thread_id = ?2",
+                        params![transport_id, thread_id],
+                        |row| row.get(0),
+                    )
+                    .optional()?)
+            })
+            .await
+            .unwrap_or_else(|_| Err(anyhow!("Interact Error")))
+    }
+
+    /// Every thread `transport_id` carries.
+    pub async fn select_all(&self, transport_id: usize) -> anyhow::Result<Vec<NativeThread>> {
+        self.pool
+            .get()
+            .await?
+            .interact(move |conn| -> anyhow::Result<Vec<NativeThread>> {
+                let mut stmt = conn.prepare(
+                    "SELECT thread_id, channel FROM threads
+                     WHERE transport_id = ?1",
+                )?;
+                let threads = stmt
+                    .query_map(params![transport_id], |row| {
+                        Ok(NativeThread {
+                            thread_id: row.get(0)?,
+                            channel: row.get(1)?,
+                        })
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                Ok(threads)
+            })
+            .await
+            .unwrap_or_else(|_| Err(anyhow!("Interact Error")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use deadpool_sqlite::{Config, Runtime};
+
+    #[tokio::test]
+    async fn threads_are_forgotten_when_deleted_or_their_root_id_is_reused() {
+        let pool = Config::new(":memory:")
+            // Every connection to ":memory:" opens its own database.
+            .builder(Runtime::Tokio1)
+            .expect("pool config")
+            .max_size(1)
+            .build()
+            .expect("pool");
+
+        pool.get()
+            .await
+            .expect("connection")
+            .interact(|conn| {
+                conn.execute_batch(
+                    "CREATE TABLE messages (id INTEGER PRIMARY KEY, slackid TEXT, discordid INTEGER);
+                     INSERT INTO messages (id, discordid) VALUES (1, 66);",
+                )
+            })
+            .await
+            .expect("interact")
+            .expect("messages table");
+
+        let threads = ThreadMap::new(pool.clone()).await.expect("threads");
+
+        threads.insert(1, 0, "66", "55").await.expect("insert");
+        threads
+            .insert(1, 1, "1700000000.000100", "C01")
+            .await
+            .expect("insert");
+
+        assert_eq!(
+            threads.select_thread(1, 0).await.expect("select"),
+            Some(NativeThread {
+                thread_id: "66".to_string(),
+                channel: "55".to_string(),
+            })
+        );
+        assert_eq!(
+            threads
+                .select_root(1, "1700000000.000100")
+                .await
+                .expect("select"),
+            Some(1)
+        );
+
+        threads.delete(1, 1).await.expect("delete");
+        assert_eq!(
+            threads
+                .select_root(1, "1700000000.000100")
+                .await
+                .expect("select"),
+            None
+        );
+        assert!(threads.select_thread(1, 0).await.expect("select").is_some());
+
+        pool.get()
+            .await
+            .expect("connection")
+            .interact(|conn| conn.execute("INSERT OR REPLACE INTO messages (id) VALUES (1)", []))
+            .await
+            .expect("interact")
+            .expect("reuse id");
+
+        assert_eq!(threads.select_thread(1, 0).await.expect("select"), None);
+        assert!(threads.select_all(1).await.expect("select").is_empty());
+    }
+}